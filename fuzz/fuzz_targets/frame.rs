@@ -0,0 +1,45 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use std::io::{self, Read};
+use tmkms_light::framing::{read_frame, write_frame};
+
+/// keeps allocations small
+const MAX_LEN: u32 = 1 << 16;
+
+/// returns at most `chunk` bytes per read (to exercise short reads)
+struct ChunkedReader<'a> {
+    data: &'a [u8],
+    chunk: usize,
+}
+
+impl<'a> Read for ChunkedReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.chunk.min(buf.len()).min(self.data.len());
+        buf[..n].copy_from_slice(&self.data[..n]);
+        self.data = &self.data[n..];
+        Ok(n)
+    }
+}
+
+// the first byte is the maximum read size, the rest is the stream
+fuzz_target!(|data: &[u8]| {
+    if let Some((&chunk, stream)) = data.split_first() {
+        let mut reader = ChunkedReader {
+            data: stream,
+            chunk: chunk.max(1) as usize,
+        };
+        if let Ok(frame) = read_frame(&mut reader, MAX_LEN) {
+            // a frame is only returned if it was read completely and its checksum matched
+            let mut encoded = Vec::new();
+            write_frame(
+                &mut encoded,
+                frame.version,
+                frame.frame_type,
+                &frame.payload,
+                MAX_LEN,
+            )
+            .expect("payload fits");
+            assert_eq!(&encoded[..], &stream[..encoded.len()]);
+        }
+    }
+});
@@ -0,0 +1,9 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use tmkms_nitro_helper::{NitroConfig, NitroRequest, NitroResponse};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = serde_json::from_slice::<NitroConfig>(data);
+    let _ = serde_json::from_slice::<NitroRequest>(data);
+    let _ = serde_json::from_slice::<NitroResponse>(data);
+});
@@ -0,0 +1,7 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use tmkms_light::rpc::Request;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Request::read(&mut &data[..]);
+});
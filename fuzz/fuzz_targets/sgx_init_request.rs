@@ -0,0 +1,20 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use sgx_isa::Keyrequest;
+use std::convert::TryInto;
+use tmkms_light_sgx_runner::{SgxInitRequest, SgxInitResponse};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(SgxInitRequest::Start {
+        sealed_key,
+        secret_connection,
+        ..
+    }) = serde_json::from_slice(data)
+    {
+        let _: Result<Keyrequest, ()> = sealed_key.seal_key_request.try_into();
+        if let Some(conn) = secret_connection {
+            let _: Result<Keyrequest, ()> = conn.sealed_key.seal_key_request.try_into();
+        }
+    }
+    let _ = serde_json::from_slice::<SgxInitResponse>(data);
+});
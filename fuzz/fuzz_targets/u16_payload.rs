@@ -0,0 +1,41 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use std::io::{self, Read};
+use tmkms_light::error::ErrorKind;
+use tmkms_light::utils::{read_u16_payload, write_u16_payload};
+
+/// returns at most `chunk` bytes per read (to exercise short reads)
+struct ChunkedReader<'a> {
+    data: &'a [u8],
+    chunk: usize,
+}
+
+impl<'a> Read for ChunkedReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.chunk.min(buf.len()).min(self.data.len());
+        buf[..n].copy_from_slice(&self.data[..n]);
+        self.data = &self.data[n..];
+        Ok(n)
+    }
+}
+
+// the first byte is the maximum read size, the rest is the stream
+fuzz_target!(|data: &[u8]| {
+    if let Some((&chunk, stream)) = data.split_first() {
+        let mut reader = ChunkedReader {
+            data: stream,
+            chunk: chunk.max(1) as usize,
+        };
+        match read_u16_payload(&mut reader) {
+            Ok(payload) => {
+                // a payload is only returned if it was read completely
+                let mut framed = Vec::new();
+                write_u16_payload(&mut framed, &payload).expect("payload fits");
+                assert_eq!(&framed[..], &stream[..framed.len()]);
+            }
+            // only a closed stream is an I/O error, any truncation is a framing one
+            Err(e) if stream.is_empty() => assert_eq!(e.kind(), &ErrorKind::IoError),
+            Err(e) => assert_eq!(e.kind(), &ErrorKind::FramingError),
+        }
+    }
+});
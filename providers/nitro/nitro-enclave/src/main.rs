@@ -1,38 +1,103 @@
 mod nitro;
-use nix::sys::socket::SockAddr;
+#[cfg(not(feature = "nitro"))]
+use anomaly::format_err;
+#[cfg(not(feature = "nitro"))]
+use nitro::kms::LocalKms;
+#[cfg(feature = "nitro")]
+use nitro::kms::NitroKms;
+use nitro::logs;
+#[cfg(not(feature = "nitro"))]
+use nitro::nsm::NoAttester;
+#[cfg(feature = "nitro")]
+use nitro::nsm::Nsm;
+#[cfg(not(feature = "nitro"))]
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+#[cfg(not(feature = "nitro"))]
+use tmkms_light::error::ErrorKind::IoError;
+use tmkms_nitro_helper::transport::Transport;
+#[cfg(not(feature = "nitro"))]
+use tmkms_nitro_helper::transport::UnixTransport;
+#[cfg(feature = "nitro")]
+use tmkms_nitro_helper::transport::VsockTransport;
 use tracing::{error, info, warn, Level};
-use tracing_subscriber::FmtSubscriber;
-use vsock::VsockListener;
 
 fn main() {
-    // TODO: subscriber for production to forward to vsock
-    // TODO: the log level via a command line arg?
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::INFO)
-        .finish();
-
-    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+    // the records are forwarded to the helper once it pushes the config
+    let (log_writer, log_forwarder) = logs::channel(logs::LOG_BUFFER_SIZE);
+    if let Err(e) = logs::init(log_writer, Level::INFO) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 
-    let port = std::env::args()
+    let mut args = std::env::args().skip(1);
+    let port = args
         .next()
-        .map(|x| x.parse::<u32>().ok())
-        .flatten()
+        .and_then(|x| x.parse::<u32>().ok())
         .unwrap_or(5050);
-    const VMADDR_CID_ANY: u32 = 0xFFFFFFFF;
-    let addr = SockAddr::new_vsock(VMADDR_CID_ANY, port);
-    let listener = VsockListener::bind(&addr).expect("bind address");
-    info!("waiting for config to be pushed on {}", addr);
-    for conn in listener.incoming() {
+    #[cfg(feature = "nitro")]
+    let (transport, kms, attester) = (VsockTransport::enclave(), NitroKms, Nsm);
+    // running as a normal process on the host (e.g. for integration tests):
+    // the connections are made via Unix sockets in the provided directory
+    // and the keys are sealed with a local key file (generated if missing)
+    #[cfg(not(feature = "nitro"))]
+    let (transport, kms, attester) = {
+        let dir = PathBuf::from(args.next().unwrap_or_else(|| ".".to_owned()));
+        let key_path = args
+            .next()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| dir.join("unseal.key"));
+        warn!(
+            "running outside of the enclave (sockets in {}, local key {})",
+            dir.display(),
+            key_path.display()
+        );
+        let kms = if key_path.exists() {
+            LocalKms::from_file(&key_path)
+        } else {
+            let kms = LocalKms::generate();
+            std::fs::write(&key_path, kms.key_bytes())
+                .map(|_| kms)
+                .map_err(|e| format_err!(IoError, "failed to write local key: {}", e).into())
+        };
+        let kms = kms.unwrap_or_else(|e| {
+            error!("{}", e);
+            std::process::exit(1);
+        });
+        (UnixTransport::new(dir), kms, NoAttester)
+    };
+    let listener = transport.listen(port).expect("bind address");
+    // (the Unix transport used outside of the enclave isn't `Copy`)
+    #[allow(clippy::clone_on_copy)]
+    let log_port = log_forwarder.launch_forwarder(Box::new(transport.clone()));
+    let enclave = Arc::new(
+        nitro::Enclave::new(
+            Box::new(transport),
+            Box::new(kms.clone()),
+            Box::new(kms),
+            Box::new(attester),
+        )
+        .with_log_forwarding(log_port),
+    );
+    info!("waiting for config to be pushed on port {}", port);
+    loop {
+        #[cfg(feature = "nitro")]
         if aws_ne_sys::seed_entropy(512).is_err() {
             error!("failed to seed initial entropy!");
             std::process::exit(1);
         }
-        match conn {
+        match listener.accept() {
             Ok(stream) => {
-                info!("got connection on {:?}", addr);
-                if let Err(e) = nitro::entry(stream) {
-                    error!("io error {}", e);
-                }
+                info!("got connection on port {}", port);
+                // the start request keeps its connection for signing,
+                // so that e.g. the attestation requests can still be handled
+                let enclave = enclave.clone();
+                thread::spawn(move || {
+                    if let Err(e) = enclave.handle(stream) {
+                        error!("io error {}", e);
+                    }
+                });
             }
             Err(e) => {
                 warn!("connection error {}", e);
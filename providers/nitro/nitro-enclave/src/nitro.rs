@@ -1,11 +1,20 @@
+/// encryption of the generated keys and decryption of the sealed keys
+pub mod kms;
+/// log forwarding to the host
+pub mod logs;
+/// attestation documents
+pub mod nsm;
 /// state persistence helper;
 mod state;
 
 use anomaly::format_err;
 use ed25519_dalek as ed25519;
-use nix::sys::socket::SockAddr;
-use std::io;
-use std::os::unix::io::AsRawFd;
+use kms::{KeySealer, KeyUnsealer};
+use nsm::Attester;
+use rand_core::OsRng;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::Sender;
+use std::sync::Mutex;
 use std::thread;
 use std::time::Duration;
 use subtle::ConstantTimeEq;
@@ -16,21 +25,22 @@ use tmkms_light::config::validator::ValidatorConfig;
 use tmkms_light::connection::{Connection, PlainConnection};
 use tmkms_light::error::{
     Error,
-    ErrorKind::{AccessError, InvalidKey, IoError},
+    ErrorKind::{AccessError, CryptoError, InvalidKey, IoError, ProtocolError},
+};
+use tmkms_light::session::SigningSwitch;
+use tmkms_nitro_helper::transport::Transport;
+use tmkms_nitro_helper::{
+    ConfigChannel, NitroConfig, NitroError, NitroKeygenConfig, NitroRequest, NitroResponse,
 };
-use tmkms_light::utils::read_u16_payload;
-use tmkms_nitro_helper::{NitroConfig, VSOCK_PROXY_CID};
 use tracing::{error, info, trace, warn};
-use vsock::VsockStream;
-use zeroize::Zeroizing;
 
 fn get_secret_connection(
+    transport: &dyn Transport,
     vsock_port: u32,
     identity_key: &ed25519::Keypair,
     peer_id: Option<Id>,
 ) -> io::Result<Box<dyn Connection>> {
-    let addr = SockAddr::new_vsock(VSOCK_PROXY_CID, vsock_port);
-    let socket = vsock::VsockStream::connect(&addr)?;
+    let socket = transport.connect(vsock_port)?;
     info!("KMS node ID: {}", PublicKey::from(identity_key));
     // the `Clone` is not derived for Keypair
     // TODO: https://github.com/dalek-cryptography/ed25519-dalek/issues/76
@@ -67,26 +77,28 @@ fn get_secret_connection(
 
 /// keeps retrying with approx. 1 sec sleep until it manages to connect to tendermint privval endpoint
 pub fn get_connection(
+    transport: &dyn Transport,
     config: &NitroConfig,
     id_keypair: Option<&ed25519::Keypair>,
 ) -> Box<dyn Connection> {
     loop {
         let conn: io::Result<Box<dyn Connection>> = if let Some(ikp) = id_keypair {
-            get_secret_connection(config.enclave_tendermint_conn, ikp, config.peer_id)
+            get_secret_connection(
+                transport,
+                config.enclave_tendermint_conn,
+                ikp,
+                config.peer_id,
+            )
+        } else if let Ok(socket) = transport.connect(config.enclave_tendermint_conn) {
+            trace!("tendermint vsock port: {}", config.enclave_tendermint_conn);
+            trace!("tendermint stream: {:?}", socket);
+            trace!("tendermint fd: {}", socket.as_raw_fd());
+            info!("connected to validator successfully");
+            let plain_conn = PlainConnection::new(socket);
+            Ok(Box::new(plain_conn))
         } else {
-            let addr = SockAddr::new_vsock(VSOCK_PROXY_CID, config.enclave_tendermint_conn);
-            if let Ok(socket) = vsock::VsockStream::connect(&addr) {
-                trace!("tendermint vsock port: {}", config.enclave_tendermint_conn);
-                trace!("tendermint peer addr: {:?}", socket.peer_addr());
-                trace!("tendermint local addr: {:?}", socket.local_addr());
-                trace!("tendermint fd: {}", socket.as_raw_fd());
-                info!("connected to validator successfully");
-                let plain_conn = PlainConnection::new(socket);
-                Ok(Box::new(plain_conn))
-            } else {
-                warn!("vsock failed to connect to validator");
-                Err(io::ErrorKind::Other.into())
-            }
+            warn!("failed to connect to validator");
+            Err(io::ErrorKind::Other.into())
         };
         if let Err(e) = conn {
             error!("tendermint connection error {:?}", e);
@@ -97,78 +109,536 @@ pub fn get_connection(
     }
 }
 
-/// a simple req-rep handling loop
-pub fn entry(mut config_stream: VsockStream) -> Result<(), Error> {
-    let json_raw = read_u16_payload(&mut config_stream)
-        .map_err(|_e| format_err!(IoError, "failed to read config"))?;
-    let mconfig = serde_json::from_slice(&json_raw);
-    match mconfig {
-        Ok(config) => {
-            let config: NitroConfig = config;
-            let key_bytes = Zeroizing::new(
-                aws_ne_sys::kms_decrypt(
-                    config.aws_region.as_bytes(),
-                    config.credentials.aws_key_id.as_bytes(),
-                    config.credentials.aws_secret_key.as_bytes(),
-                    config.credentials.aws_session_token.as_bytes(),
-                    config.sealed_consensus_key.as_ref(),
-                )
-                .map_err(|_e| format_err!(AccessError, "failed to decrypt key"))?,
-            );
-            let secret = ed25519::SecretKey::from_bytes(&*key_bytes)
-                .map_err(|e| format_err!(InvalidKey, "invalid Ed25519 key: {}", e))?;
-            let public = ed25519::PublicKey::from(&secret);
-            let keypair = ed25519::Keypair { secret, public };
-            let id_keypair = if let Some(ref ciphertext) = config.sealed_id_key {
-                let id_key_bytes = Zeroizing::new(
-                    aws_ne_sys::kms_decrypt(
-                        config.aws_region.as_bytes(),
-                        config.credentials.aws_key_id.as_bytes(),
-                        config.credentials.aws_secret_key.as_bytes(),
-                        config.credentials.aws_session_token.as_bytes(),
-                        ciphertext.as_ref(),
-                    )
-                    .map_err(|_e| format_err!(AccessError, "failed to decrypt key"))?,
+/// decrypts the sealed key
+fn unseal_keypair(
+    unsealer: &dyn KeyUnsealer,
+    config: &NitroConfig,
+    ciphertext: &[u8],
+    key_name: &str,
+) -> Result<ed25519::Keypair, NitroError> {
+    let key_bytes = unsealer
+        .unseal(config, ciphertext)
+        .map_err(|e| match e.kind() {
+            InvalidKey => NitroError::InvalidKey(format!("{} key: {}", key_name, e)),
+            _ => NitroError::DecryptionFailed(format!("{} key: {}", key_name, e)),
+        })?;
+    let secret = ed25519::SecretKey::from_bytes(&key_bytes)
+        .map_err(|e| NitroError::InvalidKey(format!("{} key: {}", key_name, e)))?;
+    let public = ed25519::PublicKey::from(&secret);
+    Ok(ed25519::Keypair { secret, public })
+}
+
+/// generates a new keypair and encrypts its secret key
+fn generate_key(
+    sealer: &dyn KeySealer,
+    config: &NitroKeygenConfig,
+) -> Result<NitroResponse, NitroError> {
+    let keypair = ed25519::Keypair::generate(&mut OsRng);
+    let sealed_key = sealer
+        .seal(config, keypair.secret.as_bytes())
+        .map_err(|e| NitroError::EncryptionFailed(e.to_string()))?;
+    info!("generated a new key");
+    Ok(NitroResponse::KeyGenerated {
+        public_key: tendermint::PublicKey::Ed25519(keypair.public),
+        sealed_key,
+    })
+}
+
+/// the signing status of the enclave
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Status {
+    /// waiting for the start request
+    Idle,
+    /// the start request is being handled
+    Starting,
+    /// signing with the consensus key
+    Started(ed25519::PublicKey),
+}
+
+/// Handles the requests on the config channel
+/// (the connections to the host are made via the transport, i.e. vsock in the enclave)
+#[derive(Debug)]
+pub struct Enclave {
+    transport: Box<dyn Transport>,
+    unsealer: Box<dyn KeyUnsealer>,
+    sealer: Box<dyn KeySealer>,
+    attester: Box<dyn Attester>,
+    status: Mutex<Status>,
+    log_port: Mutex<Option<Sender<u32>>>,
+    switch: SigningSwitch,
+}
+
+impl Enclave {
+    /// creates the request handler
+    pub fn new(
+        transport: Box<dyn Transport>,
+        unsealer: Box<dyn KeyUnsealer>,
+        sealer: Box<dyn KeySealer>,
+        attester: Box<dyn Attester>,
+    ) -> Self {
+        Self {
+            transport,
+            unsealer,
+            sealer,
+            attester,
+            status: Mutex::new(Status::Idle),
+            log_port: Mutex::new(None),
+            switch: SigningSwitch::default(),
+        }
+    }
+
+    /// the log forwarder will be given the port from the start request
+    pub fn with_log_forwarding(self, log_port: Sender<u32>) -> Self {
+        *self.log_port.lock().expect("log port lock") = Some(log_port);
+        self
+    }
+
+    fn status(&self) -> Status {
+        *self.status.lock().expect("status lock")
+    }
+
+    fn set_status(&self, status: Status) {
+        *self.status.lock().expect("status lock") = status;
+    }
+
+    /// decrypts the keys and loads the state
+    fn prepare_start(
+        &self,
+        config: &NitroConfig,
+    ) -> Result<
+        (
+            ed25519::Keypair,
+            Option<ed25519::Keypair>,
+            state::StateHolder,
+            tmkms_light::chain::state::State,
+        ),
+        NitroError,
+    > {
+        {
+            let mut status = self.status.lock().expect("status lock");
+            if *status != Status::Idle {
+                return Err(NitroError::AlreadyStarted);
+            }
+            *status = Status::Starting;
+        }
+        let unsealer = self.unsealer.as_ref();
+        let prepared = unseal_keypair(unsealer, config, &config.sealed_consensus_key, "consensus")
+            .and_then(|keypair| {
+                let id_keypair = config
+                    .sealed_id_key
+                    .as_ref()
+                    .map(|ciphertext| unseal_keypair(unsealer, config, ciphertext, "identity"))
+                    .transpose()?;
+                Ok((keypair, id_keypair))
+            })
+            .and_then(|(keypair, id_keypair)| {
+                let mut state_holder =
+                    state::StateHolder::new(self.transport.as_ref(), config.enclave_state_port)
+                        .map_err(|e| {
+                            NitroError::StateSyncFailed(format!("no state connection: {}", e))
+                        })?;
+                let state = state_holder
+                    .load_state()
+                    .map_err(|e| NitroError::StateSyncFailed(e.to_string()))?;
+                Ok((keypair, id_keypair, state_holder, state))
+            });
+        if prepared.is_err() {
+            self.set_status(Status::Idle);
+        }
+        prepared
+    }
+
+    /// starts signing (doesn't return unless the start up fails)
+    fn start<S: Read + Write>(
+        &self,
+        channel: &mut ConfigChannel<S>,
+        config: NitroConfig,
+    ) -> Result<(), Error> {
+        if let Some(port) = config.enclave_log_port {
+            if let Some(log_port) = self.log_port.lock().expect("log port lock").take() {
+                // the forwarder may only fail if it has exited
+                let _ = log_port.send(port);
+            }
+        }
+        let (keypair, id_keypair, state_holder, state) = match self.prepare_start(&config) {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                error!("{}", e);
+                return report_error(channel, e);
+            }
+        };
+        self.set_status(Status::Started(keypair.public));
+        let transport = self.transport.as_ref();
+        let conn: Box<dyn Connection> = get_connection(transport, &config, id_keypair.as_ref());
+        let started = NitroResponse::Started {
+            public_key: tendermint::PublicKey::Ed25519(keypair.public),
+            node_id: id_keypair
+                .as_ref()
+                .map(|ikp| PublicKey::from(ikp).peer_id()),
+        };
+        if let Err(e) = channel.send_response(&started) {
+            // the helper may have exited, but the signing can continue
+            warn!("failed to report the enclave status: {}", e);
+        }
+        let mut session = tmkms_light::session::Session::new(
+            ValidatorConfig {
+                chain_id: config.chain_id.clone(),
+                max_height: config.max_height,
+            },
+            conn,
+            keypair,
+            state,
+            state_holder,
+        )
+        .with_signing_switch(self.switch.clone());
+        loop {
+            if let Err(e) = session.request_loop() {
+                error!("request error: {}", e);
+            }
+            let conn: Box<dyn Connection> = get_connection(transport, &config, id_keypair.as_ref());
+            session.reset_connection(conn);
+        }
+    }
+
+    /// the attestation document with the consensus public key (if started) as the user data
+    fn attest(&self, nonce: &[u8]) -> Result<NitroResponse, NitroError> {
+        let public_key = match self.status() {
+            Status::Started(public_key) => Some(public_key),
+            _ => None,
+        };
+        let document = self
+            .attester
+            .attest(nonce, public_key.as_ref().map(|pk| pk.as_bytes().as_ref()))
+            .map_err(|e| NitroError::AttestationFailed(e.to_string()))?;
+        Ok(NitroResponse::Attestation { document })
+    }
+
+    /// handles a request on the config channel
+    /// (the status is reported back to the helper)
+    pub fn handle<S: Read + Write>(&self, config_stream: S) -> Result<(), Error> {
+        let mut channel = ConfigChannel::accept(config_stream)
+            .map_err(|e| format_err!(ProtocolError, "failed to negotiate config channel: {}", e))?;
+        let request = channel
+            .recv_request()
+            .map_err(|e| format_err!(IoError, "failed to read request: {}", e))?;
+        let response = match request {
+            NitroRequest::Start(config) => return self.start(&mut channel, config),
+            NitroRequest::KeyGen(keygen_config) => {
+                generate_key(self.sealer.as_ref(), &keygen_config)
+            }
+            NitroRequest::Attest { nonce } => self.attest(&nonce),
+            NitroRequest::Pause => {
+                let state = self.switch.pause();
+                info!("signing paused");
+                Ok(NitroResponse::Paused { state })
+            }
+            NitroRequest::Resume => {
+                self.switch.resume();
+                info!("signing resumed");
+                Ok(NitroResponse::Resumed)
+            }
+        };
+        match response {
+            Ok(response) => channel
+                .send_response(&response)
+                .map_err(|e| format_err!(IoError, "failed to send the response: {}", e).into()),
+            Err(e) => {
+                error!("{}", e);
+                report_error(&mut channel, e)
+            }
+        }
+    }
+}
+
+/// sends the error to the helper and returns it
+fn report_error<S: Read + Write>(
+    channel: &mut ConfigChannel<S>,
+    error: NitroError,
+) -> Result<(), Error> {
+    let kind = match error {
+        NitroError::DecryptionFailed(_) => AccessError,
+        NitroError::InvalidKey(_) => InvalidKey,
+        NitroError::StateSyncFailed(_) => IoError,
+        NitroError::EncryptionFailed(_) => CryptoError,
+        NitroError::AttestationFailed(_) => AccessError,
+        NitroError::AlreadyStarted => ProtocolError,
+    };
+    channel
+        .send_response(&NitroResponse::Error(error.clone()))
+        .map_err(|e| format_err!(IoError, "failed to report the error: {}", e))?;
+    Err(format_err!(kind, "{}", error).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kms::LocalKms;
+    use std::sync::Arc;
+    use std::{fs, path::Path, time::Instant};
+    use tendermint::{consensus, vote};
+    use tmkms_light::mock_validator::{Expect, MockValidator, ScriptStep};
+    use tmkms_nitro_helper::cbor::{self, Value};
+    use tmkms_nitro_helper::state::StateSyncer;
+    use tmkms_nitro_helper::transport::UnixTransport;
+    use tmkms_nitro_helper::AwsCredentials;
+
+    const CONFIG_PORT: u32 = 5050;
+    const STATE_PORT: u32 = 5051;
+    const TENDERMINT_PORT: u32 = 5052;
+
+    pub(super) fn config(sealed_consensus_key: Vec<u8>) -> NitroConfig {
+        NitroConfig {
+            chain_id: "testchain-1".parse().unwrap(),
+            max_height: None,
+            sealed_consensus_key,
+            sealed_id_key: None,
+            peer_id: None,
+            enclave_state_port: STATE_PORT,
+            enclave_log_port: None,
+            enclave_tendermint_conn: TENDERMINT_PORT,
+            credentials: AwsCredentials {
+                aws_key_id: "key".to_owned(),
+                aws_secret_key: "secret".to_owned(),
+                aws_session_token: "token".to_owned(),
+            },
+            aws_region: "ap-southeast-1".to_owned(),
+        }
+    }
+
+    pub(super) fn keygen_config() -> NitroKeygenConfig {
+        NitroKeygenConfig {
+            kms_key_id: "alias/tmkms".to_owned(),
+            credentials: config(vec![]).credentials,
+            aws_region: "ap-southeast-1".to_owned(),
+        }
+    }
+
+    /// a stand-in for NSM: the "document" is just the CBOR map of the provided data
+    #[derive(Debug)]
+    struct EchoAttester;
+
+    impl Attester for EchoAttester {
+        fn attest(&self, nonce: &[u8], user_data: Option<&[u8]>) -> Result<Vec<u8>, Error> {
+            Ok(cbor::encode(&Value::Map(vec![
+                (
+                    Value::Text("nonce".to_owned()),
+                    Value::Bytes(nonce.to_vec()),
+                ),
+                (
+                    Value::Text("user_data".to_owned()),
+                    user_data.map_or(Value::Null, |d| Value::Bytes(d.to_vec())),
+                ),
+            ])))
+        }
+    }
+
+    /// runs the enclave app on the host (as `main` does)
+    fn run_enclave(dir: &Path, kms: LocalKms) {
+        let transport = UnixTransport::new(dir);
+        let listener = transport.listen(CONFIG_PORT).unwrap();
+        let enclave = Arc::new(Enclave::new(
+            Box::new(transport),
+            Box::new(kms.clone()),
+            Box::new(kms),
+            Box::new(EchoAttester),
+        ));
+        thread::spawn(move || loop {
+            let stream = listener.accept().unwrap();
+            let enclave = enclave.clone();
+            thread::spawn(move || enclave.handle(stream));
+        });
+    }
+
+    /// pushes the request to the enclave (as the helper does)
+    fn push(dir: &Path, request: NitroRequest) -> NitroResponse {
+        let stream = UnixTransport::new(dir).connect(CONFIG_PORT).unwrap();
+        let mut channel = ConfigChannel::connect(stream).unwrap();
+        channel.send_request(&request).unwrap();
+        channel.recv_response().unwrap()
+    }
+
+    /// generates the key in the enclave
+    fn keygen(dir: &Path) -> (tendermint::PublicKey, Vec<u8>) {
+        match push(dir, NitroRequest::KeyGen(keygen_config())) {
+            NitroResponse::KeyGenerated {
+                public_key,
+                sealed_key,
+            } => (public_key, sealed_key),
+            r => panic!("unexpected response: {:?}", r),
+        }
+    }
+
+    /// the user data in the attestation "document"
+    fn attested_user_data(dir: &Path) -> Option<Vec<u8>> {
+        let nonce = vec![1u8; 32];
+        match push(
+            dir,
+            NitroRequest::Attest {
+                nonce: nonce.clone(),
+            },
+        ) {
+            NitroResponse::Attestation { document } => {
+                let document = cbor::decode(&document).unwrap();
+                assert_eq!(
+                    document.get("nonce").and_then(Value::as_bytes),
+                    Some(&nonce[..])
                 );
-                let id_secret = ed25519::SecretKey::from_bytes(&*id_key_bytes)
-                    .map_err(|e| format_err!(InvalidKey, "invalid Ed25519 key: {}", e))?;
-                let id_public = ed25519::PublicKey::from(&id_secret);
-                let id_keypair = ed25519::Keypair {
-                    secret: id_secret,
-                    public: id_public,
-                };
-                Some(id_keypair)
-            } else {
-                None
-            };
-            let mut state_holder = state::StateHolder::new(config.enclave_state_port)
-                .map_err(|_e| format_err!(IoError, "failed get state connection"))?;
-            let state = state_holder
-                .load_state()
-                .map_err(|_e| format_err!(IoError, "failed to load initial state"))?;
-            let conn: Box<dyn Connection> = get_connection(&config, id_keypair.as_ref());
-            let mut session = tmkms_light::session::Session::new(
-                ValidatorConfig {
-                    chain_id: config.chain_id.clone(),
-                    max_height: config.max_height,
-                },
-                conn,
-                keypair,
-                state,
-                state_holder,
-            );
-            loop {
-                if let Err(e) = session.request_loop() {
-                    error!("request error: {}", e);
-                }
-                let conn: Box<dyn Connection> = get_connection(&config, id_keypair.as_ref());
-                session.reset_connection(conn);
+                document
+                    .get("user_data")
+                    .and_then(Value::as_bytes)
+                    .map(<[u8]>::to_vec)
             }
+            r => panic!("unexpected response: {:?}", r),
         }
-        Err(e) => {
-            error!("config error: {}", e);
+    }
+
+    const BLOCK_ID: &str = "26C0A41F3243C6BCD7AD2DFF8A8D83A71D29D307B5326C227F734A1A512FE47D";
+    const OTHER_BLOCK_ID: &str = "2470A41F3243C6BCD7AD2DFF8A8D83A71D29D307B5326C227F734A1A512FE47D";
+    const DOUBLE_SIGN: i32 = 2;
+    const PAUSED: i32 = 3;
+
+    fn prevote(height: u32, block_id: &str) -> ScriptStep {
+        ScriptStep::Vote {
+            vote_type: vote::Type::Prevote,
+            height,
+            round: 0,
+            block_id: Some(block_id.parse().unwrap()),
         }
     }
 
-    Ok(())
+    #[test]
+    fn test_nitro_flow() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = UnixTransport::new(dir.path());
+        let state_path = dir.path().join("priv_validator_state.json");
+        StateSyncer::new(&state_path, &transport, STATE_PORT)
+            .unwrap()
+            .launch_syncer();
+        let mut validator = MockValidator::bind_unix(
+            transport.path(TENDERMINT_PORT),
+            "testchain-1".parse().unwrap(),
+        )
+        .unwrap();
+
+        run_enclave(dir.path(), LocalKms::generate());
+        let (public_key, sealed_key) = keygen(dir.path());
+        assert_eq!(attested_user_data(dir.path()), None);
+        let response = push(dir.path(), NitroRequest::Start(config(sealed_key.clone())));
+        assert_eq!(
+            response,
+            NitroResponse::Started {
+                public_key,
+                node_id: None,
+            }
+        );
+
+        validator.accept().unwrap();
+        validator
+            .run_script(&[
+                (prevote(1, BLOCK_ID), Expect::Signed),
+                (prevote(2, BLOCK_ID), Expect::Signed),
+                (prevote(2, OTHER_BLOCK_ID), Expect::Rejected(DOUBLE_SIGN)),
+            ])
+            .unwrap();
+        assert_eq!(validator.public_key(), Some(public_key));
+
+        // the enclave keeps handling requests while signing
+        assert_eq!(
+            attested_user_data(dir.path()),
+            Some(public_key.as_bytes().to_vec())
+        );
+        assert_eq!(
+            push(dir.path(), NitroRequest::Start(config(sealed_key))),
+            NitroResponse::Error(NitroError::AlreadyStarted)
+        );
+
+        // the last signed state gets persisted on the host
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let state: consensus::State =
+                serde_json::from_str(&fs::read_to_string(&state_path).unwrap()).unwrap();
+            if state.height.value() == 2 {
+                break;
+            }
+            assert!(Instant::now() < deadline, "state not persisted: {}", state);
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // no signing while paused
+        match push(dir.path(), NitroRequest::Pause) {
+            NitroResponse::Paused { state: Some(state) } => assert_eq!(state.height.value(), 2),
+            r => panic!("unexpected response: {:?}", r),
+        }
+        validator
+            .run_script(&[(prevote(3, BLOCK_ID), Expect::Rejected(PAUSED))])
+            .unwrap();
+        assert_eq!(
+            push(dir.path(), NitroRequest::Resume),
+            NitroResponse::Resumed
+        );
+        validator
+            .run_script(&[(prevote(3, BLOCK_ID), Expect::Signed)])
+            .unwrap();
+    }
+
+    #[test]
+    fn test_keygen() {
+        let dir = tempfile::tempdir().unwrap();
+        let kms = LocalKms::generate();
+        run_enclave(dir.path(), kms.clone());
+        let (public_key, sealed_key) = keygen(dir.path());
+        let secret = kms.unseal(&config(vec![]), &sealed_key).unwrap();
+        let secret = ed25519::SecretKey::from_bytes(&secret).unwrap();
+        assert_eq!(
+            public_key,
+            tendermint::PublicKey::Ed25519(ed25519::PublicKey::from(&secret))
+        );
+        // a new key each time
+        let (other_public_key, _) = keygen(dir.path());
+        assert_ne!(public_key, other_public_key);
+    }
+
+    #[test]
+    fn test_start_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let kms = LocalKms::generate();
+        run_enclave(dir.path(), kms.clone());
+        let sealed = kms.seal(&keygen_config(), &[7u8; 32]).unwrap();
+
+        // sealed with a different key
+        let other_sealed = LocalKms::generate()
+            .seal(&keygen_config(), &[7u8; 32])
+            .unwrap();
+        let response = push(dir.path(), NitroRequest::Start(config(other_sealed)));
+        assert_eq!(
+            response,
+            NitroResponse::Error(NitroError::DecryptionFailed(
+                "consensus key: access denied: local key decryption failed (wrong key?)".to_owned()
+            ))
+        );
+
+        let mut id_config = config(sealed.clone());
+        id_config.sealed_id_key = Some(vec![0u8; 8]);
+        let response = push(dir.path(), NitroRequest::Start(id_config));
+        assert_eq!(
+            response,
+            NitroResponse::Error(NitroError::InvalidKey(
+                "identity key: invalid key: sealed key is too short (8 bytes)".to_owned()
+            ))
+        );
+
+        let short = kms.seal(&keygen_config(), &[7u8; 31]).unwrap();
+        let response = push(dir.path(), NitroRequest::Start(config(short)));
+        assert!(matches!(
+            response,
+            NitroResponse::Error(NitroError::InvalidKey(_))
+        ));
+
+        // no state syncer listening
+        let response = push(dir.path(), NitroRequest::Start(config(sealed)));
+        assert!(matches!(
+            response,
+            NitroResponse::Error(NitroError::StateSyncFailed(_))
+        ));
+    }
 }
@@ -0,0 +1,89 @@
+//! Encryption of the keys generated in the enclave and decryption of the sealed keys
+//! pushed in the enclave configuration: via AWS KMS inside Nitro Enclaves,
+//! or with a local symmetric key when running on the host for development and tests.
+
+/// the local key implementation
+#[cfg(any(test, not(feature = "nitro")))]
+mod local;
+
+#[cfg(any(test, not(feature = "nitro")))]
+pub use local::LocalKms;
+
+#[cfg(feature = "nitro")]
+use anomaly::{fail, format_err};
+use std::fmt::Debug;
+use tmkms_light::error::Error;
+#[cfg(feature = "nitro")]
+use tmkms_light::error::ErrorKind::{AccessError, ConfigError};
+use tmkms_nitro_helper::{NitroConfig, NitroKeygenConfig};
+use zeroize::Zeroizing;
+
+/// Decrypts the sealed key bytes
+pub trait KeyUnsealer: Send + Sync + Debug {
+    /// returns the plaintext key bytes
+    /// (the config provides e.g. the AWS region and credentials)
+    fn unseal(&self, config: &NitroConfig, ciphertext: &[u8]) -> Result<Zeroizing<Vec<u8>>, Error>;
+}
+
+/// Encrypts the generated key bytes
+pub trait KeySealer: Send + Sync + Debug {
+    /// returns the ciphertext to be stored on the host
+    /// (the config provides e.g. the AWS region, credentials and KMS key ID)
+    fn seal(&self, config: &NitroKeygenConfig, plaintext: &[u8]) -> Result<Vec<u8>, Error>;
+}
+
+/// Decrypts via KMS using the AWS Nitro Enclaves SDK
+/// (the requests are made from the enclave, so the plaintext keys never leave it)
+#[cfg(feature = "nitro")]
+#[derive(Debug, Default, Clone)]
+pub struct NitroKms;
+
+/// `aws_ne_sys::Error` doesn't implement `Debug` or `Display`
+/// (only the decryption is used, so `SdkKmsDecryptError` is the failed `Decrypt` request)
+#[cfg(feature = "nitro")]
+fn kms_decrypt_error_reason(error: aws_ne_sys::Error) -> &'static str {
+    match error {
+        aws_ne_sys::Error::SdkInitError => "SDK initialization failed",
+        aws_ne_sys::Error::SdkGenericError => "SDK error",
+        aws_ne_sys::Error::SdkKmsConfigError => "invalid KMS configuration",
+        aws_ne_sys::Error::SdkKmsClientError => "KMS client error",
+        aws_ne_sys::Error::SdkKmsDecryptError => "KMS Decrypt request failed",
+    }
+}
+
+#[cfg(feature = "nitro")]
+impl KeyUnsealer for NitroKms {
+    fn unseal(&self, config: &NitroConfig, ciphertext: &[u8]) -> Result<Zeroizing<Vec<u8>>, Error> {
+        aws_ne_sys::kms_decrypt(
+            config.aws_region.as_bytes(),
+            config.credentials.aws_key_id.as_bytes(),
+            config.credentials.aws_secret_key.as_bytes(),
+            config.credentials.aws_session_token.as_bytes(),
+            ciphertext,
+        )
+        .map(Zeroizing::new)
+        .map_err(|e| {
+            format_err!(
+                AccessError,
+                "{} (region {})",
+                kms_decrypt_error_reason(e),
+                config.aws_region
+            )
+            .into()
+        })
+    }
+}
+
+#[cfg(feature = "nitro")]
+impl KeySealer for NitroKms {
+    /// `aws_ne_sys::kms_encrypt` (0.3) has no `KeyId` parameter, so the encryption
+    /// can't be bound to the requested KMS key: it is refused rather than made
+    /// under a key the operator didn't choose
+    fn seal(&self, config: &NitroKeygenConfig, _plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        fail!(
+            ConfigError,
+            "KMS Encrypt with the key ID `{}` isn't supported by the AWS Nitro Enclaves SDK bindings (aws-ne-sys 0.3)",
+            config.kms_key_id
+        )
+    }
+}
@@ -0,0 +1,156 @@
+//! `Aes128GcmSiv` with a key from a local file
+
+use super::{KeySealer, KeyUnsealer};
+use aes_gcm_siv::{
+    aead::{generic_array::GenericArray, Aead, NewAead},
+    Aes128GcmSiv,
+};
+use anomaly::format_err;
+use rand_core::{OsRng, RngCore};
+use std::fmt::{self, Debug};
+use std::fs;
+use std::path::Path;
+use tmkms_light::error::{
+    Error,
+    ErrorKind::{AccessError, CryptoError, InvalidKey, IoError},
+};
+use tmkms_nitro_helper::{NitroConfig, NitroKeygenConfig};
+use zeroize::Zeroizing;
+
+/// length of the local symmetric key
+pub const LOCAL_KEY_LEN: usize = 16;
+
+/// length of the nonce prepended to the ciphertext
+const NONCE_LEN: usize = 12;
+
+/// A stand-in for KMS when running on the host:
+/// the sealed keys are `nonce || ciphertext` encrypted with `Aes128GcmSiv`
+/// using the key from a local file
+#[derive(Clone)]
+pub struct LocalKms {
+    key: Zeroizing<Vec<u8>>,
+}
+
+impl Debug for LocalKms {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LocalKms").finish()
+    }
+}
+
+impl LocalKms {
+    /// the key needs to be `LOCAL_KEY_LEN` bytes
+    pub fn new(key: Zeroizing<Vec<u8>>) -> Result<Self, Error> {
+        if key.len() != LOCAL_KEY_LEN {
+            return Err(format_err!(
+                InvalidKey,
+                "local key needs to be {} bytes, got {}",
+                LOCAL_KEY_LEN,
+                key.len()
+            )
+            .into());
+        }
+        Ok(Self { key })
+    }
+
+    /// reads the raw key bytes from the file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let key = fs::read(path.as_ref()).map_err(|e| {
+            format_err!(
+                IoError,
+                "failed to read local key {}: {}",
+                path.as_ref().display(),
+                e
+            )
+        })?;
+        Self::new(Zeroizing::new(key))
+    }
+
+    /// generates a new random key
+    pub fn generate() -> Self {
+        let mut key = Zeroizing::new(vec![0u8; LOCAL_KEY_LEN]);
+        OsRng.fill_bytes(&mut key);
+        Self { key }
+    }
+
+    /// the raw key bytes (to be stored in a file)
+    pub fn key_bytes(&self) -> &[u8] {
+        &self.key
+    }
+
+    fn aead(&self) -> Aes128GcmSiv {
+        Aes128GcmSiv::new(GenericArray::from_slice(&self.key))
+    }
+}
+
+impl KeySealer for LocalKms {
+    /// encrypts the key bytes with a random nonce
+    fn seal(&self, _config: &NitroKeygenConfig, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = self
+            .aead()
+            .encrypt(GenericArray::from_slice(&nonce), plaintext)
+            .map_err(|_| format_err!(CryptoError, "local key encryption failed"))?;
+        Ok([&nonce[..], &ciphertext].concat())
+    }
+}
+
+impl KeyUnsealer for LocalKms {
+    fn unseal(
+        &self,
+        _config: &NitroConfig,
+        ciphertext: &[u8],
+    ) -> Result<Zeroizing<Vec<u8>>, Error> {
+        if ciphertext.len() < NONCE_LEN {
+            return Err(format_err!(
+                InvalidKey,
+                "sealed key is too short ({} bytes)",
+                ciphertext.len()
+            )
+            .into());
+        }
+        let (nonce, ciphertext) = ciphertext.split_at(NONCE_LEN);
+        self.aead()
+            .decrypt(GenericArray::from_slice(nonce), ciphertext)
+            .map(Zeroizing::new)
+            .map_err(|_| {
+                format_err!(AccessError, "local key decryption failed (wrong key?)").into()
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nitro::tests::{config, keygen_config};
+
+    #[test]
+    fn test_local_kms_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let key_path = dir.path().join("unseal.key");
+        let unsealer = LocalKms::generate();
+        fs::write(&key_path, unsealer.key_bytes()).unwrap();
+        let sealed = unsealer.seal(&keygen_config(), &[7u8; 32]).unwrap();
+        let unsealer = LocalKms::from_file(&key_path).unwrap();
+        let unsealed = unsealer.unseal(&config(vec![]), &sealed).unwrap();
+        assert_eq!(&unsealed[..], &[7u8; 32][..]);
+    }
+
+    #[test]
+    fn test_local_kms_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = LocalKms::from_file(dir.path().join("missing.key")).unwrap_err();
+        assert_eq!(err.kind(), &IoError);
+        let err = LocalKms::new(Zeroizing::new(vec![0u8; 32])).unwrap_err();
+        assert_eq!(err.kind(), &InvalidKey);
+
+        let sealed = LocalKms::generate()
+            .seal(&keygen_config(), &[7u8; 32])
+            .unwrap();
+        let other = LocalKms::generate();
+        let err = other.unseal(&config(vec![]), &sealed).unwrap_err();
+        assert_eq!(err.kind(), &AccessError);
+        let err = other.unseal(&config(vec![]), &sealed[..4]).unwrap_err();
+        assert_eq!(err.kind(), &InvalidKey);
+    }
+}
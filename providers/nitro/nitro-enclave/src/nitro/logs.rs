@@ -0,0 +1,230 @@
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tmkms_light::framing::{FrameType, FramedStream};
+use tmkms_light::logging::LogConfig;
+use tmkms_nitro_helper::logs::MAX_LOG_RECORD_LEN;
+use tmkms_nitro_helper::transport::Transport;
+use tracing::{info, warn, Level};
+use tracing_subscriber::fmt::{self, MakeWriter};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Registry};
+
+/// The number of records kept while the host isn't reading them
+/// (the newer records are dropped when it's full)
+pub const LOG_BUFFER_SIZE: usize = 1024;
+
+/// Writes the formatted log records into the bounded buffer;
+/// it never blocks, so that logging can't hold up signing
+#[derive(Debug, Clone)]
+pub struct LogWriter {
+    records: SyncSender<Vec<u8>>,
+    dropped: Arc<AtomicUsize>,
+}
+
+/// Buffers a single record (the formatter writes each event
+/// into a new writer) and queues it when dropped
+#[derive(Debug)]
+pub struct RecordWriter {
+    writer: LogWriter,
+    record: Vec<u8>,
+}
+
+impl Write for RecordWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.record.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for RecordWriter {
+    fn drop(&mut self) {
+        let record = std::mem::take(&mut self.record);
+        let len = record.len();
+        if len == 0 {
+            return;
+        }
+        if len > MAX_LOG_RECORD_LEN as usize {
+            self.writer.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        if self.writer.records.try_send(record).is_err() {
+            self.writer.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl MakeWriter for LogWriter {
+    type Writer = RecordWriter;
+
+    fn make_writer(&self) -> Self::Writer {
+        RecordWriter {
+            writer: self.clone(),
+            record: Vec::new(),
+        }
+    }
+}
+
+/// sets up the global subscriber: the records are printed on the console
+/// (only visible in the debug mode) and sent to the host as JSON
+/// (the log level can be set with `RUST_LOG` in the enclave image)
+pub fn init(writer: LogWriter, default_level: Level) -> Result<(), String> {
+    Registry::default()
+        .with(LogConfig::default().filter(default_level)?)
+        .with(fmt::layer())
+        .with(fmt::layer().json().with_writer(writer))
+        .try_init()
+        .map_err(|e| format!("setting default subscriber failed: {}", e))
+}
+
+/// Sends the buffered records to the host
+#[derive(Debug)]
+pub struct LogForwarder {
+    records: Receiver<Vec<u8>>,
+    dropped: Arc<AtomicUsize>,
+}
+
+/// creates the writer for the subscriber and the forwarder
+/// (with the buffer of `capacity` records between them)
+pub fn channel(capacity: usize) -> (LogWriter, LogForwarder) {
+    let (records_tx, records_rx) = mpsc::sync_channel(capacity);
+    let dropped = Arc::new(AtomicUsize::new(0));
+    (
+        LogWriter {
+            records: records_tx,
+            dropped: dropped.clone(),
+        },
+        LogForwarder {
+            records: records_rx,
+            dropped,
+        },
+    )
+}
+
+impl LogForwarder {
+    /// the notice about the records dropped since the last call (if any)
+    fn dropped_notice(&self) -> Option<Vec<u8>> {
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped == 0 {
+            return None;
+        }
+        let notice = serde_json::json!({
+            "level": "WARN",
+            "target": module_path!(),
+            "fields": {
+                "message": format!("{} log records were dropped", dropped),
+            },
+        });
+        serde_json::to_vec(&notice).ok()
+    }
+
+    /// Launches the forwarder: it waits until the port is sent
+    /// on the returned channel (i.e. the start request is received)
+    /// and then keeps (re)connecting to the host on it.
+    /// The records are kept in the buffer in the meantime.
+    pub fn launch_forwarder(self, transport: Box<dyn Transport>) -> Sender<u32> {
+        let (port_tx, port_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let port: u32 = match port_rx.recv() {
+                Ok(port) => port,
+                // log forwarding isn't configured
+                Err(_) => return,
+            };
+            let mut pending: Option<Vec<u8>> = None;
+            loop {
+                let stream = transport
+                    .connect(port)
+                    .map_err(|e| e.to_string())
+                    .and_then(|s| FramedStream::connect(s).map_err(|e| e.to_string()));
+                let mut stream = match stream {
+                    Ok(stream) => stream.with_max_len(MAX_LOG_RECORD_LEN),
+                    Err(_) => {
+                        thread::sleep(Duration::new(1, 0));
+                        continue;
+                    }
+                };
+                info!("forwarding logs on port {}", port);
+                loop {
+                    let record = match pending.take() {
+                        Some(record) => record,
+                        None => match self.dropped_notice() {
+                            Some(notice) => notice,
+                            None => match self.records.recv() {
+                                Ok(record) => record,
+                                Err(_) => return,
+                            },
+                        },
+                    };
+                    if let Err(e) = stream.send(FrameType::Log, &record) {
+                        warn!("log forwarding failed: {}", e);
+                        pending = Some(record);
+                        break;
+                    }
+                }
+            }
+        });
+        port_tx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tmkms_nitro_helper::logs::LogRecord;
+    use tmkms_nitro_helper::transport::UnixTransport;
+
+    #[test]
+    fn test_writer_never_blocks() {
+        let (writer, forwarder) = channel(2);
+        for i in 0..5 {
+            let mut record = writer.make_writer();
+            write!(
+                record,
+                "{{\"level\":\"INFO\",\"target\":\"test\",\"fields\":{{\"message\":\"{}\"}}}}",
+                i
+            )
+            .unwrap();
+        }
+        assert_eq!(
+            forwarder.records.try_recv().unwrap(),
+            br#"{"level":"INFO","target":"test","fields":{"message":"0"}}"#
+        );
+        assert!(forwarder.records.try_recv().is_ok());
+        assert!(forwarder.records.try_recv().is_err());
+        let notice = LogRecord::parse(&forwarder.dropped_notice().unwrap()).unwrap();
+        assert_eq!(notice.text(), "3 log records were dropped");
+        assert!(forwarder.dropped_notice().is_none());
+    }
+
+    #[test]
+    fn test_forwarding() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = UnixTransport::new(dir.path());
+        let listener = transport.listen(5057).unwrap();
+        let (writer, forwarder) = channel(LOG_BUFFER_SIZE);
+        // written before the port is known
+        writer
+            .make_writer()
+            .write_all(br#"{"level":"INFO","target":"test","fields":{"message":"buffered"}}"#)
+            .unwrap();
+        let port = forwarder.launch_forwarder(Box::new(transport));
+        port.send(5057).unwrap();
+        let stream = listener.accept().unwrap();
+        let mut stream = FramedStream::accept(stream).unwrap();
+        let record = LogRecord::parse(&stream.recv_payload(FrameType::Log).unwrap()).unwrap();
+        assert_eq!(record.text(), "buffered");
+        writer
+            .make_writer()
+            .write_all(br#"{"level":"DEBUG","target":"test","fields":{"message":"live"}}"#)
+            .unwrap();
+        let record = LogRecord::parse(&stream.recv_payload(FrameType::Log).unwrap()).unwrap();
+        assert_eq!(record.level, "DEBUG");
+        assert_eq!(record.text(), "live");
+    }
+}
@@ -0,0 +1,144 @@
+//! Attestation documents from the Nitro Secure Module (NSM)
+
+use std::fmt::Debug;
+use tmkms_light::error::Error;
+
+/// Provides the attestation documents
+pub trait Attester: Send + Sync + Debug {
+    /// returns the COSE_Sign1 document (CBOR) including the nonce and the user data
+    fn attest(&self, nonce: &[u8], user_data: Option<&[u8]>) -> Result<Vec<u8>, Error>;
+}
+
+#[cfg(feature = "nitro")]
+pub use self::device::Nsm;
+
+#[cfg(feature = "nitro")]
+mod device {
+    use super::Attester;
+    use anomaly::format_err;
+    use std::fs::OpenOptions;
+    use std::os::unix::io::AsRawFd;
+    use tmkms_light::error::{
+        Error,
+        ErrorKind::{IoError, ProtocolError},
+    };
+    use tmkms_nitro_helper::cbor::{self, Value};
+
+    /// `_IOWR(0x0A, 0, struct nsm_raw)` from the NSM driver
+    const NSM_IOCTL_REQUEST: u32 = 0xC020_0A00;
+
+    /// the maximum response size of the NSM driver
+    const NSM_RESPONSE_MAX_SIZE: usize = 0x3000;
+
+    const NSM_DEVICE: &str = "/dev/nsm";
+
+    #[repr(C)]
+    struct NsmIovec {
+        addr: u64,
+        len: u64,
+    }
+
+    #[repr(C)]
+    struct NsmMessage {
+        request: NsmIovec,
+        response: NsmIovec,
+    }
+
+    /// The NSM device in the enclave
+    #[derive(Debug, Default, Clone, Copy)]
+    pub struct Nsm;
+
+    impl Nsm {
+        /// sends the CBOR request to the device and returns the CBOR response
+        fn request(&self, request: &[u8]) -> Result<Value, Error> {
+            let device = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(NSM_DEVICE)
+                .map_err(|e| format_err!(IoError, "failed to open {}: {}", NSM_DEVICE, e))?;
+            let mut response = vec![0u8; NSM_RESPONSE_MAX_SIZE];
+            let mut message = NsmMessage {
+                request: NsmIovec {
+                    addr: request.as_ptr() as u64,
+                    len: request.len() as u64,
+                },
+                response: NsmIovec {
+                    addr: response.as_mut_ptr() as u64,
+                    len: response.len() as u64,
+                },
+            };
+            // the request type differs between glibc and musl
+            let rc = unsafe {
+                libc::ioctl(
+                    device.as_raw_fd(),
+                    NSM_IOCTL_REQUEST as _,
+                    &mut message as *mut NsmMessage,
+                )
+            };
+            if rc < 0 {
+                return Err(format_err!(
+                    IoError,
+                    "NSM request failed: {}",
+                    std::io::Error::last_os_error()
+                )
+                .into());
+            }
+            response.truncate(message.response.len as usize);
+            cbor::decode(&response)
+        }
+    }
+
+    impl Attester for Nsm {
+        fn attest(&self, nonce: &[u8], user_data: Option<&[u8]>) -> Result<Vec<u8>, Error> {
+            let optional_bytes =
+                |b: Option<&[u8]>| b.map_or(Value::Null, |b| Value::Bytes(b.to_vec()));
+            let request = Value::Map(vec![(
+                Value::Text("Attestation".to_owned()),
+                Value::Map(vec![
+                    (
+                        Value::Text("user_data".to_owned()),
+                        optional_bytes(user_data),
+                    ),
+                    (Value::Text("nonce".to_owned()), optional_bytes(Some(nonce))),
+                    (Value::Text("public_key".to_owned()), Value::Null),
+                ]),
+            )]);
+            let response = self.request(&cbor::encode(&request))?;
+            if let Some(error) = response.get("Error") {
+                return Err(format_err!(ProtocolError, "NSM error: {:?}", error).into());
+            }
+            let document = response
+                .get("Attestation")
+                .and_then(|attestation| attestation.get("document"))
+                .ok_or_else(|| format_err!(ProtocolError, "unexpected NSM response"))?;
+            match document {
+                Value::Bytes(document) => Ok(document.clone()),
+                // a sequence of bytes if serialized without `serde_bytes`
+                Value::Array(items) => items
+                    .iter()
+                    .map(|item| match item.as_u64() {
+                        Some(b) if b <= u8::MAX as u64 => Ok(b as u8),
+                        _ => Err(format_err!(ProtocolError, "invalid NSM document").into()),
+                    })
+                    .collect(),
+                _ => Err(format_err!(ProtocolError, "invalid NSM document").into()),
+            }
+        }
+    }
+}
+
+/// Outside of Nitro Enclaves (e.g. in tests), there's no NSM device
+#[cfg(not(feature = "nitro"))]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoAttester;
+
+#[cfg(not(feature = "nitro"))]
+impl Attester for NoAttester {
+    fn attest(&self, _nonce: &[u8], _user_data: Option<&[u8]>) -> Result<Vec<u8>, Error> {
+        Err(anomaly::format_err!(
+            tmkms_light::error::ErrorKind::AccessError,
+            "attestation is only available in Nitro Enclaves"
+        )
+        .into())
+    }
+}
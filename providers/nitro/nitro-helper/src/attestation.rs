@@ -0,0 +1,445 @@
+//! Verification of the Nitro Enclaves attestation documents:
+//! COSE_Sign1 (ECDSA P-384 / SHA-384) signed by the enclave certificate
+//! that chains up to the Nitro Enclaves root certificate, see
+//! https://docs.aws.amazon.com/enclaves/latest/user/verify-root.html
+
+use crate::cbor::{self, Value};
+use anomaly::{fail, format_err};
+use openssl::bn::BigNum;
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::MessageDigest;
+use openssl::stack::Stack;
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::verify::X509VerifyFlags;
+use openssl::x509::{X509StoreContext, X509};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use tmkms_light::error::{
+    Error,
+    ErrorKind::{ParseError, VerificationError},
+};
+
+/// SHA-256 fingerprint of the AWS Nitro Enclaves root certificate (G1)
+pub const AWS_NITRO_ROOT_G1_SHA256: &str =
+    "641A0321A3E244EFE456463195D606317ED7CDCC3C1756E09893F3C68F79BB5B";
+
+/// COSE algorithm identifier of ECDSA with SHA-384
+const COSE_ES384: u64 = 34;
+
+/// COSE_Sign1 tag
+const COSE_SIGN1_TAG: u64 = 18;
+
+/// length of the P-384 signature components
+const P384_COMPONENT_LEN: usize = 48;
+
+/// The attestation document payload
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttestationDoc {
+    /// the enclave ID
+    pub module_id: String,
+    /// the PCR digest algorithm
+    pub digest: String,
+    /// UTC milliseconds since the UNIX epoch
+    pub timestamp: u64,
+    /// the platform configuration registers (PCR0: enclave image, PCR1: kernel and bootstrap,
+    /// PCR2: application, PCR8: signing certificate)
+    pub pcrs: BTreeMap<u64, Vec<u8>>,
+    /// the enclave certificate (DER)
+    pub certificate: Vec<u8>,
+    /// the issuing certificates (DER), starting from the root
+    pub cabundle: Vec<Vec<u8>>,
+    /// the optional public key provided by the enclave
+    pub public_key: Option<Vec<u8>>,
+    /// the optional data provided by the enclave
+    pub user_data: Option<Vec<u8>>,
+    /// the optional nonce provided by the requester
+    pub nonce: Option<Vec<u8>>,
+}
+
+fn field<'a>(map: &'a Value, key: &str) -> Result<&'a Value, Error> {
+    map.get(key)
+        .ok_or_else(|| format_err!(ParseError, "missing attestation field `{}`", key).into())
+}
+
+fn bytes_field(map: &Value, key: &str) -> Result<Vec<u8>, Error> {
+    field(map, key)?
+        .as_bytes()
+        .map(|b| b.to_vec())
+        .ok_or_else(|| format_err!(ParseError, "attestation field `{}` not bytes", key).into())
+}
+
+fn optional_bytes_field(map: &Value, key: &str) -> Result<Option<Vec<u8>>, Error> {
+    match map.get(key).and_then(Value::non_null) {
+        None => Ok(None),
+        Some(v) => v
+            .as_bytes()
+            .map(|b| Some(b.to_vec()))
+            .ok_or_else(|| format_err!(ParseError, "attestation field `{}` not bytes", key).into()),
+    }
+}
+
+impl AttestationDoc {
+    /// parses the CBOR payload
+    pub fn parse(payload: &[u8]) -> Result<Self, Error> {
+        let map = cbor::decode(payload)?;
+        let text = |key| {
+            field(&map, key)?
+                .as_text()
+                .map(str::to_owned)
+                .ok_or_else(|| {
+                    Error::from(format_err!(
+                        ParseError,
+                        "attestation field `{}` not text",
+                        key
+                    ))
+                })
+        };
+        let module_id = text("module_id")?;
+        let digest = text("digest")?;
+        let timestamp = field(&map, "timestamp")?
+            .as_u64()
+            .ok_or_else(|| format_err!(ParseError, "invalid attestation timestamp"))?;
+        let mut pcrs = BTreeMap::new();
+        for (index, value) in field(&map, "pcrs")?
+            .as_map()
+            .ok_or_else(|| format_err!(ParseError, "invalid attestation PCRs"))?
+        {
+            match (index.as_u64(), value.as_bytes()) {
+                (Some(index), Some(value)) => {
+                    pcrs.insert(index, value.to_vec());
+                }
+                _ => fail!(ParseError, "invalid attestation PCR"),
+            }
+        }
+        let cabundle = field(&map, "cabundle")?
+            .as_array()
+            .ok_or_else(|| format_err!(ParseError, "invalid attestation CA bundle"))?
+            .iter()
+            .map(|cert| {
+                cert.as_bytes()
+                    .map(|b| b.to_vec())
+                    .ok_or_else(|| format_err!(ParseError, "invalid attestation CA bundle").into())
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        Ok(Self {
+            module_id,
+            digest,
+            timestamp,
+            pcrs,
+            certificate: bytes_field(&map, "certificate")?,
+            cabundle,
+            public_key: optional_bytes_field(&map, "public_key")?,
+            user_data: optional_bytes_field(&map, "user_data")?,
+            nonce: optional_bytes_field(&map, "nonce")?,
+        })
+    }
+}
+
+/// The signed parts of a COSE_Sign1 structure
+struct CoseSign1 {
+    protected: Vec<u8>,
+    payload: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+impl CoseSign1 {
+    fn parse(document: &[u8]) -> Result<Self, Error> {
+        let value = cbor::decode(document)?;
+        let value = match value {
+            Value::Tag(COSE_SIGN1_TAG, v) => *v,
+            v => v,
+        };
+        match value.as_array() {
+            Some(
+                [Value::Bytes(protected), Value::Map(_), Value::Bytes(payload), Value::Bytes(signature)],
+            ) => Ok(Self {
+                protected: protected.clone(),
+                payload: payload.clone(),
+                signature: signature.clone(),
+            }),
+            _ => fail!(ParseError, "not a COSE_Sign1 structure"),
+        }
+    }
+
+    /// the algorithm from the protected header
+    fn check_algorithm(&self) -> Result<(), Error> {
+        let header = cbor::decode(&self.protected)?;
+        let alg = header.as_map().and_then(|entries| {
+            entries.iter().find_map(|(k, v)| match (k, v) {
+                (Value::Unsigned(1), Value::Negative(alg)) => Some(*alg),
+                _ => None,
+            })
+        });
+        if alg != Some(COSE_ES384) {
+            fail!(
+                VerificationError,
+                "unsupported COSE algorithm (expected ES384)"
+            );
+        }
+        Ok(())
+    }
+
+    /// `Sig_structure` for COSE_Sign1 with no external data
+    fn signed_data(&self) -> Vec<u8> {
+        cbor::encode(&Value::Array(vec![
+            Value::Text("Signature1".to_owned()),
+            Value::Bytes(self.protected.clone()),
+            Value::Bytes(vec![]),
+            Value::Bytes(self.payload.clone()),
+        ]))
+    }
+}
+
+fn openssl_error(context: &str) -> impl Fn(openssl::error::ErrorStack) -> Error + '_ {
+    move |e| format_err!(VerificationError, "{}: {}", context, e).into()
+}
+
+/// verifies the enclave certificate chains up to the trusted root
+/// and all the certificates were valid at the time of the attestation
+/// (so that the recorded documents can be verified later)
+fn verify_chain(doc: &AttestationDoc, trusted_root: &X509) -> Result<X509, Error> {
+    let leaf = X509::from_der(&doc.certificate)
+        .map_err(|e| format_err!(ParseError, "invalid enclave certificate: {}", e))?;
+    let mut chain = Stack::new().map_err(openssl_error("stack"))?;
+    for cert in doc.cabundle.iter() {
+        let cert = X509::from_der(cert)
+            .map_err(|e| format_err!(ParseError, "invalid CA bundle certificate: {}", e))?;
+        chain.push(cert).map_err(openssl_error("stack"))?;
+    }
+    let mut store = X509StoreBuilder::new().map_err(openssl_error("store"))?;
+    store
+        .add_cert(trusted_root.clone())
+        .map_err(openssl_error("store"))?;
+    // checked below against the attestation time
+    store
+        .set_flags(X509VerifyFlags::NO_CHECK_TIME)
+        .map_err(openssl_error("store"))?;
+    let store = store.build();
+    let mut context = X509StoreContext::new().map_err(openssl_error("store"))?;
+    let (verified, result) = context
+        .init(&store, &leaf, &chain, |c| {
+            c.verify_cert().map(|verified| (verified, c.error()))
+        })
+        .map_err(openssl_error("certificate chain verification failed"))?;
+    if !verified {
+        fail!(
+            VerificationError,
+            "certificate chain verification failed: {}",
+            result.error_string()
+        );
+    }
+
+    let at = openssl::asn1::Asn1Time::from_unix((doc.timestamp / 1000) as _)
+        .map_err(openssl_error("invalid timestamp"))?;
+    for cert in std::iter::once(&*leaf).chain(chain.iter()) {
+        let not_yet_valid = cert
+            .not_before()
+            .compare(&at)
+            .map_err(openssl_error("time"))?
+            == Ordering::Greater;
+        let expired = cert
+            .not_after()
+            .compare(&at)
+            .map_err(openssl_error("time"))?
+            == Ordering::Less;
+        if not_yet_valid || expired {
+            fail!(
+                VerificationError,
+                "certificate {:?} not valid at the attestation time",
+                cert.subject_name()
+            );
+        }
+    }
+    Ok(leaf)
+}
+
+/// Verifies the attestation document: its signature, the certificate chain
+/// up to the trusted root (PEM) and that it contains the nonce.
+/// Returns the verified payload.
+pub fn verify(
+    document: &[u8],
+    trusted_root_pem: &[u8],
+    nonce: &[u8],
+) -> Result<AttestationDoc, Error> {
+    let trusted_root = X509::from_pem(trusted_root_pem)
+        .map_err(|e| format_err!(ParseError, "invalid root certificate: {}", e))?;
+    let cose = CoseSign1::parse(document)?;
+    cose.check_algorithm()?;
+    let doc = AttestationDoc::parse(&cose.payload)?;
+    if doc.digest != "SHA384" {
+        fail!(VerificationError, "unsupported PCR digest {}", doc.digest);
+    }
+    let leaf = verify_chain(&doc, &trusted_root)?;
+
+    if cose.signature.len() != 2 * P384_COMPONENT_LEN {
+        fail!(VerificationError, "invalid signature length");
+    }
+    let (r, s) = cose.signature.split_at(P384_COMPONENT_LEN);
+    let signature = BigNum::from_slice(r)
+        .and_then(|r| BigNum::from_slice(s).map(|s| (r, s)))
+        .and_then(|(r, s)| EcdsaSig::from_private_components(r, s))
+        .map_err(openssl_error("invalid signature"))?;
+    let key = leaf
+        .public_key()
+        .and_then(|key| key.ec_key())
+        .map_err(openssl_error("invalid enclave certificate key"))?;
+    let digest = openssl::sha::sha384(&cose.signed_data());
+    if !signature
+        .verify(&digest, &key)
+        .map_err(openssl_error("signature verification failed"))?
+    {
+        fail!(VerificationError, "invalid attestation signature");
+    }
+
+    if doc.nonce.as_deref() != Some(nonce) {
+        fail!(VerificationError, "attestation nonce mismatch");
+    }
+    Ok(doc)
+}
+
+/// SHA-256 fingerprint of the certificate (PEM) in uppercase hex
+pub fn fingerprint(cert_pem: &[u8]) -> Result<String, Error> {
+    let cert = X509::from_pem(cert_pem)
+        .map_err(|e| format_err!(ParseError, "invalid certificate: {}", e))?;
+    let digest = cert
+        .digest(MessageDigest::sha256())
+        .map_err(openssl_error("digest"))?;
+    Ok(String::from_utf8(subtle_encoding::hex::encode_upper(digest)).expect("hex"))
+}
+
+/// A PCR value compared to the expected one
+#[derive(Debug, Clone, PartialEq)]
+pub struct PcrCheck {
+    /// the PCR index
+    pub index: u64,
+    /// the value in the document
+    pub actual: Option<Vec<u8>>,
+    /// the expected value (if any)
+    pub expected: Option<Vec<u8>>,
+}
+
+impl PcrCheck {
+    /// true if there's no expected value or it's equal to the actual one
+    pub fn is_ok(&self) -> bool {
+        self.expected.is_none() || self.expected == self.actual
+    }
+}
+
+/// Compares the enclave image PCRs (0, 1, 2) and any other expected ones
+pub fn check_pcrs(doc: &AttestationDoc, expected: &BTreeMap<u64, Vec<u8>>) -> Vec<PcrCheck> {
+    let mut indices: Vec<u64> = vec![0, 1, 2];
+    indices.extend(expected.keys().filter(|i| **i > 2));
+    indices
+        .into_iter()
+        .map(|index| PcrCheck {
+            index,
+            actual: doc.pcrs.get(&index).cloned(),
+            expected: expected.get(&index).cloned(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// recorded documents signed by a test chain (root -> intermediate -> enclave)
+    const ROOT: &[u8] = include_bytes!("../fixtures/attestation/root.pem");
+    const DOCUMENT: &[u8] = include_bytes!("../fixtures/attestation/attestation.cbor");
+    /// signed after the enclave certificate expired
+    const EXPIRED: &[u8] = include_bytes!("../fixtures/attestation/attestation-expired.cbor");
+    const TIMESTAMP: u64 = 1_622_548_800_000;
+    const PCR0: &str = "f9ef9e90faeaa081ecc89e9b42d9ae3cd66e614dbd6e291c26dcab57cf843f0da7aa6825174426a0ac5dfa566b718691";
+
+    fn nonce() -> Vec<u8> {
+        (0..32).collect()
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        subtle_encoding::hex::decode(s).unwrap()
+    }
+
+    #[test]
+    fn test_verify_recorded_document() {
+        let doc = verify(DOCUMENT, ROOT, &nonce()).unwrap();
+        assert_eq!(doc.module_id, "i-0123456789abcdef0-enc0123456789abcdef");
+        assert_eq!(doc.timestamp, TIMESTAMP);
+        assert_eq!(doc.pcrs.len(), 16);
+        assert_eq!(doc.pcrs[&0], hex(PCR0));
+        assert_eq!(doc.cabundle.len(), 2);
+        assert_eq!(doc.public_key, None);
+        assert_eq!(doc.user_data.as_ref().map(Vec::len), Some(32));
+    }
+
+    #[test]
+    fn test_verification_failures() {
+        let err = |document: &[u8], root: &[u8], nonce: &[u8]| {
+            *verify(document, root, nonce).unwrap_err().kind()
+        };
+        assert_eq!(err(DOCUMENT, ROOT, &[0u8; 32]), VerificationError);
+        assert_eq!(err(EXPIRED, ROOT, &nonce()), VerificationError);
+
+        // the payload (the nonce) changed after signing
+        let mut tampered = DOCUMENT.to_vec();
+        let pos = tampered
+            .windows(32)
+            .position(|w| w == &nonce()[..])
+            .unwrap();
+        tampered[pos] ^= 1;
+        let mut tampered_nonce = nonce();
+        tampered_nonce[0] ^= 1;
+        assert_eq!(err(&tampered, ROOT, &tampered_nonce), VerificationError);
+
+        // a different root
+        let other_root = openssl::ec::EcKey::generate(
+            &openssl::ec::EcGroup::from_curve_name(openssl::nid::Nid::SECP384R1).unwrap(),
+        )
+        .and_then(openssl::pkey::PKey::from_ec_key)
+        .and_then(|key| -> Result<Vec<u8>, openssl::error::ErrorStack> {
+            let mut name = openssl::x509::X509NameBuilder::new()?;
+            name.append_entry_by_text("CN", "other-root")?;
+            let name = name.build();
+            let mut cert = openssl::x509::X509Builder::new()?;
+            cert.set_version(2)?;
+            cert.set_subject_name(&name)?;
+            cert.set_issuer_name(&name)?;
+            cert.set_pubkey(&key)?;
+            let not_before = openssl::asn1::Asn1Time::from_unix(0)?;
+            let not_after = openssl::asn1::Asn1Time::days_from_now(1)?;
+            cert.set_not_before(&not_before)?;
+            cert.set_not_after(&not_after)?;
+            cert.sign(&key, MessageDigest::sha384())?;
+            cert.build().to_pem()
+        })
+        .unwrap();
+        assert_eq!(err(DOCUMENT, &other_root, &nonce()), VerificationError);
+
+        assert_eq!(err(&DOCUMENT[1..], ROOT, &nonce()), ParseError);
+        assert_eq!(err(&[0x80], ROOT, &nonce()), ParseError);
+    }
+
+    #[test]
+    fn test_check_pcrs() {
+        let doc = verify(DOCUMENT, ROOT, &nonce()).unwrap();
+        let mut expected = BTreeMap::new();
+        expected.insert(0, hex(PCR0));
+        expected.insert(8, vec![0u8; 48]);
+        let checks = check_pcrs(&doc, &expected);
+        assert_eq!(
+            checks.iter().map(|c| c.index).collect::<Vec<_>>(),
+            vec![0, 1, 2, 8]
+        );
+        assert!(checks.iter().all(PcrCheck::is_ok));
+
+        expected.insert(2, hex(PCR0));
+        let checks = check_pcrs(&doc, &expected);
+        assert!(!checks[2].is_ok());
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let fingerprint = fingerprint(ROOT).unwrap();
+        assert_eq!(fingerprint.len(), 64);
+        assert_ne!(fingerprint, AWS_NITRO_ROOT_G1_SHA256);
+    }
+}
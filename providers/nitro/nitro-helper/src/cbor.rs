@@ -0,0 +1,360 @@
+//! A minimal CBOR (RFC 7049) codec for the NSM requests and the attestation documents:
+//! only definite lengths and the simple values `false`, `true` and `null`
+//! (which is what the NSM API and COSE_Sign1 documents use)
+
+use anomaly::{fail, format_err};
+use std::convert::TryFrom;
+use tmkms_light::error::{Error, ErrorKind};
+
+/// the nesting limit for decoding
+const MAX_DEPTH: usize = 16;
+
+/// A decoded CBOR data item
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// major type 0
+    Unsigned(u64),
+    /// major type 1: the value is `-1 - n`
+    Negative(u64),
+    /// major type 2
+    Bytes(Vec<u8>),
+    /// major type 3
+    Text(String),
+    /// major type 4
+    Array(Vec<Value>),
+    /// major type 5 (in the encoded order)
+    Map(Vec<(Value, Value)>),
+    /// major type 6
+    Tag(u64, Box<Value>),
+    /// major type 7: `false` / `true`
+    Bool(bool),
+    /// major type 7: `null`
+    Null,
+}
+
+impl Value {
+    /// the value of the text key in the map
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(entries) => entries.iter().find_map(|(k, v)| match k {
+                Value::Text(t) if t == key => Some(v),
+                _ => None,
+            }),
+            _ => None,
+        }
+    }
+
+    /// the byte string contents
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    /// the text string contents
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Value::Text(t) => Some(t),
+            _ => None,
+        }
+    }
+
+    /// the unsigned integer
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::Unsigned(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// the array items
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    /// the map entries
+    pub fn as_map(&self) -> Option<&[(Value, Value)]> {
+        match self {
+            Value::Map(entries) => Some(entries),
+            _ => None,
+        }
+    }
+
+    /// the value or `None` if it's `null`
+    pub fn non_null(&self) -> Option<&Value> {
+        match self {
+            Value::Null => None,
+            v => Some(v),
+        }
+    }
+}
+
+fn write_head(out: &mut Vec<u8>, major: u8, n: u64) {
+    let major = major << 5;
+    if n < 24 {
+        out.push(major | n as u8);
+    } else if n <= u8::MAX as u64 {
+        out.push(major | 24);
+        out.push(n as u8);
+    } else if n <= u16::MAX as u64 {
+        out.push(major | 25);
+        out.extend_from_slice(&(n as u16).to_be_bytes());
+    } else if n <= u32::MAX as u64 {
+        out.push(major | 26);
+        out.extend_from_slice(&(n as u32).to_be_bytes());
+    } else {
+        out.push(major | 27);
+        out.extend_from_slice(&n.to_be_bytes());
+    }
+}
+
+fn encode_into(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Unsigned(n) => write_head(out, 0, *n),
+        Value::Negative(n) => write_head(out, 1, *n),
+        Value::Bytes(b) => {
+            write_head(out, 2, b.len() as u64);
+            out.extend_from_slice(b);
+        }
+        Value::Text(t) => {
+            write_head(out, 3, t.len() as u64);
+            out.extend_from_slice(t.as_bytes());
+        }
+        Value::Array(items) => {
+            write_head(out, 4, items.len() as u64);
+            for item in items {
+                encode_into(out, item);
+            }
+        }
+        Value::Map(entries) => {
+            write_head(out, 5, entries.len() as u64);
+            for (k, v) in entries {
+                encode_into(out, k);
+                encode_into(out, v);
+            }
+        }
+        Value::Tag(tag, v) => {
+            write_head(out, 6, *tag);
+            encode_into(out, v);
+        }
+        Value::Bool(false) => out.push(0xf4),
+        Value::Bool(true) => out.push(0xf5),
+        Value::Null => out.push(0xf6),
+    }
+}
+
+/// Encodes the value (with the shortest heads)
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_into(&mut out, value);
+    out
+}
+
+struct Decoder<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.input.len() - self.pos < n {
+            fail!(ErrorKind::ParseError, "unexpected end of CBOR input");
+        }
+        let bytes = &self.input[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn read_head(&mut self) -> Result<(u8, u8, u64), Error> {
+        let initial = self.take(1)?[0];
+        let (major, info) = (initial >> 5, initial & 0x1f);
+        let n = match info {
+            0..=23 => info as u64,
+            24 => self.take(1)?[0] as u64,
+            25 => {
+                let mut buf = [0u8; 2];
+                buf.copy_from_slice(self.take(2)?);
+                u16::from_be_bytes(buf) as u64
+            }
+            26 => {
+                let mut buf = [0u8; 4];
+                buf.copy_from_slice(self.take(4)?);
+                u32::from_be_bytes(buf) as u64
+            }
+            27 => {
+                let mut buf = [0u8; 8];
+                buf.copy_from_slice(self.take(8)?);
+                u64::from_be_bytes(buf)
+            }
+            _ => fail!(
+                ErrorKind::ParseError,
+                "unsupported CBOR additional info {}",
+                info
+            ),
+        };
+        Ok((major, info, n))
+    }
+
+    /// the length of a string or a collection
+    /// (bounded by the remaining input, so that it's safe to preallocate)
+    fn read_len(&self, n: u64) -> Result<usize, Error> {
+        usize::try_from(n)
+            .ok()
+            .filter(|len| *len <= self.input.len() - self.pos)
+            .ok_or_else(|| format_err!(ErrorKind::ParseError, "CBOR length {} too large", n).into())
+    }
+
+    fn decode(&mut self, depth: usize) -> Result<Value, Error> {
+        if depth > MAX_DEPTH {
+            fail!(ErrorKind::ParseError, "CBOR nested too deeply");
+        }
+        let (major, info, n) = self.read_head()?;
+        match major {
+            0 => Ok(Value::Unsigned(n)),
+            1 => Ok(Value::Negative(n)),
+            2 => {
+                let len = self.read_len(n)?;
+                Ok(Value::Bytes(self.take(len)?.to_vec()))
+            }
+            3 => {
+                let len = self.read_len(n)?;
+                let text = std::str::from_utf8(self.take(len)?)
+                    .map_err(|e| format_err!(ErrorKind::ParseError, "invalid CBOR text: {}", e))?;
+                Ok(Value::Text(text.to_owned()))
+            }
+            4 => {
+                let len = self.read_len(n)?;
+                let mut items = Vec::with_capacity(len);
+                for _ in 0..len {
+                    items.push(self.decode(depth + 1)?);
+                }
+                Ok(Value::Array(items))
+            }
+            5 => {
+                let len = self.read_len(n)?;
+                let mut entries = Vec::with_capacity(len);
+                for _ in 0..len {
+                    let k = self.decode(depth + 1)?;
+                    let v = self.decode(depth + 1)?;
+                    entries.push((k, v));
+                }
+                Ok(Value::Map(entries))
+            }
+            6 => Ok(Value::Tag(n, Box::new(self.decode(depth + 1)?))),
+            _ => match info {
+                20 => Ok(Value::Bool(false)),
+                21 => Ok(Value::Bool(true)),
+                22 => Ok(Value::Null),
+                _ => fail!(
+                    ErrorKind::ParseError,
+                    "unsupported CBOR simple value {}",
+                    info
+                ),
+            },
+        }
+    }
+}
+
+/// Decodes a single data item (trailing bytes are rejected)
+pub fn decode(input: &[u8]) -> Result<Value, Error> {
+    let mut decoder = Decoder { input, pos: 0 };
+    let value = decoder.decode(0)?;
+    if decoder.pos != input.len() {
+        fail!(
+            ErrorKind::ParseError,
+            "{} trailing bytes after CBOR item",
+            input.len() - decoder.pos
+        );
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rfc_examples() {
+        // RFC 7049 appendix A
+        let examples: Vec<(&[u8], Value)> = vec![
+            (&[0x00], Value::Unsigned(0)),
+            (&[0x18, 0x64], Value::Unsigned(100)),
+            (&[0x19, 0x03, 0xe8], Value::Unsigned(1000)),
+            (
+                &[0x1b, 0x00, 0x00, 0x00, 0xe8, 0xd4, 0xa5, 0x10, 0x00],
+                Value::Unsigned(1_000_000_000_000),
+            ),
+            (&[0x38, 0x63], Value::Negative(99)),
+            (
+                &[0x44, 0x01, 0x02, 0x03, 0x04],
+                Value::Bytes(vec![1, 2, 3, 4]),
+            ),
+            (&[0x62, 0x22, 0x5c], Value::Text("\"\\".to_owned())),
+            (
+                &[0x83, 0x01, 0x82, 0x02, 0x03, 0x82, 0x04, 0x05],
+                Value::Array(vec![
+                    Value::Unsigned(1),
+                    Value::Array(vec![Value::Unsigned(2), Value::Unsigned(3)]),
+                    Value::Array(vec![Value::Unsigned(4), Value::Unsigned(5)]),
+                ]),
+            ),
+            (
+                &[0xa2, 0x61, 0x61, 0x01, 0x61, 0x62, 0x82, 0x02, 0x03],
+                Value::Map(vec![
+                    (Value::Text("a".to_owned()), Value::Unsigned(1)),
+                    (
+                        Value::Text("b".to_owned()),
+                        Value::Array(vec![Value::Unsigned(2), Value::Unsigned(3)]),
+                    ),
+                ]),
+            ),
+            (
+                &[0xc1, 0x1a, 0x51, 0x4b, 0x67, 0xb0],
+                Value::Tag(1, Box::new(Value::Unsigned(1_363_896_240))),
+            ),
+            (&[0xf4], Value::Bool(false)),
+            (&[0xf5], Value::Bool(true)),
+            (&[0xf6], Value::Null),
+        ];
+        for (encoded, value) in examples {
+            assert_eq!(decode(encoded).unwrap(), value);
+            assert_eq!(encode(&value), encoded);
+        }
+    }
+
+    #[test]
+    fn test_malformed() {
+        let malformed: Vec<&[u8]> = vec![
+            // empty
+            &[],
+            // truncated length
+            &[0x19, 0x03],
+            // byte string longer than the input
+            &[0x5a, 0xff, 0xff, 0xff, 0xff, 0x00],
+            // map with a huge number of entries
+            &[0xbb, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            // indefinite length
+            &[0x9f, 0x01, 0xff],
+            // float
+            &[0xf9, 0x3c, 0x00],
+            // invalid UTF-8
+            &[0x61, 0xff],
+            // trailing bytes
+            &[0x01, 0x02],
+        ];
+        for input in malformed {
+            assert_eq!(
+                decode(input).unwrap_err().kind(),
+                &ErrorKind::ParseError,
+                "{:?}",
+                input
+            );
+        }
+        let nested = [vec![0x81; MAX_DEPTH + 1], vec![0x01]].concat();
+        assert!(decode(&nested).is_err());
+    }
+}
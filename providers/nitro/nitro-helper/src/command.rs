@@ -1,34 +1,76 @@
+use crate::attestation::{self, AWS_NITRO_ROOT_G1_SHA256};
 use crate::config::NitroSignOpt;
-use crate::key_utils::generate_key;
+use crate::key_utils::{self, generate_key, generate_key_on_host};
+use crate::logs::LogReceiver;
 use crate::proxy::Proxy;
-use crate::shared::{AwsCredentials, NitroConfig};
+use crate::shared::{
+    AwsCredentials, ConfigChannel, NitroConfig, NitroKeygenConfig, NitroRequest, NitroResponse,
+};
 use crate::state::StateSyncer;
-use nix::sys::socket::SockAddr;
+use crate::transport::{Transport, VsockTransport};
+use crate::InitOpt;
+use rand_core::{OsRng, RngCore};
 use rusoto_credential::{InstanceMetadataProvider, ProvideAwsCredentials};
-use std::{fs, path::PathBuf};
+use std::fs;
+use std::path::PathBuf;
 use sysinfo::{ProcessExt, SystemExt};
-use tendermint::net;
-use tmkms_light::utils::write_u16_payload;
-use tmkms_light::utils::{print_pubkey, PubkeyDisplay};
-use tracing::{debug, Level};
-use tracing_subscriber::FmtSubscriber;
-
-/// write tmkms.toml + generate keys
-pub fn init(
-    config_path: Option<PathBuf>,
-    pubkey_display: Option<PubkeyDisplay>,
-    bech32_prefix: Option<String>,
-    aws_region: String,
-    kms_key_id: String,
-) -> Result<(), String> {
-    let cp = config_path.unwrap_or_else(|| "tmkms.toml".into());
+use tendermint::{consensus, net};
+use tmkms_light::{
+    cli::{self, ConfigOpt, LogOpt, PubkeyOpt},
+    control::{SigningControl, StatusTracker},
+    utils::print_pubkey,
+};
+use tracing::{debug, info, warn, Level};
+
+/// checks the vsock proxy (for KMS requests from the enclave) is running
+fn check_vsock_proxy() -> Result<(), String> {
+    let mut system = sysinfo::System::new_all();
+    system.refresh_all();
+    if system
+        .get_processes()
+        .iter()
+        .any(|(_pid, p)| p.name() == "vsock-proxy")
+    {
+        Ok(())
+    } else {
+        Err("vsock-proxy not running".to_owned())
+    }
+}
+
+/// the configured credentials or the ones obtained from the instance metadata (IAM)
+fn get_credentials(credentials: Option<AwsCredentials>) -> Result<AwsCredentials, String> {
+    if let Some(credentials) = credentials {
+        return Ok(credentials);
+    }
+    let mut rt = tokio::runtime::Runtime::new()
+        .map_err(|e| format!("failed to get tokio runtime: {:?}", e))?;
+    let credentials = rt
+        .block_on(async move { InstanceMetadataProvider::new().credentials().await })
+        .map_err(|e| format!("failed to obtain AWS credentials: {:?}", e))?;
+    Ok(AwsCredentials {
+        aws_key_id: credentials.aws_access_key_id().to_owned(),
+        aws_secret_key: credentials.aws_secret_access_key().to_owned(),
+        aws_session_token: credentials
+            .token()
+            .as_ref()
+            .ok_or_else(|| "failed to get a session token".to_owned())?
+            .to_owned(),
+    })
+}
+
+/// write tmkms.toml + generate keys (in the enclave, or on the host with `--host-keygen`)
+pub fn init(config_opt: ConfigOpt, pubkey_opt: PubkeyOpt, opt: InitOpt) -> Result<(), String> {
+    if !opt.host_keygen {
+        check_vsock_proxy()?;
+    }
     let config = NitroSignOpt {
-        aws_region,
+        aws_region: opt.aws_region,
         ..Default::default()
     };
     let t = toml::to_string_pretty(&config)
         .map_err(|e| format!("failed to create a config in toml: {:?}", e))?;
-    fs::write(cp, t).map_err(|e| format!("failed to write a config: {:?}", e))?;
+    fs::write(&config_opt.config_path, t)
+        .map_err(|e| format!("failed to write a config: {:?}", e))?;
     fs::create_dir_all(
         config
             .sealed_consensus_key_path
@@ -43,127 +85,361 @@ pub fn init(
             .ok_or_else(|| "cannot create a dir in a root directory".to_owned())?,
     )
     .map_err(|e| format!("failed to create dirs for state storage: {:?}", e))?;
+    let credentials = get_credentials(config.credentials)?;
+    let aws_region = config.aws_region;
+    let kms_key_id = opt.kms_key_id;
+    if opt.host_keygen {
+        warn!(
+            "generating the keys on the host (the plaintext keys aren't protected by the enclave)"
+        );
+        let pubkey = generate_key_on_host(
+            &config.sealed_consensus_key_path,
+            &aws_region,
+            &credentials,
+            kms_key_id.clone(),
+        )
+        .map_err(|e| format!("failed to generate a key: {}", e))?;
+        print_pubkey(pubkey_opt.bech32_prefix, pubkey_opt.pubkey_display, pubkey);
+        if let Some(id_path) = config.sealed_id_key_path {
+            generate_key_on_host(id_path, &aws_region, &credentials, kms_key_id)
+                .map_err(|e| format!("failed to generate a key: {}", e))?;
+        }
+        return Ok(());
+    }
+    let keygen_config = || NitroKeygenConfig {
+        kms_key_id: kms_key_id.clone(),
+        credentials: credentials.clone(),
+        aws_region: aws_region.clone(),
+    };
+    let transport = VsockTransport::host(opt.cid.unwrap_or(config.enclave_config_cid));
     let pubkey = generate_key(
         config.sealed_consensus_key_path,
-        &config.aws_region,
-        kms_key_id.clone(),
+        &transport,
+        config.enclave_config_port,
+        keygen_config(),
     )
     .map_err(|e| format!("failed to generate a key: {:?}", e))?;
-    print_pubkey(bech32_prefix, pubkey_display, pubkey);
+    print_pubkey(pubkey_opt.bech32_prefix, pubkey_opt.pubkey_display, pubkey);
     if let Some(id_path) = config.sealed_id_key_path {
-        generate_key(id_path, &config.aws_region, kms_key_id)
-            .map_err(|e| format!("failed to generate a key: {:?}", e))?;
+        generate_key(
+            id_path,
+            &transport,
+            config.enclave_config_port,
+            keygen_config(),
+        )
+        .map_err(|e| format!("failed to generate a key: {:?}", e))?;
     }
     Ok(())
 }
 
-/// push config to enclave, start up a proxy (if needed) + state syncer
-pub fn start(config_path: Option<PathBuf>, cid: Option<u32>) -> Result<(), String> {
-    let cp = config_path.unwrap_or_else(|| "tmkms.toml".into());
-    if !cp.exists() {
-        Err("missing tmkms.toml file".to_owned())
-    } else {
-        let mut system = sysinfo::System::new_all();
-        system.refresh_all();
-        if !system
-            .get_processes()
-            .iter()
-            .any(|(_pid, p)| p.name() == "vsock-proxy")
-        {
-            return Err("vsock-proxy not running".to_owned());
+/// length of the random nonce included in the attestation document
+const ATTESTATION_NONCE_LEN: usize = 32;
+
+fn read_config(config: &ConfigOpt) -> Result<NitroSignOpt, String> {
+    let toml_string = cli::read_config_file(config)?;
+    toml::from_str(&toml_string).map_err(|e| format!("toml config file failed to parse: {:?}", e))
+}
+
+/// Relays the pause/resume requests from the control socket
+/// to the enclave (over a new config channel connection)
+struct EnclaveControl {
+    transport: VsockTransport,
+    port: u32,
+}
+
+impl EnclaveControl {
+    fn request(&self, request: &NitroRequest) -> Result<NitroResponse, String> {
+        let socket = self
+            .transport
+            .connect(self.port)
+            .map_err(|e| format!("failed to connect to the enclave: {:?}", e))?;
+        let mut channel = ConfigChannel::connect(socket)
+            .map_err(|e| format!("failed to negotiate the config channel: {}", e))?;
+        channel
+            .send_request(request)
+            .map_err(|e| format!("failed to write the request: {}", e))?;
+        channel
+            .recv_response()
+            .map_err(|e| format!("failed to get the enclave response: {}", e))
+    }
+}
+
+impl SigningControl for EnclaveControl {
+    fn pause(&self) -> Result<Option<consensus::State>, String> {
+        match self.request(&NitroRequest::Pause)? {
+            NitroResponse::Paused { state } => Ok(state),
+            NitroResponse::Error(e) => Err(format!("enclave failed to pause: {}", e)),
+            r => Err(format!("unexpected response: {:?}", r)),
         }
+    }
 
-        let subscriber = FmtSubscriber::builder()
-            .with_max_level(Level::INFO)
-            .finish();
-
-        tracing::subscriber::set_global_default(subscriber)
-            .map_err(|e| format!("setting default subscriber failed: {:?}", e))?;
-        let toml_string = fs::read_to_string(cp)
-            .map_err(|e| format!("toml config file failed to read: {:?}", e))?;
-        let config: NitroSignOpt = toml::from_str(&toml_string)
-            .map_err(|e| format!("toml config file failed to parse: {:?}", e))?;
-        let credentials = if let Some(credentials) = config.credentials {
-            credentials
-        } else {
-            let mut rt = tokio::runtime::Runtime::new()
-                .map_err(|e| format!("failed to get tokio runtime: {:?}", e))?;
-            let credentials = rt
-                .block_on(async move { InstanceMetadataProvider::new().credentials().await })
-                .map_err(|e| format!("failed to obtain AWS credentials: {:?}", e))?;
-            AwsCredentials {
-                aws_key_id: credentials.aws_access_key_id().to_owned(),
-                aws_secret_key: credentials.aws_secret_access_key().to_owned(),
-                aws_session_token: credentials
-                    .token()
-                    .as_ref()
-                    .ok_or_else(|| "failed to get a session token".to_owned())?
-                    .to_owned(),
-            }
-        };
-        let peer_id = match &config.address {
-            net::Address::Tcp { peer_id, .. } => *peer_id,
-            _ => None,
+    fn resume(&self) -> Result<(), String> {
+        match self.request(&NitroRequest::Resume)? {
+            NitroResponse::Resumed => Ok(()),
+            NitroResponse::Error(e) => Err(format!("enclave failed to resume: {}", e)),
+            r => Err(format!("unexpected response: {:?}", r)),
+        }
+    }
+}
+
+/// request an attestation document from the enclave, verify it and compare its PCRs
+/// with the expected values
+pub fn attest(config: ConfigOpt, cid: Option<u32>) -> Result<(), String> {
+    let config = read_config(&config)?;
+    let root_path = config
+        .attestation_root_cert_path
+        .ok_or_else(|| "missing `attestation_root_cert_path` in the config".to_owned())?;
+    let root = fs::read(&root_path).map_err(|e| {
+        format!(
+            "failed to read the root certificate `{}`: {:?}",
+            root_path.display(),
+            e
+        )
+    })?;
+    let fingerprint =
+        attestation::fingerprint(&root).map_err(|e| format!("invalid root certificate: {}", e))?;
+    if fingerprint != AWS_NITRO_ROOT_G1_SHA256 {
+        eprintln!(
+            "warning: the root certificate is not the AWS Nitro Enclaves one (SHA-256: {})",
+            fingerprint
+        );
+    }
+    let expected = config.expected_pcrs.unwrap_or_default().to_map()?;
+
+    let mut nonce = vec![0u8; ATTESTATION_NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    let transport = VsockTransport::host(cid.unwrap_or(config.enclave_config_cid));
+    let socket = transport
+        .connect(config.enclave_config_port)
+        .map_err(|e| format!("failed to connect to the enclave: {:?}", e))?;
+    let mut channel = ConfigChannel::connect(socket)
+        .map_err(|e| format!("failed to negotiate the config channel: {}", e))?;
+    channel
+        .send_request(&NitroRequest::Attest {
+            nonce: nonce.clone(),
+        })
+        .map_err(|e| format!("failed to write the attestation request: {}", e))?;
+    let document = match channel
+        .recv_response()
+        .map_err(|e| format!("failed to get the attestation response: {}", e))?
+    {
+        NitroResponse::Attestation { document } => document,
+        NitroResponse::Error(e) => return Err(format!("enclave failed to attest: {}", e)),
+        r => return Err(format!("unexpected response: {:?}", r)),
+    };
+    let doc = attestation::verify(&document, &root, &nonce)
+        .map_err(|e| format!("attestation document verification failed: {}", e))?;
+
+    println!("module ID: {}", doc.module_id);
+    println!("timestamp: {}", doc.timestamp);
+    let checks = attestation::check_pcrs(&doc, &expected);
+    let hex = |v: &Option<Vec<u8>>| {
+        v.as_ref().map_or_else(
+            || "-".to_owned(),
+            |v| String::from_utf8(subtle_encoding::hex::encode(v)).expect("hex"),
+        )
+    };
+    for check in checks.iter() {
+        let status = match (&check.expected, check.is_ok()) {
+            (None, _) => "not checked",
+            (Some(_), true) => "OK",
+            (Some(_), false) => "MISMATCH",
         };
-        let state_syncer = StateSyncer::new(config.state_file_path, config.enclave_state_port)
-            .map_err(|e| format!("failed to get a state syncing helper: {:?}", e))?;
-        let sealed_consensus_key = fs::read(config.sealed_consensus_key_path)
-            .map_err(|e| format!("failed to read a sealed consensus key: {:?}", e))?;
-        let sealed_id_key = if let Some(p) = config.sealed_id_key_path {
-            if let net::Address::Tcp { .. } = config.address {
-                Some(
-                    fs::read(p)
-                        .map_err(|e| format!("failed to read a sealed identity key: {:?}", e))?,
-                )
-            } else {
-                None
-            }
+        println!("PCR{}: {} ({})", check.index, hex(&check.actual), status);
+        if !check.is_ok() {
+            println!("  expected: {}", hex(&check.expected));
+        }
+    }
+    // the started enclave includes its consensus public key in the document
+    match doc.user_data.as_ref() {
+        Some(data) => match ed25519_dalek::PublicKey::from_bytes(data) {
+            Ok(public) => print_pubkey(None, None, public),
+            Err(_) => println!("user data: {}", hex(&doc.user_data)),
+        },
+        None => println!("the enclave has not been started"),
+    }
+    if checks.iter().all(|c| c.is_ok()) {
+        Ok(())
+    } else {
+        Err("PCR values do not match the expected ones".to_owned())
+    }
+}
+
+/// push config to enclave, start up a proxy (if needed), log receiver (if configured) + state syncer
+pub fn start(
+    config: ConfigOpt,
+    log: LogOpt,
+    default_log_level: Level,
+    cid: Option<u32>,
+) -> Result<(), String> {
+    let config = read_config(&config)?;
+    check_vsock_proxy()?;
+    log.init(&config.logging, default_log_level)?;
+    let credentials = get_credentials(config.credentials)?;
+    let peer_id = match &config.address {
+        net::Address::Tcp { peer_id, .. } => *peer_id,
+        _ => None,
+    };
+    let transport = VsockTransport::host(cid.unwrap_or(config.enclave_config_cid));
+    // the Unix connections are proxied by the helper, the TCP ones aren't visible to it
+    let tracker = StatusTracker::new(matches!(config.address, net::Address::Unix { .. }));
+    let state_syncer = StateSyncer::new(
+        config.state_file_path,
+        &transport,
+        config.enclave_state_port,
+    )
+    .map_err(|e| format!("failed to get a state syncing helper: {:?}", e))?
+    .with_tracker(tracker.clone());
+    let log_receiver = config
+        .enclave_log_port
+        .map(|port| LogReceiver::new(&transport, port))
+        .transpose()
+        .map_err(|e| format!("failed to get an enclave log receiver: {}", e))?;
+    let sealed_consensus_key = fs::read(&config.sealed_consensus_key_path)
+        .map_err(|e| format!("failed to read a sealed consensus key: {:?}", e))?;
+    let sealed_id_key = if let Some(p) = config.sealed_id_key_path {
+        if let net::Address::Tcp { .. } = config.address {
+            Some(
+                fs::read(p)
+                    .map_err(|e| format!("failed to read a sealed identity key: {:?}", e))?,
+            )
         } else {
             None
-        };
-        let enclave_config = NitroConfig {
-            chain_id: config.chain_id.clone(),
-            max_height: config.max_height,
-            sealed_consensus_key,
-            sealed_id_key,
-            peer_id,
-            enclave_state_port: config.enclave_state_port,
-            enclave_tendermint_conn: config.enclave_tendermint_conn,
-            credentials,
-            aws_region: config.aws_region,
-        };
-        let addr = if let Some(cid) = cid {
-            SockAddr::new_vsock(cid, config.enclave_config_port)
-        } else {
-            SockAddr::new_vsock(config.enclave_config_cid, config.enclave_config_port)
-        };
-        let mut socket = vsock::VsockStream::connect(&addr).map_err(|e| {
-            format!(
-                "failed to connect to the enclave to push its config: {:?}",
-                e
+        }
+    } else {
+        None
+    };
+    let enclave_config = NitroConfig {
+        chain_id: config.chain_id.clone(),
+        max_height: config.max_height,
+        sealed_consensus_key,
+        sealed_id_key,
+        peer_id,
+        enclave_state_port: config.enclave_state_port,
+        enclave_log_port: config.enclave_log_port,
+        enclave_tendermint_conn: config.enclave_tendermint_conn,
+        credentials,
+        aws_region: config.aws_region,
+    };
+    let proxy = match &config.address {
+        net::Address::Unix { path } => {
+            debug!(
+                "{}: Creating a proxy {}...",
+                &config.chain_id, &config.address
+            );
+
+            Some(
+                Proxy::new(
+                    Box::new(transport),
+                    config.enclave_tendermint_conn,
+                    path.clone(),
+                )
+                .with_tracker(tracker.clone()),
             )
-        })?;
-        let config_raw = serde_json::to_vec(&enclave_config)
-            .map_err(|e| format!("failed to serialize the config: {:?}", e))?;
-        write_u16_payload(&mut socket, &config_raw)
-            .map_err(|e| format!("failed to write the config: {:?}", e))?;
-        let proxy = match &config.address {
-            net::Address::Unix { path } => {
-                debug!(
-                    "{}: Creating a proxy {}...",
-                    &config.chain_id, &config.address
-                );
-
-                Some(Proxy::new(config.enclave_tendermint_conn, path.clone()))
+        }
+        _ => None,
+    };
+    if let Some(p) = proxy {
+        p.launch_proxy();
+    }
+    if let Some(r) = log_receiver {
+        r.launch_receiver();
+    }
+    let control = EnclaveControl {
+        transport,
+        port: config.enclave_config_port,
+    };
+    cli::launch_control_socket(
+        config.control_socket_path.as_deref(),
+        &tracker,
+        Box::new(control),
+    )?;
+    // the enclave loads its state and connects to the validator before reporting its status
+    let state_syncing = state_syncer.launch_syncer();
+    let socket = transport.connect(config.enclave_config_port).map_err(|e| {
+        format!(
+            "failed to connect to the enclave to push its config: {:?}",
+            e
+        )
+    })?;
+    let mut channel = ConfigChannel::connect(socket)
+        .map_err(|e| format!("failed to negotiate the config channel: {}", e))?;
+    channel
+        .send_request(&NitroRequest::Start(enclave_config))
+        .map_err(|e| format!("failed to write the config: {}", e))?;
+    info!("waiting for the enclave to start up");
+    match channel
+        .recv_response()
+        .map_err(|e| format!("failed to get the enclave status: {}", e))?
+    {
+        NitroResponse::Started {
+            public_key,
+            node_id,
+        } => {
+            info!("enclave connected to the validator");
+            tracker.set_public_key(public_key);
+            if let Some(ed25519) = public_key.ed25519() {
+                print_pubkey(None, None, ed25519);
+                // for the keys generated before the public keys were stored
+                let path = &config.sealed_consensus_key_path;
+                if key_utils::read_public_key(path).ok() != Some(ed25519) {
+                    key_utils::write_public_key(path, &ed25519)?;
+                }
+            }
+            if let Some(id) = node_id {
+                println!("KMS node ID: {}", id);
             }
-            _ => None,
-        };
-        if let Some(p) = proxy {
-            p.launch_proxy();
         }
-        // state syncing runs in an infinite loop (so does the proxy)
-        // TODO: check if signal capture + a graceful shutdown would help with anything (given state writing is via "tempfile")
-        state_syncer.launch_syncer().join().expect("state syncing");
-        Ok(())
+        NitroResponse::Error(e) => return Err(format!("enclave failed to start: {}", e)),
+        r => return Err(format!("unexpected response: {:?}", r)),
     }
+    // state syncing runs in an infinite loop (so does the proxy)
+    // TODO: check if signal capture + a graceful shutdown would help with anything (given state writing is via "tempfile")
+    state_syncing.join().expect("state syncing");
+    Ok(())
+}
+
+/// display the consensus public key (stored next to the sealed key)
+pub fn pubkey(config: ConfigOpt, pubkey: PubkeyOpt) -> Result<(), String> {
+    let config = read_config(&config)?;
+    let public = key_utils::read_public_key(&config.sealed_consensus_key_path)?;
+    print_pubkey(pubkey.bech32_prefix, pubkey.pubkey_display, public);
+    Ok(())
+}
+
+/// check the config and the sealed key files
+pub fn validate(config: ConfigOpt) -> Result<(), String> {
+    let config = read_config(&config)?;
+    cli::check_file_exists(&config.sealed_consensus_key_path, "sealed consensus key")?;
+    match (&config.address, &config.sealed_id_key_path) {
+        (net::Address::Tcp { .. }, None) => {
+            return Err("missing `sealed_id_key_path` for the TCP connection".to_owned())
+        }
+        (net::Address::Tcp { .. }, Some(id_path)) => {
+            cli::check_file_exists(id_path, "sealed id key")?;
+        }
+        _ => {}
+    }
+    if let Some(expected) = &config.expected_pcrs {
+        expected.to_map()?;
+    }
+    cli::read_state_file(&config.state_file_path)?;
+    println!("configuration OK");
+    Ok(())
+}
+
+/// display the signer status
+pub fn status(config: ConfigOpt) -> Result<(), String> {
+    let config = read_config(&config)?;
+    cli::print_status(
+        &config.chain_id,
+        &config.address,
+        &config.state_file_path,
+        config.control_socket_path.as_deref(),
+    )
+}
+
+/// the control socket of the running helper (if configured)
+pub fn control_socket_path(config: ConfigOpt) -> Result<Option<PathBuf>, String> {
+    Ok(read_config(&config)?.control_socket_path)
 }
@@ -1,7 +1,8 @@
 use crate::shared::AwsCredentials;
 use serde::{Deserialize, Serialize};
-use std::{convert::TryFrom, path::PathBuf};
+use std::{collections::BTreeMap, convert::TryFrom, path::PathBuf};
 use tendermint::{chain, net};
+use tmkms_light::logging::LogConfig;
 
 /// nitro options for toml configuration
 #[derive(Debug, Serialize, Deserialize)]
@@ -25,12 +26,59 @@ pub struct NitroSignOpt {
     pub enclave_config_port: u32,
     /// Vsock port to listen on for state synchronization
     pub enclave_state_port: u32,
+    /// Vsock port to listen on for the enclave log records (not forwarded if not set)
+    pub enclave_log_port: Option<u32>,
     /// Vsock port to forward privval plain traffic to TM over UDS (or just pass to enclave if TCP/secret connection)
     pub enclave_tendermint_conn: u32,
     /// AWS credentials -- if not set, they'll be obtained from IAM
     pub credentials: Option<AwsCredentials>,
     /// AWS region
     pub aws_region: String,
+    /// Path to the Nitro Enclaves root certificate (PEM) for verifying the attestation documents
+    pub attestation_root_cert_path: Option<PathBuf>,
+    /// Expected PCR values of the enclave image (hex-encoded SHA-384, as printed by `nitro-cli build-enclave`)
+    pub expected_pcrs: Option<ExpectedPcrs>,
+    /// Path to the control socket for querying the running signer (if any)
+    pub control_socket_path: Option<PathBuf>,
+    /// Logging options
+    #[serde(default)]
+    pub logging: LogConfig,
+}
+
+/// The expected PCR values of the enclave
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExpectedPcrs {
+    /// enclave image file
+    pub pcr0: Option<String>,
+    /// Linux kernel and bootstrap
+    pub pcr1: Option<String>,
+    /// application
+    pub pcr2: Option<String>,
+    /// signing certificate (if the enclave image was signed)
+    pub pcr8: Option<String>,
+}
+
+impl ExpectedPcrs {
+    /// the decoded values by their PCR index
+    pub fn to_map(&self) -> Result<BTreeMap<u64, Vec<u8>>, String> {
+        let mut map = BTreeMap::new();
+        for (index, value) in [
+            (0, &self.pcr0),
+            (1, &self.pcr1),
+            (2, &self.pcr2),
+            (8, &self.pcr8),
+        ]
+        .iter()
+        {
+            if let Some(hex) = value {
+                let pcr = subtle_encoding::hex::decode(hex.trim().to_lowercase())
+                    .map_err(|e| format!("invalid PCR{} value: {}", index, e))?;
+                map.insert(*index, pcr);
+            }
+        }
+        Ok(map)
+    }
 }
 
 impl Default for NitroSignOpt {
@@ -47,9 +95,14 @@ impl Default for NitroSignOpt {
             enclave_config_cid: 15,
             enclave_config_port: 5050,
             enclave_state_port: 5555,
+            enclave_log_port: Some(5557),
             enclave_tendermint_conn: 5000,
             credentials: None,
             aws_region: "ap-southeast-1".to_owned(),
+            attestation_root_cert_path: Some("AWS_NitroEnclaves_Root-G1.pem".into()),
+            expected_pcrs: None,
+            control_socket_path: Some("state/control.sock".into()),
+            logging: LogConfig::default(),
         }
     }
 }
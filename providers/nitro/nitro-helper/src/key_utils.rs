@@ -1,52 +1,166 @@
+use crate::shared::{
+    AwsCredentials, ConfigChannel, NitroKeygenConfig, NitroRequest, NitroResponse,
+};
+use crate::transport::Transport;
 use bytes::Bytes;
 use ed25519_dalek::{Keypair, PublicKey};
 use rand_core::OsRng;
 use rusoto_core::{region::Region, HttpClient};
-use rusoto_credential::InstanceMetadataProvider;
+use rusoto_credential::StaticProvider;
 use rusoto_kms::{EncryptRequest, Kms, KmsClient};
 use std::str::FromStr;
-use std::{fs::OpenOptions, io::Write, os::unix::fs::OpenOptionsExt, path::Path};
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
 
-/// Generates key and encrypts with AWS KMS at the given path
-/// TODO: generate in NE after this is merged https://github.com/aws/aws-nitro-enclaves-sdk-c/pull/25
+/// The path of the public key file next to the sealed key
+/// (the sealed key can only be decrypted in the enclave)
+pub fn public_key_path(sealed_key_path: impl AsRef<Path>) -> PathBuf {
+    let mut path = sealed_key_path.as_ref().as_os_str().to_owned();
+    path.push(".pub");
+    path.into()
+}
+
+/// Writes the base64-encoded public key next to the sealed key
+pub fn write_public_key(
+    sealed_key_path: impl AsRef<Path>,
+    public: &PublicKey,
+) -> Result<(), String> {
+    let path = public_key_path(sealed_key_path);
+    fs::write(&path, subtle_encoding::base64::encode(public))
+        .map_err(|e| format!("couldn't write `{}`: {}", path.display(), e))
+}
+
+/// Reads the public key stored next to the sealed key
+pub fn read_public_key(sealed_key_path: impl AsRef<Path>) -> Result<PublicKey, String> {
+    let path = public_key_path(sealed_key_path);
+    let encoded = fs::read_to_string(&path)
+        .map_err(|e| format!("couldn't read `{}`: {}", path.display(), e))?;
+    let bytes = subtle_encoding::base64::decode(encoded.trim())
+        .map_err(|e| format!("invalid public key in `{}`: {}", path.display(), e))?;
+    PublicKey::from_bytes(&bytes)
+        .map_err(|e| format!("invalid public key in `{}`: {}", path.display(), e))
+}
+
+/// Requests the enclave to generate a key and encrypt it with AWS KMS,
+/// and writes the ciphertext at the given path and the public key next to it
+/// (the plaintext key never leaves the enclave)
 pub fn generate_key(
+    path: impl AsRef<Path>,
+    transport: &dyn Transport,
+    enclave_config_port: u32,
+    keygen_config: NitroKeygenConfig,
+) -> Result<PublicKey, String> {
+    let socket = transport
+        .connect(enclave_config_port)
+        .map_err(|e| format!("failed to connect to the enclave: {:?}", e))?;
+    let mut channel = ConfigChannel::connect(socket)
+        .map_err(|e| format!("failed to negotiate the config channel: {}", e))?;
+    channel
+        .send_request(&NitroRequest::KeyGen(keygen_config))
+        .map_err(|e| format!("failed to write the keygen request: {}", e))?;
+    let (public_key, ciphertext) = match channel
+        .recv_response()
+        .map_err(|e| format!("failed to get the keygen response: {}", e))?
+    {
+        NitroResponse::KeyGenerated {
+            public_key,
+            sealed_key,
+        } => (public_key, sealed_key),
+        NitroResponse::Error(e) => {
+            return Err(format!("enclave failed to generate a key: {}", e));
+        }
+        r => return Err(format!("unexpected response: {:?}", r)),
+    };
+    let public = public_key
+        .ed25519()
+        .ok_or_else(|| "unexpected public key type".to_owned())?;
+    write_sealed_key(path, &ciphertext, &public)?;
+    Ok(public)
+}
+
+/// Generates a key on the host and encrypts it with AWS KMS,
+/// and writes the ciphertext at the given path and the public key next to it
+/// (the plaintext key is seen by the host: only used with `init --host-keygen`,
+/// as the enclave SDK bindings can't encrypt with the chosen KMS key yet)
+pub fn generate_key_on_host(
     path: impl AsRef<Path>,
     region: &str,
+    credentials: &AwsCredentials,
     key_id: String,
 ) -> Result<PublicKey, String> {
     let region = Region::from_str(region).map_err(|e| format!("invalid region: {}", e))?;
-    let mut csprng = OsRng {};
-    let keypair: Keypair = Keypair::generate(&mut csprng);
-    let public = keypair.public;
+    let keypair = Keypair::generate(&mut OsRng);
+    let provider = StaticProvider::new(
+        credentials.aws_key_id.clone(),
+        credentials.aws_secret_key.clone(),
+        Some(credentials.aws_session_token.clone()),
+        None,
+    );
+    let request = EncryptRequest {
+        encryption_context: None,
+        grant_tokens: None,
+        encryption_algorithm: None,
+        key_id,
+        plaintext: Bytes::copy_from_slice(keypair.secret.as_bytes()),
+    };
     let mut rt = tokio::runtime::Runtime::new()
-        .map_err(|err| format!("Failed to init tokio runtime: {}", err))?;
+        .map_err(|e| format!("failed to get tokio runtime: {:?}", e))?;
     let ciphertext = rt
         .block_on(async move {
-            let provider = InstanceMetadataProvider::new();
             let dispatcher = HttpClient::new()
-                .map_err(|e| format!("failed to create request dispatcher {}", e))?;
+                .map_err(|e| format!("failed to create request dispatcher: {}", e))?;
             let client = KmsClient::new_with(dispatcher, provider, region);
             client
-                .encrypt(EncryptRequest {
-                    encryption_context: None,
-                    grant_tokens: None,
-                    encryption_algorithm: None,
-                    key_id,
-                    plaintext: Bytes::copy_from_slice(keypair.secret.as_bytes()),
-                })
+                .encrypt(request)
                 .await
-                .map_err(|err| format!("Failed to obtain ciphertext from instance: {}", err))
+                .map_err(|e| format!("KMS Encrypt request failed: {}", e))
         })?
         .ciphertext_blob
-        .ok_or_else(|| "failed to obtain ciphertext blob".to_owned())?;
+        .ok_or_else(|| "no ciphertext in the KMS Encrypt response".to_owned())?;
+    write_sealed_key(path, &ciphertext, &keypair.public)?;
+    Ok(keypair.public)
+}
 
+/// Writes the ciphertext (only readable by the owner) and the public key next to it
+fn write_sealed_key(
+    path: impl AsRef<Path>,
+    ciphertext: &[u8],
+    public: &PublicKey,
+) -> Result<(), String> {
     OpenOptions::new()
         .create(true)
         .write(true)
         .truncate(true)
         .mode(0o600)
         .open(path.as_ref())
-        .and_then(|mut file| file.write_all(&*ciphertext))
+        .and_then(|mut file| file.write_all(ciphertext))
         .map_err(|e| format!("couldn't write `{}`: {}", path.as_ref().display(), e))?;
-    Ok(public)
+    write_public_key(path, public)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::Keypair;
+    use rand_core::OsRng;
+
+    #[test]
+    fn test_public_key_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let sealed_key_path = dir.path().join("consensus-key.sealed");
+        assert_eq!(
+            public_key_path(&sealed_key_path),
+            dir.path().join("consensus-key.sealed.pub")
+        );
+        assert!(read_public_key(&sealed_key_path).is_err());
+        let public = Keypair::generate(&mut OsRng).public;
+        write_public_key(&sealed_key_path, &public).unwrap();
+        assert_eq!(read_public_key(&sealed_key_path).unwrap(), public);
+        fs::write(public_key_path(&sealed_key_path), "AAAA").unwrap();
+        assert!(read_public_key(&sealed_key_path).is_err());
+    }
 }
@@ -1,2 +1,8 @@
+#[cfg(feature = "main")]
+pub mod attestation;
+pub mod cbor;
+pub mod logs;
 pub mod shared;
+pub mod state;
+pub mod transport;
 pub use shared::*;
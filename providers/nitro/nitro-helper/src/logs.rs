@@ -0,0 +1,144 @@
+//! The enclave has no console in production, so its log records
+//! (JSON objects as written by the `tracing-subscriber` JSON formatter)
+//! are sent over a dedicated port and re-emitted here
+//! with the `enclave` target.
+
+use crate::transport::{Listener, Stream, Transport};
+use anomaly::format_err;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::thread;
+use tmkms_light::error::{Error, ErrorKind};
+use tmkms_light::framing::{FrameType, FramedStream};
+use tracing::{debug, event, info, warn, Level};
+
+/// The upper bound on the size of a single log record
+pub const MAX_LOG_RECORD_LEN: u32 = 64 * 1024;
+
+/// A log record from the enclave
+#[derive(Debug, Deserialize)]
+pub struct LogRecord {
+    /// `ERROR`, `WARN`, `INFO`, `DEBUG` or `TRACE`
+    pub level: String,
+    /// the module path in the enclave
+    pub target: String,
+    /// the message and other event fields
+    #[serde(default)]
+    pub fields: Map<String, Value>,
+}
+
+impl LogRecord {
+    /// parses the JSON record
+    pub fn parse(raw: &[u8]) -> Result<Self, Error> {
+        serde_json::from_slice(raw).map_err(|e| {
+            format_err!(ErrorKind::SerializationError, "invalid log record: {}", e).into()
+        })
+    }
+
+    /// the message followed by the other fields (as `key=value`)
+    pub fn text(&self) -> String {
+        let mut text = match self.fields.get("message") {
+            Some(Value::String(message)) => message.clone(),
+            Some(other) => other.to_string(),
+            None => String::new(),
+        };
+        for (key, value) in self.fields.iter().filter(|(k, _)| *k != "message") {
+            if !text.is_empty() {
+                text.push(' ');
+            }
+            match value {
+                Value::String(s) => text.push_str(&format!("{}={}", key, s)),
+                other => text.push_str(&format!("{}={}", key, other)),
+            }
+        }
+        text
+    }
+
+    /// re-emits the record via the helper's subscriber
+    pub fn emit(&self) {
+        macro_rules! emit {
+            ($level:expr) => {
+                event!(target: "enclave", $level, module = %self.target, "{}", self.text())
+            };
+        }
+        match self.level.as_str() {
+            "ERROR" => emit!(Level::ERROR),
+            "WARN" => emit!(Level::WARN),
+            "DEBUG" => emit!(Level::DEBUG),
+            "TRACE" => emit!(Level::TRACE),
+            _ => emit!(Level::INFO),
+        }
+    }
+}
+
+/// receives the log records from the enclave
+pub struct LogReceiver {
+    listener: Box<dyn Listener>,
+}
+
+impl LogReceiver {
+    /// binds a listener for the enclave's log connection on the provided port
+    pub fn new(transport: &dyn Transport, port: u32) -> Result<Self, Error> {
+        let listener = transport.listen(port).map_err(|e| {
+            format_err!(
+                ErrorKind::IoError,
+                "failed to listen on port {}: {}",
+                port,
+                e
+            )
+        })?;
+        Ok(Self { listener })
+    }
+
+    /// Launches the receiver
+    pub fn launch_receiver(self) -> thread::JoinHandle<()> {
+        thread::spawn(move || loop {
+            match self.listener.accept() {
+                Ok(stream) => {
+                    info!("enclave log connection established");
+                    match FramedStream::accept(stream) {
+                        Ok(stream) => Self::receive(stream.with_max_len(MAX_LOG_RECORD_LEN)),
+                        Err(e) => warn!("log stream negotiation failed: {}", e),
+                    }
+                }
+                Err(e) => {
+                    warn!("log connection failed: {}", e);
+                }
+            }
+        })
+    }
+
+    /// re-emits the records until the stream is closed or can no longer be read
+    fn receive(mut stream: FramedStream<Box<dyn Stream>>) {
+        loop {
+            match stream.recv_payload(FrameType::Log) {
+                Ok(raw) => match LogRecord::parse(&raw) {
+                    Ok(record) => record.emit(),
+                    Err(e) => debug!("{}", e),
+                },
+                Err(e) => {
+                    info!("enclave log stream closed: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_record() {
+        let raw = br#"{"timestamp":"Jun 01 00:00:00.000","level":"WARN","fields":{"message":"failed to connect to validator","height":5,"chain_id":"testchain-1"},"target":"tmkms_nitro_enclave::nitro"}"#;
+        let record = LogRecord::parse(raw).unwrap();
+        assert_eq!(record.level, "WARN");
+        assert_eq!(record.target, "tmkms_nitro_enclave::nitro");
+        assert_eq!(
+            record.text(),
+            "failed to connect to validator chain_id=testchain-1 height=5"
+        );
+        assert!(LogRecord::parse(b"not json").is_err());
+    }
+}
@@ -2,64 +2,89 @@ mod command;
 mod config;
 mod key_utils;
 mod proxy;
-mod shared;
-mod state;
 
 use std::path::PathBuf;
 use structopt::StructOpt;
-use tmkms_light::utils::PubkeyDisplay;
+use tmkms_light::cli::{self, ConfigOpt, LogOpt, Provider, PubkeyOpt};
+use tmkms_nitro_helper::{attestation, logs, shared, state, transport};
+
+/// Nitro-specific options for `init`
+#[derive(Debug, StructOpt)]
+pub struct InitOpt {
+    #[structopt(short)]
+    aws_region: String,
+    #[structopt(short)]
+    kms_key_id: String,
+    #[structopt(long)]
+    cid: Option<u32>,
+    /// generate the keys on the host and encrypt them with the KMS key via the instance's KMS client
+    /// (the host sees the plaintext keys; only until the enclave SDK bindings can encrypt with a chosen KMS key)
+    #[structopt(long)]
+    host_keygen: bool,
+}
+
+/// Nitro-specific options for `start`
+#[derive(Debug, StructOpt)]
+pub struct StartOpt {
+    #[structopt(long)]
+    cid: Option<u32>,
+}
 
 /// Helper sub-commands
 #[derive(Debug, StructOpt)]
-#[structopt(
-    name = "tmkms-nitro-helper",
-    about = "helper (proxies etc.) for nitro enclave execution"
-)]
-enum TmkmsLight {
-    #[structopt(name = "init", about = "Create config + keygen")]
-    /// Create config + keygen
-    Init {
-        #[structopt(short)]
-        config_path: Option<PathBuf>,
-        #[structopt(short)]
-        pubkey_display: Option<PubkeyDisplay>,
-        #[structopt(short)]
-        bech32_prefix: Option<String>,
-        #[structopt(short)]
-        aws_region: String,
-        #[structopt(short)]
-        kms_key_id: String,
-    },
-    #[structopt(name = "start", about = "start tmkms process")]
-    /// start tmkms process (push config + start up proxy and state persistence)
-    Start {
-        #[structopt(short)]
-        config_path: Option<PathBuf>,
+enum NitroCommand {
+    #[structopt(name = "attest", about = "verify the enclave attestation")]
+    /// request an attestation document from the enclave, verify it and print its PCRs
+    Attest {
+        #[structopt(flatten)]
+        config: ConfigOpt,
         #[structopt(long)]
         cid: Option<u32>,
     },
 }
 
-fn main() {
-    let opt = TmkmsLight::from_args();
-    let result = match opt {
-        TmkmsLight::Init {
-            config_path,
-            pubkey_display,
-            bech32_prefix,
-            aws_region,
-            kms_key_id,
-        } => command::init(
-            config_path,
-            pubkey_display,
-            bech32_prefix,
-            aws_region,
-            kms_key_id,
-        ),
-        TmkmsLight::Start { config_path, cid } => command::start(config_path, cid),
-    };
-    if let Err(e) = result {
-        eprintln!("{}", e);
-        std::process::exit(1);
+/// helper (proxies etc.) for nitro enclave execution
+struct NitroHelper;
+
+impl Provider for NitroHelper {
+    type InitOpt = InitOpt;
+    type StartOpt = StartOpt;
+    type Command = NitroCommand;
+
+    const NAME: &'static str = "tmkms-nitro-helper";
+    const ABOUT: &'static str = "helper (proxies etc.) for nitro enclave execution";
+
+    fn init(&self, config: ConfigOpt, pubkey: PubkeyOpt, opt: InitOpt) -> Result<(), String> {
+        command::init(config, pubkey, opt)
+    }
+
+    fn start(&self, config: ConfigOpt, log: LogOpt, opt: StartOpt) -> Result<(), String> {
+        command::start(config, log, Self::DEFAULT_LOG_LEVEL, opt.cid)
     }
+
+    fn pubkey(&self, config: ConfigOpt, pubkey: PubkeyOpt) -> Result<(), String> {
+        command::pubkey(config, pubkey)
+    }
+
+    fn validate(&self, config: ConfigOpt) -> Result<(), String> {
+        command::validate(config)
+    }
+
+    fn status(&self, config: ConfigOpt) -> Result<(), String> {
+        command::status(config)
+    }
+
+    fn control_socket_path(&self, config: ConfigOpt) -> Result<Option<PathBuf>, String> {
+        command::control_socket_path(config)
+    }
+
+    fn command(&self, command: NitroCommand) -> Result<(), String> {
+        match command {
+            NitroCommand::Attest { config, cid } => command::attest(config, cid),
+        }
+    }
+}
+
+fn main() {
+    cli::main(&NitroHelper);
 }
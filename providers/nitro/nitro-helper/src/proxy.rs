@@ -1,6 +1,5 @@
-use crate::shared::VSOCK_PROXY_CID;
+use crate::transport::{Listener, Transport};
 use nix::sys::select::{select, FdSet};
-use nix::sys::socket::SockAddr;
 use std::io::Read;
 use std::io::Write;
 use std::os::unix::io::AsRawFd;
@@ -8,45 +7,61 @@ use std::os::unix::net::UnixStream;
 use std::path::PathBuf;
 use std::thread;
 use std::time::Duration;
+use tmkms_light::control::StatusTracker;
 use tracing::{error, info, trace};
-use vsock::VsockListener;
 
 /// Configuration parameters for port listening and remote destination
 pub struct Proxy {
+    transport: Box<dyn Transport>,
     local_port: u32,
     remote_addr: PathBuf,
+    tracker: Option<StatusTracker>,
 }
 
 impl Proxy {
-    /// creates a new vsock<->uds proxy
-    pub fn new(local_port: u32, remote_addr: PathBuf) -> Self {
+    /// creates a new vsock<->uds proxy (or another transport<->uds)
+    pub fn new(transport: Box<dyn Transport>, local_port: u32, remote_addr: PathBuf) -> Self {
         Self {
+            transport,
             local_port,
             remote_addr,
+            tracker: None,
+        }
+    }
+
+    /// records the validator connections in the tracker
+    pub fn with_tracker(self, tracker: StatusTracker) -> Self {
+        Self {
+            tracker: Some(tracker),
+            ..self
         }
     }
 
     /// Creates a listening socket
     /// Returns the file descriptor for it or the appropriate error
-    pub fn sock_listen(&self) -> Result<VsockListener, String> {
-        info!("binding proxy to vsock port: {}", self.local_port);
-        let sockaddr = SockAddr::new_vsock(VSOCK_PROXY_CID, self.local_port);
-        let listener = VsockListener::bind(&sockaddr)
-            .map_err(|_| format!("Could not bind to {:?}", sockaddr))?;
-        info!("Bound to {:?}", sockaddr);
+    pub fn sock_listen(&self) -> Result<Box<dyn Listener>, String> {
+        info!("binding proxy to port: {}", self.local_port);
+        let listener = self
+            .transport
+            .listen(self.local_port)
+            .map_err(|_| format!("Could not bind to port {}", self.local_port))?;
+        info!("Bound to port {} ({:?})", self.local_port, self.transport);
         Ok(listener)
     }
 
     /// Accepts an incoming connection coming on listener and handles it on a
     /// different thread
     /// Returns the handle for the new thread or the appropriate error
-    pub fn sock_accept(&self, listener: &VsockListener) -> Result<(), String> {
-        let (mut client, client_addr) = listener
+    pub fn sock_accept(&self, listener: &dyn Listener) -> Result<(), String> {
+        let mut client = listener
             .accept()
             .map_err(|_| "Could not accept connection")?;
-        info!("Accepted connection on {:?}", client_addr);
+        info!("Accepted connection {:?}", client);
         let mut server = UnixStream::connect(&self.remote_addr)
             .map_err(|_| format!("Could not connect to {:?}", self.remote_addr))?;
+        if let Some(tracker) = &self.tracker {
+            tracker.connected();
+        }
 
         let client_socket = client.as_raw_fd();
         let server_socket = server.as_raw_fd();
@@ -56,8 +71,6 @@ impl Proxy {
             let mut set = FdSet::new();
             set.insert(client_socket);
             set.insert(server_socket);
-            trace!("proxy peer addr: {:?}", client.peer_addr());
-            trace!("proxy local addr: {:?}", client.local_addr());
             trace!("proxy fd: {} {}", client.as_raw_fd(), client_socket);
             trace!("proxy uds/server fd: {}", server_socket);
             select(None, Some(&mut set), None, None, None).expect("select");
@@ -71,7 +84,10 @@ impl Proxy {
                 disconnected = transfer(&mut server, &mut client);
             }
         }
-        info!("Client on {:?} disconnected", client_addr);
+        info!("Client {:?} disconnected", client);
+        if let Some(tracker) = &self.tracker {
+            tracker.disconnected();
+        }
         Ok(())
     }
 
@@ -80,7 +96,7 @@ impl Proxy {
         thread::spawn(move || loop {
             match self.sock_listen() {
                 Ok(listener) => {
-                    if let Err(e) = self.sock_accept(&listener) {
+                    if let Err(e) = self.sock_accept(listener.as_ref()) {
                         error!("connection failed {}", e);
                         thread::sleep(Duration::new(1, 0));
                     }
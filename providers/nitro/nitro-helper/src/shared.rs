@@ -1,5 +1,10 @@
-use serde::{Deserialize, Serialize};
-use tendermint::{chain, node};
+use anomaly::format_err;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::io::{Read, Write};
+use tendermint::{chain, consensus, node};
+use thiserror::Error;
+use tmkms_light::error::{Error, ErrorKind};
+use tmkms_light::framing::{FrameType, FramedStream};
 
 /// CID for listening on the host
 pub const VSOCK_PROXY_CID: u32 = 3;
@@ -20,6 +25,8 @@ pub struct NitroConfig {
     pub peer_id: Option<node::Id>,
     /// Vsock port to listen on for state synchronization
     pub enclave_state_port: u32,
+    /// Vsock port to forward the enclave log records to (if any)
+    pub enclave_log_port: Option<u32>,
     /// Vsock port to forward privval plain traffic to TM over UDS or TCP
     pub enclave_tendermint_conn: u32,
     /// AWS credentials -- if not set, they'll be obtained from IAM
@@ -39,3 +46,266 @@ pub struct AwsCredentials {
     /// SessionToken
     pub aws_session_token: String,
 }
+
+/// Key generation config to be pushed to the enclave
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NitroKeygenConfig {
+    /// AWS KMS key ID to encrypt the generated key with
+    pub kms_key_id: String,
+    /// AWS credentials -- if not set, they'll be obtained from IAM
+    pub credentials: AwsCredentials,
+    /// AWS region
+    pub aws_region: String,
+}
+
+/// Requests from the helper to the enclave on the config channel
+#[derive(Debug, Serialize, Deserialize)]
+pub enum NitroRequest {
+    /// Decrypt the keys and start signing with the provided configuration
+    Start(NitroConfig),
+    /// Generate a new Ed25519 key and return it encrypted by KMS
+    KeyGen(NitroKeygenConfig),
+    /// Return an NSM attestation document with the nonce
+    /// (and the consensus public key as its user data if started)
+    Attest {
+        /// a fresh random nonce from the helper
+        nonce: Vec<u8>,
+    },
+    /// Stop answering the sign requests (until resumed)
+    Pause,
+    /// Resume signing
+    Resume,
+}
+
+/// Responses from the enclave on the config channel
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum NitroResponse {
+    /// The keys were decrypted and the enclave is connected to the validator
+    Started {
+        /// The consensus public key in use
+        public_key: tendermint::PublicKey,
+        /// The node ID of the identity key (if the secret connection is used)
+        node_id: Option<node::Id>,
+    },
+    /// The key was generated in the enclave
+    KeyGenerated {
+        /// The public key of the generated key
+        public_key: tendermint::PublicKey,
+        /// The KMS-encrypted secret key to be stored on the host
+        sealed_key: Vec<u8>,
+    },
+    /// The attestation document (COSE_Sign1) from NSM
+    Attestation {
+        /// The CBOR-encoded document
+        document: Vec<u8>,
+    },
+    /// No more requests will be signed
+    Paused {
+        /// The last signed state (if any was signed)
+        state: Option<consensus::State>,
+    },
+    /// Signing was resumed
+    Resumed,
+    /// The request failed
+    Error(NitroError),
+}
+
+/// Reasons why the enclave failed to handle a request
+#[derive(Debug, Clone, PartialEq, Eq, Error, Serialize, Deserialize)]
+pub enum NitroError {
+    /// KMS failed to decrypt a sealed key
+    #[error("decryption failed: {0}")]
+    DecryptionFailed(String),
+    /// The decrypted key is not a valid Ed25519 key
+    #[error("invalid key: {0}")]
+    InvalidKey(String),
+    /// The state couldn't be loaded from the host
+    #[error("state synchronization failed: {0}")]
+    StateSyncFailed(String),
+    /// KMS failed to encrypt a generated key
+    #[error("encryption failed: {0}")]
+    EncryptionFailed(String),
+    /// NSM failed to provide an attestation document
+    #[error("attestation failed: {0}")]
+    AttestationFailed(String),
+    /// The enclave was already started
+    #[error("already started")]
+    AlreadyStarted,
+}
+
+/// Typed request/response channel between the helper and the enclave
+/// (over vsock or a Unix socket pair in tests)
+#[derive(Debug)]
+pub struct ConfigChannel<S> {
+    stream: FramedStream<S>,
+}
+
+impl<S: Read + Write> ConfigChannel<S> {
+    /// Opens the channel from the helper
+    pub fn connect(stream: S) -> Result<Self, Error> {
+        Ok(Self {
+            stream: FramedStream::connect(stream)?,
+        })
+    }
+
+    /// Opens the channel in the enclave
+    pub fn accept(stream: S) -> Result<Self, Error> {
+        Ok(Self {
+            stream: FramedStream::accept(stream)?,
+        })
+    }
+
+    fn send<T: Serialize>(&mut self, frame_type: FrameType, message: &T) -> Result<(), Error> {
+        let raw = serde_json::to_vec(message).map_err(|e| {
+            format_err!(
+                ErrorKind::SerializationError,
+                "failed to serialize {:?}: {}",
+                frame_type,
+                e
+            )
+        })?;
+        self.stream.send(frame_type, &raw)
+    }
+
+    fn recv<T: DeserializeOwned>(&mut self, frame_type: FrameType) -> Result<T, Error> {
+        let raw = self.stream.recv_payload(frame_type)?;
+        serde_json::from_slice(&raw).map_err(|e| {
+            format_err!(
+                ErrorKind::SerializationError,
+                "failed to deserialize {:?}: {}",
+                frame_type,
+                e
+            )
+            .into()
+        })
+    }
+
+    /// Sends a request to the enclave
+    pub fn send_request(&mut self, request: &NitroRequest) -> Result<(), Error> {
+        self.send(FrameType::Request, request)
+    }
+
+    /// Waits for a request from the helper
+    pub fn recv_request(&mut self) -> Result<NitroRequest, Error> {
+        self.recv(FrameType::Request)
+    }
+
+    /// Sends a response to the helper
+    pub fn send_response(&mut self, response: &NitroResponse) -> Result<(), Error> {
+        self.send(FrameType::Response, response)
+    }
+
+    /// Waits for a response from the enclave
+    pub fn recv_response(&mut self) -> Result<NitroResponse, Error> {
+        self.recv(FrameType::Response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    fn config() -> NitroConfig {
+        NitroConfig {
+            chain_id: "testchain-1".parse().unwrap(),
+            max_height: None,
+            sealed_consensus_key: vec![1, 2, 3],
+            sealed_id_key: None,
+            peer_id: None,
+            enclave_state_port: 5555,
+            enclave_log_port: Some(5557),
+            enclave_tendermint_conn: 5556,
+            credentials: AwsCredentials {
+                aws_key_id: "key".to_owned(),
+                aws_secret_key: "secret".to_owned(),
+                aws_session_token: "token".to_owned(),
+            },
+            aws_region: "ap-southeast-1".to_owned(),
+        }
+    }
+
+    fn keygen_config() -> NitroKeygenConfig {
+        NitroKeygenConfig {
+            kms_key_id: "alias/tmkms".to_owned(),
+            credentials: config().credentials,
+            aws_region: "ap-southeast-1".to_owned(),
+        }
+    }
+
+    /// runs a stand-in enclave that responds to a single request
+    fn roundtrip(request: NitroRequest, response: NitroResponse) -> (NitroRequest, NitroResponse) {
+        let (helper, enclave) = UnixStream::pair().unwrap();
+        let enclave = thread::spawn(move || {
+            let mut channel = ConfigChannel::accept(enclave).unwrap();
+            let request = channel.recv_request().unwrap();
+            channel.send_response(&response).unwrap();
+            request
+        });
+        let mut channel = ConfigChannel::connect(helper).unwrap();
+        channel.send_request(&request).unwrap();
+        let response = channel.recv_response().unwrap();
+        (enclave.join().unwrap(), response)
+    }
+
+    #[test]
+    fn test_start_statuses() {
+        let keypair = ed25519_dalek::Keypair::generate(&mut rand_core::OsRng);
+        let public_key = tendermint::PublicKey::Ed25519(keypair.public);
+        let responses = vec![
+            NitroResponse::Started {
+                public_key,
+                node_id: None,
+            },
+            NitroResponse::Error(NitroError::DecryptionFailed("access denied".to_owned())),
+            NitroResponse::Error(NitroError::InvalidKey("wrong length".to_owned())),
+            NitroResponse::Error(NitroError::StateSyncFailed("closed".to_owned())),
+        ];
+        for response in responses {
+            let (request, received) = roundtrip(NitroRequest::Start(config()), response.clone());
+            assert_eq!(received, response);
+            match request {
+                NitroRequest::Start(c) => assert_eq!(c.sealed_consensus_key, vec![1, 2, 3]),
+                r => panic!("unexpected request: {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn test_keygen_statuses() {
+        let keypair = ed25519_dalek::Keypair::generate(&mut rand_core::OsRng);
+        let responses = vec![
+            NitroResponse::KeyGenerated {
+                public_key: tendermint::PublicKey::Ed25519(keypair.public),
+                sealed_key: vec![4, 5, 6],
+            },
+            NitroResponse::Error(NitroError::EncryptionFailed("access denied".to_owned())),
+        ];
+        for response in responses {
+            let (request, received) =
+                roundtrip(NitroRequest::KeyGen(keygen_config()), response.clone());
+            assert_eq!(received, response);
+            match request {
+                NitroRequest::KeyGen(c) => assert_eq!(c.kms_key_id, "alias/tmkms"),
+                r => panic!("unexpected request: {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn test_unexpected_message() {
+        let (helper, enclave) = UnixStream::pair().unwrap();
+        let enclave = thread::spawn(move || {
+            let mut channel = ConfigChannel::accept(enclave).unwrap();
+            // a response instead of a request
+            channel.recv_request().unwrap_err()
+        });
+        let mut channel = ConfigChannel::connect(helper).unwrap();
+        channel
+            .send_response(&NitroResponse::Error(NitroError::InvalidKey(String::new())))
+            .unwrap();
+        assert_eq!(enclave.join().unwrap().kind(), &ErrorKind::FramingError);
+    }
+}
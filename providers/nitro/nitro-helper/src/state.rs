@@ -1,7 +1,5 @@
-use crate::shared::VSOCK_PROXY_CID;
+use crate::transport::{Listener, Stream, Transport};
 use anomaly::{fail, format_err};
-use nix::sys::socket::SockAddr;
-use std::os::unix::io::AsRawFd;
 use std::thread;
 use std::{
     fs,
@@ -9,38 +7,43 @@ use std::{
     path::{Path, PathBuf},
 };
 use tempfile::NamedTempFile;
-use tmkms_light::chain::state::{consensus, StateError, StateErrorKind};
-use tmkms_light::utils::{read_u16_payload, write_u16_payload};
-use tracing::{debug, info, warn};
-use vsock::{VsockListener, VsockStream};
+use tmkms_light::chain::state::{consensus, State, StateError, StateErrorKind};
+use tmkms_light::control::StatusTracker;
+use tmkms_light::error::ErrorKind;
+use tmkms_light::framing::{FrameType, FramedStream};
+use tracing::{debug, error, info, warn};
 
 /// helps the enclave to load the state previously persisted on the host
 /// + to persist new states
 pub struct StateSyncer {
     state_file_path: PathBuf,
-    vsock_listener: VsockListener,
-    state: consensus::State,
+    listener: Box<dyn Listener>,
+    state: State,
+    tracker: Option<StatusTracker>,
 }
 
 impl StateSyncer {
     /// creates a new state file or loads the previous one
-    /// and binds a listener for incoming vsock connections from the enclave
-    /// on the proxy CID on the provided port
-    pub fn new<P: AsRef<Path>>(path: P, vsock_port: u32) -> Result<Self, StateError> {
+    /// and binds a listener for incoming connections from the enclave
+    /// on the provided port (on the proxy CID in the case of vsock)
+    pub fn new<P: AsRef<Path>>(
+        path: P,
+        transport: &dyn Transport,
+        port: u32,
+    ) -> Result<Self, StateError> {
         let state_file_path = path.as_ref().to_owned();
         let state = match fs::read_to_string(&path) {
             Ok(state_json) => {
-                let consensus_state: consensus::State =
-                    serde_json::from_str(&state_json).map_err(|e| {
-                        format_err!(
-                            StateErrorKind::SyncError,
-                            "error parsing {}: {}",
-                            path.as_ref().display(),
-                            e
-                        )
-                    })?;
-
-                Ok(consensus_state)
+                let state: State = serde_json::from_str(&state_json).map_err(|e| {
+                    format_err!(
+                        StateErrorKind::SyncError,
+                        "error parsing {}: {}",
+                        path.as_ref().display(),
+                        e
+                    )
+                })?;
+
+                Ok(state)
             }
             Err(e) if e.kind() == io::ErrorKind::NotFound => {
                 Self::write_initial_state(&state_file_path)
@@ -53,36 +56,46 @@ impl StateSyncer {
             ),
         }?;
 
-        let sockaddr = SockAddr::new_vsock(VSOCK_PROXY_CID, vsock_port);
-        let vsock_listener = VsockListener::bind(&sockaddr).map_err(|e| {
+        let listener = transport.listen(port).map_err(|e| {
             format_err!(
                 StateErrorKind::SyncError,
-                "failed to listen on vsock: {}",
+                "failed to listen on port {}: {}",
+                port,
                 e
             )
         })?;
 
         Ok(Self {
             state_file_path,
-            vsock_listener,
+            listener,
             state,
+            tracker: None,
         })
     }
 
+    /// records the loaded and persisted states in the tracker
+    pub fn with_tracker(self, tracker: StatusTracker) -> Self {
+        tracker.set_state(self.state.consensus_state().clone());
+        Self {
+            tracker: Some(tracker),
+            ..self
+        }
+    }
+
     /// Write the initial state to the given path on disk
-    fn write_initial_state(path: &Path) -> Result<consensus::State, StateError> {
-        let consensus_state = consensus::State {
+    fn write_initial_state(path: &Path) -> Result<State, StateError> {
+        let state = State::from(consensus::State {
             height: 0u32.into(),
             ..Default::default()
-        };
+        });
 
-        Self::persist_state(path, &consensus_state)?;
+        Self::persist_state(path, &state)?;
 
-        Ok(consensus_state)
+        Ok(state)
     }
 
-    /// dump the current state to the provided vsock stream
-    fn sync_to_stream(&self, stream: &mut VsockStream) -> Result<(), StateError> {
+    /// dump the current state to the provided stream
+    fn sync_to_stream(&self, stream: &mut FramedStream<Box<dyn Stream>>) -> Result<(), StateError> {
         let json_raw = serde_json::to_vec(&self.state).map_err(|e| {
             format_err!(
                 StateErrorKind::SyncError,
@@ -90,16 +103,14 @@ impl StateSyncer {
                 e
             )
         })?;
-        write_u16_payload(stream, &json_raw).map_err(|e| {
+        stream.send(FrameType::State, &json_raw).map_err(|e| {
             format_err!(StateErrorKind::SyncError, "failed to write state: {}", e).into()
         })
     }
 
-    /// load state from the provided vsock stream
-    fn sync_from_stream(mut stream: &mut VsockStream) -> Result<consensus::State, StateError> {
-        let json_raw = read_u16_payload(&mut stream)
-            .map_err(|e| format_err!(StateErrorKind::SyncError, "failed to read state: {}", e))?;
-        serde_json::from_slice(&json_raw).map_err(|e| {
+    /// parse the state received from the enclave
+    fn parse_state(json_raw: &[u8]) -> Result<State, StateError> {
+        serde_json::from_slice(json_raw).map_err(|e| {
             format_err!(
                 StateErrorKind::SyncError,
                 "failed to deserialize state: {}",
@@ -113,39 +124,78 @@ impl StateSyncer {
     pub fn launch_syncer(mut self) -> thread::JoinHandle<()> {
         thread::spawn(move || {
             info!("listening for enclave persistence");
-            for conn in self.vsock_listener.incoming() {
-                match conn {
-                    Ok(mut stream) => {
-                        info!("vsock persistence connection established");
-                        debug!("state peer addr: {:?}", stream.peer_addr());
-                        debug!("state local addr: {:?}", stream.local_addr());
+            loop {
+                match self.listener.accept() {
+                    Ok(stream) => {
+                        info!("persistence connection established");
+                        debug!("state stream: {:?}", stream);
                         debug!("state fd: {}", stream.as_raw_fd());
 
+                        let mut stream = match FramedStream::accept(stream) {
+                            Ok(stream) => stream,
+                            Err(e) => {
+                                warn!("state stream negotiation failed: {}", e);
+                                continue;
+                            }
+                        };
                         if let Err(e) = self.sync_to_stream(&mut stream) {
                             warn!("error serializing to json {}", e);
                         } else {
-                            loop {
-                                if let Ok(consensus_state) = Self::sync_from_stream(&mut stream) {
-                                    self.state = consensus_state;
-                                    if let Err(e) =
-                                        Self::persist_state(&self.state_file_path, &self.state)
-                                    {
-                                        warn!("state persistence failed: {}", e);
-                                    }
-                                }
-                            }
+                            Self::sync_from_stream(
+                                &self.state_file_path,
+                                &mut self.state,
+                                self.tracker.as_ref(),
+                                &mut stream,
+                            );
                         }
                     }
                     Err(e) => {
-                        warn!("Vsock connection failed: {}", e);
+                        warn!("persistence connection failed: {}", e);
                     }
                 }
             }
         })
     }
 
+    /// persists the states received from the enclave until the stream
+    /// is closed or can no longer be read
+    fn sync_from_stream(
+        state_file_path: &Path,
+        state: &mut State,
+        tracker: Option<&StatusTracker>,
+        stream: &mut FramedStream<Box<dyn Stream>>,
+    ) {
+        loop {
+            let json_raw = match stream.recv_payload(FrameType::State) {
+                Ok(json_raw) => json_raw,
+                Err(e) if *e.kind() == ErrorKind::IoError => {
+                    info!("state stream closed: {}", e);
+                    return;
+                }
+                Err(e) => {
+                    error!("failed to read state: {}", e);
+                    return;
+                }
+            };
+            match Self::parse_state(&json_raw) {
+                Ok(new_state) => {
+                    *state = new_state;
+                    match Self::persist_state(state_file_path, state) {
+                        Ok(()) => {
+                            if let Some(tracker) = tracker {
+                                tracker.set_state(state.consensus_state().clone());
+                            }
+                        }
+                        Err(e) => warn!("state persistence failed: {}", e),
+                    }
+                }
+                Err(e) => warn!("{}", e),
+            }
+        }
+    }
+
     /// write the new state into a file on the host
-    fn persist_state(path: &Path, new_state: &consensus::State) -> Result<(), StateError> {
+    fn persist_state(path: &Path, new_state: &State) -> Result<(), StateError> {
         debug!(
             "writing new consensus state to {}: {:?}",
             path.display(),
@@ -181,7 +231,7 @@ impl StateSyncer {
                 e
             )
         })?;
-        state_file.persist(&path).map_err(|e| {
+        state_file.persist(path).map_err(|e| {
             format_err!(
                 StateErrorKind::SyncError,
                 "error persisting {}: {}",
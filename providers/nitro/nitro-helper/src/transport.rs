@@ -0,0 +1,208 @@
+//! Transports for the connections between the enclave and the host:
+//! vsock in production, Unix domain sockets or TCP for running the enclave app
+//! as a normal process on the host (e.g. in integration tests).
+//! The connections are identified by port numbers from the configuration.
+
+use crate::shared::VSOCK_PROXY_CID;
+use nix::sys::socket::SockAddr;
+use std::convert::TryFrom;
+use std::fmt::Debug;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::{fs, io::ErrorKind};
+use vsock::{VsockListener, VsockStream};
+
+/// Any of the underlying streams (vsock, Unix or TCP)
+pub trait Stream: Read + Write + AsRawFd + Send + Sync + Debug {}
+
+impl<T> Stream for T where T: Read + Write + AsRawFd + Send + Sync + Debug {}
+
+/// Accepts incoming connections on a port
+pub trait Listener: Send + Sync {
+    /// waits for the next incoming connection
+    fn accept(&self) -> io::Result<Box<dyn Stream>>;
+}
+
+impl Listener for VsockListener {
+    fn accept(&self) -> io::Result<Box<dyn Stream>> {
+        VsockListener::accept(self).map(|(stream, _)| Box::new(stream) as Box<dyn Stream>)
+    }
+}
+
+impl Listener for UnixListener {
+    fn accept(&self) -> io::Result<Box<dyn Stream>> {
+        UnixListener::accept(self).map(|(stream, _)| Box::new(stream) as Box<dyn Stream>)
+    }
+}
+
+impl Listener for TcpListener {
+    fn accept(&self) -> io::Result<Box<dyn Stream>> {
+        TcpListener::accept(self).map(|(stream, _)| Box::new(stream) as Box<dyn Stream>)
+    }
+}
+
+/// Connects to or listens on ports on the other side
+pub trait Transport: Send + Sync + Debug {
+    /// connects to the port on the other side
+    fn connect(&self, port: u32) -> io::Result<Box<dyn Stream>>;
+    /// listens for connections from the other side on the port
+    fn listen(&self, port: u32) -> io::Result<Box<dyn Listener>>;
+}
+
+/// vsock between the enclave and the parent instance
+#[derive(Debug, Clone, Copy)]
+pub struct VsockTransport {
+    /// CID to connect to
+    pub peer_cid: u32,
+    /// CID to listen on
+    pub local_cid: u32,
+}
+
+impl VsockTransport {
+    /// Listens on any CID
+    pub const VMADDR_CID_ANY: u32 = 0xFFFF_FFFF;
+
+    /// used in the enclave: it connects to the parent instance
+    pub fn enclave() -> Self {
+        Self {
+            peer_cid: VSOCK_PROXY_CID,
+            local_cid: Self::VMADDR_CID_ANY,
+        }
+    }
+
+    /// used on the parent instance: it connects to the enclave with the given CID
+    pub fn host(enclave_cid: u32) -> Self {
+        Self {
+            peer_cid: enclave_cid,
+            local_cid: VSOCK_PROXY_CID,
+        }
+    }
+}
+
+impl Transport for VsockTransport {
+    fn connect(&self, port: u32) -> io::Result<Box<dyn Stream>> {
+        let addr = SockAddr::new_vsock(self.peer_cid, port);
+        Ok(Box::new(VsockStream::connect(&addr)?))
+    }
+
+    fn listen(&self, port: u32) -> io::Result<Box<dyn Listener>> {
+        let addr = SockAddr::new_vsock(self.local_cid, port);
+        Ok(Box::new(VsockListener::bind(&addr)?))
+    }
+}
+
+/// Unix domain sockets named after the ports in a directory
+#[derive(Debug, Clone)]
+pub struct UnixTransport {
+    dir: PathBuf,
+}
+
+impl UnixTransport {
+    /// both sides need to use the same directory
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// the socket path for the port
+    pub fn path(&self, port: u32) -> PathBuf {
+        self.dir.join(format!("{}.sock", port))
+    }
+}
+
+impl Transport for UnixTransport {
+    fn connect(&self, port: u32) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(UnixStream::connect(self.path(port))?))
+    }
+
+    fn listen(&self, port: u32) -> io::Result<Box<dyn Listener>> {
+        let path = self.path(port);
+        // a socket left by a previous listener
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        Ok(Box::new(UnixListener::bind(path)?))
+    }
+}
+
+/// TCP on the given host (both sides need to run on the same host
+/// or the host needs to be reachable from both)
+#[derive(Debug, Clone)]
+pub struct TcpTransport {
+    host: String,
+}
+
+impl TcpTransport {
+    /// e.g. "127.0.0.1"
+    pub fn new<S: Into<String>>(host: S) -> Self {
+        Self { host: host.into() }
+    }
+
+    fn port(port: u32) -> io::Result<u16> {
+        u16::try_from(port).map_err(|_| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("port {} is out of the TCP range", port),
+            )
+        })
+    }
+}
+
+impl Transport for TcpTransport {
+    fn connect(&self, port: u32) -> io::Result<Box<dyn Stream>> {
+        let port = Self::port(port)?;
+        Ok(Box::new(TcpStream::connect((self.host.as_str(), port))?))
+    }
+
+    fn listen(&self, port: u32) -> io::Result<Box<dyn Listener>> {
+        let port = Self::port(port)?;
+        Ok(Box::new(TcpListener::bind((self.host.as_str(), port))?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn echo(transport: Box<dyn Transport>, port: u32) {
+        let listener = transport.listen(port).unwrap();
+        let server = thread::spawn(move || {
+            let mut stream = listener.accept().unwrap();
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).unwrap();
+            stream.write_all(&buf).unwrap();
+        });
+        let mut stream = transport.connect(port).unwrap();
+        stream.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_unix_transport() {
+        let dir = tempfile::tempdir().unwrap();
+        echo(Box::new(UnixTransport::new(dir.path())), 5050);
+        // the stale socket gets replaced
+        echo(Box::new(UnixTransport::new(dir.path())), 5050);
+    }
+
+    #[test]
+    fn test_tcp_transport() {
+        let transport = TcpTransport::new("127.0.0.1");
+        let port = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        echo(Box::new(transport.clone()), port as u32);
+        assert_eq!(
+            transport.connect(u32::MAX).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+    }
+}
@@ -4,16 +4,22 @@ pub(crate) mod keypair_seal;
 mod state;
 use ed25519_dalek::Keypair;
 use keypair_seal::CloudWrapKey;
-use rand::rngs::OsRng;
+use rand::{rngs::OsRng, RngCore};
+use serde::Serialize;
+use sgx_isa::{Keypolicy, Report, Targetinfo};
 use std::{io, net::TcpStream, thread, time::Duration};
 use subtle::ConstantTimeEq;
 use tendermint_p2p::secret_connection::{self, PublicKey, SecretConnection};
 use tmkms_light::{
     connection::{Connection, PlainConnection},
-    utils::write_u16_payload,
+    framing::{FrameType, FramedStream},
+    session::SigningSwitch,
 };
 use tmkms_light_sgx_runner::{
-    RemoteConnectionConfig, {SgxInitRequest, SgxInitResponse},
+    shamir::{self, BackupKeyShare, BackupKeySplit},
+    GeneratedKeyData, KeyRole, RemoteConnectionConfig, SgxControlCommand, SgxControlRequest,
+    SgxControlResponse, SgxControlResult, SgxInitRequest, SgxInitResponse, SgxKeyGenResponse,
+    SgxRecoverResponse, SgxReportResponse, MIN_CLOUD_SECRET_LEN,
 };
 use tracing::{debug, error, info, warn};
 
@@ -85,58 +91,237 @@ pub fn get_connection(secret_connection: Option<&RemoteConnectionConfig>) -> Box
     }
 }
 
+/// negotiates the framing on the "init" stream and sends the response to the host
+fn write_response<T: Serialize>(host_response: TcpStream, response: &T) -> io::Result<()> {
+    let v = serde_json::to_vec(response)?;
+    let mut stream = FramedStream::connect(host_response).map_err(|e| {
+        error!("init stream negotiation failed: {}", e);
+        io::Error::from(io::ErrorKind::Other)
+    })?;
+    debug!("writing response");
+    stream.send(FrameType::InitResponse, &v).map_err(|e| {
+        error!("failed to write response: {}", e);
+        io::Error::from(io::ErrorKind::Other)
+    })
+}
+
+/// handles the pause/resume requests from the host on the "init" stream
+/// (in a separate thread, so that they don't wait for the privval requests)
+fn launch_control(host_response: TcpStream, switch: SigningSwitch) {
+    thread::spawn(move || {
+        let mut stream = match FramedStream::connect(host_response) {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("control stream negotiation failed: {}", e);
+                return;
+            }
+        };
+        loop {
+            let request = match stream.recv_payload(FrameType::Request) {
+                Ok(request) => request,
+                Err(e) => {
+                    debug!("control stream closed: {}", e);
+                    return;
+                }
+            };
+            let request: SgxControlRequest = match serde_json::from_slice(&request) {
+                Ok(request) => request,
+                Err(e) => {
+                    warn!("invalid control request: {}", e);
+                    continue;
+                }
+            };
+            let result = match request.command {
+                SgxControlCommand::Pause => {
+                    let state = switch.pause();
+                    info!("signing paused");
+                    SgxControlResult::Paused { state }
+                }
+                SgxControlCommand::Resume => {
+                    switch.resume();
+                    info!("signing resumed");
+                    SgxControlResult::Resumed
+                }
+            };
+            let response = SgxControlResponse {
+                id: request.id,
+                result,
+            };
+            let sent = serde_json::to_vec(&response)
+                .map_err(|e| e.to_string())
+                .and_then(|v| {
+                    stream
+                        .send(FrameType::Response, &v)
+                        .map_err(|e| e.to_string())
+                });
+            if let Err(e) = sent {
+                warn!("failed to write control response: {}", e);
+                return;
+            }
+        }
+    });
+}
+
+/// the report for the target enclave (e.g. the quoting one) with the public key
+/// followed by zeros in the report data
+fn get_report(target_info: &[u8], keypair: &Keypair) -> Option<SgxReportResponse> {
+    let target_info = Targetinfo::try_copy_from(target_info)?;
+    let mut report_data = [0u8; 64];
+    report_data[..32].copy_from_slice(keypair.public.as_bytes());
+    let report = Report::for_target(&target_info, &report_data);
+    Some(SgxReportResponse {
+        public_key: keypair.public.to_bytes(),
+        report: AsRef::<[u8]>::as_ref(&report).to_vec(),
+    })
+}
+
+/// generates a new keypair, seals it and optionally backs it up with the provided key
+fn generate_key(
+    csprng: &mut OsRng,
+    role: KeyRole,
+    cloud_backup_key: Option<&CloudWrapKey>,
+    keypolicy: Keypolicy,
+) -> Option<GeneratedKeyData> {
+    let kp = Keypair::generate(csprng);
+    let cloud_backup_key_data = match cloud_backup_key {
+        Some(key) => Some(keypair_seal::cloud_backup(csprng, key, &kp).ok()?),
+        None => None,
+    };
+    let sealed_key_data = keypair_seal::seal(csprng, &kp, keypolicy).ok()?;
+    Some(GeneratedKeyData {
+        role,
+        sealed_key_data,
+        cloud_backup_key_data,
+    })
+}
+
+/// generates a new backup secret and splits it into shares
+/// (only the shares leave the enclave)
+fn generate_split_backup_key(
+    csprng: &mut OsRng,
+    split: BackupKeySplit,
+) -> Option<(CloudWrapKey, Vec<BackupKeyShare>)> {
+    let mut secret = vec![0u8; MIN_CLOUD_SECRET_LEN];
+    csprng.fill_bytes(&mut secret);
+    let key_shares = shamir::split(csprng, &secret, split.threshold, split.count);
+    let key = CloudWrapKey::new(secret)?;
+    Some((key, key_shares.ok()?))
+}
+
 /// a simple req-rep handling loop
 /// `TcpStream` is either provided in tests or from the "init"
 /// enclave runner's user call extension.
-/// TODO: no need to pass the host_response stream + cloud_backup_key for "Start"
+/// (the host_response stream is used for the control requests after "Start")
+/// TODO: no need to pass cloud_backup_key for "Start"
 pub fn entry(
-    mut host_response: TcpStream,
+    host_response: TcpStream,
     request: SgxInitRequest,
     cloud_backup_key: Option<CloudWrapKey>,
 ) -> io::Result<()> {
     let mut csprng = OsRng {};
     match (request, cloud_backup_key) {
-        (SgxInitRequest::KeyGen, cbk) => {
-            let kp = Keypair::generate(&mut csprng);
-            let cloud_backup_key_data =
-                cbk.and_then(|key| keypair_seal::cloud_backup(&mut csprng, key, &kp).ok());
-            if let Ok(sealed_key_data) = keypair_seal::seal(&mut csprng, &kp) {
-                let response = SgxInitResponse {
-                    sealed_key_data,
-                    cloud_backup_key_data,
-                };
-                match serde_json::to_vec(&response) {
-                    Ok(v) => {
-                        debug!("writing response");
-                        write_u16_payload(&mut host_response, &v)?;
-                    }
-                    Err(e) => {
-                        error!("keygen error: {}", e);
+        (
+            SgxInitRequest::KeyGen {
+                sealing_policy,
+                roles,
+                backup_key_split,
+            },
+            cbk,
+        ) => {
+            let (cbk, key_shares) = match (cbk, backup_key_split) {
+                (cbk, None) => (cbk, vec![]),
+                (None, Some(split)) => match generate_split_backup_key(&mut csprng, split) {
+                    Some((key, key_shares)) => (Some(key), key_shares),
+                    None => {
+                        error!("failed to split the backup key");
+                        return Err(io::ErrorKind::InvalidInput.into());
                     }
+                },
+                (Some(_), Some(_)) => {
+                    // the provided key would recover the keypairs without the shares
+                    error!("the provided backup key can't be split");
+                    return Err(io::ErrorKind::InvalidInput.into());
                 }
+            };
+            let keys: Option<Vec<_>> = roles
+                .into_iter()
+                .map(|role| generate_key(&mut csprng, role, cbk.as_ref(), sealing_policy.into()))
+                .collect();
+            drop(cbk);
+            if let Some(keys) = keys {
+                let response = SgxKeyGenResponse { keys, key_shares };
+                write_response(host_response, &response)?;
             } else {
                 error!("sealing failed");
             }
         }
-        (SgxInitRequest::CloudRecover { key_data }, Some(backup_key)) => {
+        (
+            SgxInitRequest::CloudRecover {
+                key_data,
+                sealing_policy,
+                key_shares,
+            },
+            cbk,
+        ) => {
+            let backup_key = cbk.or_else(|| CloudWrapKey::from_shares(&key_shares));
+            let backup_key = if let Some(backup_key) = backup_key {
+                backup_key
+            } else {
+                error!("no valid backup key or key shares");
+                return Err(io::ErrorKind::Other.into());
+            };
+            let sealed_key_data: Result<Vec<_>, _> = key_data
+                .into_iter()
+                .map(|backup| {
+                    keypair_seal::seal_recover_cloud_backup(
+                        &mut csprng,
+                        &backup_key,
+                        backup,
+                        sealing_policy.into(),
+                    )
+                })
+                .collect();
+            drop(backup_key);
+            if let Ok(sealed_key_data) = sealed_key_data {
+                let response = SgxRecoverResponse { sealed_key_data };
+                write_response(host_response, &response)?;
+            } else {
+                error!("recovery failed");
+            }
+        }
+        (
+            SgxInitRequest::Reseal {
+                sealed_key,
+                sealing_policy,
+            },
+            None,
+        ) => {
             if let Ok(sealed_key_data) =
-                keypair_seal::seal_recover_cloud_backup(&mut csprng, backup_key, key_data)
+                keypair_seal::reseal(&mut csprng, &sealed_key, sealing_policy.into())
             {
-                let response = SgxInitResponse {
-                    sealed_key_data,
-                    cloud_backup_key_data: None,
-                };
-                match serde_json::to_vec(&response) {
-                    Ok(v) => {
-                        debug!("writing response");
-                        write_u16_payload(&mut host_response, &v)?;
-                    }
-                    Err(e) => {
-                        error!("recovery error: {}", e);
-                    }
-                }
+                let response = SgxInitResponse { sealed_key_data };
+                write_response(host_response, &response)?;
             } else {
-                error!("recovery failed");
+                error!("resealing failed");
+                return Err(io::ErrorKind::Other.into());
+            }
+        }
+        (
+            SgxInitRequest::Report {
+                sealed_key,
+                target_info,
+            },
+            None,
+        ) => {
+            // unsealing checks the keypair belongs to this enclave
+            let response = keypair_seal::unseal(&sealed_key)
+                .ok()
+                .and_then(|keypair| get_report(&target_info, &keypair));
+            if let Some(response) = response {
+                write_response(host_response, &response)?;
+            } else {
+                error!("report failed");
+                return Err(io::ErrorKind::Other.into());
             }
         }
         (
@@ -150,14 +335,17 @@ pub fn entry(
         ) => {
             let state_holder = state::StateHolder::new()?;
             if let Ok(keypair) = keypair_seal::unseal(&sealed_key) {
+                let switch = SigningSwitch::default();
+                launch_control(host_response, switch.clone());
                 let conn: Box<dyn Connection> = get_connection(secret_connection.as_ref());
                 let mut session = tmkms_light::session::Session::new(
                     config,
                     conn,
                     keypair,
-                    initial_state.into(),
+                    initial_state,
                     state_holder,
-                );
+                )
+                .with_signing_switch(switch);
                 loop {
                     if let Err(e) = session.request_loop() {
                         error!("request error: {}", e);
@@ -181,9 +369,12 @@ pub fn entry(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use rand::RngCore;
+    use secrecy::ExposeSecret;
+    use sgx_isa::Keypolicy;
     use std::net::{TcpListener, TcpStream};
-    use tmkms_light::utils::read_u16_payload;
+    use tmkms_light_sgx_runner::{
+        cloud_backup_key_id, CloudBackupAlgorithm, SealingPolicy, CLOUD_BACKUP_VERSION,
+    };
 
     // can be run with `cargo test --target x86_64-fortanix-unknown-sgx`
     #[test]
@@ -192,46 +383,109 @@ mod tests {
         let mut backup_key = vec![0u8; 16];
         csprng.fill_bytes(&mut backup_key);
         let bk1 = CloudWrapKey::new(backup_key.clone()).unwrap();
-        let bk2 = CloudWrapKey::new(backup_key).unwrap();
+        let key_shares = shamir::split(&mut csprng, &backup_key, 2, 3).unwrap();
         let listener = TcpListener::bind("127.0.0.1:0").unwrap();
         let addr = listener.local_addr().unwrap();
 
         let handler = std::thread::spawn(move || {
             entry(
                 TcpStream::connect(addr).unwrap(),
-                SgxInitRequest::KeyGen,
+                SgxInitRequest::KeyGen {
+                    sealing_policy: SealingPolicy::MrEnclave,
+                    roles: vec![KeyRole::Consensus, KeyRole::Id],
+                    backup_key_split: None,
+                },
                 Some(bk1),
             )
         });
-        let (mut stream_signer, _) = listener.accept().unwrap();
-        let resp1 = read_u16_payload(&mut stream_signer).expect("response1");
-        let response1: SgxInitResponse = serde_json::from_slice(&resp1).expect("response1");
-        let r1_seal = response1.sealed_key_data.clone();
+        let (stream_signer, _) = listener.accept().unwrap();
+        let resp1 = FramedStream::accept(stream_signer)
+            .and_then(|mut stream| stream.recv_payload(FrameType::InitResponse))
+            .expect("response1");
+        let response1: SgxKeyGenResponse = serde_json::from_slice(&resp1).expect("response1");
         let _ = handler.join();
+        assert_eq!(response1.keys.len(), 2);
+        assert_eq!(response1.keys[0].role, KeyRole::Consensus);
+        assert_eq!(response1.keys[1].role, KeyRole::Id);
+        let keyids: Vec<_> = response1
+            .keys
+            .iter()
+            .map(|key| key.sealed_key_data.seal_key_request.keyid)
+            .collect();
+        assert_ne!(keyids[0], keyids[1]);
+        let key_data = response1
+            .keys
+            .into_iter()
+            .map(|key| key.cloud_backup_key_data.expect("backup"))
+            .collect();
         let handler = std::thread::spawn(move || {
             entry(
                 TcpStream::connect(addr).unwrap(),
                 SgxInitRequest::CloudRecover {
-                    key_data: response1.cloud_backup_key_data.expect("backup"),
+                    key_data,
+                    sealing_policy: SealingPolicy::MrEnclave,
+                    key_shares: vec![key_shares[2].clone(), key_shares[0].clone()],
                 },
-                Some(bk2),
+                None,
             )
         });
-        let (mut stream_signer, _) = listener.accept().unwrap();
-        let resp2 = read_u16_payload(&mut stream_signer).expect("response2");
-        let response2: SgxInitResponse = serde_json::from_slice(&resp2).expect("response2");
+        let (stream_signer, _) = listener.accept().unwrap();
+        let resp2 = FramedStream::accept(stream_signer)
+            .and_then(|mut stream| stream.recv_payload(FrameType::InitResponse))
+            .expect("response2");
+        let response2: SgxRecoverResponse = serde_json::from_slice(&resp2).expect("response2");
         let _ = handler.join();
-        assert_eq!(
-            r1_seal.seal_key_request.keyid,
-            response2.sealed_key_data.seal_key_request.keyid
-        );
+        let recovered_keyids: Vec<_> = response2
+            .sealed_key_data
+            .iter()
+            .map(|sealed_key| sealed_key.seal_key_request.keyid)
+            .collect();
+        assert_eq!(keyids, recovered_keyids);
+    }
+
+    #[test]
+    fn test_split_backup_key_flow() {
+        let mut csprng = OsRng {};
+        let split = BackupKeySplit {
+            threshold: 2,
+            count: 3,
+        };
+        let (key, key_shares) = generate_split_backup_key(&mut csprng, split).unwrap();
+        assert_eq!(key_shares.len(), 3);
+        assert_eq!(key.expose_secret().len(), MIN_CLOUD_SECRET_LEN);
+        assert!(key_shares.iter().all(|share| share.key_id == key.key_id()));
+        let kp = Keypair::generate(&mut csprng);
+        let backup = keypair_seal::cloud_backup(&mut csprng, &key, &kp).unwrap();
+        let recombined = CloudWrapKey::from_shares(&key_shares[1..]).unwrap();
+        let sealed_key = keypair_seal::seal_recover_cloud_backup(
+            &mut csprng,
+            &recombined,
+            backup,
+            Keypolicy::MRENCLAVE,
+        )
+        .unwrap();
+        assert_eq!(sealed_key.seal_key_request.keyid, kp.public.to_bytes());
+        // a provided backup key can't be split
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let err = entry(
+            stream,
+            SgxInitRequest::KeyGen {
+                sealing_policy: SealingPolicy::MrEnclave,
+                roles: vec![KeyRole::Consensus],
+                backup_key_split: Some(split),
+            },
+            Some(key),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
     }
 
     #[test]
     fn test_unseal() {
         let mut csprng = OsRng {};
         let kp = Keypair::generate(&mut csprng);
-        let sealed_data = keypair_seal::seal(&mut csprng, &kp).unwrap();
+        let sealed_data = keypair_seal::seal(&mut csprng, &kp, Keypolicy::MRSIGNER).unwrap();
         let mut mangled_sealed_data = sealed_data.clone();
         mangled_sealed_data.nonce[0] ^= 1;
         assert!(keypair_seal::unseal(&mangled_sealed_data).is_err());
@@ -241,18 +495,55 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_reseal() {
+        let mut csprng = OsRng {};
+        let kp = Keypair::generate(&mut csprng);
+        let sealed_data = keypair_seal::seal(&mut csprng, &kp, Keypolicy::MRSIGNER).unwrap();
+        let resealed_data =
+            keypair_seal::reseal(&mut csprng, &sealed_data, Keypolicy::MRENCLAVE).unwrap();
+        assert_eq!(
+            resealed_data.seal_key_request.keypolicy,
+            Keypolicy::MRENCLAVE.bits()
+        );
+        assert_eq!(
+            keypair_seal::unseal(&resealed_data).unwrap().public,
+            kp.public
+        );
+        assert!(keypair_seal::seal(&mut csprng, &kp, Keypolicy::empty()).is_err());
+    }
+
+    #[test]
+    fn test_report() {
+        let mut csprng = OsRng {};
+        let kp = Keypair::generate(&mut csprng);
+        let target_info = Targetinfo::from(Report::for_self());
+        let response = get_report(AsRef::<[u8]>::as_ref(&target_info), &kp).unwrap();
+        let report = Report::try_copy_from(&response.report).unwrap();
+        assert_eq!(response.public_key, kp.public.to_bytes());
+        assert_eq!(&report.reportdata[..32], kp.public.as_bytes());
+        assert_eq!(report.reportdata[32..], [0u8; 32]);
+        assert_eq!(report.mrenclave, Report::for_self().mrenclave);
+        assert!(get_report(&[0u8; 16], &kp).is_none());
+    }
+
     #[test]
     fn test_recover() {
         let mut csprng = OsRng {};
         let kp = Keypair::generate(&mut csprng);
-        let sealed_data = keypair_seal::seal(&mut csprng, &kp).unwrap();
+        let sealed_data = keypair_seal::seal(&mut csprng, &kp, Keypolicy::MRSIGNER).unwrap();
         let mut backup_key = vec![0u8; 16];
         csprng.fill_bytes(&mut backup_key);
         let bk1 = CloudWrapKey::new(backup_key.clone()).unwrap();
         let bk2 = CloudWrapKey::new(backup_key).unwrap();
-        let backup_data = keypair_seal::cloud_backup(&mut csprng, bk1, &kp).unwrap();
-        let recovered_sealed_data =
-            keypair_seal::seal_recover_cloud_backup(&mut csprng, bk2, backup_data).unwrap();
+        let backup_data = keypair_seal::cloud_backup(&mut csprng, &bk1, &kp).unwrap();
+        let recovered_sealed_data = keypair_seal::seal_recover_cloud_backup(
+            &mut csprng,
+            &bk2,
+            backup_data,
+            Keypolicy::MRSIGNER,
+        )
+        .unwrap();
         assert_eq!(
             keypair_seal::unseal(&sealed_data).unwrap().public,
             kp.public
@@ -262,4 +553,61 @@ mod tests {
             kp.public
         );
     }
+
+    #[test]
+    fn test_recover_derived_key() {
+        let mut csprng = OsRng {};
+        let kp = Keypair::generate(&mut csprng);
+        let mut backup_secret = vec![0u8; 64];
+        csprng.fill_bytes(&mut backup_secret);
+        assert!(CloudWrapKey::new(vec![0u8; 20]).is_none());
+        let bk = CloudWrapKey::new(backup_secret.clone()).unwrap();
+        let backup_data = keypair_seal::cloud_backup(&mut csprng, &bk, &kp).unwrap();
+        assert_eq!(backup_data.version, CLOUD_BACKUP_VERSION);
+        assert_eq!(
+            backup_data.algorithm,
+            CloudBackupAlgorithm::HkdfSha256Aes128GcmSiv
+        );
+        assert_eq!(
+            backup_data.key_id,
+            Some(cloud_backup_key_id(&backup_secret))
+        );
+
+        // a different key
+        let mut other_secret = vec![0u8; 64];
+        csprng.fill_bytes(&mut other_secret);
+        let bk = CloudWrapKey::new(other_secret).unwrap();
+        assert!(keypair_seal::seal_recover_cloud_backup(
+            &mut csprng,
+            &bk,
+            backup_data.clone(),
+            Keypolicy::MRSIGNER,
+        )
+        .is_err());
+
+        // tampered metadata
+        let mut tampered = backup_data.clone();
+        tampered.salt.as_mut().unwrap()[0] ^= 1;
+        let bk = CloudWrapKey::new(backup_secret.clone()).unwrap();
+        assert!(keypair_seal::seal_recover_cloud_backup(
+            &mut csprng,
+            &bk,
+            tampered,
+            Keypolicy::MRSIGNER,
+        )
+        .is_err());
+
+        let bk = CloudWrapKey::new(backup_secret).unwrap();
+        let recovered_sealed_data = keypair_seal::seal_recover_cloud_backup(
+            &mut csprng,
+            &bk,
+            backup_data,
+            Keypolicy::MRSIGNER,
+        )
+        .unwrap();
+        assert_eq!(
+            keypair_seal::unseal(&recovered_sealed_data).unwrap().public,
+            kp.public
+        );
+    }
 }
@@ -7,10 +7,16 @@ use rand::{rngs::OsRng, RngCore};
 use secrecy::{ExposeSecret, SecretVec};
 use sgx_isa::{ErrorCode, Keyname, Keypolicy, Keyrequest, Report};
 use std::convert::TryInto;
-use tmkms_light_sgx_runner::{CloudBackupKeyData, SealedKeyData, CLOUD_KEY_LEN};
+use tmkms_light_sgx_runner::{
+    cloud_backup_key_id,
+    shamir::{self, BackupKeyShare},
+    CloudBackupAlgorithm, CloudBackupKeyData, SealedKeyData, CLOUD_BACKUP_SALT_LEN,
+    CLOUD_BACKUP_VERSION,
+};
 use zeroize::Zeroize;
 
 /// symmetric key wrap -- e.g. from cloud KMS
+/// (either the raw key or a longer secret the key is derived from)
 pub struct CloudWrapKey(SecretVec<u8>);
 
 impl ExposeSecret<Vec<u8>> for CloudWrapKey {
@@ -22,12 +28,28 @@ impl ExposeSecret<Vec<u8>> for CloudWrapKey {
 impl CloudWrapKey {
     /// creates the new wrapper if the length is correct
     pub fn new(secret: Vec<u8>) -> Option<Self> {
-        if secret.len() == CLOUD_KEY_LEN {
+        if CloudBackupAlgorithm::for_secret(&secret).is_some() {
             Some(Self(SecretVec::new(secret)))
         } else {
             None
         }
     }
+
+    /// recombines the key from the backup key shares
+    pub fn from_shares(shares: &[BackupKeyShare]) -> Option<Self> {
+        let secret = shamir::combine(shares).ok()?;
+        Self::new(secret.to_vec())
+    }
+
+    /// the algorithm for this key
+    fn algorithm(&self) -> CloudBackupAlgorithm {
+        CloudBackupAlgorithm::for_secret(self.expose_secret()).expect("checked length")
+    }
+
+    /// the key identifier recorded in the backups
+    pub fn key_id(&self) -> String {
+        cloud_backup_key_id(self.expose_secret())
+    }
 }
 
 /// As cloud vendors may not guarantee HW affinity,
@@ -37,65 +59,110 @@ impl CloudWrapKey {
 /// when the instance is relocated etc.
 pub fn cloud_backup(
     csprng: &mut OsRng,
-    seal_key: CloudWrapKey,
+    seal_key: &CloudWrapKey,
     keypair: &Keypair,
 ) -> Result<CloudBackupKeyData, aes_gcm_siv::aead::Error> {
     let mut nonce = [0u8; 12];
     csprng.fill_bytes(&mut nonce);
+    let algorithm = seal_key.algorithm();
+    let salt = if algorithm == CloudBackupAlgorithm::HkdfSha256Aes128GcmSiv {
+        let mut salt = vec![0u8; CLOUD_BACKUP_SALT_LEN];
+        csprng.fill_bytes(&mut salt);
+        Some(salt)
+    } else {
+        None
+    };
+    let mut backup_data = CloudBackupKeyData {
+        version: CLOUD_BACKUP_VERSION,
+        algorithm,
+        key_id: Some(seal_key.key_id()),
+        salt,
+        nonce,
+        sealed_secret: vec![],
+        public_key: keypair.public,
+    };
+    let key = algorithm
+        .derive_key(
+            seal_key.expose_secret(),
+            backup_data.salt.as_deref().unwrap_or_default(),
+        )
+        .ok_or(aes_gcm_siv::aead::Error)?;
+    let aad = backup_data.aad();
     let payload = Payload {
         msg: keypair.secret.as_bytes(),
-        aad: keypair.public.as_bytes(),
+        aad: &aad,
     };
     let nonce_ga = GenericArray::from_slice(&nonce);
-    let gk = GenericArray::from_slice(seal_key.expose_secret());
+    let gk = GenericArray::from_slice(&key[..]);
     let aead = Aes128GcmSiv::new(gk);
-    aead.encrypt(nonce_ga, payload)
-        .map(|sealed_secret| CloudBackupKeyData {
-            sealed_secret,
-            nonce,
-            public_key: keypair.public,
-        })
+    backup_data.sealed_secret = aead.encrypt(nonce_ga, payload)?;
+    Ok(backup_data)
 }
 
 /// Recovers the backed up keypair (decrypt it using the externally
 /// provided key, e.g. injected from cloud HSM) and seals it on that CPU.
 pub fn seal_recover_cloud_backup(
     csprng: &mut OsRng,
-    seal_key: CloudWrapKey,
+    seal_key: &CloudWrapKey,
     backup_data: CloudBackupKeyData,
+    keypolicy: Keypolicy,
 ) -> Result<SealedKeyData, ErrorCode> {
+    if backup_data.version > CLOUD_BACKUP_VERSION {
+        return Err(ErrorCode::InvalidAttribute);
+    }
+    if let Some(key_id) = &backup_data.key_id {
+        if *key_id != seal_key.key_id() {
+            return Err(ErrorCode::InvalidKeyname);
+        }
+    }
+    let key = backup_data
+        .algorithm
+        .derive_key(
+            seal_key.expose_secret(),
+            backup_data.salt.as_deref().unwrap_or_default(),
+        )
+        .ok_or(ErrorCode::InvalidAttribute)?;
     let nonce_ga = GenericArray::from_slice(&backup_data.nonce);
-    let gk = GenericArray::from_slice(&seal_key.expose_secret());
+    let gk = GenericArray::from_slice(&key[..]);
     let aead = Aes128GcmSiv::new(gk);
+    let aad = backup_data.aad();
     let payload = Payload {
         msg: &backup_data.sealed_secret,
-        aad: backup_data.public_key.as_bytes(),
+        aad: &aad,
     };
     if let Ok(mut secret_key) = aead.decrypt(nonce_ga, payload) {
-        drop(seal_key);
         let secret = SecretKey::from_bytes(&secret_key).map_err(|_| ErrorCode::InvalidSignature)?;
         secret_key.zeroize();
         let public = PublicKey::from(&secret);
+        if public != backup_data.public_key {
+            return Err(ErrorCode::InvalidSignature);
+        }
         let mut kp = Keypair { secret, public };
-        let sealed = seal(csprng, &kp);
+        let sealed = seal(csprng, &kp, keypolicy);
         kp.secret.zeroize();
         sealed
     } else {
-        drop(seal_key);
         Err(ErrorCode::MacCompareFail)
     }
 }
 
 /// Seals the provided ed25519 keypair with `Aes128GcmSiv`
 /// via a key request against MRSIGNER (so that versions with higher `isvsvn`
-/// can unseal the keypair)
-pub fn seal(csprng: &mut OsRng, keypair: &Keypair) -> Result<SealedKeyData, ErrorCode> {
+/// can unseal the keypair) or MRENCLAVE (only this enclave build can unseal the keypair)
+pub fn seal(
+    csprng: &mut OsRng,
+    keypair: &Keypair,
+    keypolicy: Keypolicy,
+) -> Result<SealedKeyData, ErrorCode> {
+    if !(keypolicy == Keypolicy::MRSIGNER || keypolicy == Keypolicy::MRENCLAVE) {
+        return Err(ErrorCode::InvalidAttribute);
+    }
     let mut nonce = [0u8; 12];
     csprng.fill_bytes(&mut nonce);
     let report = Report::for_self();
     let key_request = Keyrequest {
         keyname: Keyname::Seal as _,
-        keypolicy: Keypolicy::MRSIGNER,
+        keypolicy,
         isvsvn: report.isvsvn,
         cpusvn: report.cpusvn,
         keyid: keypair.public.to_bytes(),
@@ -122,6 +189,19 @@ pub fn seal(csprng: &mut OsRng, keypair: &Keypair) -> Result<SealedKeyData, Erro
     }
 }
 
+/// Unseals the keypair and seals it again under the provided policy
+/// with the current ISVSVN and CPUSVN
+pub fn reseal(
+    csprng: &mut OsRng,
+    sealed_data: &SealedKeyData,
+    keypolicy: Keypolicy,
+) -> Result<SealedKeyData, ErrorCode> {
+    let mut kp = unseal(sealed_data)?;
+    let sealed = seal(csprng, &kp, keypolicy);
+    kp.secret.zeroize();
+    sealed
+}
+
 /// Checks the provided keyrequests
 /// and attempts to unseal the ed25519 keypair with `Aes128GcmSiv`
 pub fn unseal(sealed_data: &SealedKeyData) -> Result<Keypair, ErrorCode> {
@@ -1,23 +1,26 @@
 use anomaly::format_err;
 use std::{io, net::TcpStream};
 use tmkms_light::{
-    chain::state::{consensus, PersistStateSync, State, StateError, StateErrorKind},
-    utils::{read_u16_payload, write_u16_payload},
+    chain::state::{PersistStateSync, State, StateError, StateErrorKind},
+    framing::{FrameType, FramedStream},
 };
-use tracing::debug;
+use tracing::{debug, error};
 
 /// holds the connection for persiting the state outside of the enclave
 pub struct StateHolder {
-    state_conn: TcpStream,
+    state_conn: FramedStream<TcpStream>,
 }
 
 impl StateHolder {
     /// tries to connect to "state" address which is provided
     /// as "usercall extension" in the runner
+    /// and negotiates the framing version with the host
     pub fn new() -> io::Result<Self> {
-        Ok(Self {
-            state_conn: TcpStream::connect("state")?,
-        })
+        let state_conn = FramedStream::connect(TcpStream::connect("state")?).map_err(|e| {
+            error!("state stream negotiation failed: {}", e);
+            io::Error::from(io::ErrorKind::Other)
+        })?;
+        Ok(Self { state_conn })
     }
 }
 
@@ -25,27 +28,31 @@ impl PersistStateSync for StateHolder {
     fn load_state(&mut self) -> Result<State, StateError> {
         // TODO: currently unused as the initial state is now provided/loaded via "args"
         // so `PersistStateSync` is to be revisited
-        let json_raw = read_u16_payload(&mut self.state_conn)
+        let json_raw = self
+            .state_conn
+            .recv_payload(FrameType::State)
             .map_err(|e| format_err!(StateErrorKind::SyncError, "error reading state: {}", e))?;
-        let consensus_state: consensus::State = serde_json::from_slice(&json_raw)
-            .map_err(|e| format_err!(StateErrorKind::SyncError, "error parsing state: {}", e))?;
-        Ok(State::from(consensus_state))
+        serde_json::from_slice(&json_raw).map_err(|e| {
+            format_err!(StateErrorKind::SyncError, "error parsing state: {}", e).into()
+        })
     }
 
-    fn persist_state(&mut self, new_state: &consensus::State) -> Result<(), StateError> {
+    fn persist_state(&mut self, new_state: &State) -> Result<(), StateError> {
         debug!("writing new consensus state to state conn");
 
         let json_raw = serde_json::to_vec(&new_state).map_err(|e| {
             format_err!(StateErrorKind::SyncError, "error serializing state: {}", e)
         })?;
 
-        write_u16_payload(&mut self.state_conn, &json_raw).map_err(|e| {
-            format_err!(
-                StateErrorKind::SyncError,
-                "error state writting to socket {}",
-                e
-            )
-        })?;
+        self.state_conn
+            .send(FrameType::State, &json_raw)
+            .map_err(|e| {
+                format_err!(
+                    StateErrorKind::SyncError,
+                    "error state writting to socket {}",
+                    e
+                )
+            })?;
 
         debug!("successfully wrote new consensus state to state connection");
 
@@ -1,30 +1,108 @@
-use std::{fs, path::PathBuf};
+use std::convert::TryFrom;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 
-use crate::{SgxInitRequest, CLOUD_KEY_LEN};
 use tendermint::net;
 use tmkms_light::{
+    cli::{self, ConfigOpt, LogOpt, PubkeyOpt},
     config::validator::ValidatorConfig,
-    utils::{print_pubkey, PubkeyDisplay},
+    control::StatusTracker,
+    utils::print_pubkey,
+};
+use tmkms_light_sgx_runner::{
+    cloud_backup_key_id, keyring,
+    shamir::{BackupKeyShare, BackupKeySplit},
+    CloudBackupKeyData, KeyRole, PublicKey, SealingPolicy, SgxInitRequest,
 };
-use tracing::debug;
+use tracing::{debug, Level};
 use zeroize::Zeroizing;
 
-use crate::{config, runner::TmkmsSgxSigner};
+use crate::{
+    config,
+    runner::{EcdsaQuoter, TmkmsSgxSigner},
+    InitOpt, RecoverOpt,
+};
+use tmkms_light_sgx_runner::quote;
+
+fn read_config(config: &ConfigOpt) -> Result<config::SgxSignOpt, String> {
+    let toml_string = cli::read_config_file(config)?;
+    toml::from_str(&toml_string).map_err(|e| format!("toml config file failed to parse: {:?}", e))
+}
+
+/// checks that each backup key share goes to its own directory (e.g. a custodian's removable media)
+/// that isn't the one with the backups they unlock, and that no existing file is overwritten
+fn check_share_paths(share_paths: &[PathBuf], backup_dir: &Path) -> Result<(), String> {
+    let dir_of = |path: &Path| {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        dir.canonicalize()
+            .map_err(|e| format!("invalid directory for `{}`: {}", path.display(), e))
+    };
+    let backup_dir = if backup_dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        backup_dir
+    };
+    let backup_dir = backup_dir
+        .canonicalize()
+        .map_err(|e| format!("invalid backup data directory: {}", e))?;
+    let mut share_dirs = Vec::with_capacity(share_paths.len());
+    for path in share_paths.iter() {
+        if path.exists() {
+            return Err(format!("`{}` already exists", path.display()));
+        }
+        let dir = dir_of(path)?;
+        if dir == backup_dir {
+            return Err(format!(
+                "the backup key share `{}` is in the backup data directory",
+                path.display()
+            ));
+        }
+        if share_dirs.contains(&dir) {
+            return Err(format!(
+                "the backup key share `{}` is in the same directory as another share",
+                path.display()
+            ));
+        }
+        share_dirs.push(dir);
+    }
+    Ok(())
+}
 
 /// write tmkms.toml + generate keys (sealed for machine CPU
-/// + backup if an external key is provided)
-pub fn init(
-    config_path: Option<PathBuf>,
-    pubkey_display: Option<PubkeyDisplay>,
-    bech32_prefix: Option<String>,
-    external_backup_key_path: Option<PathBuf>,
-    key_backup_data_path: Option<PathBuf>,
-) -> Result<(), String> {
-    let cp = config_path.unwrap_or_else(|| "tmkms.toml".into());
-    let config = config::SgxSignOpt::default();
+/// + backup if an external key is provided or the backup key is split into shares)
+pub fn init(config_opt: ConfigOpt, pubkey: PubkeyOpt, opt: InitOpt) -> Result<(), String> {
+    let base_backup_path = opt.key_backup_data_path.unwrap_or_else(|| "".into());
+    let backup_key_split = match (opt.share_threshold, opt.key_share_paths.len()) {
+        (None, 0) => None,
+        (Some(_), 0) | (None, _) => {
+            return Err(
+                "both the share threshold and the share paths need to be provided".to_owned(),
+            )
+        }
+        (Some(_), _) if opt.external_backup_key_path.is_some() => {
+            return Err(
+                "the external backup key would recover the keys without the shares".to_owned(),
+            )
+        }
+        (Some(threshold), count) => {
+            let count = u8::try_from(count).map_err(|_| "too many backup key shares".to_owned())?;
+            check_share_paths(&opt.key_share_paths, &base_backup_path)?;
+            Some(BackupKeySplit { threshold, count })
+        }
+    };
+    let config = config::SgxSignOpt {
+        sealing_policy: opt.sealing_policy.unwrap_or_default(),
+        ..Default::default()
+    };
     let t =
         toml::to_string_pretty(&config).map_err(|e| format!("config to toml failed: {:?}", e))?;
-    fs::write(cp, t).map_err(|e| format!("failed to write a config: {:?}", e))?;
+    fs::write(&config_opt.config_path, t)
+        .map_err(|e| format!("failed to write a config: {:?}", e))?;
     fs::create_dir_all(
         config
             .sealed_consensus_key_path
@@ -39,170 +117,343 @@ pub fn init(
             .ok_or_else(|| "cannot create a dir in a root directory".to_owned())?,
     )
     .map_err(|e| format!("failed to create dirs for state storage: {:?}", e))?;
-    let request = SgxInitRequest::KeyGen;
+    let roles: Vec<KeyRole> = std::iter::once(KeyRole::Consensus)
+        .chain(config.sealed_id_key_path.as_ref().map(|_| KeyRole::Id))
+        .collect();
+    let request = SgxInitRequest::KeyGen {
+        sealing_policy: config.sealing_policy,
+        roles: roles.clone(),
+        backup_key_split,
+    };
     let request_bytes = serde_json::to_vec(&request)
         .map_err(|e| format!("failed to convert request to json: {:?}", e))?;
-    let backup_key = if let Some(bkp) = external_backup_key_path {
-        let key_bytes = Zeroizing::new(
-            fs::read(bkp).map_err(|e| format!("failed to read backup key: {:?}", e))?,
-        );
-        if key_bytes.len() != CLOUD_KEY_LEN {
-            return Err("incorrect backup key length".to_owned());
+    // a split backup key is generated in the enclave and only its shares are returned
+    let backup_key = match opt.external_backup_key_path {
+        Some(bkp) => {
+            let key_bytes = keyring::read_backup_key(bkp)?;
+            debug!("backup key id: {}", cloud_backup_key_id(&key_bytes));
+            Some(Zeroizing::new(subtle_encoding::hex::encode(&*key_bytes)))
         }
-        Some(Zeroizing::new(subtle_encoding::hex::encode(&*key_bytes)))
-    } else {
-        None
+        None => None,
     };
     debug!("launching enclave");
     let (state_syncer, _, state_stream) = TmkmsSgxSigner::get_state_syncer(&config.state_file_path)
         .map_err(|e| format!("state persistence error: {:?}", e))?;
     let mut enclave_args: Vec<&[u8]> = vec![request_bytes.as_ref()];
     if let Some(ref bkp) = backup_key {
-        enclave_args.push(&*bkp);
+        enclave_args.push(bkp);
     }
     let runner = TmkmsSgxSigner::launch_enclave_app(
         &config.enclave_path,
         None,
+        None,
         state_syncer,
         state_stream,
         &enclave_args,
     )
     .map_err(|e| format!("failed to launch the enclave app: {:?}", e))?;
     debug!("waiting for keygen");
-    let sealed_key = runner
-        .get_init_response()
-        .map_err(|e| format!("failed to generate consensus key: {:?}", e))?;
-    config::write_sealed_file(
-        config.sealed_consensus_key_path,
-        &sealed_key.sealed_key_data,
-    )
-    .map_err(|e| format!("failed to write consensus key: {:?}", e))?;
-    let public_key =
-        ed25519_dalek::PublicKey::from_bytes(&sealed_key.sealed_key_data.seal_key_request.keyid)
-            .map_err(|e| format!("invalid keyid: {:?}", e))?;
-    print_pubkey(bech32_prefix, pubkey_display, public_key);
-    let base_backup_path = key_backup_data_path.unwrap_or_else(|| "".into());
-    if let Some(bkp) = sealed_key.cloud_backup_key_data {
-        config::write_backup_file(base_backup_path.join("consensus-key.backup"), &bkp)
-            .map_err(|e| format!("failed to write consensus key backup: {:?}", e))?;
-    }
-    if let Some(id_path) = config.sealed_id_key_path {
-        let (state_syncer, _, state_stream) =
-            TmkmsSgxSigner::get_state_syncer(&config.state_file_path)
-                .map_err(|e| format!("state persistence error: {:?}", e))?;
-
-        let runner = TmkmsSgxSigner::launch_enclave_app(
-            &config.enclave_path,
-            None,
-            state_syncer,
-            state_stream,
-            &enclave_args,
-        )
-        .map_err(|e| format!("failed to launch the enclave app: {:?}", e))?;
-        let sealed_key = runner
-            .get_init_response()
-            .map_err(|e| format!("failed to generate id key: {:?}", e))?;
-        config::write_sealed_file(id_path, &sealed_key.sealed_key_data)
-            .map_err(|e| format!("failed to write id key: {:?}", e))?;
-        if let Some(bkp) = sealed_key.cloud_backup_key_data {
-            config::write_backup_file(base_backup_path.join("id-key.backup"), &bkp)
-                .map_err(|e| format!("failed to write id key backup: {:?}", e))?;
+    let response = runner
+        .get_keygen_response()
+        .map_err(|e| format!("failed to generate keys: {:?}", e))?;
+    if response.keys.len() != roles.len() {
+        return Err("unexpected number of generated keys".to_owned());
+    }
+    if response.key_shares.len() != opt.key_share_paths.len() {
+        return Err("unexpected number of backup key shares".to_owned());
+    }
+    for (role, key) in roles.iter().zip(response.keys.iter()) {
+        if key.role != *role {
+            return Err(format!("expected {}, got {}", role, key.role));
+        }
+        // the roles are requested for the configured paths
+        let key_path = config.sealed_key_path(*role).expect("configured key path");
+        config::write_sealed_file(key_path, &key.sealed_key_data)
+            .map_err(|e| format!("failed to write {}: {:?}", role, e))?;
+        if let Some(bkp) = &key.cloud_backup_key_data {
+            config::write_backup_file(base_backup_path.join(role.backup_file_name()), bkp)
+                .map_err(|e| format!("failed to write {} backup: {:?}", role, e))?;
         }
     }
+    let public_key = ed25519_dalek::PublicKey::from_bytes(
+        &response.keys[0].sealed_key_data.seal_key_request.keyid,
+    )
+    .map_err(|e| format!("invalid keyid: {:?}", e))?;
+    print_pubkey(pubkey.bech32_prefix, pubkey.pubkey_display, public_key);
+    for (share, share_path) in response.key_shares.iter().zip(opt.key_share_paths.iter()) {
+        config::write_share_file(share_path, share)
+            .map_err(|e| format!("failed to write backup key share: {:?}", e))?;
+        println!(
+            "backup key share {} of {} (any {} recover the keys): {}",
+            share.index,
+            response.key_shares.len(),
+            share.threshold,
+            share_path.display()
+        );
+    }
     Ok(())
 }
 
 /// startup the enclave with Unix socket pairs for retrieving state updates and persisting them on the host
-pub fn start(config_path: Option<PathBuf>) -> Result<(), String> {
-    let cp = config_path.unwrap_or_else(|| "tmkms.toml".into());
-    if !cp.exists() {
-        Err("missing tmkms.toml file".to_owned())
+pub fn start(config: ConfigOpt, log: LogOpt, default_log_level: Level) -> Result<(), String> {
+    let config = read_config(&config)?;
+    log.init(&config.logging, default_log_level)?;
+    let tm_conn = match &config.address {
+        net::Address::Unix { path } => {
+            debug!(
+                "{}: Connecting to socket at {}...",
+                &config.chain_id, &config.address
+            );
+
+            Some(path.clone())
+        }
+        _ => None,
+    };
+    let remote = if let (None, Some(path)) = (&tm_conn, config.sealed_id_key_path) {
+        Some((config.address, path))
     } else {
-        let toml_string = fs::read_to_string(cp)
-            .map_err(|e| format!("toml config file failed to read: {:?}", e))?;
-        let config: config::SgxSignOpt = toml::from_str(&toml_string)
-            .map_err(|e| format!("toml config file failed to parse: {:?}", e))?;
-        let tm_conn = match &config.address {
-            net::Address::Unix { path } => {
-                debug!(
-                    "{}: Connecting to socket at {}...",
-                    &config.chain_id, &config.address
-                );
-
-                Some(path.clone())
+        None
+    };
+    // the Unix connections are made via the runner, the TCP ones aren't visible to it
+    let tracker = StatusTracker::new(tm_conn.is_some());
+    let (state_syncer, state, state_stream) =
+        TmkmsSgxSigner::get_state_syncer(&config.state_file_path)
+            .map_err(|e| format!("state persistence error: {:?}", e))?;
+    tracker.set_state(state.consensus_state().clone());
+    if let Some(public_key) = TmkmsSgxSigner::read_sealed_key(&config.sealed_consensus_key_path)
+        .ok()
+        .and_then(|key| ed25519_dalek::PublicKey::from_bytes(&key.seal_key_request.keyid).ok())
+    {
+        tracker.set_public_key(tendermint::PublicKey::Ed25519(public_key));
+    }
+    let start_request_bytes = TmkmsSgxSigner::get_start_request_bytes(
+        config.sealed_consensus_key_path,
+        ValidatorConfig {
+            chain_id: config.chain_id,
+            max_height: config.max_height,
+        },
+        state,
+        remote,
+    )
+    .map_err(|e| format!("failed to get enclave request: {:?}", e))?;
+    let runner = TmkmsSgxSigner::launch_enclave_app(
+        &config.enclave_path,
+        tm_conn,
+        Some(tracker.clone()),
+        state_syncer,
+        state_stream,
+        &[&start_request_bytes],
+    )
+    .map_err(|e| format!("failed to launch the enclave app: {:?}", e))?;
+    let control = runner
+        .signing_control()
+        .map_err(|e| format!("failed to get the enclave control stream: {:?}", e))?;
+    cli::launch_control_socket(
+        config.control_socket_path.as_deref(),
+        &tracker,
+        Box::new(control),
+    )?;
+    runner
+        .start()
+        .map_err(|e| format!("enclave running failed: {:?}", e))?;
+    Ok(())
+}
+
+/// checks the public keys in the backups against their roles:
+/// the existing sealed keypair with the same role (if any) needs to have the same public key,
+/// and the keypairs with different roles need to be different
+fn check_backup_roles(
+    backups: &[(KeyRole, CloudBackupKeyData)],
+    existing: &[(KeyRole, PublicKey)],
+) -> Result<(), String> {
+    for (role, backup) in backups.iter() {
+        let public_key = backup.public_key.as_bytes();
+        for (existing_role, existing_key) in existing.iter() {
+            if existing_role == role && existing_key != public_key {
+                return Err(format!(
+                    "the {} backup doesn't match the existing sealed {}",
+                    role, existing_role
+                ));
             }
-            _ => None,
-        };
-        let remote = if let (None, Some(path)) = (&tm_conn, config.sealed_id_key_path) {
-            Some((config.address, path))
-        } else {
-            None
-        };
-        let (state_syncer, state, state_stream) =
-            TmkmsSgxSigner::get_state_syncer(&config.state_file_path)
-                .map_err(|e| format!("state persistence error: {:?}", e))?;
-        let start_request_bytes = TmkmsSgxSigner::get_start_request_bytes(
-            config.sealed_consensus_key_path,
-            ValidatorConfig {
-                chain_id: config.chain_id,
-                max_height: config.max_height,
-            },
-            state,
-            remote,
-        )
-        .map_err(|e| format!("failed to get enclave request: {:?}", e))?;
-        let runner = TmkmsSgxSigner::launch_enclave_app(
-            &config.enclave_path,
-            tm_conn,
-            state_syncer,
-            state_stream,
-            &[&start_request_bytes],
-        )
-        .map_err(|e| format!("failed to launch the enclave app: {:?}", e))?;
-        runner
-            .start()
-            .map_err(|e| format!("enclave running failed: {:?}", e))?;
-        Ok(())
+            if existing_role != role && existing_key == public_key {
+                return Err(format!(
+                    "the {} backup contains the existing sealed {}",
+                    role, existing_role
+                ));
+            }
+        }
+        for (other_role, other) in backups.iter() {
+            if other_role != role && other.public_key == backup.public_key {
+                return Err(format!(
+                    "the {} and {} backups contain the same keypair",
+                    role, other_role
+                ));
+            }
+        }
     }
+    Ok(())
 }
 
-/// recover the previously backed up id/consensus key (e.g. in cloud settings where
+/// recover the previously backed up id and/or consensus key (e.g. in cloud settings where
 /// physical CPU-affinity isn't guaranteed)
-pub fn recover(
-    config_path: Option<PathBuf>,
-    pubkey_display: Option<PubkeyDisplay>,
-    bech32_prefix: Option<String>,
-    external_backup_key_path: PathBuf,
-    key_backup_data_path: PathBuf,
-    recover_consensus_key: bool,
-) -> Result<(), String> {
-    let cp = config_path.unwrap_or_else(|| "tmkms.toml".into());
-    if !cp.exists() {
-        Err("missing tmkms.toml file".to_owned())
+pub fn recover(config: ConfigOpt, pubkey: PubkeyOpt, opt: RecoverOpt) -> Result<(), String> {
+    let config = read_config(&config)?;
+    let roles = if opt.recover_all_keys {
+        std::iter::once(KeyRole::Consensus)
+            .chain(config.sealed_id_key_path.as_ref().map(|_| KeyRole::Id))
+            .collect()
+    } else if opt.recover_consensus_key {
+        vec![KeyRole::Consensus]
     } else {
-        let toml_string = fs::read_to_string(cp)
-            .map_err(|e| format!("toml config file failed to read: {:?}", e))?;
-        let config: config::SgxSignOpt = toml::from_str(&toml_string)
-            .map_err(|e| format!("toml config file failed to parse: {:?}", e))?;
-        if !recover_consensus_key && config.sealed_id_key_path.is_none() {
+        if config.sealed_id_key_path.is_none() {
             return Err("empty id key path in config".to_owned());
         }
-        let backup_key = {
-            let key_bytes = Zeroizing::new(
-                fs::read(external_backup_key_path)
-                    .map_err(|e| format!("failed to read backup key: {:?}", e))?,
-            );
-            if key_bytes.len() != CLOUD_KEY_LEN {
-                return Err("incorrect backup key length".to_owned());
+        vec![KeyRole::Id]
+    };
+    let backups = roles
+        .iter()
+        .map(|role| {
+            let backup_path = opt.key_backup_data_path.join(role.backup_file_name());
+            let key_data: CloudBackupKeyData =
+                serde_json::from_str(&fs::read_to_string(&backup_path).map_err(|e| {
+                    format!(
+                        "failed to read {} backup data `{}`: {:?}",
+                        role,
+                        backup_path.display(),
+                        e
+                    )
+                })?)
+                .map_err(|e| format!("failed to parse {} backup data: {:?}", role, e))?;
+            Ok((*role, key_data))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let existing = [KeyRole::Consensus, KeyRole::Id]
+        .iter()
+        .filter_map(|role| {
+            config
+                .sealed_key_path(*role)
+                .filter(|path| path.exists())
+                .map(|path| (*role, path))
+        })
+        .map(|(role, path)| {
+            TmkmsSgxSigner::read_sealed_key(path)
+                .map(|sealed_key| (role, sealed_key.seal_key_request.keyid))
+                .map_err(|e| format!("failed to read `{}`: {}", path.display(), e))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    check_backup_roles(&backups, &existing)?;
+    let key_id = backups[0].1.key_id.clone();
+    if backups.iter().any(|(_, backup)| backup.key_id != key_id) {
+        return Err("the backups were made with different backup keys".to_owned());
+    }
+    let key_shares = opt
+        .key_share_paths
+        .iter()
+        .map(|path| {
+            let share: BackupKeyShare = serde_json::from_str(
+                &fs::read_to_string(path)
+                    .map_err(|e| format!("failed to read backup key share: {:?}", e))?,
+            )
+            .map_err(|e| format!("failed to parse backup key share: {:?}", e))?;
+            match &key_id {
+                Some(key_id) if *key_id != share.key_id => Err(format!(
+                    "the backup key share {} belongs to a different key ({})",
+                    path.display(),
+                    share.key_id
+                )),
+                _ => Ok(share),
+            }
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let key_bytes = match (opt.external_backup_key_path, opt.keyring_path, &key_id) {
+        // the key is recombined from the shares in the enclave
+        (None, None, _) if !key_shares.is_empty() => None,
+        (Some(path), _, key_id) => {
+            let key_bytes = keyring::read_backup_key(path)?;
+            if let Some(key_id) = key_id {
+                if cloud_backup_key_id(&key_bytes) != *key_id {
+                    return Err(format!(
+                        "the backup was made with a different key ({})",
+                        key_id
+                    ));
+                }
             }
-            Zeroizing::new(subtle_encoding::hex::encode(&*key_bytes))
+            Some(key_bytes)
+        }
+        (None, Some(dir), Some(key_id)) => Some(keyring::find_backup_key(dir, key_id)?),
+        (None, Some(_), None) => {
+            return Err(
+                "the backup has no key identifier, the backup key needs to be provided".to_owned(),
+            )
+        }
+        (None, None, _) => return Err("no backup key, keyring or key shares provided".to_owned()),
+    };
+    let backup_key =
+        key_bytes.map(|key_bytes| Zeroizing::new(subtle_encoding::hex::encode(&*key_bytes)));
+    let request = SgxInitRequest::CloudRecover {
+        key_data: backups.iter().map(|(_, backup)| backup.clone()).collect(),
+        sealing_policy: config.sealing_policy,
+        key_shares,
+    };
+    let request_bytes = serde_json::to_vec(&request)
+        .map_err(|e| format!("failed to convert request to json: {:?}", e))?;
+    debug!("launching enclave");
+    let (state_syncer, _, state_stream) = TmkmsSgxSigner::get_state_syncer(&config.state_file_path)
+        .map_err(|e| format!("state persistence error: {:?}", e))?;
+    let mut enclave_args: Vec<&[u8]> = vec![request_bytes.as_ref()];
+    if let Some(ref bkp) = backup_key {
+        enclave_args.push(bkp);
+    }
+    let runner = TmkmsSgxSigner::launch_enclave_app(
+        &config.enclave_path,
+        None,
+        None,
+        state_syncer,
+        state_stream,
+        &enclave_args,
+    )
+    .map_err(|e| format!("failed to launch the enclave app: {:?}", e))?;
+    debug!("waiting for recover");
+    let response = runner
+        .get_recover_response()
+        .map_err(|e| format!("failed to recover keys: {:?}", e))?;
+    if response.sealed_key_data.len() != backups.len() {
+        return Err("unexpected number of recovered keys".to_owned());
+    }
+    let mut consensus_pubkey = None;
+    for ((role, backup), sealed_key_data) in backups.iter().zip(response.sealed_key_data.iter()) {
+        if sealed_key_data.seal_key_request.keyid != backup.public_key.to_bytes() {
+            return Err(format!("the recovered {} doesn't match its backup", role));
+        }
+        // the paths of all recovered roles are checked above
+        let key_path = config.sealed_key_path(*role).expect("configured key path");
+        config::write_sealed_file(key_path, sealed_key_data)
+            .map_err(|e| format!("failed to write {}: {:?}", role, e))?;
+        println!("recovered {}", role);
+        if *role == KeyRole::Consensus {
+            consensus_pubkey = Some(backup.public_key);
+        }
+    }
+    if let Some(public_key) = consensus_pubkey {
+        print_pubkey(pubkey.bech32_prefix, pubkey.pubkey_display, public_key);
+    }
+    Ok(())
+}
+
+/// reseal the consensus (and id) keys under the provided policy (or the one in the config)
+/// with the current enclave version; the previous sealed files are kept with the `.bak` suffix
+pub fn reseal(config: ConfigOpt, sealing_policy: Option<SealingPolicy>) -> Result<(), String> {
+    let config = read_config(&config)?;
+    let sealing_policy = sealing_policy.unwrap_or(config.sealing_policy);
+    let key_paths = std::iter::once(config.sealed_consensus_key_path)
+        .chain(config.sealed_id_key_path.into_iter().filter(|p| p.exists()));
+    for key_path in key_paths {
+        let sealed_key = TmkmsSgxSigner::read_sealed_key(&key_path)
+            .map_err(|e| format!("failed to read `{}`: {}", key_path.display(), e))?;
+        let request = SgxInitRequest::Reseal {
+            sealed_key,
+            sealing_policy,
         };
-        let key_data = serde_json::from_str(
-            &fs::read_to_string(key_backup_data_path.join("consensus-key.backup"))
-                .map_err(|e| format!("failed to read backup data: {:?}", e))?,
-        )
-        .map_err(|e| format!("failed to parse backup data: {:?}", e))?;
-        let request = SgxInitRequest::CloudRecover { key_data };
         let request_bytes = serde_json::to_vec(&request)
             .map_err(|e| format!("failed to convert request to json: {:?}", e))?;
         debug!("launching enclave");
@@ -212,33 +463,229 @@ pub fn recover(
         let runner = TmkmsSgxSigner::launch_enclave_app(
             &config.enclave_path,
             None,
+            None,
             state_syncer,
             state_stream,
-            &[request_bytes.as_ref(), &*backup_key],
+            &[&request_bytes],
         )
         .map_err(|e| format!("failed to launch the enclave app: {:?}", e))?;
-        debug!("waiting for recover");
-        let sealed_key = runner
+        let resealed = runner
             .get_init_response()
-            .map_err(|e| format!("failed to recover key: {:?}", e))?;
-        if recover_consensus_key {
-            config::write_sealed_file(
-                config.sealed_consensus_key_path,
-                &sealed_key.sealed_key_data,
-            )
-            .map_err(|e| format!("failed to write consensus key: {:?}", e))?;
-            let public_key = ed25519_dalek::PublicKey::from_bytes(
-                &sealed_key.sealed_key_data.seal_key_request.keyid,
-            )
-            .map_err(|e| format!("ivalid keyid: {:?}", e))?;
-            println!("recovered key");
-            print_pubkey(bech32_prefix, pubkey_display, public_key);
-        } else {
-            // checked above after config parsing
-            let id_path = config.sealed_id_key_path.unwrap();
-            config::write_sealed_file(id_path, &sealed_key.sealed_key_data)
-                .map_err(|e| format!("failed to write id key: {:?}", e))?;
+            .map_err(|e| format!("failed to reseal `{}`: {:?}", key_path.display(), e))?;
+        let mut backup_path = key_path.clone().into_os_string();
+        backup_path.push(".bak");
+        fs::copy(&key_path, &backup_path)
+            .map_err(|e| format!("failed to back up `{}`: {:?}", key_path.display(), e))?;
+        config::write_sealed_file(&key_path, &resealed.sealed_key_data)
+            .map_err(|e| format!("failed to write `{}`: {:?}", key_path.display(), e))?;
+        println!("resealed `{}` ({:?})", key_path.display(), sealing_policy);
+    }
+    Ok(())
+}
+
+/// get a DCAP quote of the enclave app with the consensus public key in its report data
+pub fn export_quote(config: ConfigOpt, quote_path: Option<PathBuf>) -> Result<(), String> {
+    let config = read_config(&config)?;
+    let quoter =
+        EcdsaQuoter::new().map_err(|e| format!("failed to get the quoting enclave: {}", e))?;
+    let target_info = quoter
+        .target_info()
+        .map_err(|e| format!("failed to get the quoting enclave target info: {}", e))?;
+    let sealed_key = TmkmsSgxSigner::read_sealed_key(&config.sealed_consensus_key_path)
+        .map_err(|e| format!("failed to read the consensus key: {}", e))?;
+    let request = SgxInitRequest::Report {
+        sealed_key,
+        target_info,
+    };
+    let request_bytes = serde_json::to_vec(&request)
+        .map_err(|e| format!("failed to convert request to json: {:?}", e))?;
+    debug!("launching enclave");
+    let (state_syncer, _, state_stream) = TmkmsSgxSigner::get_state_syncer(&config.state_file_path)
+        .map_err(|e| format!("state persistence error: {:?}", e))?;
+    let runner = TmkmsSgxSigner::launch_enclave_app(
+        &config.enclave_path,
+        None,
+        None,
+        state_syncer,
+        state_stream,
+        &[&request_bytes],
+    )
+    .map_err(|e| format!("failed to launch the enclave app: {:?}", e))?;
+    debug!("waiting for report");
+    let response = runner
+        .get_report_response()
+        .map_err(|e| format!("failed to get the enclave report: {:?}", e))?;
+    let quote = quoter
+        .get_quote(response.report)
+        .map_err(|e| format!("failed to get the quote: {}", e))?;
+    let quote_path = quote_path.unwrap_or_else(|| "quote.dat".into());
+    fs::write(&quote_path, quote).map_err(|e| format!("failed to write the quote: {:?}", e))?;
+    println!("quote written to `{}`", quote_path.display());
+    Ok(())
+}
+
+/// verify the quote and check the enclave identity and the public key in it
+/// against the configuration
+pub fn verify_quote(
+    config: ConfigOpt,
+    quote_path: Option<PathBuf>,
+    pubkey: PubkeyOpt,
+) -> Result<(), String> {
+    let config = read_config(&config)?;
+    let root_path = config
+        .attestation_root_cert_path
+        .ok_or_else(|| "missing `attestation_root_cert_path` in the config".to_owned())?;
+    let root = fs::read(&root_path).map_err(|e| {
+        format!(
+            "failed to read the root certificate `{}`: {:?}",
+            root_path.display(),
+            e
+        )
+    })?;
+    let expected = config.expected_identity.unwrap_or_default().decode()?;
+    let quote_path = quote_path.unwrap_or_else(|| "quote.dat".into());
+    let quote_bytes =
+        fs::read(&quote_path).map_err(|e| format!("failed to read the quote: {:?}", e))?;
+    let quote = quote::verify(&quote_bytes, &root)
+        .map_err(|e| format!("quote verification failed: {}", e))?;
+    let report = &quote.report_body;
+    let hex = |b: &[u8]| String::from_utf8(subtle_encoding::hex::encode(b)).expect("hex");
+    println!("MRENCLAVE: {}", hex(&report.mrenclave));
+    println!("MRSIGNER: {}", hex(&report.mrsigner));
+    println!(
+        "ISV product ID: {}, SVN: {}",
+        report.isv_prod_id, report.isv_svn
+    );
+    let mut mismatches = quote::check_identity(report, &expected);
+    let public_key = ed25519_dalek::PublicKey::from_bytes(&report.report_data[..32])
+        .map_err(|e| format!("invalid public key in the report data: {:?}", e))?;
+    print_pubkey(pubkey.bech32_prefix, pubkey.pubkey_display, public_key);
+    if let Ok(sealed_key) = TmkmsSgxSigner::read_sealed_key(&config.sealed_consensus_key_path) {
+        if sealed_key.seal_key_request.keyid != public_key.to_bytes() {
+            mismatches.push("public key differs from the sealed consensus key".to_owned());
         }
+    }
+    if mismatches.is_empty() {
+        println!("quote verified");
         Ok(())
+    } else {
+        Err(format!("quote mismatches: {}", mismatches.join(", ")))
+    }
+}
+
+/// display the consensus public key (the key id of the sealed keypair)
+pub fn pubkey(config: ConfigOpt, pubkey: PubkeyOpt) -> Result<(), String> {
+    let config = read_config(&config)?;
+    let sealed_key = TmkmsSgxSigner::read_sealed_key(&config.sealed_consensus_key_path)
+        .map_err(|e| format!("failed to read the consensus key: {}", e))?;
+    let public_key = ed25519_dalek::PublicKey::from_bytes(&sealed_key.seal_key_request.keyid)
+        .map_err(|e| format!("invalid keyid: {:?}", e))?;
+    print_pubkey(pubkey.bech32_prefix, pubkey.pubkey_display, public_key);
+    Ok(())
+}
+
+/// check the config, the enclave app and the sealed key files
+pub fn validate(config: ConfigOpt) -> Result<(), String> {
+    let config = read_config(&config)?;
+    cli::check_file_exists(&config.enclave_path, "enclave app")?;
+    for role in [KeyRole::Consensus, KeyRole::Id].iter() {
+        if let Some(path) = config.sealed_key_path(*role) {
+            TmkmsSgxSigner::read_sealed_key(path)
+                .map_err(|e| format!("invalid sealed {} `{}`: {}", role, path.display(), e))?;
+        }
+    }
+    if let (net::Address::Tcp { .. }, None) = (&config.address, &config.sealed_id_key_path) {
+        return Err("missing `sealed_id_key_path` for the TCP connection".to_owned());
+    }
+    if let Some(expected) = &config.expected_identity {
+        expected.decode()?;
+    }
+    cli::read_state_file(&config.state_file_path)?;
+    println!("configuration OK");
+    Ok(())
+}
+
+/// display the signer status
+pub fn status(config: ConfigOpt) -> Result<(), String> {
+    let config = read_config(&config)?;
+    cli::print_status(
+        &config.chain_id,
+        &config.address,
+        &config.state_file_path,
+        config.control_socket_path.as_deref(),
+    )
+}
+
+/// the control socket of the running runner (if configured)
+pub fn control_socket_path(config: ConfigOpt) -> Result<Option<PathBuf>, String> {
+    Ok(read_config(&config)?.control_socket_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::Keypair;
+    use rand_core::OsRng;
+
+    fn backup(public_key: ed25519_dalek::PublicKey) -> CloudBackupKeyData {
+        CloudBackupKeyData {
+            version: 0,
+            algorithm: Default::default(),
+            key_id: None,
+            salt: None,
+            nonce: [0u8; 12],
+            sealed_secret: vec![],
+            public_key,
+        }
+    }
+
+    #[test]
+    fn test_check_backup_roles() {
+        let consensus = Keypair::generate(&mut OsRng).public;
+        let id = Keypair::generate(&mut OsRng).public;
+        let backups = [
+            (KeyRole::Consensus, backup(consensus)),
+            (KeyRole::Id, backup(id)),
+        ];
+        assert!(check_backup_roles(&backups, &[]).is_ok());
+        assert!(check_backup_roles(
+            &backups,
+            &[
+                (KeyRole::Consensus, consensus.to_bytes()),
+                (KeyRole::Id, id.to_bytes())
+            ]
+        )
+        .is_ok());
+        // the id key backup copied over the consensus one
+        let swapped = [(KeyRole::Consensus, backup(id)), (KeyRole::Id, backup(id))];
+        assert!(check_backup_roles(&swapped, &[]).is_err());
+        assert!(check_backup_roles(&swapped[..1], &[(KeyRole::Id, id.to_bytes())]).is_err());
+        assert!(check_backup_roles(&backups[..1], &[(KeyRole::Consensus, id.to_bytes())]).is_err());
+    }
+
+    #[test]
+    fn test_check_share_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup_dir = dir.path().join("backup");
+        let custodians: Vec<PathBuf> = (1..=3)
+            .map(|i| {
+                let custodian = dir.path().join(format!("custodian{}", i));
+                fs::create_dir(&custodian).unwrap();
+                custodian.join("backup-key.share")
+            })
+            .collect();
+        fs::create_dir(&backup_dir).unwrap();
+        assert!(check_share_paths(&custodians, &backup_dir).is_ok());
+        // next to the backups
+        let in_backup_dir = [custodians[0].clone(), backup_dir.join("backup-key.share")];
+        assert!(check_share_paths(&in_backup_dir, &backup_dir).is_err());
+        // two shares in one directory
+        let same_dir = [custodians[0].clone(), custodians[0].with_extension("2")];
+        assert!(check_share_paths(&same_dir, &backup_dir).is_err());
+        // a missing directory or an existing file
+        let missing = [dir.path().join("missing").join("backup-key.share")];
+        assert!(check_share_paths(&missing, &backup_dir).is_err());
+        fs::write(&custodians[1], b"").unwrap();
+        assert!(check_share_paths(&custodians, &backup_dir).is_err());
     }
 }
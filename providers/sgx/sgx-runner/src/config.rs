@@ -1,9 +1,11 @@
-use crate::shared::CloudBackupKeyData;
-use crate::shared::SealedKeyData;
 use serde::{Deserialize, Serialize};
 use std::{convert::TryFrom, path::PathBuf};
 use std::{fs::OpenOptions, io, os::unix::fs::OpenOptionsExt, path::Path};
 use tendermint::{chain, net};
+use tmkms_light::logging::LogConfig;
+use tmkms_light_sgx_runner::quote::ExpectedIdentity;
+use tmkms_light_sgx_runner::shamir::BackupKeyShare;
+use tmkms_light_sgx_runner::{CloudBackupKeyData, KeyRole, SealedKeyData, SealingPolicy};
 use tracing::error;
 
 /// runner configuration in toml
@@ -24,6 +26,59 @@ pub struct SgxSignOpt {
     pub state_file_path: PathBuf,
     /// Path to sgxs + signature files
     pub enclave_path: PathBuf,
+    /// Enclave identity the keys are sealed to (`mrsigner` or `mrenclave`)
+    #[serde(default)]
+    pub sealing_policy: SealingPolicy,
+    /// Path to the Intel SGX root CA certificate (PEM) for verifying the quotes
+    pub attestation_root_cert_path: Option<PathBuf>,
+    /// Expected identity of the enclave in the quotes
+    pub expected_identity: Option<ExpectedEnclaveIdentity>,
+    /// Path to the control socket for querying the running signer (if any)
+    pub control_socket_path: Option<PathBuf>,
+    /// Logging options
+    #[serde(default)]
+    pub logging: LogConfig,
+}
+
+/// The expected enclave identity fields
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExpectedEnclaveIdentity {
+    /// hex-encoded enclave measurement
+    pub mrenclave: Option<String>,
+    /// hex-encoded hash of the enclave signing key
+    pub mrsigner: Option<String>,
+    /// enclave product ID
+    pub isv_prod_id: Option<u16>,
+    /// minimum enclave security version
+    pub isv_svn: Option<u16>,
+    /// if the enclaves launched in the debug mode are accepted
+    #[serde(default)]
+    pub allow_debug: bool,
+}
+
+impl ExpectedEnclaveIdentity {
+    /// the decoded identity for the quote checks
+    pub fn decode(&self) -> Result<ExpectedIdentity, String> {
+        let measurement = |name: &str, value: &Option<String>| {
+            value
+                .as_ref()
+                .map(|hex| {
+                    let bytes = subtle_encoding::hex::decode(hex.trim().to_lowercase())
+                        .map_err(|e| format!("invalid {}: {}", name, e))?;
+                    <[u8; 32]>::try_from(bytes.as_slice())
+                        .map_err(|_| format!("invalid {} length", name))
+                })
+                .transpose()
+        };
+        Ok(ExpectedIdentity {
+            mrenclave: measurement("MRENCLAVE", &self.mrenclave)?,
+            mrsigner: measurement("MRSIGNER", &self.mrsigner)?,
+            isv_prod_id: self.isv_prod_id,
+            isv_svn: self.isv_svn,
+            allow_debug: self.allow_debug,
+        })
+    }
 }
 
 impl Default for SgxSignOpt {
@@ -38,6 +93,23 @@ impl Default for SgxSignOpt {
             sealed_id_key_path: Some("secrets/id.key".into()),
             state_file_path: "state/priv_validator_state.json".into(),
             enclave_path: "enclave/tmkms-light-sgx-app.sgxs".into(),
+            sealing_policy: SealingPolicy::default(),
+            attestation_root_cert_path: Some(
+                "Intel_SGX_Provisioning_Certification_RootCA.pem".into(),
+            ),
+            expected_identity: None,
+            control_socket_path: Some("state/control.sock".into()),
+            logging: LogConfig::default(),
+        }
+    }
+}
+
+impl SgxSignOpt {
+    /// the path of the sealed keypair with the given role (if configured)
+    pub fn sealed_key_path(&self, role: KeyRole) -> Option<&Path> {
+        match role {
+            KeyRole::Consensus => Some(&self.sealed_consensus_key_path),
+            KeyRole::Id => self.sealed_id_key_path.as_deref(),
         }
     }
 }
@@ -72,3 +144,8 @@ pub fn write_backup_file<P: AsRef<Path>>(
 ) -> io::Result<()> {
     write_json_file(path, sealed_data)
 }
+
+/// write a backup key share
+pub fn write_share_file<P: AsRef<Path>>(path: P, share: &BackupKeyShare) -> io::Result<()> {
+    write_json_file(path, share)
+}
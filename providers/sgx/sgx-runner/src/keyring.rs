@@ -0,0 +1,66 @@
+use crate::shared::{cloud_backup_key_id, CloudBackupAlgorithm};
+use std::fs;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+/// reads the cloud backup key (or the secret it's derived from) and checks its length
+pub fn read_backup_key<P: AsRef<Path>>(path: P) -> Result<Zeroizing<Vec<u8>>, String> {
+    let key_bytes = Zeroizing::new(
+        fs::read(path.as_ref()).map_err(|e| format!("failed to read backup key: {:?}", e))?,
+    );
+    if CloudBackupAlgorithm::for_secret(&key_bytes).is_none() {
+        return Err("incorrect backup key length".to_owned());
+    }
+    Ok(key_bytes)
+}
+
+/// finds the cloud backup key with the given identifier
+/// in the keyring directory (one key per file, other files are skipped)
+pub fn find_backup_key<P: AsRef<Path>>(dir: P, key_id: &str) -> Result<Zeroizing<Vec<u8>>, String> {
+    let entries =
+        fs::read_dir(dir.as_ref()).map_err(|e| format!("failed to read keyring: {:?}", e))?;
+    for entry in entries {
+        let path = entry
+            .map_err(|e| format!("failed to read keyring: {:?}", e))?
+            .path();
+        if !path.is_file() {
+            continue;
+        }
+        if let Ok(key_bytes) = read_backup_key(&path) {
+            if cloud_backup_key_id(&key_bytes) == key_id {
+                return Ok(key_bytes);
+            }
+        }
+    }
+    Err(format!("backup key {} not found in the keyring", key_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::CLOUD_KEY_LEN;
+
+    #[test]
+    fn test_find_backup_key() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("old.key"), [1u8; CLOUD_KEY_LEN]).unwrap();
+        fs::write(dir.path().join("new.key"), [2u8; 64]).unwrap();
+        fs::write(dir.path().join("README"), b"not a key").unwrap();
+        fs::create_dir(dir.path().join("archive")).unwrap();
+
+        let old_id = cloud_backup_key_id(&[1u8; CLOUD_KEY_LEN]);
+        let new_id = cloud_backup_key_id(&[2u8; 64]);
+        assert_eq!(
+            *find_backup_key(dir.path(), &old_id).unwrap(),
+            vec![1u8; CLOUD_KEY_LEN]
+        );
+        assert_eq!(
+            *find_backup_key(dir.path(), &new_id).unwrap(),
+            vec![2u8; 64]
+        );
+        let missing_id = cloud_backup_key_id(&[3u8; CLOUD_KEY_LEN]);
+        assert!(find_backup_key(dir.path(), &missing_id).is_err());
+        assert!(find_backup_key(dir.path().join("missing"), &old_id).is_err());
+        assert!(read_backup_key(dir.path().join("README")).is_err());
+    }
+}
@@ -1,3 +1,8 @@
+#[cfg(not(target_env = "sgx"))]
+pub mod keyring;
+#[cfg(not(target_env = "sgx"))]
+pub mod quote;
+pub mod shamir;
 mod shared;
 
 pub use shared::*;
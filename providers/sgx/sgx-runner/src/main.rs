@@ -1,99 +1,153 @@
 mod command;
 mod config;
 mod runner;
-mod shared;
 mod state;
-use shared::{SgxInitRequest, CLOUD_KEY_LEN};
 use std::fmt::Debug;
 use std::path::PathBuf;
 use structopt::StructOpt;
-use tmkms_light::utils::PubkeyDisplay;
-use tracing::{error, Level};
-use tracing_subscriber::FmtSubscriber;
+use tmkms_light::cli::{self, ConfigOpt, LogOpt, NoOpt, Provider, PubkeyOpt};
+use tmkms_light_sgx_runner::SealingPolicy;
+use tracing::Level;
+
+/// SGX-specific options for `init`
 #[derive(Debug, StructOpt)]
-#[structopt(
-    name = "tmkms-light-sgx-runner",
-    about = "runner for signing backend app using SGX"
-)]
-enum TmkmsLight {
-    #[structopt(name = "init", about = "Create config and generate keys")]
-    /// Create config + keygen
-    Init {
-        #[structopt(short)]
-        config_path: Option<PathBuf>,
-        #[structopt(short)]
-        pubkey_display: Option<PubkeyDisplay>,
-        #[structopt(short)]
-        bech32_prefix: Option<String>,
-        #[structopt(short)]
-        external_backup_key_path: Option<PathBuf>,
-        #[structopt(short)]
-        key_backup_data_path: Option<PathBuf>,
-    },
+pub struct InitOpt {
+    #[structopt(short)]
+    external_backup_key_path: Option<PathBuf>,
+    #[structopt(short)]
+    key_backup_data_path: Option<PathBuf>,
+    #[structopt(short, long)]
+    sealing_policy: Option<SealingPolicy>,
+    /// the number of backup key shares needed for recovery
+    #[structopt(long)]
+    share_threshold: Option<u8>,
+    /// backup key share files to create, one per custodian
+    /// (a new backup key is generated in the enclave and split into these shares)
+    #[structopt(long = "key-share")]
+    key_share_paths: Vec<PathBuf>,
+}
+
+/// The options for recovering from cloud backup
+#[derive(Debug, StructOpt)]
+pub struct RecoverOpt {
+    #[structopt(short)]
+    external_backup_key_path: Option<PathBuf>,
+    /// directory with the backup keys (the one matching the backup's key id is used)
+    #[structopt(long)]
+    keyring_path: Option<PathBuf>,
+    /// backup key share files (the key is recombined in the enclave)
+    #[structopt(long = "key-share")]
+    key_share_paths: Vec<PathBuf>,
+    #[structopt(short)]
+    key_backup_data_path: PathBuf,
+    #[structopt(short)]
+    recover_consensus_key: bool,
+    /// recover both the consensus and id keys (if configured)
+    #[structopt(long = "all-keys")]
+    recover_all_keys: bool,
+}
+
+/// SGX-specific subcommands
+#[derive(Debug, StructOpt)]
+enum SgxCommand {
     #[structopt(name = "recover", about = "Recover from cloud backup")]
     /// Recover from cloud backup payload
     Recover {
+        #[structopt(flatten)]
+        config: ConfigOpt,
+        #[structopt(flatten)]
+        pubkey: PubkeyOpt,
+        #[structopt(flatten)]
+        opt: RecoverOpt,
+    },
+    #[structopt(
+        name = "reseal",
+        about = "Reseal keys under a new policy or enclave version"
+    )]
+    /// Reseal keys under a new policy or enclave version
+    Reseal {
+        #[structopt(flatten)]
+        config: ConfigOpt,
+        #[structopt(short, long)]
+        sealing_policy: Option<SealingPolicy>,
+    },
+    #[structopt(name = "quote", about = "Get a DCAP quote of the enclave")]
+    /// Get a DCAP quote of the enclave with the consensus public key
+    Quote {
+        #[structopt(flatten)]
+        config: ConfigOpt,
         #[structopt(short)]
-        config_path: Option<PathBuf>,
-        #[structopt(short)]
-        pubkey_display: Option<PubkeyDisplay>,
-        #[structopt(short)]
-        bech32_prefix: Option<String>,
-        #[structopt(short)]
-        external_backup_key_path: PathBuf,
-        #[structopt(short)]
-        key_backup_data_path: PathBuf,
-        #[structopt(short)]
-        recover_consensus_key: bool,
+        quote_path: Option<PathBuf>,
     },
-    #[structopt(name = "start", about = "Start tmkms process")]
-    /// start tmkms process
-    Start {
+    #[structopt(name = "verify-quote", about = "Verify a DCAP quote")]
+    /// Verify a DCAP quote and check its enclave identity against the config
+    VerifyQuote {
+        #[structopt(flatten)]
+        config: ConfigOpt,
         #[structopt(short)]
-        config_path: Option<PathBuf>,
+        quote_path: Option<PathBuf>,
+        #[structopt(flatten)]
+        pubkey: PubkeyOpt,
     },
 }
 
-fn main() {
-    let opt = TmkmsLight::from_args();
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::DEBUG)
-        .finish();
+/// runner for signing backend app using SGX
+struct SgxRunner;
+
+impl Provider for SgxRunner {
+    type InitOpt = InitOpt;
+    type StartOpt = NoOpt;
+    type Command = SgxCommand;
+
+    const NAME: &'static str = "tmkms-light-sgx-runner";
+    const ABOUT: &'static str = "runner for signing backend app using SGX";
+    const DEFAULT_LOG_LEVEL: Level = Level::DEBUG;
+
+    fn init(&self, config: ConfigOpt, pubkey: PubkeyOpt, opt: InitOpt) -> Result<(), String> {
+        command::init(config, pubkey, opt)
+    }
+
+    fn start(&self, config: ConfigOpt, log: LogOpt, _opt: NoOpt) -> Result<(), String> {
+        command::start(config, log, Self::DEFAULT_LOG_LEVEL)
+    }
+
+    fn pubkey(&self, config: ConfigOpt, pubkey: PubkeyOpt) -> Result<(), String> {
+        command::pubkey(config, pubkey)
+    }
 
-    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
-    let result = match opt {
-        TmkmsLight::Init {
-            config_path,
-            pubkey_display,
-            bech32_prefix,
-            external_backup_key_path,
-            key_backup_data_path,
-        } => command::init(
-            config_path,
-            pubkey_display,
-            bech32_prefix,
-            external_backup_key_path,
-            key_backup_data_path,
-        ),
-        TmkmsLight::Start { config_path } => command::start(config_path),
-        TmkmsLight::Recover {
-            config_path,
-            pubkey_display,
-            bech32_prefix,
-            external_backup_key_path,
-            key_backup_data_path,
-            recover_consensus_key,
-        } => command::recover(
-            config_path,
-            pubkey_display,
-            bech32_prefix,
-            external_backup_key_path,
-            key_backup_data_path,
-            recover_consensus_key,
-        ),
-    };
-    if let Err(e) = result {
-        error!("{}", e);
-        std::process::exit(1);
+    fn validate(&self, config: ConfigOpt) -> Result<(), String> {
+        command::validate(config)
     }
+
+    fn status(&self, config: ConfigOpt) -> Result<(), String> {
+        command::status(config)
+    }
+
+    fn control_socket_path(&self, config: ConfigOpt) -> Result<Option<PathBuf>, String> {
+        command::control_socket_path(config)
+    }
+
+    fn command(&self, command: SgxCommand) -> Result<(), String> {
+        match command {
+            SgxCommand::Recover {
+                config,
+                pubkey,
+                opt,
+            } => command::recover(config, pubkey, opt),
+            SgxCommand::Reseal {
+                config,
+                sealing_policy,
+            } => command::reseal(config, sealing_policy),
+            SgxCommand::Quote { config, quote_path } => command::export_quote(config, quote_path),
+            SgxCommand::VerifyQuote {
+                config,
+                quote_path,
+                pubkey,
+            } => command::verify_quote(config, quote_path, pubkey),
+        }
+    }
+}
+
+fn main() {
+    cli::main(&SgxRunner);
 }
@@ -0,0 +1,447 @@
+//! Parsing and verification of the SGX DCAP (ECDSA) quotes (version 3):
+//! the enclave report signed by the attestation key that is certified
+//! by the quoting enclave's report signed by the PCK certificate
+//! that chains up to the Intel SGX root CA, see
+//! https://download.01.org/intel-sgx/latest/dcap-latest/linux/docs/Intel_SGX_ECDSA_QuoteLibReference_DCAP_API.pdf
+//! (the TCB status and QE identity collateral checks are out of scope here)
+
+use anomaly::{fail, format_err};
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::ecdsa::EcdsaSig;
+use openssl::nid::Nid;
+use openssl::stack::Stack;
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::{X509StoreContext, X509};
+use std::convert::TryInto;
+use tmkms_light::error::{
+    Error,
+    ErrorKind::{ParseError, VerificationError},
+};
+
+/// the supported quote version
+const QUOTE_VERSION: u16 = 3;
+
+/// ECDSA-256-with-P-256 attestation key type
+const ATT_KEY_TYPE_ECDSA_P256: u16 = 2;
+
+/// PCK certificate chain (PEM) certification data type
+const CERT_DATA_PCK_CHAIN: u16 = 5;
+
+const HEADER_LEN: usize = 48;
+
+const REPORT_BODY_LEN: usize = 384;
+
+/// r || s of ECDSA P-256
+const SIGNATURE_LEN: usize = 64;
+
+/// x || y of the P-256 public key
+const ATT_KEY_LEN: usize = 64;
+
+/// the debug enclave attribute flag
+const ATTRIBUTE_DEBUG: u64 = 0x2;
+
+/// The enclave identity (and other data) from the report
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReportBody {
+    pub cpusvn: [u8; 16],
+    pub miscselect: u32,
+    /// enclave attribute flags
+    pub flags: u64,
+    pub xfrm: u64,
+    /// the enclave measurement
+    pub mrenclave: [u8; 32],
+    /// the hash of the enclave signing key
+    pub mrsigner: [u8; 32],
+    pub isv_prod_id: u16,
+    pub isv_svn: u16,
+    /// the data provided by the enclave
+    /// (the consensus public key followed by zeros in `tmkms-light-sgx-app`)
+    pub report_data: [u8; 64],
+}
+
+impl ReportBody {
+    fn parse(data: &[u8]) -> Self {
+        debug_assert_eq!(data.len(), REPORT_BODY_LEN);
+        ReportBody {
+            cpusvn: data[0..16].try_into().expect("length"),
+            miscselect: u32::from_le_bytes(data[16..20].try_into().expect("length")),
+            flags: u64::from_le_bytes(data[48..56].try_into().expect("length")),
+            xfrm: u64::from_le_bytes(data[56..64].try_into().expect("length")),
+            mrenclave: data[64..96].try_into().expect("length"),
+            mrsigner: data[128..160].try_into().expect("length"),
+            isv_prod_id: u16::from_le_bytes(data[256..258].try_into().expect("length")),
+            isv_svn: u16::from_le_bytes(data[258..260].try_into().expect("length")),
+            report_data: data[320..384].try_into().expect("length"),
+        }
+    }
+
+    /// true if the enclave was launched in the debug mode
+    /// (its memory can be inspected by the host)
+    pub fn is_debug(&self) -> bool {
+        self.flags & ATTRIBUTE_DEBUG != 0
+    }
+}
+
+/// The parsed quote
+#[derive(Debug, Clone)]
+pub struct Quote {
+    pub version: u16,
+    pub qe_svn: u16,
+    pub pce_svn: u16,
+    pub qe_vendor_id: [u8; 16],
+    /// the quoted enclave report
+    pub report_body: ReportBody,
+    /// the signed header and report body
+    signed_data: Vec<u8>,
+    report_signature: Vec<u8>,
+    attestation_key: Vec<u8>,
+    qe_report_body: Vec<u8>,
+    qe_report_signature: Vec<u8>,
+    qe_auth_data: Vec<u8>,
+    /// PEM-encoded PCK certificate chain (starting from the leaf)
+    pck_cert_chain: Vec<u8>,
+}
+
+/// sequential reading of the little-endian fields
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.data.len() < n {
+            fail!(ParseError, "truncated quote");
+        }
+        let (taken, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(
+            self.take(2)?.try_into().expect("length"),
+        ))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(
+            self.take(4)?.try_into().expect("length"),
+        ))
+    }
+}
+
+impl Quote {
+    /// parses the quote (as returned by the quoting enclave)
+    pub fn parse(quote: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader { data: quote };
+        let header = reader.take(HEADER_LEN)?;
+        let mut header_reader = Reader { data: header };
+        let version = header_reader.u16()?;
+        let att_key_type = header_reader.u16()?;
+        if version != QUOTE_VERSION {
+            fail!(ParseError, "unsupported quote version {}", version);
+        }
+        if att_key_type != ATT_KEY_TYPE_ECDSA_P256 {
+            fail!(
+                ParseError,
+                "unsupported attestation key type {}",
+                att_key_type
+            );
+        }
+        header_reader.take(4)?;
+        let qe_svn = header_reader.u16()?;
+        let pce_svn = header_reader.u16()?;
+        let qe_vendor_id = header_reader.take(16)?.try_into().expect("length");
+        let report_body = ReportBody::parse(reader.take(REPORT_BODY_LEN)?);
+        let signed_data = quote[..HEADER_LEN + REPORT_BODY_LEN].to_vec();
+
+        let signature_data_len = reader.u32()? as usize;
+        if reader.data.len() != signature_data_len {
+            fail!(ParseError, "invalid quote signature data length");
+        }
+        let report_signature = reader.take(SIGNATURE_LEN)?.to_vec();
+        let attestation_key = reader.take(ATT_KEY_LEN)?.to_vec();
+        let qe_report_body = reader.take(REPORT_BODY_LEN)?.to_vec();
+        let qe_report_signature = reader.take(SIGNATURE_LEN)?.to_vec();
+        let qe_auth_data_len = reader.u16()? as usize;
+        let qe_auth_data = reader.take(qe_auth_data_len)?.to_vec();
+        let cert_data_type = reader.u16()?;
+        if cert_data_type != CERT_DATA_PCK_CHAIN {
+            fail!(
+                ParseError,
+                "unsupported certification data type {}",
+                cert_data_type
+            );
+        }
+        let cert_data_len = reader.u32()? as usize;
+        let pck_cert_chain = reader.take(cert_data_len)?.to_vec();
+        if !reader.data.is_empty() {
+            fail!(ParseError, "trailing bytes in the quote");
+        }
+        Ok(Self {
+            version,
+            qe_svn,
+            pce_svn,
+            qe_vendor_id,
+            report_body,
+            signed_data,
+            report_signature,
+            attestation_key,
+            qe_report_body,
+            qe_report_signature,
+            qe_auth_data,
+            pck_cert_chain,
+        })
+    }
+}
+
+fn openssl_error(context: &str) -> impl Fn(openssl::error::ErrorStack) -> Error + '_ {
+    move |e| format_err!(VerificationError, "{}: {}", context, e).into()
+}
+
+/// verifies the r || s signature of the data's SHA-256 digest
+fn verify_signature(
+    key: &EcKey<openssl::pkey::Public>,
+    signature: &[u8],
+    data: &[u8],
+) -> Result<bool, Error> {
+    let (r, s) = signature.split_at(SIGNATURE_LEN / 2);
+    let signature = BigNum::from_slice(r)
+        .and_then(|r| BigNum::from_slice(s).and_then(|s| EcdsaSig::from_private_components(r, s)))
+        .map_err(openssl_error("invalid signature"))?;
+    signature
+        .verify(&openssl::sha::sha256(data), key)
+        .map_err(openssl_error("signature verification"))
+}
+
+/// verifies the PCK certificate chain up to the trusted root
+/// and returns the PCK certificate
+fn verify_pck_chain(pck_cert_chain: &[u8], trusted_root: &X509) -> Result<X509, Error> {
+    let mut certs = X509::stack_from_pem(pck_cert_chain)
+        .map_err(|e| format_err!(ParseError, "invalid PCK certificate chain: {}", e))?
+        .into_iter();
+    let leaf = certs
+        .next()
+        .ok_or_else(|| format_err!(ParseError, "empty PCK certificate chain"))?;
+    let mut chain = Stack::new().map_err(openssl_error("stack"))?;
+    for cert in certs {
+        chain.push(cert).map_err(openssl_error("stack"))?;
+    }
+    let mut store = X509StoreBuilder::new().map_err(openssl_error("store"))?;
+    store
+        .add_cert(trusted_root.clone())
+        .map_err(openssl_error("store"))?;
+    let store = store.build();
+    let mut context = X509StoreContext::new().map_err(openssl_error("store"))?;
+    let (verified, result) = context
+        .init(&store, &leaf, &chain, |c| {
+            c.verify_cert().map(|verified| (verified, c.error()))
+        })
+        .map_err(openssl_error("PCK certificate chain verification failed"))?;
+    if !verified {
+        fail!(
+            VerificationError,
+            "PCK certificate chain verification failed: {}",
+            result.error_string()
+        );
+    }
+    Ok(leaf)
+}
+
+/// Verifies the quote: the PCK certificate chain up to the trusted root (PEM),
+/// the quoting enclave's report signed by the PCK certificate key
+/// that certifies the attestation key, and the enclave report signature
+/// by the attestation key. Returns the parsed quote.
+pub fn verify(quote: &[u8], trusted_root_pem: &[u8]) -> Result<Quote, Error> {
+    let quote = Quote::parse(quote)?;
+    let trusted_root = X509::from_pem(trusted_root_pem)
+        .map_err(|e| format_err!(ParseError, "invalid root certificate: {}", e))?;
+    let pck = verify_pck_chain(&quote.pck_cert_chain, &trusted_root)?;
+    let pck_key = pck
+        .public_key()
+        .and_then(|key| key.ec_key())
+        .map_err(|e| format_err!(VerificationError, "unexpected PCK certificate key: {}", e))?;
+    if !verify_signature(&pck_key, &quote.qe_report_signature, &quote.qe_report_body)? {
+        fail!(VerificationError, "invalid QE report signature");
+    }
+
+    // the QE report data binds the attestation key
+    let qe_report = ReportBody::parse(&quote.qe_report_body);
+    let expected_hash =
+        openssl::sha::sha256(&[&quote.attestation_key[..], &quote.qe_auth_data].concat());
+    if qe_report.report_data[..32] != expected_hash || qe_report.report_data[32..] != [0u8; 32] {
+        fail!(
+            VerificationError,
+            "attestation key not certified by the QE report"
+        );
+    }
+
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).map_err(openssl_error("group"))?;
+    let (x, y) = quote.attestation_key.split_at(ATT_KEY_LEN / 2);
+    let attestation_key = BigNum::from_slice(x)
+        .and_then(|x| {
+            BigNum::from_slice(y)
+                .and_then(|y| EcKey::from_public_key_affine_coordinates(&group, &x, &y))
+        })
+        .map_err(openssl_error("invalid attestation key"))?;
+    if !verify_signature(
+        &attestation_key,
+        &quote.report_signature,
+        &quote.signed_data,
+    )? {
+        fail!(VerificationError, "invalid enclave report signature");
+    }
+    Ok(quote)
+}
+
+/// The expected enclave identity
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExpectedIdentity {
+    pub mrenclave: Option<[u8; 32]>,
+    pub mrsigner: Option<[u8; 32]>,
+    pub isv_prod_id: Option<u16>,
+    /// the minimum security version
+    pub isv_svn: Option<u16>,
+    /// if the debug enclaves are accepted
+    pub allow_debug: bool,
+}
+
+/// Checks the report identity fields against the expected ones
+/// and returns the descriptions of the mismatches (if any)
+pub fn check_identity(report: &ReportBody, expected: &ExpectedIdentity) -> Vec<String> {
+    let hex = |b: &[u8]| String::from_utf8(subtle_encoding::hex::encode(b)).expect("hex");
+    let mut mismatches = Vec::new();
+    if let Some(mrenclave) = expected.mrenclave {
+        if mrenclave != report.mrenclave {
+            mismatches.push(format!(
+                "MRENCLAVE {} (expected {})",
+                hex(&report.mrenclave),
+                hex(&mrenclave)
+            ));
+        }
+    }
+    if let Some(mrsigner) = expected.mrsigner {
+        if mrsigner != report.mrsigner {
+            mismatches.push(format!(
+                "MRSIGNER {} (expected {})",
+                hex(&report.mrsigner),
+                hex(&mrsigner)
+            ));
+        }
+    }
+    if let Some(isv_prod_id) = expected.isv_prod_id {
+        if isv_prod_id != report.isv_prod_id {
+            mismatches.push(format!(
+                "ISV product ID {} (expected {})",
+                report.isv_prod_id, isv_prod_id
+            ));
+        }
+    }
+    if let Some(isv_svn) = expected.isv_svn {
+        if report.isv_svn < isv_svn {
+            mismatches.push(format!(
+                "ISV SVN {} (expected at least {})",
+                report.isv_svn, isv_svn
+            ));
+        }
+    }
+    if report.is_debug() && !expected.allow_debug {
+        mismatches.push("debug enclave".to_owned());
+    }
+    mismatches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// recorded quotes signed by a test chain (root -> PCK platform CA -> PCK certificate)
+    const ROOT: &[u8] = include_bytes!("../fixtures/quote/root.pem");
+    const QUOTE: &[u8] = include_bytes!("../fixtures/quote/quote.dat");
+    /// the same enclave launched in the debug mode
+    const DEBUG_QUOTE: &[u8] = include_bytes!("../fixtures/quote/quote-debug.dat");
+    const MRENCLAVE: &str = "d9d687aaff999006926c8471f6354429f9f08330e09fa56165870bdc5d78b6f7";
+    const MRSIGNER: &str = "b0cf34e5279f82a084612a5d91eb39951520d3c93338bd999d941e59747c3324";
+    const PUBKEY: &str = "8a88e3dd7409f195fd52db2d3cba5d72ca6709bf1d94121bf3748801b40f6f5c";
+
+    fn hex32(s: &str) -> [u8; 32] {
+        subtle_encoding::hex::decode(s)
+            .unwrap()
+            .as_slice()
+            .try_into()
+            .unwrap()
+    }
+
+    fn expected() -> ExpectedIdentity {
+        ExpectedIdentity {
+            mrenclave: Some(hex32(MRENCLAVE)),
+            mrsigner: Some(hex32(MRSIGNER)),
+            isv_prod_id: Some(0),
+            isv_svn: Some(1),
+            allow_debug: false,
+        }
+    }
+
+    #[test]
+    fn test_verify_recorded_quote() {
+        let quote = verify(QUOTE, ROOT).unwrap();
+        assert_eq!(quote.version, 3);
+        assert_eq!(quote.report_body.mrenclave, hex32(MRENCLAVE));
+        assert_eq!(quote.report_body.report_data[..32], hex32(PUBKEY));
+        assert_eq!(quote.report_body.report_data[32..], [0u8; 32]);
+        assert!(!quote.report_body.is_debug());
+        assert!(check_identity(&quote.report_body, &expected()).is_empty());
+    }
+
+    #[test]
+    fn test_check_identity() {
+        let report = verify(DEBUG_QUOTE, ROOT).unwrap().report_body;
+        assert!(report.is_debug());
+        assert_eq!(check_identity(&report, &expected()), vec!["debug enclave"]);
+        let allow_debug = ExpectedIdentity {
+            allow_debug: true,
+            ..expected()
+        };
+        assert!(check_identity(&report, &allow_debug).is_empty());
+
+        let other = ExpectedIdentity {
+            mrenclave: Some([1u8; 32]),
+            isv_svn: Some(2),
+            allow_debug: true,
+            ..expected()
+        };
+        let mismatches = check_identity(&report, &other);
+        assert_eq!(mismatches.len(), 2);
+        assert!(mismatches[0].starts_with("MRENCLAVE"));
+        assert!(check_identity(&report, &ExpectedIdentity::default())
+            .iter()
+            .all(|m| m == "debug enclave"));
+    }
+
+    #[test]
+    fn test_verification_failures() {
+        let err = |quote: &[u8], root: &[u8]| *verify(quote, root).unwrap_err().kind();
+
+        // the report data changed after signing
+        let mut tampered = QUOTE.to_vec();
+        tampered[HEADER_LEN + 320] ^= 1;
+        assert_eq!(err(&tampered, ROOT), VerificationError);
+
+        // the QE report changed after signing
+        let mut tampered = QUOTE.to_vec();
+        tampered[HEADER_LEN + REPORT_BODY_LEN + 4 + SIGNATURE_LEN + ATT_KEY_LEN] ^= 1;
+        assert_eq!(err(&tampered, ROOT), VerificationError);
+
+        // a different root (the PCK chain's intermediate one)
+        let chain = X509::stack_from_pem(&Quote::parse(QUOTE).unwrap().pck_cert_chain).unwrap();
+        let other_root = chain[1].to_pem().unwrap();
+        assert_eq!(err(QUOTE, &other_root), VerificationError);
+
+        assert_eq!(err(&QUOTE[..QUOTE.len() - 1], ROOT), ParseError);
+        assert_eq!(err(&QUOTE[..HEADER_LEN], ROOT), ParseError);
+        let mut v4 = QUOTE.to_vec();
+        v4[0] = 4;
+        assert_eq!(err(&v4, ROOT), ParseError);
+    }
+}
@@ -1,4 +1,3 @@
-use crate::shared::{RemoteConnectionConfig, SealedKeyData, SgxInitRequest, SgxInitResponse};
 use crate::state::StateSyncer;
 use aesm_client::AesmClient;
 use anomaly::format_err;
@@ -6,17 +5,28 @@ use enclave_runner::{
     usercalls::{AsyncStream, UsercallExtension},
     EnclaveBuilder,
 };
+use serde::de::DeserializeOwned;
 use sgxs_loaders::isgx::Device;
 use std::os::unix::net::UnixStream;
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
 use std::thread;
+use std::time::Duration;
 use std::{fs, path::PathBuf};
 use std::{future::Future, io, pin::Pin};
 use tendermint::consensus;
 use tendermint::net;
+use tmkms_light::chain::state::State;
 use tmkms_light::config::validator::ValidatorConfig;
+use tmkms_light::control::{SigningControl, StatusTracker};
 use tmkms_light::error::{Error, ErrorKind};
-use tmkms_light::utils::read_u16_payload;
+use tmkms_light::framing::{FrameType, FramedStream};
+use tmkms_light_sgx_runner::{
+    RemoteConnectionConfig, SealedKeyData, SgxControlCommand, SgxControlRequest,
+    SgxControlResponse, SgxControlResult, SgxInitRequest, SgxInitResponse, SgxKeyGenResponse,
+    SgxRecoverResponse, SgxReportResponse,
+};
 use tracing::{debug, error};
 
 /// type alias for outputs in UsercallExtension async return type
@@ -29,6 +39,7 @@ struct TmkmsSgxRunner {
     init_stream: UnixStream,
     state_stream: UnixStream,
     tm_conn: Option<PathBuf>,
+    tracker: Option<StatusTracker>,
 }
 
 impl UsercallExtension for TmkmsSgxRunner {
@@ -50,8 +61,15 @@ impl UsercallExtension for TmkmsSgxRunner {
                 }
                 "tendermint" => {
                     if let Some(ref path) = this.tm_conn {
-                        let stream = tokio::net::UnixStream::connect(path).await?;
-                        Ok(Some(Box::new(stream)))
+                        let stream = tokio::net::UnixStream::connect(path).await;
+                        // the enclave reconnects (after a while) when the connection is closed
+                        if let Some(tracker) = &this.tracker {
+                            match stream {
+                                Ok(_) => tracker.connected(),
+                                Err(_) => tracker.disconnected(),
+                            }
+                        }
+                        Ok(Some(Box::new(stream?)))
                     } else {
                         Ok(None)
                     }
@@ -63,6 +81,154 @@ impl UsercallExtension for TmkmsSgxRunner {
     }
 }
 
+/// the algorithm ID offset in `sgx_att_key_id_ext_t`
+const ATT_KEY_ID_ALGORITHM_OFFSET: usize = 154;
+
+/// `SGX_QL_ALG_ECDSA_P256` (DCAP)
+const SGX_QL_ALG_ECDSA_P256: u32 = 2;
+
+/// DCAP quoting via AESM
+pub struct EcdsaQuoter {
+    client: AesmClient,
+    att_key_id: Vec<u8>,
+}
+
+impl EcdsaQuoter {
+    /// finds the ECDSA attestation key supported by AESM
+    /// (the DCAP quote provider library needs to be installed)
+    pub fn new() -> Result<Self, Error> {
+        let client = AesmClient::new();
+        let att_key_id = client
+            .get_supported_att_key_ids()
+            .map_err(|e| format_err!(ErrorKind::AccessError, "AESM request failed: {:?}", e))?
+            .into_iter()
+            .find(|id| {
+                id.get(ATT_KEY_ID_ALGORITHM_OFFSET..ATT_KEY_ID_ALGORITHM_OFFSET + 4)
+                    .map(|alg| {
+                        let mut buf = [0u8; 4];
+                        buf.copy_from_slice(alg);
+                        u32::from_le_bytes(buf) == SGX_QL_ALG_ECDSA_P256
+                    })
+                    .unwrap_or(false)
+            })
+            .ok_or_else(|| {
+                format_err!(
+                    ErrorKind::AccessError,
+                    "no ECDSA attestation key supported by AESM"
+                )
+            })?;
+        Ok(Self { client, att_key_id })
+    }
+
+    /// `Targetinfo` of the quoting enclave
+    pub fn target_info(&self) -> Result<Vec<u8>, Error> {
+        self.client
+            .init_quote_ex(self.att_key_id.clone())
+            .map(|info| info.target_info().to_vec())
+            .map_err(|e| format_err!(ErrorKind::AccessError, "AESM request failed: {:?}", e).into())
+    }
+
+    /// turns the report (targeted at the quoting enclave) into a quote
+    pub fn get_quote(&self, report: Vec<u8>) -> Result<Vec<u8>, Error> {
+        // the nonce is only used for the QE report returned to the requesting enclave
+        // (which isn't needed here)
+        let nonce = vec![0u8; 16];
+        self.client
+            .get_quote_ex(self.att_key_id.clone(), report, None, nonce)
+            .map(|result| result.quote().to_vec())
+            .map_err(|e| format_err!(ErrorKind::AccessError, "AESM request failed: {:?}", e).into())
+    }
+}
+
+/// how long to wait for the enclave app to answer a control request
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// the host end of the "init" stream of the started enclave app
+enum ControlStream {
+    /// the enclave app hasn't been asked yet
+    Pending(UnixStream),
+    /// the framing was negotiated
+    Ready(FramedStream<UnixStream>),
+    /// the negotiation or the stream failed
+    Closed,
+}
+
+/// Relays the pause/resume requests from the control socket
+/// to the started enclave app (over its "init" stream)
+pub struct SgxSigningControl {
+    stream: Mutex<ControlStream>,
+    last_id: AtomicU64,
+}
+
+impl SgxSigningControl {
+    fn new(stream: UnixStream) -> Self {
+        Self {
+            stream: Mutex::new(ControlStream::Pending(stream)),
+            last_id: AtomicU64::new(0),
+        }
+    }
+
+    fn request(&self, command: SgxControlCommand) -> Result<SgxControlResult, String> {
+        let mut guard = self.stream.lock().expect("control stream lock");
+        if let ControlStream::Pending(_) = &*guard {
+            if let ControlStream::Pending(stream) =
+                std::mem::replace(&mut *guard, ControlStream::Closed)
+            {
+                let stream = FramedStream::accept(stream)
+                    .map_err(|e| format!("control stream negotiation failed: {}", e))?;
+                *guard = ControlStream::Ready(stream);
+            }
+        }
+        let stream = match &mut *guard {
+            ControlStream::Ready(stream) => stream,
+            _ => return Err("the enclave app is not reachable".to_owned()),
+        };
+        let id = self.last_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let payload = serde_json::to_vec(&SgxControlRequest { id, command })
+            .map_err(|e| format!("failed to serialize the request: {}", e))?;
+        if let Err(e) = stream.send(FrameType::Request, &payload) {
+            *guard = ControlStream::Closed;
+            return Err(format!("failed to write the request: {}", e));
+        }
+        // responses to the earlier (timed out) requests may still arrive first
+        loop {
+            let response = match stream.recv_payload(FrameType::Response) {
+                Ok(response) => response,
+                // e.g. a timeout: the stream is kept and resynchronised on the next request
+                Err(e) if e.kind() == &ErrorKind::IoError => {
+                    return Err(format!("failed to get the enclave response: {}", e));
+                }
+                Err(e) => {
+                    *guard = ControlStream::Closed;
+                    return Err(format!("failed to get the enclave response: {}", e));
+                }
+            };
+            let response: SgxControlResponse = serde_json::from_slice(&response)
+                .map_err(|e| format!("invalid enclave response: {}", e))?;
+            if response.id == id {
+                return Ok(response.result);
+            }
+            debug!("discarding a stale control response: {:?}", response);
+        }
+    }
+}
+
+impl SigningControl for SgxSigningControl {
+    fn pause(&self) -> Result<Option<consensus::State>, String> {
+        match self.request(SgxControlCommand::Pause)? {
+            SgxControlResult::Paused { state } => Ok(state),
+            r => Err(format!("unexpected response: {:?}", r)),
+        }
+    }
+
+    fn resume(&self) -> Result<(), String> {
+        match self.request(SgxControlCommand::Resume)? {
+            SgxControlResult::Resumed => Ok(()),
+            r => Err(format!("unexpected response: {:?}", r)),
+        }
+    }
+}
+
 /// controller for launching the enclave app and providing the communication with it
 pub struct TmkmsSgxSigner {
     stream_to_enclave: UnixStream,
@@ -74,7 +240,7 @@ impl TmkmsSgxSigner {
     /// and the unix socket to pass to the enclave runner
     pub fn get_state_syncer<P: AsRef<Path>>(
         state_path: P,
-    ) -> Result<(StateSyncer, consensus::State, UnixStream), Error> {
+    ) -> Result<(StateSyncer, State, UnixStream), Error> {
         let (state_from_enclave, state_stream) = UnixStream::pair().map_err(|e| {
             format_err!(
                 ErrorKind::IoError,
@@ -98,16 +264,18 @@ impl TmkmsSgxSigner {
     pub fn launch_enclave_app<P: AsRef<Path>>(
         sgxs_path: P,
         tm_conn: Option<PathBuf>,
+        tracker: Option<StatusTracker>,
         state_syncer: StateSyncer,
         state_stream: UnixStream,
         args: &[&[u8]],
     ) -> io::Result<Self> {
         let (stream_to_enclave, init_stream) = UnixStream::pair()?;
-        state_syncer.launch_syncer();
+        state_syncer.launch_syncer(tracker.clone());
         let runner = TmkmsSgxRunner {
             init_stream,
             state_stream,
             tm_conn,
+            tracker,
         };
         let mut device = Device::new()?
             .einittoken_provider(AesmClient::new())
@@ -138,40 +306,73 @@ impl TmkmsSgxSigner {
     }
 
     /// get the response from the enclave via the init stream
-    pub fn get_init_response(mut self) -> Result<SgxInitResponse, Error> {
+    fn get_response<T: DeserializeOwned>(self) -> Result<T, Error> {
         debug!("waiting for response");
-        let response_bytes = read_u16_payload(&mut self.stream_to_enclave)
-            .map_err(|e| format_err!(ErrorKind::IoError, "error reading response: {:?}", e))?;
-        let resp: SgxInitResponse = serde_json::from_slice(&response_bytes).map_err(|e| {
+        let mut stream = FramedStream::accept(&self.stream_to_enclave).map_err(|e| {
+            format_err!(
+                ErrorKind::ProtocolError,
+                "init stream negotiation failed: {}",
+                e
+            )
+        })?;
+        let response_bytes = stream
+            .recv_payload(FrameType::InitResponse)
+            .map_err(|e| format_err!(ErrorKind::IoError, "error reading response: {}", e))?;
+        let resp: T = serde_json::from_slice(&response_bytes).map_err(|e| {
             format_err!(ErrorKind::IoError, "error deserializing response: {:?}", e)
         })?;
         self.join_enclave_thread()?;
         Ok(resp)
     }
 
+    /// get the resealing response from the enclave
+    pub fn get_init_response(self) -> Result<SgxInitResponse, Error> {
+        self.get_response()
+    }
+
+    /// get the keygen response from the enclave
+    pub fn get_keygen_response(self) -> Result<SgxKeyGenResponse, Error> {
+        self.get_response()
+    }
+
+    /// get the recovery response from the enclave
+    pub fn get_recover_response(self) -> Result<SgxRecoverResponse, Error> {
+        self.get_response()
+    }
+
+    /// get the report from the enclave
+    pub fn get_report_response(self) -> Result<SgxReportResponse, Error> {
+        self.get_response()
+    }
+
+    /// read the sealed consensus key
+    pub fn read_sealed_key<P: AsRef<Path>>(sealed_key_path: P) -> Result<SealedKeyData, Error> {
+        serde_json::from_slice(&fs::read(sealed_key_path).map_err(|e| {
+            format_err!(
+                ErrorKind::IoError,
+                "error reading sealed consensus key: {:?}",
+                e
+            )
+        })?)
+        .map_err(|e| {
+            format_err!(
+                ErrorKind::IoError,
+                "invalid sealed consensus key format: {:?}",
+                e
+            )
+            .into()
+        })
+    }
+
     /// get the request payload that's passed as an argument to the enclave
     /// to start up the tmkms handling
     pub fn get_start_request_bytes<P: AsRef<Path>>(
         sealed_key_path: P,
         config: ValidatorConfig,
-        initial_state: consensus::State,
+        initial_state: State,
         remote_conn: Option<(net::Address, P)>,
     ) -> Result<Vec<u8>, Error> {
-        let sealed_key: SealedKeyData =
-            serde_json::from_slice(&fs::read(sealed_key_path).map_err(|e| {
-                format_err!(
-                    ErrorKind::IoError,
-                    "error reading sealed consensus key: {:?}",
-                    e
-                )
-            })?)
-            .map_err(|e| {
-                format_err!(
-                    ErrorKind::IoError,
-                    "invalid sealed consensus key format: {:?}",
-                    e
-                )
-            })?;
+        let sealed_key = Self::read_sealed_key(sealed_key_path)?;
         let secret_connection = match remote_conn {
             Some((
                 net::Address::Tcp {
@@ -221,8 +422,63 @@ impl TmkmsSgxSigner {
         }
     }
 
+    /// the pause/resume control of the enclave app started with the start request
+    pub fn signing_control(&self) -> io::Result<SgxSigningControl> {
+        let stream = self.stream_to_enclave.try_clone()?;
+        stream.set_read_timeout(Some(CONTROL_TIMEOUT))?;
+        Ok(SgxSigningControl::new(stream))
+    }
+
     /// run the main privval handling
     pub fn start(self) -> Result<(), Error> {
         self.join_enclave_thread()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_late_control_response() {
+        let (host, enclave) = UnixStream::pair().unwrap();
+        host.set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let control = SgxSigningControl::new(host);
+        let (timed_out, wait) = mpsc::channel();
+        // the enclave app answers the first request only after it timed out on the host
+        let handle = thread::spawn(move || {
+            let mut stream = FramedStream::connect(enclave).unwrap();
+            for i in 0..3 {
+                let request = stream.recv_payload(FrameType::Request).unwrap();
+                let request: SgxControlRequest = serde_json::from_slice(&request).unwrap();
+                if i == 0 {
+                    wait.recv().unwrap();
+                }
+                let result = match request.command {
+                    SgxControlCommand::Pause => SgxControlResult::Paused { state: None },
+                    SgxControlCommand::Resume => SgxControlResult::Resumed,
+                };
+                let response = SgxControlResponse {
+                    id: request.id,
+                    result,
+                };
+                stream
+                    .send(FrameType::Response, &serde_json::to_vec(&response).unwrap())
+                    .unwrap();
+            }
+        });
+        let err = control.pause().unwrap_err();
+        assert!(
+            err.starts_with("failed to get the enclave response"),
+            "{}",
+            err
+        );
+        timed_out.send(()).unwrap();
+        // the late `Paused` response is skipped
+        assert_eq!(control.resume(), Ok(()));
+        assert_eq!(control.pause(), Ok(None));
+        handle.join().unwrap();
+    }
+}
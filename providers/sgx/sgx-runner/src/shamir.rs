@@ -0,0 +1,244 @@
+//! Shamir's secret sharing (over GF(256)) of the cloud backup keys,
+//! so that no single custodian can recover the validator keys.
+use crate::shared::cloud_backup_key_id;
+use rand_core::{CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use thiserror::Error;
+use zeroize::{Zeroize, Zeroizing};
+
+/// One share of the cloud backup key (or the secret it's derived from)
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BackupKeyShare {
+    /// the identifier of the split backup key
+    pub key_id: String,
+    /// the number of shares needed for recovering the key
+    pub threshold: u8,
+    /// the share index (the non-zero evaluation point)
+    pub index: u8,
+    /// the polynomial values for each byte of the key
+    pub share: Vec<u8>,
+}
+
+impl fmt::Debug for BackupKeyShare {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BackupKeyShare")
+            .field("key_id", &self.key_id)
+            .field("threshold", &self.threshold)
+            .field("index", &self.index)
+            .finish()
+    }
+}
+
+impl Drop for BackupKeyShare {
+    fn drop(&mut self) {
+        self.share.zeroize();
+    }
+}
+
+/// How the backup key generated in the enclave is split into shares
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq)]
+pub struct BackupKeySplit {
+    /// the number of shares needed for recovering the key
+    pub threshold: u8,
+    /// the number of shares
+    pub count: u8,
+}
+
+/// Errors from splitting or recombining the backup key
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ShareError {
+    #[error("invalid threshold or number of shares")]
+    InvalidParameters,
+    #[error("not enough distinct shares provided")]
+    NotEnoughShares,
+    #[error("shares belong to different keys or splits")]
+    InconsistentShares,
+    #[error("the recombined key doesn't match the key identifier")]
+    InvalidShares,
+}
+
+/// multiplication in GF(256) with the AES (0x11b) reduction polynomial
+/// (without data-dependent branches)
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut result = 0u8;
+    for _ in 0..8 {
+        result ^= a & (b & 1).wrapping_neg();
+        let carry = (a >> 7).wrapping_neg();
+        a = (a << 1) ^ (carry & 0x1b);
+        b >>= 1;
+    }
+    result
+}
+
+/// multiplicative inverse in GF(256): a^254 (0 for 0)
+fn gf_inv(a: u8) -> u8 {
+    let mut result = 1u8;
+    let mut base = a;
+    let mut exp = 254u8;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = gf_mul(result, base);
+        }
+        base = gf_mul(base, base);
+        exp >>= 1;
+    }
+    result
+}
+
+/// splits the secret into `count` shares, any `threshold` of which recover it
+pub fn split<R: RngCore + CryptoRng>(
+    rng: &mut R,
+    secret: &[u8],
+    threshold: u8,
+    count: u8,
+) -> Result<Vec<BackupKeyShare>, ShareError> {
+    if threshold < 2 || threshold > count || secret.is_empty() {
+        return Err(ShareError::InvalidParameters);
+    }
+    let key_id = cloud_backup_key_id(secret);
+    let mut shares: Vec<BackupKeyShare> = (1..=count)
+        .map(|index| BackupKeyShare {
+            key_id: key_id.clone(),
+            threshold,
+            index,
+            share: Vec::with_capacity(secret.len()),
+        })
+        .collect();
+    // the polynomial coefficients (the constant term is the secret byte)
+    let mut coefficients = Zeroizing::new(vec![0u8; threshold as usize]);
+    for byte in secret {
+        coefficients[0] = *byte;
+        rng.fill_bytes(&mut coefficients[1..]);
+        for share in shares.iter_mut() {
+            // Horner's method
+            let value = coefficients
+                .iter()
+                .rev()
+                .fold(0u8, |acc, c| gf_mul(acc, share.index) ^ c);
+            share.share.push(value);
+        }
+    }
+    Ok(shares)
+}
+
+/// recombines the secret from the shares
+/// (only the first `threshold` shares with distinct indices are used)
+pub fn combine(shares: &[BackupKeyShare]) -> Result<Zeroizing<Vec<u8>>, ShareError> {
+    let first = shares.first().ok_or(ShareError::NotEnoughShares)?;
+    if first.threshold < 2 {
+        return Err(ShareError::InvalidParameters);
+    }
+    let mut selected: Vec<&BackupKeyShare> = Vec::with_capacity(first.threshold as usize);
+    for share in shares {
+        if share.key_id != first.key_id
+            || share.threshold != first.threshold
+            || share.share.len() != first.share.len()
+            || share.index == 0
+        {
+            return Err(ShareError::InconsistentShares);
+        }
+        if selected.len() < first.threshold as usize
+            && selected.iter().all(|s| s.index != share.index)
+        {
+            selected.push(share);
+        }
+    }
+    if selected.len() < first.threshold as usize {
+        return Err(ShareError::NotEnoughShares);
+    }
+    // the Lagrange basis polynomials evaluated at 0
+    let basis: Vec<u8> = selected
+        .iter()
+        .map(|si| {
+            selected
+                .iter()
+                .filter(|sj| sj.index != si.index)
+                .fold(1u8, |acc, sj| {
+                    gf_mul(acc, gf_mul(sj.index, gf_inv(sj.index ^ si.index)))
+                })
+        })
+        .collect();
+    let mut secret = Zeroizing::new(vec![0u8; first.share.len()]);
+    for (i, byte) in secret.iter_mut().enumerate() {
+        *byte = selected
+            .iter()
+            .zip(basis.iter())
+            .fold(0u8, |acc, (s, l)| acc ^ gf_mul(s.share[i], *l));
+    }
+    if cloud_backup_key_id(&secret) != first.key_id {
+        return Err(ShareError::InvalidShares);
+    }
+    Ok(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_core::OsRng;
+
+    #[test]
+    fn test_gf() {
+        assert_eq!(gf_mul(0x57, 0x83), 0xc1);
+        assert_eq!(gf_mul(0x57, 0x13), 0xfe);
+        for a in 1..=255u8 {
+            assert_eq!(gf_mul(a, gf_inv(a)), 1);
+        }
+        assert_eq!(gf_inv(0), 0);
+    }
+
+    #[test]
+    fn test_split_combine() {
+        let mut secret = vec![0u8; 64];
+        OsRng.fill_bytes(&mut secret);
+        let shares = split(&mut OsRng, &secret, 3, 5).unwrap();
+        assert_eq!(shares.len(), 5);
+        for share in shares.iter() {
+            assert_eq!(share.key_id, cloud_backup_key_id(&secret));
+            assert_ne!(share.share, secret);
+        }
+        // any 3 shares (in any order)
+        for (a, b, c) in [(0, 1, 2), (4, 2, 0), (1, 3, 4), (3, 0, 2)].iter() {
+            let subset = vec![shares[*a].clone(), shares[*b].clone(), shares[*c].clone()];
+            assert_eq!(*combine(&subset).unwrap(), secret);
+        }
+        assert_eq!(*combine(&shares).unwrap(), secret);
+    }
+
+    #[test]
+    fn test_combine_failures() {
+        let secret = [7u8; 16];
+        let shares = split(&mut OsRng, &secret, 2, 3).unwrap();
+        assert_eq!(combine(&[]).unwrap_err(), ShareError::NotEnoughShares);
+        assert_eq!(
+            combine(&shares[..1]).unwrap_err(),
+            ShareError::NotEnoughShares
+        );
+        // duplicate shares don't count
+        assert_eq!(
+            combine(&[shares[0].clone(), shares[0].clone()]).unwrap_err(),
+            ShareError::NotEnoughShares
+        );
+        // a share from a different split
+        let other = split(&mut OsRng, &[8u8; 16], 2, 3).unwrap();
+        assert_eq!(
+            combine(&[shares[0].clone(), other[1].clone()]).unwrap_err(),
+            ShareError::InconsistentShares
+        );
+        // a corrupted share
+        let mut corrupted = shares[1].clone();
+        corrupted.share[0] ^= 1;
+        assert_eq!(
+            combine(&[shares[0].clone(), corrupted]).unwrap_err(),
+            ShareError::InvalidShares
+        );
+        assert_eq!(
+            split(&mut OsRng, &secret, 1, 3).unwrap_err(),
+            ShareError::InvalidParameters
+        );
+        assert_eq!(
+            split(&mut OsRng, &secret, 4, 3).unwrap_err(),
+            ShareError::InvalidParameters
+        );
+    }
+}
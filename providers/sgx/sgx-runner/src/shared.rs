@@ -1,9 +1,16 @@
+use crate::shamir::{BackupKeyShare, BackupKeySplit};
+use hkdf::Hkdf;
 use serde::{Deserialize, Serialize};
 use sgx_isa::{Keypolicy, Keyrequest};
+use sha2::{Digest, Sha256};
 use std::convert::TryInto;
+use std::fmt;
+use std::str::FromStr;
 use tendermint::consensus;
 use tendermint::node;
+use tmkms_light::chain::state::State;
 use tmkms_light::config::validator::ValidatorConfig;
+use zeroize::Zeroizing;
 
 /// keyseal is fixed in the enclave app
 pub type AesGcm128SivNonce = [u8; 12];
@@ -56,6 +63,69 @@ impl From<Keyrequest> for KeyRequestWrap {
     }
 }
 
+/// Which enclave identity the sealed keypairs are bound to
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SealingPolicy {
+    /// any enclave signed by the same key with an equal or higher ISVSVN
+    /// (and the same product ID) can unseal the keypair
+    #[default]
+    MrSigner,
+    /// only the same enclave build (measurement) can unseal the keypair
+    MrEnclave,
+}
+
+/// the key policy for the sealing key requests
+impl From<SealingPolicy> for Keypolicy {
+    fn from(policy: SealingPolicy) -> Self {
+        match policy {
+            SealingPolicy::MrSigner => Keypolicy::MRSIGNER,
+            SealingPolicy::MrEnclave => Keypolicy::MRENCLAVE,
+        }
+    }
+}
+
+impl FromStr for SealingPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mrsigner" => Ok(SealingPolicy::MrSigner),
+            "mrenclave" => Ok(SealingPolicy::MrEnclave),
+            _ => Err("unknown sealing policy (expected `mrsigner` or `mrenclave`)".to_owned()),
+        }
+    }
+}
+
+/// What the keypair is used for
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyRole {
+    /// the validator consensus key
+    Consensus,
+    /// the node key for the Tendermint P2P secret connection
+    Id,
+}
+
+impl KeyRole {
+    /// the file name of the cloud backup for the keypair
+    pub fn backup_file_name(self) -> &'static str {
+        match self {
+            KeyRole::Consensus => "consensus-key.backup",
+            KeyRole::Id => "id-key.backup",
+        }
+    }
+}
+
+impl fmt::Display for KeyRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyRole::Consensus => write!(f, "consensus key"),
+            KeyRole::Id => write!(f, "id key"),
+        }
+    }
+}
+
 /// Returned from the enclave app after keygen
 /// and expected to be persisted by tmkms
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -71,6 +141,87 @@ pub type PublicKey = [u8; 32];
 /// length of symmetric key wrap for cloud backup using e.g. cloud KMS
 pub const CLOUD_KEY_LEN: usize = 16;
 
+/// the minimum length of a secret the cloud backup key is derived from
+pub const MIN_CLOUD_SECRET_LEN: usize = 32;
+
+/// the maximum length of a secret the cloud backup key is derived from
+pub const MAX_CLOUD_SECRET_LEN: usize = 1024;
+
+/// the current cloud backup format version
+/// (0 is the unversioned format with only the raw 16-byte keys)
+pub const CLOUD_BACKUP_VERSION: u16 = 1;
+
+/// length of the HKDF salt in the cloud backups
+pub const CLOUD_BACKUP_SALT_LEN: usize = 32;
+
+/// HKDF info for deriving the cloud backup keys
+const CLOUD_BACKUP_HKDF_INFO: &[u8] = b"tmkms-light cloud backup key";
+
+/// domain separation for the cloud backup key identifiers
+const CLOUD_BACKUP_KEY_ID_DOMAIN: &[u8] = b"tmkms-light cloud backup key id";
+
+/// How the keypair in the cloud backup is encrypted
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum CloudBackupAlgorithm {
+    /// `Aes128GcmSiv` with the raw 16-byte key
+    #[default]
+    Aes128GcmSiv,
+    /// `Aes128GcmSiv` with the key derived by HKDF-SHA256 from a longer secret
+    /// (and a random salt stored in the backup)
+    HkdfSha256Aes128GcmSiv,
+}
+
+impl CloudBackupAlgorithm {
+    /// the algorithm for the provided secret: the raw key if it has `CLOUD_KEY_LEN` bytes,
+    /// HKDF if it has between `MIN_CLOUD_SECRET_LEN` and `MAX_CLOUD_SECRET_LEN` bytes
+    pub fn for_secret(secret: &[u8]) -> Option<Self> {
+        match secret.len() {
+            CLOUD_KEY_LEN => Some(CloudBackupAlgorithm::Aes128GcmSiv),
+            MIN_CLOUD_SECRET_LEN..=MAX_CLOUD_SECRET_LEN => {
+                Some(CloudBackupAlgorithm::HkdfSha256Aes128GcmSiv)
+            }
+            _ => None,
+        }
+    }
+
+    /// the identifier in the additional authenticated data
+    fn id(self) -> u8 {
+        match self {
+            CloudBackupAlgorithm::Aes128GcmSiv => 1,
+            CloudBackupAlgorithm::HkdfSha256Aes128GcmSiv => 2,
+        }
+    }
+
+    /// derives the symmetric key (`salt` is only used with HKDF)
+    pub fn derive_key(self, secret: &[u8], salt: &[u8]) -> Option<Zeroizing<[u8; CLOUD_KEY_LEN]>> {
+        if Self::for_secret(secret) != Some(self) {
+            return None;
+        }
+        let mut key = Zeroizing::new([0u8; CLOUD_KEY_LEN]);
+        match self {
+            CloudBackupAlgorithm::Aes128GcmSiv => key.copy_from_slice(secret),
+            CloudBackupAlgorithm::HkdfSha256Aes128GcmSiv => {
+                Hkdf::<Sha256>::new(Some(salt), secret)
+                    .expand(CLOUD_BACKUP_HKDF_INFO, &mut key[..])
+                    .ok()?;
+            }
+        }
+        Some(key)
+    }
+}
+
+/// The identifier of the cloud backup key (or the secret it's derived from):
+/// the hex-encoded first 8 bytes of its (domain-separated) SHA-256 hash,
+/// so that the backups can be matched with the keys without revealing them
+pub fn cloud_backup_key_id(secret: &[u8]) -> String {
+    let hash = Sha256::new()
+        .chain(CLOUD_BACKUP_KEY_ID_DOMAIN)
+        .chain(secret)
+        .finalize();
+    String::from_utf8(subtle_encoding::hex::encode(&hash[..8])).expect("hex")
+}
+
 /// Returned from the enclave app after keygen
 /// if the cloud backup option is requested.
 /// This may be needed in cloud settings
@@ -78,11 +229,42 @@ pub const CLOUD_KEY_LEN: usize = 16;
 /// may be relocated and fail to unseal `SealedKeyData`
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CloudBackupKeyData {
+    /// the format version (missing in the unversioned backups)
+    #[serde(default)]
+    pub version: u16,
+    /// the encryption algorithm
+    #[serde(default)]
+    pub algorithm: CloudBackupAlgorithm,
+    /// the identifier of the key the keypair was encrypted with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_id: Option<String>,
+    /// the HKDF salt (if the key was derived)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub salt: Option<Vec<u8>>,
     pub nonce: AesGcm128SivNonce,
     pub sealed_secret: Ciphertext,
     pub public_key: ed25519_dalek::PublicKey,
 }
 
+impl CloudBackupKeyData {
+    /// the additional authenticated data for the keypair encryption:
+    /// the public key (and the format metadata in the versioned backups)
+    pub fn aad(&self) -> Vec<u8> {
+        let mut aad = self.public_key.as_bytes().to_vec();
+        if self.version > 0 {
+            aad.extend_from_slice(&self.version.to_le_bytes());
+            aad.push(self.algorithm.id());
+            if let Some(key_id) = &self.key_id {
+                aad.extend_from_slice(key_id.as_bytes());
+            }
+            if let Some(salt) = &self.salt {
+                aad.extend_from_slice(salt);
+            }
+        }
+        aad
+    }
+}
+
 /// configuration for direct remote communication with TM
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RemoteConnectionConfig {
@@ -100,26 +282,200 @@ pub struct RemoteConnectionConfig {
 }
 
 /// request sent to the enclave app
+/// (only one is deserialized per enclave launch, so the variant sizes don't matter)
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Serialize, Deserialize)]
 pub enum SgxInitRequest {
-    /// generate a new keypair
-    KeyGen,
-    /// reseal the keypair from a backup
-    CloudRecover { key_data: CloudBackupKeyData },
+    /// generate new keypairs (one for each of the roles)
+    /// and back them up with the provided key or a new one split into shares
+    KeyGen {
+        sealing_policy: SealingPolicy,
+        roles: Vec<KeyRole>,
+        #[serde(default)]
+        backup_key_split: Option<BackupKeySplit>,
+    },
+    /// reseal the keypairs from their backups
+    /// (decrypted with the provided key or the one recombined from `key_shares`)
+    CloudRecover {
+        key_data: Vec<CloudBackupKeyData>,
+        sealing_policy: SealingPolicy,
+        #[serde(default)]
+        key_shares: Vec<BackupKeyShare>,
+    },
+    /// reseal the keypair under the provided policy with the current enclave's
+    /// ISVSVN and CPUSVN: it's used for migrating to a different policy
+    /// or to a new enclave version (which needs to be able to unseal the keypair
+    /// with its current policy, i.e. only MRSIGNER-sealed keypairs can be migrated
+    /// to a new enclave build)
+    Reseal {
+        sealed_key: SealedKeyData,
+        sealing_policy: SealingPolicy,
+    },
+    /// produce a report (for the quoting enclave) that includes the public key
+    /// of the provided sealed keypair in its report data
+    Report {
+        sealed_key: SealedKeyData,
+        /// `Targetinfo` of the quoting enclave
+        target_info: Vec<u8>,
+    },
     /// start the main loop for processing Tendermint privval requests
     Start {
         sealed_key: SealedKeyData,
         config: ValidatorConfig,
         secret_connection: Option<RemoteConnectionConfig>,
-        initial_state: consensus::State,
+        initial_state: State,
     },
 }
 
-/// response sent from the enclave app
+/// response sent from the enclave app after resealing
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SgxInitResponse {
-    /// freshly generated or recovered sealed keypair
+    /// the resealed keypair
+    pub sealed_key_data: SealedKeyData,
+}
+
+/// freshly generated keypair
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GeneratedKeyData {
+    /// the role requested for the keypair
+    pub role: KeyRole,
+    /// the sealed keypair
     pub sealed_key_data: SealedKeyData,
     /// if requested, keypair encrypted with the provided key
     pub cloud_backup_key_data: Option<CloudBackupKeyData>,
 }
+
+/// response sent from the enclave app after keygen
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SgxKeyGenResponse {
+    /// the generated keypairs (in the order of the roles in the request)
+    pub keys: Vec<GeneratedKeyData>,
+    /// the shares of the backup key generated in the enclave (if it was split)
+    #[serde(default)]
+    pub key_shares: Vec<BackupKeyShare>,
+}
+
+/// response sent from the enclave app after the recovery
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SgxRecoverResponse {
+    /// the recovered sealed keypairs (in the order of the backups in the request)
+    pub sealed_key_data: Vec<SealedKeyData>,
+}
+
+/// report sent from the enclave app
+/// (the report data is the public key followed by zeros)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SgxReportResponse {
+    /// the public key of the sealed keypair
+    pub public_key: PublicKey,
+    /// `Report` targeted at the quoting enclave
+    pub report: Vec<u8>,
+}
+
+/// request sent to the started enclave app on the "init" stream
+/// (it's kept open for the control requests after the start up)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SgxControlRequest {
+    /// echoed in the response (so that late responses to the timed out
+    /// requests can be told apart)
+    pub id: u64,
+    /// what to do
+    pub command: SgxControlCommand,
+}
+
+/// pause/resume commands for the started enclave app
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SgxControlCommand {
+    /// stop answering the sign requests (until resumed)
+    Pause,
+    /// resume signing
+    Resume,
+}
+
+/// response sent from the started enclave app to a control request
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct SgxControlResponse {
+    /// the id of the request
+    pub id: u64,
+    /// the outcome
+    pub result: SgxControlResult,
+}
+
+/// outcome of a control command
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub enum SgxControlResult {
+    /// no more requests will be signed
+    Paused {
+        /// the last signed state (if any was signed)
+        state: Option<consensus::State>,
+    },
+    /// signing was resumed
+    Resumed,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cloud_backup_algorithm() {
+        assert_eq!(
+            CloudBackupAlgorithm::for_secret(&[1u8; CLOUD_KEY_LEN]),
+            Some(CloudBackupAlgorithm::Aes128GcmSiv)
+        );
+        assert_eq!(
+            CloudBackupAlgorithm::for_secret(&[1u8; 64]),
+            Some(CloudBackupAlgorithm::HkdfSha256Aes128GcmSiv)
+        );
+        assert_eq!(CloudBackupAlgorithm::for_secret(&[1u8; 24]), None);
+        assert_eq!(
+            CloudBackupAlgorithm::for_secret(&vec![1u8; MAX_CLOUD_SECRET_LEN + 1]),
+            None
+        );
+
+        let raw = CloudBackupAlgorithm::Aes128GcmSiv
+            .derive_key(&[1u8; CLOUD_KEY_LEN], &[])
+            .unwrap();
+        assert_eq!(*raw, [1u8; CLOUD_KEY_LEN]);
+        assert!(CloudBackupAlgorithm::Aes128GcmSiv
+            .derive_key(&[1u8; 64], &[])
+            .is_none());
+
+        let hkdf = CloudBackupAlgorithm::HkdfSha256Aes128GcmSiv;
+        let key1 = hkdf.derive_key(&[1u8; 64], &[2u8; 32]).unwrap();
+        let key2 = hkdf.derive_key(&[1u8; 64], &[3u8; 32]).unwrap();
+        assert_ne!(*key1, *key2);
+        assert_eq!(*key1, *hkdf.derive_key(&[1u8; 64], &[2u8; 32]).unwrap());
+    }
+
+    #[test]
+    fn test_cloud_backup_key_id() {
+        let id = cloud_backup_key_id(&[1u8; CLOUD_KEY_LEN]);
+        assert_eq!(id.len(), 16);
+        assert_eq!(id, cloud_backup_key_id(&[1u8; CLOUD_KEY_LEN]));
+        assert_ne!(id, cloud_backup_key_id(&[2u8; CLOUD_KEY_LEN]));
+    }
+
+    #[test]
+    fn test_legacy_backup_format() {
+        let legacy = r#"{"nonce":[0,0,0,0,0,0,0,0,0,0,0,0],"sealed_secret":[1,2,3],
+            "public_key":[215,90,152,1,130,177,10,183,213,75,254,211,201,100,7,58,14,225,114,243,218,166,35,37,175,2,26,104,247,7,81,26]}"#;
+        let backup: CloudBackupKeyData = serde_json::from_str(legacy).unwrap();
+        assert_eq!(backup.version, 0);
+        assert_eq!(backup.algorithm, CloudBackupAlgorithm::Aes128GcmSiv);
+        assert_eq!(backup.key_id, None);
+        // the legacy backups only authenticate the public key
+        assert_eq!(backup.aad(), backup.public_key.as_bytes());
+
+        let versioned = CloudBackupKeyData {
+            version: CLOUD_BACKUP_VERSION,
+            key_id: Some(cloud_backup_key_id(&[1u8; CLOUD_KEY_LEN])),
+            ..backup
+        };
+        let json = serde_json::to_string(&versioned).unwrap();
+        assert!(json.contains(r#""algorithm":"aes128-gcm-siv""#));
+        let parsed: CloudBackupKeyData = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.aad(), versioned.aad());
+        assert_ne!(parsed.aad(), versioned.public_key.as_bytes());
+    }
+}
@@ -7,9 +7,11 @@ use std::{
     path::{Path, PathBuf},
 };
 use tempfile::NamedTempFile;
-use tmkms_light::chain::state::{consensus, StateError, StateErrorKind};
-use tmkms_light::utils::read_u16_payload;
-use tracing::{debug, warn};
+use tmkms_light::chain::state::{consensus, State, StateError, StateErrorKind};
+use tmkms_light::control::StatusTracker;
+use tmkms_light::error::ErrorKind;
+use tmkms_light::framing::{FrameType, FramedStream};
+use tracing::{debug, error, info, warn};
 
 pub struct StateSyncer {
     state_file_path: PathBuf,
@@ -20,21 +22,20 @@ impl StateSyncer {
     pub fn new<P: AsRef<Path>>(
         path: P,
         stream_to_enclave: UnixStream,
-    ) -> Result<(Self, consensus::State), StateError> {
+    ) -> Result<(Self, State), StateError> {
         let state_file_path = path.as_ref().to_owned();
         let state = match fs::read_to_string(&path) {
             Ok(state_json) => {
-                let consensus_state: consensus::State =
-                    serde_json::from_str(&state_json).map_err(|e| {
-                        format_err!(
-                            StateErrorKind::SyncError,
-                            "error parsing {}: {}",
-                            path.as_ref().display(),
-                            e
-                        )
-                    })?;
+                let state: State = serde_json::from_str(&state_json).map_err(|e| {
+                    format_err!(
+                        StateErrorKind::SyncError,
+                        "error parsing {}: {}",
+                        path.as_ref().display(),
+                        e
+                    )
+                })?;
 
-                Ok(consensus_state)
+                Ok(state)
             }
             Err(e) if e.kind() == io::ErrorKind::NotFound => {
                 Self::write_initial_state(&state_file_path)
@@ -55,11 +56,9 @@ impl StateSyncer {
         ))
     }
 
-    /// load state from the provided vsock stream
-    fn sync_from_stream(&mut self) -> Result<consensus::State, StateError> {
-        let json_raw = read_u16_payload(&mut self.stream_to_enclave)
-            .map_err(|e| format_err!(StateErrorKind::SyncError, "failed to read state: {}", e))?;
-        serde_json::from_slice(&json_raw).map_err(|e| {
+    /// parse the state received from the enclave
+    fn parse_state(json_raw: &[u8]) -> Result<State, StateError> {
+        serde_json::from_slice(json_raw).map_err(|e| {
             format_err!(
                 StateErrorKind::SyncError,
                 "failed to deserialize state: {}",
@@ -70,29 +69,58 @@ impl StateSyncer {
     }
 
     /// Write the initial state to the given path on disk
-    fn write_initial_state(path: &Path) -> Result<consensus::State, StateError> {
-        let consensus_state = consensus::State {
+    fn write_initial_state(path: &Path) -> Result<State, StateError> {
+        let state = State::from(consensus::State {
             height: 0u32.into(),
             ..Default::default()
-        };
+        });
 
-        Self::persist_state(path, &consensus_state)?;
+        Self::persist_state(path, &state)?;
 
-        Ok(consensus_state)
+        Ok(state)
     }
 
     /// Launches the state syncer
-    pub fn launch_syncer(mut self) {
-        thread::spawn(move || loop {
-            if let Ok(ref consensus_state) = self.sync_from_stream() {
-                if let Err(e) = Self::persist_state(&self.state_file_path, consensus_state) {
-                    warn!("state persistence failed: {}", e);
+    /// (it negotiates the framing version once the enclave app connects
+    /// and stops when the stream is closed or can no longer be read;
+    /// the persisted states are recorded in the tracker if provided)
+    pub fn launch_syncer(self, tracker: Option<StatusTracker>) {
+        thread::spawn(move || {
+            let mut stream = match FramedStream::accept(self.stream_to_enclave) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("state stream negotiation failed: {}", e);
+                    return;
+                }
+            };
+            loop {
+                let json_raw = match stream.recv_payload(FrameType::State) {
+                    Ok(json_raw) => json_raw,
+                    Err(e) if *e.kind() == ErrorKind::IoError => {
+                        info!("state stream closed: {}", e);
+                        return;
+                    }
+                    Err(e) => {
+                        error!("failed to read state: {}", e);
+                        return;
+                    }
+                };
+                match Self::parse_state(&json_raw) {
+                    Ok(state) => match Self::persist_state(&self.state_file_path, &state) {
+                        Ok(()) => {
+                            if let Some(tracker) = &tracker {
+                                tracker.set_state(state.consensus_state().clone());
+                            }
+                        }
+                        Err(e) => warn!("state persistence failed: {}", e),
+                    },
+                    Err(e) => warn!("{}", e),
                 }
             }
         });
     }
 
-    fn persist_state(path: &Path, new_state: &consensus::State) -> Result<(), StateError> {
+    fn persist_state(path: &Path, new_state: &State) -> Result<(), StateError> {
         debug!(
             "writing new consensus state to {}: {:?}",
             path.display(),
@@ -128,7 +156,7 @@ impl StateSyncer {
                 e
             )
         })?;
-        state_file.persist(&path).map_err(|e| {
+        state_file.persist(path).map_err(|e| {
             format_err!(
                 StateErrorKind::SyncError,
                 "error persisting {}: {}",
@@ -0,0 +1,285 @@
+use crate::{config::SoftSignOpt, key_utils, state::StateHolder};
+use std::{fs, os::unix::net::UnixStream, path::Path, path::PathBuf};
+use std::{net::TcpStream, sync::Arc, time::Duration};
+use subtle::ConstantTimeEq;
+use tendermint::net;
+use tendermint_p2p::secret_connection::{self, PublicKey, SecretConnection};
+use tmkms_light::{
+    chain::state::PersistStateSync,
+    cli::{self, ConfigOpt, LogOpt, PubkeyOpt},
+    config::validator::ValidatorConfig,
+    connection::{Connection, PlainConnection},
+    control::{SigningControl, StatusTracker, TrackedStateSync},
+    ha::{FileLeaseStore, HaCoordinator, HaSigningControl, Lease, LeaseStateSync},
+    session::{Session, SigningSwitch},
+    utils::print_pubkey,
+};
+use tracing::{debug, info, warn, Level};
+
+/// Default timeout in seconds
+const DEFAULT_TIMEOUT: u16 = 10;
+
+fn read_config(config: &ConfigOpt) -> Result<SoftSignOpt, String> {
+    let toml_string = cli::read_config_file(config)?;
+    toml::from_str(&toml_string).map_err(|e| format!("toml config file failed to parse: {:?}", e))
+}
+
+fn create_parent_dir(path: &Path) -> Result<(), String> {
+    fs::create_dir_all(
+        path.parent()
+            .ok_or_else(|| "cannot create a dir in a root directory".to_owned())?,
+    )
+    .map_err(|e| format!("failed to create dirs for `{}`: {:?}", path.display(), e))
+}
+
+/// write tmkms.toml + generate keys
+pub fn init(config: ConfigOpt, pubkey: PubkeyOpt) -> Result<(), String> {
+    let sign_opt = SoftSignOpt::default();
+    let t =
+        toml::to_string_pretty(&sign_opt).map_err(|e| format!("config to toml failed: {:?}", e))?;
+    fs::write(&config.config_path, t).map_err(|e| format!("failed to write a config: {:?}", e))?;
+    create_parent_dir(&sign_opt.consensus_key_path)?;
+    key_utils::generate_key(&sign_opt.consensus_key_path)
+        .map_err(|e| format!("failed to generate consensus key: {}", e))?;
+    if let Some(id_path) = &sign_opt.id_key_path {
+        create_parent_dir(id_path)?;
+        key_utils::generate_key(id_path)
+            .map_err(|e| format!("failed to generate id key: {}", e))?;
+    }
+    create_parent_dir(&sign_opt.state_file_path)?;
+    let keypair = key_utils::load_base64_ed25519_key(&sign_opt.consensus_key_path)
+        .map_err(|e| format!("failed to load consensus key: {}", e))?;
+    print_pubkey(pubkey.bech32_prefix, pubkey.pubkey_display, keypair.public);
+    Ok(())
+}
+
+/// connect to the validator
+fn connect(config: &SoftSignOpt) -> Result<Box<dyn Connection>, String> {
+    match &config.address {
+        net::Address::Tcp {
+            peer_id,
+            host,
+            port,
+        } => {
+            debug!(
+                "[{}@{}] connecting to validator...",
+                &config.chain_id, &config.address
+            );
+            let identity_key_path = config.id_key_path.as_ref().ok_or_else(|| {
+                format!(
+                    "config error: no `id_key_path` for validator: {}:{}",
+                    host, port
+                )
+            })?;
+
+            let identity_key = key_utils::load_base64_ed25519_key(identity_key_path)
+                .map_err(|e| format!("failed to load id key: {}", e))?;
+            info!("KMS node ID: {}", PublicKey::from(&identity_key));
+            let mut msocket;
+            loop {
+                msocket = TcpStream::connect(format!("{}:{}", host, port)).ok();
+                if msocket.is_some() || !config.retry {
+                    break;
+                }
+            }
+            let socket =
+                msocket.ok_or_else(|| format!("failed to connect to {}:{}", host, port))?;
+            let timeout = Duration::from_secs(config.timeout.unwrap_or(DEFAULT_TIMEOUT).into());
+            socket
+                .set_read_timeout(Some(timeout))
+                .map_err(|e| format!("failed to set read timeout: {:?}", e))?;
+            socket
+                .set_write_timeout(Some(timeout))
+                .map_err(|e| format!("failed to set write timeout: {:?}", e))?;
+
+            let connection =
+                SecretConnection::new(socket, identity_key, secret_connection::Version::V0_34)
+                    .map_err(|e| format!("secret connection failed: {}", e))?;
+            let actual_peer_id = connection.remote_pubkey().peer_id();
+
+            // TODO: https://github.com/informalsystems/tendermint-rs/issues/786
+            if let Some(expected_peer_id) = peer_id {
+                if expected_peer_id.ct_eq(&actual_peer_id).unwrap_u8() == 0 {
+                    return Err(format!(
+                        "{}:{}: validator peer ID mismatch! (expected {}, got {})",
+                        host, port, expected_peer_id, actual_peer_id
+                    ));
+                }
+            }
+            info!(
+                "[{}@{}] connected to validator successfully",
+                &config.chain_id, &config.address
+            );
+
+            if peer_id.is_none() {
+                // TODO: https://github.com/informalsystems/tendermint-rs/issues/786
+                warn!(
+                    "[{}@{}]: unverified validator peer ID! ({})",
+                    &config.chain_id,
+                    &config.address,
+                    connection.remote_pubkey().peer_id()
+                );
+            }
+
+            Ok(Box::new(connection))
+        }
+        net::Address::Unix { path } => {
+            if let Some(timeout) = config.timeout {
+                warn!("timeouts not supported with Unix sockets: {}", timeout);
+            }
+
+            debug!(
+                "{}: Connecting to socket at {}...",
+                &config.chain_id, &config.address
+            );
+            let mut msocket;
+            loop {
+                msocket = UnixStream::connect(path).ok();
+                if msocket.is_some() || !config.retry {
+                    break;
+                }
+            }
+            let socket =
+                msocket.ok_or_else(|| format!("failed to connect to {}", path.display()))?;
+            let conn = PlainConnection::new(socket);
+
+            info!(
+                "[{}@{}] connected to validator successfully",
+                &config.chain_id, &config.address
+            );
+
+            Ok(Box::new(conn))
+        }
+    }
+}
+
+/// start signing
+pub fn start(config: ConfigOpt, log: LogOpt, default_log_level: Level) -> Result<(), String> {
+    let config = read_config(&config)?;
+    log.init(&config.logging, default_log_level)?;
+    let tracker = StatusTracker::new(true);
+    let mut state_holder =
+        TrackedStateSync::new(StateHolder::new(&config.state_file_path), tracker.clone());
+    let state = state_holder
+        .load_state()
+        .map_err(|e| format!("failed to load state: {}", e))?;
+    let keypair = key_utils::load_base64_ed25519_key(&config.consensus_key_path)
+        .map_err(|e| format!("failed to load consensus key: {}", e))?;
+    tracker.set_public_key(tendermint::PublicKey::Ed25519(keypair.public));
+    let switch = SigningSwitch::default();
+    // only the lease holder signs (the standby keeps its state file in sync)
+    let lease = match &config.ha {
+        Some(ha) => {
+            let store = FileLeaseStore::new(&ha.lease_dir).map_err(|e| {
+                format!(
+                    "failed to open the lease directory {}: {}",
+                    ha.lease_dir.display(),
+                    e
+                )
+            })?;
+            let lease = Arc::new(Lease::new(
+                Box::new(store),
+                ha.holder_id.clone(),
+                ha.lease_ttl(),
+            ));
+            let local =
+                TrackedStateSync::new(StateHolder::new(&config.state_file_path), tracker.clone());
+            HaCoordinator::new(
+                lease.clone(),
+                switch.clone(),
+                tracker.clone(),
+                local,
+                state.clone(),
+            )
+            .launch();
+            Some(lease)
+        }
+        None => None,
+    };
+    // with HA, the signing is only resumed while the lease is held
+    let control: Box<dyn SigningControl> = match &lease {
+        Some(lease) => Box::new(HaSigningControl::new(lease.clone(), switch.clone())),
+        None => Box::new(switch.clone()),
+    };
+    cli::launch_control_socket(config.control_socket_path.as_deref(), &tracker, control)?;
+    let connection = connect(&config)?;
+    let validator_config = ValidatorConfig {
+        chain_id: config.chain_id,
+        max_height: config.max_height,
+    };
+    match lease {
+        Some(lease) => {
+            let state_holder = LeaseStateSync::new(state_holder, lease.clone());
+            let session = Session::new(validator_config, connection, keypair, state, state_holder)
+                .with_signing_switch(switch.clone());
+            let result = run_session(session, &tracker);
+            // the standby can take over right away
+            switch.pause();
+            if let Err(e) = lease.release() {
+                warn!("failed to release the lease: {}", e);
+            }
+            result
+        }
+        None => {
+            let session = Session::new(validator_config, connection, keypair, state, state_holder)
+                .with_signing_switch(switch);
+            run_session(session, &tracker)
+        }
+    }
+}
+
+/// handles the validator requests until the connection fails
+fn run_session<S: PersistStateSync>(
+    mut session: Session<S>,
+    tracker: &StatusTracker,
+) -> Result<(), String> {
+    tracker.connected();
+    let result = session.request_loop();
+    tracker.disconnected();
+    result.map_err(|e| format!("request loop failed: {}", e))
+}
+
+/// display the consensus public key
+pub fn pubkey(config: ConfigOpt, pubkey: PubkeyOpt) -> Result<(), String> {
+    let config = read_config(&config)?;
+    let keypair = key_utils::load_base64_ed25519_key(config.consensus_key_path)
+        .map_err(|e| format!("failed to load consensus key: {}", e))?;
+    print_pubkey(pubkey.bech32_prefix, pubkey.pubkey_display, keypair.public);
+    Ok(())
+}
+
+/// check the config and the key files
+pub fn validate(config: ConfigOpt) -> Result<(), String> {
+    let config = read_config(&config)?;
+    key_utils::load_base64_ed25519_key(&config.consensus_key_path)
+        .map_err(|e| format!("invalid consensus key: {}", e))?;
+    match (&config.address, &config.id_key_path) {
+        (net::Address::Tcp { .. }, None) => {
+            return Err("missing `id_key_path` for the TCP connection".to_owned())
+        }
+        (net::Address::Tcp { .. }, Some(id_path)) => {
+            key_utils::load_base64_ed25519_key(id_path)
+                .map_err(|e| format!("invalid id key: {}", e))?;
+        }
+        _ => {}
+    }
+    cli::read_state_file(&config.state_file_path)?;
+    println!("configuration OK");
+    Ok(())
+}
+
+/// display the signer status
+pub fn status(config: ConfigOpt) -> Result<(), String> {
+    let config = read_config(&config)?;
+    cli::print_status(
+        &config.chain_id,
+        &config.address,
+        &config.state_file_path,
+        config.control_socket_path.as_deref(),
+    )
+}
+
+/// the control socket of the running signer (if configured)
+pub fn control_socket_path(config: ConfigOpt) -> Result<Option<PathBuf>, String> {
+    Ok(read_config(&config)?.control_socket_path)
+}
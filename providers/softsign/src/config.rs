@@ -1,6 +1,7 @@
 use serde::{Deserialize, Serialize};
 use std::{convert::TryFrom, path::PathBuf};
 use tendermint::{chain, net};
+use tmkms_light::{ha::HaConfig, logging::LogConfig};
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -21,6 +22,13 @@ pub struct SoftSignOpt {
     pub timeout: Option<u16>,
     /// Retry connection
     pub retry: bool,
+    /// Path to the control socket for querying the running signer (if any)
+    pub control_socket_path: Option<PathBuf>,
+    /// Logging options
+    #[serde(default)]
+    pub logging: LogConfig,
+    /// Active-passive failover with other signers (if configured)
+    pub ha: Option<HaConfig>,
 }
 
 impl Default for SoftSignOpt {
@@ -36,6 +44,9 @@ impl Default for SoftSignOpt {
             state_file_path: "state/priv_validator_state.json".into(),
             timeout: None,
             retry: true,
+            control_socket_path: Some("state/control.sock".into()),
+            logging: LogConfig::default(),
+            ha: None,
         }
     }
 }
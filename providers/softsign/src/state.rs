@@ -21,14 +21,14 @@ impl StateHolder {
 
     /// Write the initial state to the given path on disk
     fn write_initial_state(&mut self) -> Result<State, StateError> {
-        let consensus_state = consensus::State {
+        let state = State::from(consensus::State {
             height: 0u32.into(),
             ..Default::default()
-        };
+        });
 
-        self.persist_state(&consensus_state)?;
+        self.persist_state(&state)?;
 
-        Ok(State::from(consensus_state))
+        Ok(state)
     }
 }
 
@@ -36,17 +36,16 @@ impl PersistStateSync for StateHolder {
     fn load_state(&mut self) -> Result<State, StateError> {
         match fs::read_to_string(&self.state_file_path) {
             Ok(state_json) => {
-                let consensus_state: consensus::State =
-                    serde_json::from_str(&state_json).map_err(|e| {
-                        format_err!(
-                            StateErrorKind::SyncError,
-                            "error parsing {}: {}",
-                            self.state_file_path.display(),
-                            e
-                        )
-                    })?;
+                let state: State = serde_json::from_str(&state_json).map_err(|e| {
+                    format_err!(
+                        StateErrorKind::SyncError,
+                        "error parsing {}: {}",
+                        self.state_file_path.display(),
+                        e
+                    )
+                })?;
 
-                Ok(State::from(consensus_state))
+                Ok(state)
             }
             Err(e) if e.kind() == io::ErrorKind::NotFound => self.write_initial_state(),
             Err(e) => fail!(
@@ -58,7 +57,7 @@ impl PersistStateSync for StateHolder {
         }
     }
 
-    fn persist_state(&mut self, new_state: &consensus::State) -> Result<(), StateError> {
+    fn persist_state(&mut self, new_state: &State) -> Result<(), StateError> {
         debug!(
             "writing new consensus state to {}: {:?}",
             self.state_file_path.display(),
@@ -9,6 +9,7 @@ tmkms_path = os.environ.get("TMKMS_RUNNER", "tmkms-light-sgx-runner")
 backup_key_path = os.environ.get("BACKUP_KEY", "cloudbackup.key")
 backup_data_path = os.environ.get("BACKUP_DATA", "/tmp/")
 recover_consensus_key = os.environ.get("RECOVER_CONSENSUS_KEY", None) != None
+recover_all_keys = os.environ.get("RECOVER_ALL_KEYS", None) != None
 key_vault_name = os.environ["KEY_VAULT_NAME"]
 backup_prefix = os.environ.get("CLOUD_BACKUP_PREFIX", "cloud-backup")
 pubkey_display = os.environ.get("PUBKEY_DISPLAY", "bech32")
@@ -24,7 +25,9 @@ try:
         f.write(secret_value) # change
     os.chmod(backup_key_path, 0o400)
     command = [tmkms_path, "recover", "-p", pubkey_display, "-b", bech32_prefix, "-e", backup_key_path, "-k", backup_data_path]
-    if recover_consensus_key:
+    if recover_all_keys:
+        command.append("--all-keys")
+    elif recover_consensus_key:
         command.append("-r")
     subprocess.run(command)
 finally:
@@ -2,20 +2,77 @@
 //! Modifications Copyright (c) 2021, Foris Limited (licensed under the Apache License, Version 2.0)
 
 mod error;
+#[cfg(test)]
+mod proptests;
 pub use self::error::{StateError, StateErrorKind};
 use anomaly::fail;
+use ed25519_dalek::Signature;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
 pub use tendermint::consensus;
-use tendermint::{proposal::SignProposalRequest, vote::SignVoteRequest};
+use tendermint::{proposal::SignProposalRequest, vote::SignVoteRequest, Time};
 /// State tracking for double signing prevention
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(into = "PersistedState", try_from = "PersistedState")]
 pub struct State {
     consensus_state: consensus::State,
+    /// the timestamp of the last signed message
+    timestamp: Option<Time>,
+    /// the signature of the last signed message
+    signature: Option<Signature>,
+}
+
+/// The persisted state: the consensus state fields
+/// (so that the files with the consensus state only can still be loaded)
+/// with the timestamp and the signature of the last signed message
+#[derive(Serialize, Deserialize)]
+struct PersistedState {
+    #[serde(flatten)]
+    consensus_state: consensus::State,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timestamp: Option<Time>,
+    /// base64-encoded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+}
+
+impl From<State> for PersistedState {
+    fn from(state: State) -> Self {
+        Self {
+            consensus_state: state.consensus_state,
+            timestamp: state.timestamp,
+            signature: state.signature.map(|signature| {
+                String::from_utf8(subtle_encoding::base64::encode(signature.to_bytes()))
+                    .expect("base64 is UTF-8")
+            }),
+        }
+    }
+}
+
+impl TryFrom<PersistedState> for State {
+    type Error = String;
+
+    fn try_from(state: PersistedState) -> Result<Self, Self::Error> {
+        let signature = state
+            .signature
+            .map(|encoded| {
+                let raw = subtle_encoding::base64::decode(encoded)
+                    .map_err(|e| format!("invalid signature encoding: {}", e))?;
+                Signature::try_from(raw.as_slice()).map_err(|e| format!("invalid signature: {}", e))
+            })
+            .transpose()?;
+        Ok(Self {
+            consensus_state: state.consensus_state,
+            timestamp: state.timestamp,
+            signature,
+        })
+    }
 }
 
 /// State persistence over sockets or files
 pub trait PersistStateSync {
     fn load_state(&mut self) -> Result<State, StateError>;
-    fn persist_state(&mut self, new_state: &consensus::State) -> Result<(), StateError>;
+    fn persist_state(&mut self, new_state: &State) -> Result<(), StateError>;
 }
 
 impl State {
@@ -24,6 +81,26 @@ impl State {
         &self.consensus_state
     }
 
+    /// the timestamp and the signature of the last signed message if the provided state
+    /// is at the same height, round and step with the same block ID,
+    /// i.e. the new message can only differ in the timestamp
+    pub fn last_signed_for(
+        &self,
+        new_state: &consensus::State,
+    ) -> Option<(Option<Time>, Signature)> {
+        if *new_state == self.consensus_state {
+            self.signature.map(|signature| (self.timestamp, signature))
+        } else {
+            None
+        }
+    }
+
+    /// whether the provided state is at a later height, round or step
+    pub fn is_behind(&self, other: &consensus::State) -> bool {
+        let own = &self.consensus_state;
+        (other.height, other.round, other.step) > (own.height, own.round, own.step)
+    }
+
     fn check_height(&self, new_state: &consensus::State) -> Result<(), StateError> {
         if new_state.height < self.consensus_state.height {
             fail!(
@@ -105,15 +182,44 @@ impl State {
         syncer: &mut S,
     ) -> Result<(), StateError> {
         self.check_consensus_state(&new_state)?;
+        let new_state = State::from(new_state);
         syncer.persist_state(&new_state)?;
-        self.consensus_state = new_state;
+        *self = new_state;
         Ok(())
     }
+
+    /// Check + sign + update the state with the one of the signed message
+    /// (the timestamp and the signature are persisted with it,
+    /// so the message can be returned again if re-sent)
+    pub fn check_update_state<S, F>(
+        &mut self,
+        new_state: State,
+        sign: F,
+        syncer: &mut S,
+    ) -> Result<Signature, StateError>
+    where
+        S: PersistStateSync,
+        F: FnOnce() -> Signature,
+    {
+        self.check_consensus_state(&new_state.consensus_state)?;
+        let signature = sign();
+        let new_state = State {
+            signature: Some(signature),
+            ..new_state
+        };
+        syncer.persist_state(&new_state)?;
+        *self = new_state;
+        Ok(signature)
+    }
 }
 
 impl From<consensus::State> for State {
     fn from(consensus_state: consensus::State) -> Self {
-        Self { consensus_state }
+        Self {
+            consensus_state,
+            timestamp: None,
+            signature: None,
+        }
     }
 }
 
@@ -126,6 +232,8 @@ impl From<SignProposalRequest> for State {
                 step: 0,
                 block_id: req.proposal.block_id,
             },
+            timestamp: req.proposal.timestamp,
+            signature: None,
         }
     }
 }
@@ -139,6 +247,8 @@ impl From<SignVoteRequest> for State {
                 step: if req.vote.is_precommit() { 2 } else { 1 },
                 block_id: req.vote.block_id,
             },
+            timestamp: req.vote.timestamp,
+            signature: None,
         }
     }
 }
@@ -178,11 +288,9 @@ mod tests {
         ($name:ident, $old_state:expr, $new_state:expr) => {
             #[test]
             fn $name() {
-                State {
-                    consensus_state: $old_state,
-                }
-                .check_consensus_state(&$new_state)
-                .unwrap();
+                State::from($old_state)
+                    .check_consensus_state(&$new_state)
+                    .unwrap();
             }
         };
     }
@@ -192,11 +300,9 @@ mod tests {
         ($name:ident, $old_state:expr, $new_state:expr) => {
             #[test]
             fn $name() {
-                let err = State {
-                    consensus_state: $old_state,
-                }
-                .check_consensus_state(&$new_state)
-                .expect_err("expected StateErrorKind::DoubleSign but succeeded");
+                let err = State::from($old_state)
+                    .check_consensus_state(&$new_state)
+                    .expect_err("expected StateErrorKind::DoubleSign but succeeded");
 
                 assert_eq!(err.kind(), &StateErrorKind::DoubleSign)
             }
@@ -244,4 +350,72 @@ mod tests {
         state!(1, 1, 2, None),
         state!(1, 1, 2, block_id!(EXAMPLE_BLOCK_ID))
     );
+
+    struct NoopSyncer;
+
+    impl PersistStateSync for NoopSyncer {
+        fn load_state(&mut self) -> Result<State, StateError> {
+            Ok(State::from(consensus::State::default()))
+        }
+
+        fn persist_state(&mut self, _new_state: &State) -> Result<(), StateError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn last_signed_kept_for_same_hrs_and_block_id() {
+        let signed = state!(1, 1, 1, block_id!(EXAMPLE_BLOCK_ID));
+        let timestamp = Time::unix_epoch();
+        let signature = Signature::new([7; 64]);
+        let mut state = State::from(state!(1, 0, 2, None));
+        state
+            .check_update_state(
+                State {
+                    consensus_state: signed.clone(),
+                    timestamp: Some(timestamp),
+                    signature: None,
+                },
+                || signature,
+                &mut NoopSyncer,
+            )
+            .unwrap();
+        assert_eq!(
+            state.last_signed_for(&signed),
+            Some((Some(timestamp), signature))
+        );
+        assert_eq!(
+            state.last_signed_for(&state!(1, 1, 1, block_id!(EXAMPLE_DOUBLE_SIGN_BLOCK_ID))),
+            None
+        );
+        assert_eq!(state.last_signed_for(&state!(1, 1, 2, None)), None);
+        // a state without a signed message has no timestamp or signature
+        state
+            .check_update_consensus_state(state!(1, 1, 2, None), &mut NoopSyncer)
+            .unwrap();
+        assert_eq!(state.last_signed_for(&state!(1, 1, 2, None)), None);
+    }
+
+    #[test]
+    fn persisted_state_round_trip() {
+        let consensus_state = state!(1, 1, 1, block_id!(EXAMPLE_BLOCK_ID));
+        // the consensus state only (as written by the earlier versions)
+        let json = serde_json::to_string(&consensus_state).unwrap();
+        let loaded: State = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded, State::from(consensus_state.clone()));
+        assert_eq!(serde_json::to_string(&loaded).unwrap(), json);
+
+        let signed = State {
+            consensus_state: consensus_state.clone(),
+            timestamp: Some(Time::unix_epoch()),
+            signature: Some(Signature::new([7; 64])),
+        };
+        let json = serde_json::to_string(&signed).unwrap();
+        assert_eq!(serde_json::from_str::<State>(&json).unwrap(), signed);
+        // which can still be read as the consensus state
+        assert_eq!(
+            serde_json::from_str::<consensus::State>(&json).unwrap(),
+            consensus_state
+        );
+    }
 }
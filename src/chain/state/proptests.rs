@@ -0,0 +1,143 @@
+//! Property-based tests for double signing prevention
+
+use super::*;
+use proptest::prelude::*;
+use std::collections::HashMap;
+use tendermint::block;
+
+const BLOCK_IDS: [&str; 3] = [
+    "26C0A41F3243C6BCD7AD2DFF8A8D83A71D29D307B5326C227F734A1A512FE47D",
+    "2470A41F3243C6BCD7AD2DFF8A8D83A71D29D307B5326C227F734A1A512FE47D",
+    "C9A20D8B7D1C42D8F4E5A5FD6B51C5E2E0D5AA1A8F0F3D4A5F2E7C6B4A3D2E1F",
+];
+
+/// records all persisted states
+#[derive(Default)]
+struct RecordingSyncer {
+    persisted: Vec<consensus::State>,
+}
+
+impl PersistStateSync for RecordingSyncer {
+    /// the last persisted state after a round trip through JSON
+    /// (as in `priv_validator_state.json`)
+    fn load_state(&mut self) -> Result<State, StateError> {
+        let last = self.persisted.last().cloned().unwrap_or_else(initial_state);
+        let json = serde_json::to_string(&State::from(last)).unwrap();
+        Ok(serde_json::from_str(&json).unwrap())
+    }
+
+    fn persist_state(&mut self, new_state: &State) -> Result<(), StateError> {
+        self.persisted.push(new_state.consensus_state().clone());
+        Ok(())
+    }
+}
+
+fn initial_state() -> consensus::State {
+    consensus::State {
+        height: 0u32.into(),
+        ..Default::default()
+    }
+}
+
+/// a vote or a proposal (step 0 = proposal, 1 = prevote, 2 = precommit)
+/// at a small range of heights and rounds, so that conflicts are frequent
+fn arb_request() -> impl Strategy<Value = consensus::State> {
+    (
+        0u32..4,
+        0u16..3,
+        0i8..3,
+        prop::option::of(0usize..BLOCK_IDS.len()),
+    )
+        .prop_map(|(height, round, step, block_id)| consensus::State {
+            height: block::Height::from(height),
+            round: block::Round::from(round),
+            step,
+            block_id: block_id.map(|i| BLOCK_IDS[i].parse::<block::Id>().unwrap()),
+        })
+}
+
+fn arb_requests() -> impl Strategy<Value = Vec<consensus::State>> {
+    prop::collection::vec(arb_request(), 0..64)
+}
+
+/// feeds the requests and returns the accepted ones
+fn accepted(state: &mut State, requests: &[consensus::State]) -> Vec<consensus::State> {
+    let mut syncer = RecordingSyncer::default();
+    requests
+        .iter()
+        .filter(|req| {
+            state
+                .check_update_consensus_state((*req).clone(), &mut syncer)
+                .is_ok()
+        })
+        .cloned()
+        .collect()
+}
+
+proptest! {
+    // rejected requests capture backtraces in `anomaly` errors, which is slow in debug builds
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn no_conflicting_block_ids_at_same_hrs(requests in arb_requests()) {
+        let mut state = State::from(initial_state());
+        let mut signed: HashMap<(block::Height, block::Round, i8), block::Id> = HashMap::new();
+        for req in accepted(&mut state, &requests) {
+            if let Some(block_id) = req.block_id {
+                let previous = signed
+                    .entry((req.height, req.round, req.step))
+                    .or_insert(block_id);
+                prop_assert_eq!(*previous, block_id, "conflicting block IDs at {}", req);
+            }
+        }
+    }
+
+    #[test]
+    fn accepted_states_are_monotonic(requests in arb_requests()) {
+        let mut state = State::from(initial_state());
+        let accepted = accepted(&mut state, &requests);
+        for pair in accepted.windows(2) {
+            prop_assert!(pair[0] <= pair[1], "{} accepted after {}", pair[1], pair[0]);
+        }
+        if let Some(last) = accepted.last() {
+            prop_assert_eq!(state.consensus_state(), last);
+        }
+    }
+
+    #[test]
+    fn persisted_and_accepted_states_match(requests in arb_requests()) {
+        let mut state = State::from(initial_state());
+        let mut syncer = RecordingSyncer::default();
+        let mut accepted = Vec::new();
+        for req in requests {
+            if state.check_update_consensus_state(req.clone(), &mut syncer).is_ok() {
+                accepted.push(req);
+            }
+        }
+        prop_assert_eq!(&syncer.persisted, &accepted);
+        let reloaded = syncer.load_state().unwrap();
+        prop_assert_eq!(reloaded.consensus_state(), state.consensus_state());
+    }
+
+    #[test]
+    fn reloaded_state_behaves_identically(
+        prefix in arb_requests(),
+        suffix in arb_requests(),
+    ) {
+        let mut state = State::from(initial_state());
+        let mut syncer = RecordingSyncer::default();
+        for req in prefix {
+            let _ = state.check_update_consensus_state(req, &mut syncer);
+        }
+        let mut reloaded = syncer.load_state().unwrap();
+        for req in suffix {
+            let original = state.check_update_consensus_state(req.clone(), &mut syncer);
+            let restored = reloaded.check_update_consensus_state(req, &mut syncer);
+            prop_assert_eq!(
+                original.as_ref().map_err(|e| *e.kind()),
+                restored.as_ref().map_err(|e| *e.kind())
+            );
+            prop_assert_eq!(state.consensus_state(), reloaded.consensus_state());
+        }
+    }
+}
@@ -0,0 +1,506 @@
+//! Command-line interface shared by the provider binaries
+//! Copyright (c) 2021, Foris Limited (licensed under the Apache License, Version 2.0)
+//!
+//! Each provider (softsign, SGX, Nitro) implements [`Provider`], which supplies
+//! the provider-specific `init` and `start` options and any additional subcommands,
+//! while the common subcommands (`init`, `start`, `pubkey`, `validate`, `status`, `pause`, `resume`)
+//! and their options are defined here, so that all binaries share the same interface.
+
+use crate::control::{
+    self, ControlRequest, ControlResponse, ControlServer, SigningControl, StatusTracker,
+};
+use crate::logging::{LogConfig, LogFormat};
+use crate::utils::{format_pubkey, PubkeyDisplay};
+use std::{fs, path::Path, path::PathBuf};
+use structopt::{StructOpt, StructOptInternal};
+use tendermint::{chain, consensus, net};
+use tracing::Level;
+
+/// The configuration file option
+#[derive(Debug, StructOpt)]
+pub struct ConfigOpt {
+    /// Path to the configuration file
+    #[structopt(short, long = "config", default_value = "tmkms.toml")]
+    pub config_path: PathBuf,
+}
+
+/// The options for displaying the consensus public key
+#[derive(Debug, StructOpt)]
+pub struct PubkeyOpt {
+    /// Public key display format (`base64`, `bech32`, `json` or `tendermint`)
+    #[structopt(short, long)]
+    pub pubkey_display: Option<PubkeyDisplay>,
+    /// Bech32 prefix (if the `bech32` format is used)
+    #[structopt(short, long)]
+    pub bech32_prefix: Option<String>,
+}
+
+/// The logging options (overriding the `[logging]` section of the configuration file)
+#[derive(Debug, StructOpt)]
+pub struct LogOpt {
+    /// Log level (`error`, `warn`, `info`, `debug` or `trace`) or `EnvFilter` directives
+    #[structopt(long)]
+    pub log_level: Option<String>,
+    /// Log format (`text` or `json`)
+    #[structopt(long)]
+    pub log_format: Option<LogFormat>,
+}
+
+impl LogOpt {
+    /// sets up the logging with these options or the configured ones
+    /// (`RUST_LOG` still overrides the level)
+    pub fn init(self, config: &LogConfig, default_level: Level) -> Result<(), String> {
+        LogConfig {
+            level: self.log_level.or_else(|| config.level.clone()),
+            format: self.log_format.or(config.format),
+        }
+        .init(default_level)
+    }
+}
+
+/// No provider-specific options
+#[derive(Debug, StructOpt)]
+pub struct NoOpt {}
+
+/// No provider-specific subcommands
+#[derive(Debug, StructOpt)]
+pub enum NoCommand {}
+
+/// The subcommands of the provider binaries
+#[derive(Debug, StructOpt)]
+pub enum Command<I: StructOpt, S: StructOpt, C: StructOpt> {
+    /// Create config + keygen
+    #[structopt(name = "init")]
+    Init {
+        #[structopt(flatten)]
+        config: ConfigOpt,
+        #[structopt(flatten)]
+        pubkey: PubkeyOpt,
+        #[structopt(flatten)]
+        opt: I,
+    },
+    /// Start the tmkms process
+    #[structopt(name = "start")]
+    Start {
+        #[structopt(flatten)]
+        config: ConfigOpt,
+        #[structopt(flatten)]
+        log: LogOpt,
+        #[structopt(flatten)]
+        opt: S,
+    },
+    /// Display the consensus public key
+    #[structopt(name = "pubkey")]
+    Pubkey {
+        #[structopt(flatten)]
+        config: ConfigOpt,
+        #[structopt(flatten)]
+        pubkey: PubkeyOpt,
+    },
+    /// Check the configuration and the key files
+    #[structopt(name = "validate")]
+    Validate {
+        #[structopt(flatten)]
+        config: ConfigOpt,
+    },
+    /// Display the signer status
+    #[structopt(name = "status")]
+    Status {
+        #[structopt(flatten)]
+        config: ConfigOpt,
+    },
+    /// Pause the signing of the running signer (and display the last signed state)
+    #[structopt(name = "pause")]
+    Pause {
+        #[structopt(flatten)]
+        config: ConfigOpt,
+    },
+    /// Resume the signing of the running signer
+    #[structopt(name = "resume")]
+    Resume {
+        #[structopt(flatten)]
+        config: ConfigOpt,
+    },
+    #[structopt(flatten)]
+    Provider(C),
+}
+
+/// The provider command type
+pub type ProviderCommand<P> =
+    Command<<P as Provider>::InitOpt, <P as Provider>::StartOpt, <P as Provider>::Command>;
+
+/// The signing provider behind a binary
+/// (the additional subcommands shouldn't reuse the names of the common ones;
+/// `StructOptInternal` is implemented together with `StructOpt` by its derive macro)
+pub trait Provider {
+    /// Provider-specific options for `init` (e.g. backup keys)
+    type InitOpt: StructOpt + StructOptInternal;
+    /// Provider-specific options for `start`
+    type StartOpt: StructOpt + StructOptInternal;
+    /// Additional provider-specific subcommands
+    type Command: StructOpt + StructOptInternal;
+
+    /// The binary name
+    const NAME: &'static str;
+    /// The binary description
+    const ABOUT: &'static str;
+    /// The log level if it's not configured
+    const DEFAULT_LOG_LEVEL: Level = Level::INFO;
+
+    /// write the config + generate keys
+    fn init(&self, config: ConfigOpt, pubkey: PubkeyOpt, opt: Self::InitOpt) -> Result<(), String>;
+
+    /// start signing (and set up the logging from the config and `log`)
+    fn start(&self, config: ConfigOpt, log: LogOpt, opt: Self::StartOpt) -> Result<(), String>;
+
+    /// display the consensus public key
+    fn pubkey(&self, config: ConfigOpt, pubkey: PubkeyOpt) -> Result<(), String>;
+
+    /// check the config and the key files
+    fn validate(&self, config: ConfigOpt) -> Result<(), String>;
+
+    /// display the signer status
+    fn status(&self, config: ConfigOpt) -> Result<(), String>;
+
+    /// the control socket of the running signer (if configured)
+    fn control_socket_path(&self, config: ConfigOpt) -> Result<Option<PathBuf>, String>;
+
+    /// run the provider-specific subcommand
+    fn command(&self, command: Self::Command) -> Result<(), String>;
+}
+
+/// parses the command-line arguments of the provider binary
+pub fn parse_args<P, I>(args: I) -> Result<ProviderCommand<P>, structopt::clap::Error>
+where
+    P: Provider,
+    I: IntoIterator,
+    I::Item: Into<std::ffi::OsString> + Clone,
+{
+    let app = ProviderCommand::<P>::clap()
+        .bin_name(P::NAME)
+        .about(P::ABOUT);
+    Ok(ProviderCommand::<P>::from_clap(
+        &app.get_matches_from_safe(args)?,
+    ))
+}
+
+/// runs the parsed command with the provider
+pub fn run<P: Provider>(provider: &P, command: ProviderCommand<P>) -> Result<(), String> {
+    match command {
+        Command::Init {
+            config,
+            pubkey,
+            opt,
+        } => provider.init(config, pubkey, opt),
+        Command::Start { config, log, opt } => provider.start(config, log, opt),
+        Command::Pubkey { config, pubkey } => provider.pubkey(config, pubkey),
+        Command::Validate { config } => provider.validate(config),
+        Command::Status { config } => provider.status(config),
+        Command::Pause { config } => pause(provider.control_socket_path(config)?),
+        Command::Resume { config } => resume(provider.control_socket_path(config)?),
+        Command::Provider(command) => provider.command(command),
+    }
+}
+
+/// the entry point of the provider binaries:
+/// parses the arguments, runs the command and exits with an error code if it failed
+/// (the commands other than `start` log with the default level unless `RUST_LOG` is set)
+pub fn main<P: Provider>(provider: &P) {
+    let command = parse_args::<P, _>(std::env::args_os()).unwrap_or_else(|e| e.exit());
+    let logging = match command {
+        Command::Start { .. } => Ok(()),
+        _ => LogConfig::default().init(P::DEFAULT_LOG_LEVEL),
+    };
+    if let Err(e) = logging.and_then(|_| run(provider, command)) {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// reads the configuration file
+pub fn read_config_file(config: &ConfigOpt) -> Result<String, String> {
+    if !config.config_path.exists() {
+        return Err(format!("missing {} file", config.config_path.display()));
+    }
+    fs::read_to_string(&config.config_path)
+        .map_err(|e| format!("toml config file failed to read: {:?}", e))
+}
+
+/// checks the file exists (with the description for the error message)
+pub fn check_file_exists(path: &Path, description: &str) -> Result<(), String> {
+    if path.is_file() {
+        Ok(())
+    } else {
+        Err(format!("missing {} `{}`", description, path.display()))
+    }
+}
+
+/// reads the last signed state from the state file
+pub fn read_state_file(path: &Path) -> Result<Option<consensus::State>, String> {
+    match fs::read_to_string(path) {
+        Ok(json) => serde_json::from_str(&json)
+            .map(Some)
+            .map_err(|e| format!("error parsing {}: {}", path.display(), e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("error reading {}: {}", path.display(), e)),
+    }
+}
+
+/// starts answering the requests on the control socket (if configured)
+pub fn launch_control_socket(
+    path: Option<&Path>,
+    tracker: &StatusTracker,
+    control: Box<dyn SigningControl>,
+) -> Result<(), String> {
+    if let Some(path) = path {
+        ControlServer::bind(path, tracker.clone(), control)
+            .map_err(|e| {
+                format!(
+                    "failed to bind the control socket {}: {}",
+                    path.display(),
+                    e
+                )
+            })?
+            .launch();
+    }
+    Ok(())
+}
+
+/// sends the request to the control socket (which needs to be configured)
+fn control_request(
+    path: Option<PathBuf>,
+    request: &ControlRequest,
+) -> Result<ControlResponse, String> {
+    let path = path.ok_or_else(|| "no `control_socket_path` in the configuration".to_owned())?;
+    match control::query(&path, request)? {
+        ControlResponse::Error(e) => Err(e),
+        response => Ok(response),
+    }
+}
+
+/// pauses the signing and prints the last signed state
+fn pause(path: Option<PathBuf>) -> Result<(), String> {
+    match control_request(path, &ControlRequest::Pause)? {
+        ControlResponse::Paused { state } => {
+            println!("signing paused");
+            match state {
+                Some(state) => print_state(&state),
+                None => println!("last signed: -"),
+            }
+            Ok(())
+        }
+        r => Err(format!("unexpected response: {:?}", r)),
+    }
+}
+
+/// resumes the signing
+fn resume(path: Option<PathBuf>) -> Result<(), String> {
+    match control_request(path, &ControlRequest::Resume)? {
+        ControlResponse::Resumed => {
+            println!("signing resumed");
+            Ok(())
+        }
+        r => Err(format!("unexpected response: {:?}", r)),
+    }
+}
+
+fn print_state(state: &consensus::State) {
+    println!(
+        "last signed: height {} round {} step {}",
+        state.height, state.round, state.step
+    );
+}
+
+/// prints the chain, validator address and the status of the running signer
+/// from its control socket (if configured), or the last signed state
+/// from the state file if the signer isn't running
+pub fn print_status(
+    chain_id: &chain::Id,
+    address: &net::Address,
+    state_file_path: &Path,
+    control_socket_path: Option<&Path>,
+) -> Result<(), String> {
+    println!("chain ID: {}", chain_id);
+    println!("validator: {}", address);
+    if let Some(path) = control_socket_path {
+        match control::query(path, &ControlRequest::Status) {
+            Ok(ControlResponse::Status(status)) => {
+                println!("signer: running (uptime {}s)", status.uptime_secs);
+                println!(
+                    "connection: {} (reconnects: {})",
+                    status.connection, status.reconnects
+                );
+                if status.paused {
+                    println!("signing: paused");
+                }
+                if let Some(public_key) = status.public_key.and_then(|pk| pk.ed25519()) {
+                    println!("{}", format_pubkey(None, None, public_key));
+                }
+                match status.state {
+                    Some(state) => print_state(&state),
+                    None => println!("last signed: -"),
+                }
+                return Ok(());
+            }
+            Ok(ControlResponse::Error(e)) => return Err(format!("status request failed: {}", e)),
+            Ok(r) => return Err(format!("unexpected response: {:?}", r)),
+            Err(e) => println!("signer: not running ({})", e),
+        }
+    }
+    match read_state_file(state_file_path)? {
+        Some(state) => print_state(&state),
+        None => println!("last signed: - (no state file)"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestProvider;
+
+    #[derive(Debug, StructOpt)]
+    struct TestInitOpt {
+        #[structopt(short)]
+        external_backup_key_path: Option<PathBuf>,
+    }
+
+    #[derive(Debug, StructOpt)]
+    enum TestCommand {
+        #[structopt(name = "recover")]
+        Recover {
+            #[structopt(flatten)]
+            config: ConfigOpt,
+        },
+    }
+
+    impl Provider for TestProvider {
+        type InitOpt = TestInitOpt;
+        type StartOpt = NoOpt;
+        type Command = TestCommand;
+
+        const NAME: &'static str = "tmkms-test";
+        const ABOUT: &'static str = "test provider";
+
+        fn init(
+            &self,
+            config: ConfigOpt,
+            pubkey: PubkeyOpt,
+            opt: TestInitOpt,
+        ) -> Result<(), String> {
+            match (pubkey.pubkey_display, opt.external_backup_key_path) {
+                (Some(PubkeyDisplay::Bech32), Some(_))
+                    if config.config_path.ends_with("a.toml") =>
+                {
+                    Ok(())
+                }
+                _ => Err("unexpected init options".to_owned()),
+            }
+        }
+
+        fn start(&self, _config: ConfigOpt, log: LogOpt, _opt: NoOpt) -> Result<(), String> {
+            match (log.log_level, log.log_format) {
+                (None, None) | (Some(_), Some(LogFormat::Json)) => Ok(()),
+                _ => Err("unexpected log options".to_owned()),
+            }
+        }
+
+        fn pubkey(&self, _config: ConfigOpt, _pubkey: PubkeyOpt) -> Result<(), String> {
+            Err("no key".to_owned())
+        }
+
+        fn validate(&self, config: ConfigOpt) -> Result<(), String> {
+            read_config_file(&config).map(|_| ())
+        }
+
+        fn status(&self, _config: ConfigOpt) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn control_socket_path(&self, _config: ConfigOpt) -> Result<Option<PathBuf>, String> {
+            Ok(None)
+        }
+
+        fn command(&self, command: TestCommand) -> Result<(), String> {
+            match command {
+                TestCommand::Recover { config } if config.config_path.ends_with("tmkms.toml") => {
+                    Ok(())
+                }
+                _ => Err("unexpected recover options".to_owned()),
+            }
+        }
+    }
+
+    fn run_args(args: &[&str]) -> Result<(), String> {
+        let command = parse_args::<TestProvider, _>(args).map_err(|e| e.to_string())?;
+        run(&TestProvider, command)
+    }
+
+    #[test]
+    fn test_common_commands() {
+        assert!(run_args(&[
+            "tmkms-test",
+            "init",
+            "-c",
+            "a.toml",
+            "-p",
+            "bech32",
+            "-e",
+            "key"
+        ])
+        .is_ok());
+        assert!(run_args(&[
+            "tmkms-test",
+            "init",
+            "--config",
+            "a.toml",
+            "--pubkey-display",
+            "bech32",
+            "-e",
+            "key"
+        ])
+        .is_ok());
+        assert!(run_args(&["tmkms-test", "init", "-p", "bech32", "-e", "key"]).is_err());
+        assert!(run_args(&["tmkms-test", "start"]).is_ok());
+        assert!(run_args(&[
+            "tmkms-test",
+            "start",
+            "--log-level",
+            "debug",
+            "--log-format",
+            "json"
+        ])
+        .is_ok());
+        assert!(run_args(&["tmkms-test", "start", "--log-format", "xml"]).is_err());
+        assert!(run_args(&["tmkms-test", "pubkey", "-p", "base64"]).is_err());
+        assert!(run_args(&["tmkms-test", "pubkey", "-p", "hex"]).is_err());
+        assert!(run_args(&["tmkms-test", "status"]).is_ok());
+        // no control socket
+        assert!(run_args(&["tmkms-test", "pause"]).is_err());
+        assert!(run_args(&["tmkms-test", "resume", "-c", "a.toml"]).is_err());
+        assert!(run_args(&["tmkms-test", "validate", "-c", "missing.toml"]).is_err());
+        assert!(run_args(&["tmkms-test", "recover"]).is_ok());
+        assert!(run_args(&["tmkms-test", "unknown"]).is_err());
+        assert!(run_args(&["tmkms-test"]).is_err());
+    }
+
+    #[test]
+    fn test_read_state_file() {
+        let dir = std::env::temp_dir().join(format!("tmkms-cli-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("priv_validator_state.json");
+        assert_eq!(read_state_file(&path).unwrap(), None);
+        fs::write(
+            &path,
+            r#"{"height":"10","round":"1","step":2,"block_id":null}"#,
+        )
+        .unwrap();
+        let state = read_state_file(&path).unwrap().unwrap();
+        assert_eq!(state.height.value(), 10);
+        assert_eq!(state.step, 2);
+        fs::write(&path, "{").unwrap();
+        assert!(read_state_file(&path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
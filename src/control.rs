@@ -0,0 +1,449 @@
+//! Control socket of the running signer
+//! Copyright (c) 2021, Foris Limited (licensed under the Apache License, Version 2.0)
+//!
+//! `start` listens on a local Unix socket (`control_socket_path` in the configuration),
+//! so that the signer status can be queried without parsing the logs.
+//! Each connection carries a single request and its response,
+//! both as JSON objects on one line, e.g. `{"request":"status"}`.
+//! The signing can also be paused (e.g. before the validator is migrated to another signer)
+//! and resumed with the `pause` and `resume` requests.
+
+use crate::chain::state::{PersistStateSync, State, StateError};
+use crate::session::SigningSwitch;
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::{fmt, fs};
+use tendermint::consensus;
+use tracing::{debug, info, warn};
+
+/// How long a client may take to send its request (or read the response)
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The maximal length of a request line (the requests are short JSON objects)
+const MAX_REQUEST_LEN: u64 = 1024;
+
+/// The validator connection as observed by the signer process
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionState {
+    /// not (yet) connected
+    Disconnected,
+    /// connected to the validator
+    Connected,
+    /// the connection isn't visible to this process
+    /// (e.g. the enclave connects to the validator directly)
+    Unknown,
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionState::Disconnected => write!(f, "disconnected"),
+            ConnectionState::Connected => write!(f, "connected"),
+            ConnectionState::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// The status of the running signer
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SignerStatus {
+    /// the validator connection
+    pub connection: ConnectionState,
+    /// the number of connections to the validator after the first one
+    pub reconnects: u64,
+    /// the last persisted consensus state
+    pub state: Option<consensus::State>,
+    /// the consensus public key (once known)
+    pub public_key: Option<tendermint::PublicKey>,
+    /// whether the signing is paused
+    pub paused: bool,
+    /// seconds since the signer started
+    pub uptime_secs: u64,
+}
+
+/// Requests on the control socket
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub enum ControlRequest {
+    /// the current status
+    Status,
+    /// stop signing (until resumed)
+    Pause,
+    /// resume signing
+    Resume,
+}
+
+/// Responses on the control socket
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlResponse {
+    /// the current status
+    Status(Box<SignerStatus>),
+    /// the signing was paused
+    Paused {
+        /// the last signed state at the moment of pausing
+        state: Option<consensus::State>,
+    },
+    /// the signing was resumed
+    Resumed,
+    /// the request couldn't be handled
+    Error(String),
+}
+
+#[derive(Debug)]
+struct TrackedStatus {
+    connection: ConnectionState,
+    connections: u64,
+    state: Option<consensus::State>,
+    public_key: Option<tendermint::PublicKey>,
+    paused: bool,
+}
+
+/// Collects the signer status (shared by the signing, state persistence
+/// and connection threads and the control socket)
+#[derive(Debug, Clone)]
+pub struct StatusTracker {
+    started_at: Instant,
+    status: Arc<Mutex<TrackedStatus>>,
+}
+
+impl StatusTracker {
+    /// a tracker of a signer that sees (or doesn't see) its validator connection
+    pub fn new(connection_visible: bool) -> Self {
+        let connection = if connection_visible {
+            ConnectionState::Disconnected
+        } else {
+            ConnectionState::Unknown
+        };
+        Self {
+            started_at: Instant::now(),
+            status: Arc::new(Mutex::new(TrackedStatus {
+                connection,
+                connections: 0,
+                state: None,
+                public_key: None,
+                paused: false,
+            })),
+        }
+    }
+
+    fn update<F: FnOnce(&mut TrackedStatus)>(&self, f: F) {
+        f(&mut self.status.lock().expect("status lock"))
+    }
+
+    /// a new validator connection was established
+    pub fn connected(&self) {
+        self.update(|s| {
+            s.connections += 1;
+            if s.connection != ConnectionState::Unknown {
+                s.connection = ConnectionState::Connected;
+            }
+        })
+    }
+
+    /// the validator connection was closed
+    pub fn disconnected(&self) {
+        self.update(|s| {
+            if s.connection != ConnectionState::Unknown {
+                s.connection = ConnectionState::Disconnected;
+            }
+        })
+    }
+
+    /// a new state was persisted (or loaded)
+    pub fn set_state(&self, state: consensus::State) {
+        self.update(|s| s.state = Some(state))
+    }
+
+    /// the consensus public key in use
+    pub fn set_public_key(&self, public_key: tendermint::PublicKey) {
+        self.update(|s| s.public_key = Some(public_key))
+    }
+
+    /// the signing was paused or resumed
+    pub fn set_paused(&self, paused: bool) {
+        self.update(|s| s.paused = paused)
+    }
+
+    /// the current status
+    pub fn status(&self) -> SignerStatus {
+        let s = self.status.lock().expect("status lock");
+        SignerStatus {
+            connection: s.connection,
+            reconnects: s.connections.saturating_sub(1),
+            state: s.state.clone(),
+            public_key: s.public_key,
+            paused: s.paused,
+            uptime_secs: self.started_at.elapsed().as_secs(),
+        }
+    }
+}
+
+/// Records the loaded and persisted states in the tracker
+/// (for the signers that persist the states in the same process)
+pub struct TrackedStateSync<S> {
+    inner: S,
+    tracker: StatusTracker,
+}
+
+impl<S: PersistStateSync> TrackedStateSync<S> {
+    /// wraps the state persistence
+    pub fn new(inner: S, tracker: StatusTracker) -> Self {
+        Self { inner, tracker }
+    }
+}
+
+impl<S: PersistStateSync> PersistStateSync for TrackedStateSync<S> {
+    fn load_state(&mut self) -> Result<State, StateError> {
+        let state = self.inner.load_state()?;
+        self.tracker.set_state(state.consensus_state().clone());
+        Ok(state)
+    }
+
+    fn persist_state(&mut self, new_state: &State) -> Result<(), StateError> {
+        self.inner.persist_state(new_state)?;
+        self.tracker.set_state(new_state.consensus_state().clone());
+        Ok(())
+    }
+}
+
+/// Pauses and resumes the signing: directly in the signer process,
+/// or the requests are relayed to the enclave
+pub trait SigningControl: Send + Sync {
+    /// stops signing and returns the last signed state
+    fn pause(&self) -> Result<Option<consensus::State>, String>;
+    /// resumes signing
+    fn resume(&self) -> Result<(), String>;
+}
+
+impl SigningControl for SigningSwitch {
+    fn pause(&self) -> Result<Option<consensus::State>, String> {
+        Ok(SigningSwitch::pause(self))
+    }
+
+    fn resume(&self) -> Result<(), String> {
+        SigningSwitch::resume(self);
+        Ok(())
+    }
+}
+
+/// Answers the requests on the control socket
+pub struct ControlServer {
+    path: PathBuf,
+    listener: UnixListener,
+    tracker: StatusTracker,
+    control: Box<dyn SigningControl>,
+}
+
+impl ControlServer {
+    /// binds the socket (only accessible by the owner)
+    pub fn bind<P: AsRef<Path>>(
+        path: P,
+        tracker: StatusTracker,
+        control: Box<dyn SigningControl>,
+    ) -> io::Result<Self> {
+        let path = path.as_ref().to_owned();
+        // a socket left by a previous run
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let listener = bind_private(&path)?;
+        Ok(Self {
+            path,
+            listener,
+            tracker,
+            control,
+        })
+    }
+
+    /// the response to the request
+    pub fn handle_request(&self, request: ControlRequest) -> ControlResponse {
+        match request {
+            ControlRequest::Status => ControlResponse::Status(Box::new(self.tracker.status())),
+            ControlRequest::Pause => match self.control.pause() {
+                Ok(state) => {
+                    info!("signing paused");
+                    self.tracker.set_paused(true);
+                    ControlResponse::Paused { state }
+                }
+                Err(e) => ControlResponse::Error(format!("pausing failed: {}", e)),
+            },
+            ControlRequest::Resume => match self.control.resume() {
+                Ok(()) => {
+                    info!("signing resumed");
+                    self.tracker.set_paused(false);
+                    ControlResponse::Resumed
+                }
+                Err(e) => ControlResponse::Error(format!("resuming failed: {}", e)),
+            },
+        }
+    }
+
+    fn handle(&self, stream: UnixStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(CONTROL_TIMEOUT))?;
+        stream.set_write_timeout(Some(CONTROL_TIMEOUT))?;
+        let mut line = String::new();
+        BufReader::new((&stream).take(MAX_REQUEST_LEN)).read_line(&mut line)?;
+        let response = if line.len() as u64 == MAX_REQUEST_LEN && !line.ends_with('\n') {
+            ControlResponse::Error("invalid request: too long".to_owned())
+        } else {
+            match serde_json::from_str(&line) {
+                Ok(request) => {
+                    debug!("control request: {:?}", request);
+                    self.handle_request(request)
+                }
+                Err(e) => ControlResponse::Error(format!("invalid request: {}", e)),
+            }
+        };
+        let mut raw = serde_json::to_vec(&response)?;
+        raw.push(b'\n');
+        (&stream).write_all(&raw)
+    }
+
+    /// Launches the control socket listener
+    pub fn launch(self) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            info!("control socket listening on {}", self.path.display());
+            for stream in self.listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = self.handle(stream) {
+                            warn!("control request failed: {}", e);
+                        }
+                    }
+                    Err(e) => warn!("control connection failed: {}", e),
+                }
+            }
+        })
+    }
+}
+
+/// Binds the socket in a new directory only accessible by the owner
+/// and moves it to `path` once its permissions are restricted
+/// (so it's never accessible by others, whatever the process umask is)
+fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no socket file name"))?;
+    let mut dir_name = std::ffi::OsString::from(".");
+    dir_name.push(name);
+    dir_name.push(format!(".{}", std::process::id()));
+    let dir = path.with_file_name(dir_name);
+    // a directory left by a previous run
+    match fs::remove_dir_all(&dir) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let bound = (|| {
+        let temp = dir.join(name);
+        let listener = UnixListener::bind(&temp)?;
+        fs::set_permissions(&temp, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&temp, path)?;
+        Ok(listener)
+    })();
+    let _ = fs::remove_dir_all(&dir);
+    bound
+}
+
+/// sends the request to the running signer
+pub fn query<P: AsRef<Path>>(path: P, request: &ControlRequest) -> Result<ControlResponse, String> {
+    let path = path.as_ref();
+    let stream = UnixStream::connect(path)
+        .map_err(|e| format!("failed to connect to {}: {}", path.display(), e))?;
+    let io_error = |e: io::Error| format!("control socket error: {}", e);
+    stream
+        .set_read_timeout(Some(CONTROL_TIMEOUT))
+        .map_err(io_error)?;
+    let mut raw = serde_json::to_vec(request).map_err(|e| e.to_string())?;
+    raw.push(b'\n');
+    (&stream).write_all(&raw).map_err(io_error)?;
+    let mut line = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut line)
+        .map_err(io_error)?;
+    serde_json::from_str(&line).map_err(|e| format!("invalid response: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_query() {
+        let dir = std::env::temp_dir().join(format!("tmkms-control-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("control.sock");
+        let tracker = StatusTracker::new(true);
+        let switch = SigningSwitch::default();
+        ControlServer::bind(&path, tracker.clone(), Box::new(switch.clone()))
+            .unwrap()
+            .launch();
+        let status = |request| match query(&path, &request).unwrap() {
+            ControlResponse::Status(status) => *status,
+            r => panic!("unexpected response: {:?}", r),
+        };
+        let initial = status(ControlRequest::Status);
+        assert_eq!(initial.connection, ConnectionState::Disconnected);
+        assert_eq!(initial.state, None);
+        tracker.connected();
+        tracker.disconnected();
+        tracker.connected();
+        tracker.set_state(consensus::State {
+            height: 10u32.into(),
+            ..Default::default()
+        });
+        let current = status(ControlRequest::Status);
+        assert_eq!(current.connection, ConnectionState::Connected);
+        assert_eq!(current.reconnects, 1);
+        assert_eq!(current.state.unwrap().height.value(), 10);
+        assert_eq!(
+            serde_json::to_string(&ControlRequest::Status).unwrap(),
+            r#"{"request":"status"}"#
+        );
+        assert_eq!(
+            query(&path, &ControlRequest::Pause).unwrap(),
+            ControlResponse::Paused { state: None }
+        );
+        assert!(switch.is_paused());
+        assert!(status(ControlRequest::Status).paused);
+        assert_eq!(
+            query(&path, &ControlRequest::Resume).unwrap(),
+            ControlResponse::Resumed
+        );
+        assert!(!switch.is_paused());
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        // only the socket is left in the directory
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        let mut stream = UnixStream::connect(&path).unwrap();
+        let mut long = vec![b' '; MAX_REQUEST_LEN as usize];
+        long.extend_from_slice(b"{\"request\":\"status\"}\n");
+        stream.write_all(&long).unwrap();
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line).unwrap();
+        assert_eq!(
+            serde_json::from_str::<ControlResponse>(&line).unwrap(),
+            ControlResponse::Error("invalid request: too long".to_owned())
+        );
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream.write_all(b"{\"request\":\"restart\"}\n").unwrap();
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line).unwrap();
+        assert!(matches!(
+            serde_json::from_str(&line).unwrap(),
+            ControlResponse::Error(_)
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
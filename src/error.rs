@@ -16,6 +16,10 @@ pub enum ErrorKind {
     #[error("chain ID error")]
     ChainIdError,
 
+    /// Frame checksum does not match its contents
+    #[error("frame checksum mismatch")]
+    ChecksumError,
+
     /// Error in configuration file
     #[error("config error")]
     ConfigError,
@@ -32,6 +36,14 @@ pub enum ErrorKind {
     #[error("cryptographic error")]
     CryptoError,
 
+    /// Frame length exceeds the maximum accepted on the channel
+    #[error("frame too large")]
+    FrameTooLarge,
+
+    /// Length-prefixed payload is truncated or has an invalid length
+    #[error("payload framing error")]
+    FramingError,
+
     /// Error running a subcommand to update chain state
     #[error("subcommand hook failed")]
     HookError,
@@ -48,6 +60,10 @@ pub enum ErrorKind {
     #[error("I/O error")]
     IoError,
 
+    /// The HA lease is not held (or it was taken over by another signer)
+    #[error("lease lost")]
+    LeaseLost,
+
     /// KMS internal panic
     #[error("internal crash")]
     PanicError,
@@ -76,6 +92,14 @@ pub enum ErrorKind {
     #[error("Tendermint error")]
     TendermintError,
 
+    /// Frame ended before its declared length
+    #[error("truncated frame")]
+    TruncatedFrame,
+
+    /// Peer does not support any common framing protocol version
+    #[error("unsupported protocol version")]
+    UnsupportedVersion,
+
     /// Verification operation failed
     #[error("verification failed")]
     VerificationError,
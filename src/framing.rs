@@ -0,0 +1,482 @@
+//! Versioned framing for the channels between the host and the enclave apps
+//! (SGX init responses and state sync, Nitro config requests and state sync).
+//!
+//! Each frame is laid out as:
+//!
+//! ```text
+//! | magic "TMKL" (4) | version (1) | type (1) | length (4, LE) | payload | CRC32 (4, LE) |
+//! ```
+//!
+//! The checksum covers the version, type, length and payload.
+//! Every channel starts with a `Hello` exchange: the connecting side sends the list of versions
+//! it supports and the accepting side replies with the highest common one (or an empty list
+//! if there is none). `Hello` frames are always encoded with version 1, so that peers
+//! of different versions can still parse them.
+
+use anomaly::{fail, format_err};
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use tracing::{debug, trace};
+
+use crate::error::{
+    Error,
+    ErrorKind::{
+        ChecksumError, FrameTooLarge, FramingError, IoError, TruncatedFrame, UnsupportedVersion,
+    },
+};
+
+/// Marks the start of each frame
+pub const MAGIC: [u8; 4] = *b"TMKL";
+
+/// Magic + version + type + payload length
+pub const HEADER_LEN: usize = 10;
+
+/// CRC32 trailer
+pub const CHECKSUM_LEN: usize = 4;
+
+/// Framing protocol versions supported by this build (in ascending order)
+pub const SUPPORTED_VERSIONS: &[u8] = &[1];
+
+/// Default limit on the payload length
+/// (state and config payloads are a few kilobytes at most)
+pub const DEFAULT_MAX_FRAME_LEN: u32 = 16 * 1024 * 1024;
+
+/// The version used for encoding `Hello` frames
+const HELLO_VERSION: u8 = 1;
+
+/// `Hello` frames only carry a list of versions
+const MAX_HELLO_LEN: u32 = u8::MAX as u32;
+
+/// Types of messages carried in frames
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameType {
+    /// Version negotiation
+    Hello = 0,
+    /// Request from the host (e.g. to start the enclave with a configuration)
+    Request = 1,
+    /// Consensus state to be loaded or persisted
+    State = 2,
+    /// Enclave initialization (key generation or recovery) response
+    InitResponse = 3,
+    /// Response from the enclave to a request
+    Response = 4,
+    /// Log record forwarded from the enclave
+    Log = 5,
+}
+
+impl TryFrom<u8> for FrameType {
+    type Error = Error;
+
+    fn try_from(tag: u8) -> Result<Self, Self::Error> {
+        match tag {
+            0 => Ok(FrameType::Hello),
+            1 => Ok(FrameType::Request),
+            2 => Ok(FrameType::State),
+            3 => Ok(FrameType::InitResponse),
+            4 => Ok(FrameType::Response),
+            5 => Ok(FrameType::Log),
+            _ => fail!(FramingError, "unknown frame type: {}", tag),
+        }
+    }
+}
+
+/// A decoded frame
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    /// Framing protocol version the frame was encoded with
+    pub version: u8,
+    /// Message type
+    pub frame_type: FrameType,
+    /// Message contents
+    pub payload: Vec<u8>,
+}
+
+fn checksum(header: &[u8], payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header[MAGIC.len()..]);
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+/// reads the remainder of a frame that has already started
+fn read_rest<S: Read>(stream: &mut S, buf: &mut [u8], part: &str) -> Result<(), Error> {
+    stream.read_exact(buf).map_err(|e| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            format_err!(
+                TruncatedFrame,
+                "missing {} ({} bytes expected)",
+                part,
+                buf.len()
+            )
+        } else {
+            format_err!(IoError, "error reading frame {}: {}", part, e)
+        }
+    })?;
+    Ok(())
+}
+
+/// Write a single frame with the given version
+pub fn write_frame<S: Write>(
+    stream: &mut S,
+    version: u8,
+    frame_type: FrameType,
+    payload: &[u8],
+    max_len: u32,
+) -> Result<(), Error> {
+    if payload.len() > max_len as usize {
+        fail!(
+            FrameTooLarge,
+            "payload of {} bytes exceeds the maximum of {} bytes",
+            payload.len(),
+            max_len
+        );
+    }
+    let mut header = [0u8; HEADER_LEN];
+    header[..MAGIC.len()].copy_from_slice(&MAGIC);
+    header[4] = version;
+    header[5] = frame_type as u8;
+    header[6..].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+    let crc = checksum(&header, payload);
+    trace!("writing {:?} frame ({} bytes)", frame_type, payload.len());
+    stream
+        .write_all(&header)
+        .and_then(|_| stream.write_all(payload))
+        .and_then(|_| stream.write_all(&crc.to_le_bytes()))
+        .and_then(|_| stream.flush())
+        .map_err(|e| format_err!(IoError, "error writing frame: {}", e))?;
+    Ok(())
+}
+
+/// Read a single frame (of any version) whose payload is at most `max_len` bytes
+pub fn read_frame<S: Read>(stream: &mut S, max_len: u32) -> Result<Frame, Error> {
+    let mut header = [0u8; HEADER_LEN];
+    stream.read_exact(&mut header[..1]).map_err(|e| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            format_err!(IoError, "connection closed")
+        } else {
+            format_err!(IoError, "error reading frame header: {}", e)
+        }
+    })?;
+    read_rest(stream, &mut header[1..], "header")?;
+    if header[..MAGIC.len()] != MAGIC {
+        fail!(
+            FramingError,
+            "invalid magic bytes (peer may not support versioned framing)"
+        );
+    }
+    let mut len_b = [0u8; 4];
+    len_b.copy_from_slice(&header[6..]);
+    let len = u32::from_le_bytes(len_b);
+    if len > max_len {
+        fail!(
+            FrameTooLarge,
+            "declared length of {} bytes exceeds the maximum of {} bytes",
+            len,
+            max_len
+        );
+    }
+    let mut payload = vec![0u8; len as usize];
+    read_rest(stream, &mut payload, "payload")?;
+    let mut crc_b = [0u8; CHECKSUM_LEN];
+    read_rest(stream, &mut crc_b, "checksum")?;
+    if u32::from_le_bytes(crc_b) != checksum(&header, &payload) {
+        fail!(ChecksumError, "corrupted frame ({} bytes)", len);
+    }
+    let frame_type = FrameType::try_from(header[5])?;
+    trace!("read {:?} frame ({} bytes)", frame_type, len);
+    Ok(Frame {
+        version: header[4],
+        frame_type,
+        payload,
+    })
+}
+
+/// the highest version supported by both sides
+fn highest_common(ours: &[u8], theirs: &[u8]) -> Option<u8> {
+    ours.iter().filter(|v| theirs.contains(v)).max().copied()
+}
+
+/// A stream on which the framing protocol version has been negotiated
+#[derive(Debug)]
+pub struct FramedStream<S> {
+    stream: S,
+    version: u8,
+    max_len: u32,
+}
+
+impl<S: Read + Write> FramedStream<S> {
+    /// Negotiates the version as the connecting side
+    /// (the one that sends its supported versions first)
+    pub fn connect(stream: S) -> Result<Self, Error> {
+        Self::connect_with_versions(stream, SUPPORTED_VERSIONS)
+    }
+
+    /// Negotiates the version as the accepting side
+    /// (the one that picks the version)
+    pub fn accept(stream: S) -> Result<Self, Error> {
+        Self::accept_with_versions(stream, SUPPORTED_VERSIONS)
+    }
+
+    fn connect_with_versions(mut stream: S, versions: &[u8]) -> Result<Self, Error> {
+        write_frame(
+            &mut stream,
+            HELLO_VERSION,
+            FrameType::Hello,
+            versions,
+            MAX_HELLO_LEN,
+        )?;
+        let hello = Self::read_hello(&mut stream)?;
+        match hello.as_slice() {
+            [version] if versions.contains(version) => {
+                debug!("negotiated framing version {}", version);
+                Ok(Self::new(stream, *version))
+            }
+            [] => fail!(
+                UnsupportedVersion,
+                "peer supports none of the versions {:?}",
+                versions
+            ),
+            other => fail!(
+                UnsupportedVersion,
+                "peer selected an unexpected version: {:?}",
+                other
+            ),
+        }
+    }
+
+    fn accept_with_versions(mut stream: S, versions: &[u8]) -> Result<Self, Error> {
+        let theirs = Self::read_hello(&mut stream)?;
+        let selected = highest_common(versions, &theirs);
+        let reply = selected.map(|v| vec![v]).unwrap_or_default();
+        write_frame(
+            &mut stream,
+            HELLO_VERSION,
+            FrameType::Hello,
+            &reply,
+            MAX_HELLO_LEN,
+        )?;
+        match selected {
+            Some(version) => {
+                debug!("negotiated framing version {}", version);
+                Ok(Self::new(stream, version))
+            }
+            None => fail!(
+                UnsupportedVersion,
+                "peer versions {:?} (supported: {:?})",
+                theirs,
+                versions
+            ),
+        }
+    }
+
+    fn read_hello(stream: &mut S) -> Result<Vec<u8>, Error> {
+        let frame = read_frame(stream, MAX_HELLO_LEN)?;
+        if frame.frame_type != FrameType::Hello {
+            fail!(
+                FramingError,
+                "expected a hello frame, got {:?}",
+                frame.frame_type
+            );
+        }
+        Ok(frame.payload)
+    }
+
+    fn new(stream: S, version: u8) -> Self {
+        Self {
+            stream,
+            version,
+            max_len: DEFAULT_MAX_FRAME_LEN,
+        }
+    }
+
+    /// Sets the limit on payload lengths (on both reading and writing)
+    pub fn with_max_len(mut self, max_len: u32) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    /// The negotiated version
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Writes a frame with the negotiated version
+    pub fn send(&mut self, frame_type: FrameType, payload: &[u8]) -> Result<(), Error> {
+        write_frame(
+            &mut self.stream,
+            self.version,
+            frame_type,
+            payload,
+            self.max_len,
+        )
+    }
+
+    /// Reads a frame and checks it uses the negotiated version
+    pub fn recv(&mut self) -> Result<Frame, Error> {
+        let frame = read_frame(&mut self.stream, self.max_len)?;
+        if frame.version != self.version {
+            fail!(
+                UnsupportedVersion,
+                "frame version {} (negotiated {})",
+                frame.version,
+                self.version
+            );
+        }
+        Ok(frame)
+    }
+
+    /// Reads a frame of the expected type and returns its payload
+    pub fn recv_payload(&mut self, expected: FrameType) -> Result<Vec<u8>, Error> {
+        let frame = self.recv()?;
+        if frame.frame_type != expected {
+            fail!(
+                FramingError,
+                "expected a {:?} frame, got {:?}",
+                expected,
+                frame.frame_type
+            );
+        }
+        Ok(frame.payload)
+    }
+
+    /// The underlying stream
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Returns the underlying stream
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorKind;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    fn encode(frame_type: FrameType, payload: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, 1, frame_type, payload, DEFAULT_MAX_FRAME_LEN).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_frame_roundtrip() {
+        // includes payloads above the previous u16 limit
+        for &len in &[0usize, 1, 1000, u16::MAX as usize + 1, 1 << 20] {
+            let payload = vec![0xab; len];
+            let buf = encode(FrameType::State, &payload);
+            assert_eq!(buf.len(), HEADER_LEN + len + CHECKSUM_LEN);
+            let frame = read_frame(&mut buf.as_slice(), DEFAULT_MAX_FRAME_LEN).unwrap();
+            assert_eq!(frame.version, 1);
+            assert_eq!(frame.frame_type, FrameType::State);
+            assert_eq!(frame.payload, payload);
+        }
+    }
+
+    #[test]
+    fn test_frame_too_large() {
+        let err = write_frame(&mut Vec::new(), 1, FrameType::Request, &[0u8; 11], 10).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::FrameTooLarge);
+        // rejected based on the header before reading the payload
+        let buf = encode(FrameType::Request, &[0u8; 11]);
+        let err = read_frame(&mut &buf[..HEADER_LEN], 10).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::FrameTooLarge);
+    }
+
+    #[test]
+    fn test_frame_truncated() {
+        let buf = encode(FrameType::State, b"{}");
+        let err = read_frame(&mut &buf[..0], DEFAULT_MAX_FRAME_LEN).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::IoError);
+        for &len in &[1, HEADER_LEN - 1, HEADER_LEN + 1, buf.len() - 1] {
+            let err = read_frame(&mut &buf[..len], DEFAULT_MAX_FRAME_LEN).unwrap_err();
+            assert_eq!(err.kind(), &ErrorKind::TruncatedFrame, "length {}", len);
+        }
+    }
+
+    #[test]
+    fn test_frame_corrupted() {
+        let buf = encode(FrameType::State, b"{}");
+        for i in MAGIC.len()..buf.len() {
+            let mut corrupted = buf.clone();
+            corrupted[i] ^= 0x01;
+            assert!(read_frame(&mut corrupted.as_slice(), DEFAULT_MAX_FRAME_LEN).is_err());
+        }
+        let mut corrupted = buf.clone();
+        corrupted[HEADER_LEN] ^= 0x01;
+        let err = read_frame(&mut corrupted.as_slice(), DEFAULT_MAX_FRAME_LEN).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::ChecksumError);
+        // a legacy u16-prefixed payload
+        let legacy = b"\x0c\x00{\"height\":1}";
+        let err = read_frame(&mut &legacy[..], DEFAULT_MAX_FRAME_LEN).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::FramingError);
+    }
+
+    #[test]
+    fn test_unknown_frame_type() {
+        let mut buf = encode(FrameType::State, b"{}");
+        buf[5] = 0xff;
+        let crc = checksum(&buf[..HEADER_LEN], b"{}");
+        let crc_start = buf.len() - CHECKSUM_LEN;
+        buf[crc_start..].copy_from_slice(&crc.to_le_bytes());
+        let err = read_frame(&mut buf.as_slice(), DEFAULT_MAX_FRAME_LEN).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::FramingError);
+    }
+
+    fn handshake(
+        client_versions: &'static [u8],
+        server_versions: &'static [u8],
+    ) -> (
+        Result<FramedStream<UnixStream>, Error>,
+        Result<FramedStream<UnixStream>, Error>,
+    ) {
+        let (client, server) = UnixStream::pair().unwrap();
+        let server =
+            thread::spawn(move || FramedStream::accept_with_versions(server, server_versions));
+        let client = FramedStream::connect_with_versions(client, client_versions);
+        (client, server.join().unwrap())
+    }
+
+    #[test]
+    fn test_version_negotiation() {
+        let (client, server) = handshake(&[1, 2], &[1]);
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+        assert_eq!(client.version(), 1);
+        assert_eq!(server.version(), 1);
+        client.send(FrameType::State, b"state").unwrap();
+        assert_eq!(server.recv_payload(FrameType::State).unwrap(), b"state");
+        server.send(FrameType::Response, b"response").unwrap();
+        let err = client.recv_payload(FrameType::State).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::FramingError);
+
+        let (client, server) = handshake(&[1, 2], &[1, 2, 3]);
+        assert_eq!(client.unwrap().version(), 2);
+        assert_eq!(server.unwrap().version(), 2);
+
+        let (client, server) = handshake(&[2], &[1]);
+        assert_eq!(client.unwrap_err().kind(), &ErrorKind::UnsupportedVersion);
+        assert_eq!(server.unwrap_err().kind(), &ErrorKind::UnsupportedVersion);
+    }
+
+    #[test]
+    fn test_version_mismatch_after_negotiation() {
+        let (client, server) = handshake(&[1, 2], &[1, 2]);
+        let (client, mut server) = (client.unwrap(), server.unwrap());
+        let mut stream = client.into_inner();
+        write_frame(
+            &mut stream,
+            1,
+            FrameType::State,
+            b"{}",
+            DEFAULT_MAX_FRAME_LEN,
+        )
+        .unwrap();
+        let err = server.recv().unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::UnsupportedVersion);
+    }
+}
@@ -0,0 +1,737 @@
+//! Active-passive high availability
+//! Copyright (c) 2021, Foris Limited (licensed under the Apache License, Version 2.0)
+//!
+//! Several signers can be started for the same validator, but only the holder of a shared lease signs.
+//! The lease is a record in a store shared by the signers (a directory on a shared filesystem,
+//! or an etcd-like service with compare-and-swap updates): it names the holder, its expiry
+//! and the last state the holder persisted (incl. the timestamp and the signature of the last signed message).
+//! The holder records each new state in the lease before responding with the signature, so once another signer
+//! took the lease over, the old holder's update fails and nothing more is signed.
+//! The standby signers keep their state files in sync with the recorded state
+//! and the new holder continues from it.
+//! The signing can only be resumed via the control socket while the lease is held.
+
+use crate::chain::state::{PersistStateSync, State, StateError, StateErrorKind};
+use crate::control::{SigningControl, StatusTracker};
+use crate::error::{Error, ErrorKind};
+use crate::session::SigningSwitch;
+use anomaly::{fail, format_err};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tendermint::consensus;
+use tracing::{debug, info, warn};
+
+/// The lease validity if not configured
+pub const DEFAULT_LEASE_TTL_SECS: u64 = 10;
+
+/// High availability configuration (the `[ha]` section in `tmkms.toml`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HaConfig {
+    /// The lease directory on a filesystem shared by the signers
+    pub lease_dir: PathBuf,
+    /// The unique name of this signer (e.g. its host name)
+    pub holder_id: String,
+    /// How long the lease is valid without renewals (in seconds)
+    pub lease_ttl_secs: Option<u64>,
+}
+
+impl HaConfig {
+    /// the configured lease validity
+    pub fn lease_ttl(&self) -> Duration {
+        Duration::from_secs(self.lease_ttl_secs.unwrap_or(DEFAULT_LEASE_TTL_SECS))
+    }
+}
+
+/// milliseconds since the Unix epoch
+/// (the signers' clocks need to be synchronized for the expiry to be respected)
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// The lease as stored
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LeaseRecord {
+    /// the signer holding the lease
+    pub holder: String,
+    /// the expiry (in milliseconds since the Unix epoch)
+    pub expires_at_ms: u64,
+    /// the last state persisted by the holder
+    pub state: Option<State>,
+}
+
+impl LeaseRecord {
+    fn is_expired(&self) -> bool {
+        self.expires_at_ms <= now_ms()
+    }
+}
+
+/// Shared storage of the lease record with compare-and-swap updates
+/// (each update increases the record's revision)
+pub trait LeaseStore: Send + Sync {
+    /// the current record and its revision (if any)
+    fn load(&self) -> Result<Option<(u64, LeaseRecord)>, Error>;
+
+    /// replaces the record if it's still at the expected revision (`None` if there's no record yet)
+    /// and returns the new revision (or `None` if the record was updated in the meantime)
+    fn compare_and_swap(
+        &self,
+        revision: Option<u64>,
+        record: &LeaseRecord,
+    ) -> Result<Option<u64>, Error>;
+}
+
+/// A local stand-in for an etcd-like service
+/// (e.g. in tests or for signers sharing one process)
+#[derive(Debug, Clone, Default)]
+pub struct MemoryLeaseStore {
+    inner: Arc<Mutex<Option<(u64, LeaseRecord)>>>,
+}
+
+impl LeaseStore for MemoryLeaseStore {
+    fn load(&self) -> Result<Option<(u64, LeaseRecord)>, Error> {
+        Ok(self.inner.lock().expect("lease store lock").clone())
+    }
+
+    fn compare_and_swap(
+        &self,
+        revision: Option<u64>,
+        record: &LeaseRecord,
+    ) -> Result<Option<u64>, Error> {
+        let mut inner = self.inner.lock().expect("lease store lock");
+        let current = inner.as_ref().map(|(r, _)| *r);
+        if current != revision {
+            return Ok(None);
+        }
+        let next = current.map_or(1, |r| r + 1);
+        *inner = Some((next, record.clone()));
+        Ok(Some(next))
+    }
+}
+
+/// The prefix of the revision files in the lease directory
+const LEASE_FILE_PREFIX: &str = "lease.";
+
+/// The lease stored in a directory on a shared filesystem:
+/// each revision is a separate file and the new one is created with a hard link
+/// (which fails if another signer created it first), so no locking is needed.
+/// The older revisions are removed, except for the previous one.
+#[derive(Debug, Clone)]
+pub struct FileLeaseStore {
+    dir: PathBuf,
+}
+
+impl FileLeaseStore {
+    /// uses (or creates) the lease directory
+    pub fn new<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(Self {
+            dir: dir.as_ref().to_owned(),
+        })
+    }
+
+    fn revision_path(&self, revision: u64) -> PathBuf {
+        self.dir
+            .join(format!("{}{:020}", LEASE_FILE_PREFIX, revision))
+    }
+
+    /// the revisions present in the directory
+    fn revisions(&self) -> Result<Vec<u64>, Error> {
+        let entries = fs::read_dir(&self.dir).map_err(|e| {
+            format_err!(
+                ErrorKind::IoError,
+                "failed to read the lease directory {}: {}",
+                self.dir.display(),
+                e
+            )
+        })?;
+        let mut revisions = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(|e| format_err!(ErrorKind::IoError, "{}", e))?;
+            let name = entry.file_name();
+            if let Some(revision) = name
+                .to_str()
+                .and_then(|n| n.strip_prefix(LEASE_FILE_PREFIX))
+                .and_then(|r| r.parse().ok())
+            {
+                revisions.push(revision);
+            }
+        }
+        revisions.sort_unstable();
+        Ok(revisions)
+    }
+
+    fn latest(&self) -> Result<Option<u64>, Error> {
+        Ok(self.revisions()?.pop())
+    }
+
+    /// writes the record to a temporary file
+    fn write_temp(&self, record: &LeaseRecord) -> Result<PathBuf, Error> {
+        let path = self
+            .dir
+            .join(format!(".tmp.{}.{}", std::process::id(), now_ms()));
+        let raw = serde_json::to_vec(record)
+            .map_err(|e| format_err!(ErrorKind::SerializationError, "{}", e))?;
+        let written = fs::File::create(&path).and_then(|mut file| {
+            file.write_all(&raw)?;
+            file.sync_all()
+        });
+        if let Err(e) = written {
+            let _ = fs::remove_file(&path);
+            fail!(ErrorKind::IoError, "failed to write the lease: {}", e);
+        }
+        Ok(path)
+    }
+}
+
+impl LeaseStore for FileLeaseStore {
+    fn load(&self) -> Result<Option<(u64, LeaseRecord)>, Error> {
+        // the revision may be removed after a newer one is created
+        for _ in 0..3 {
+            let revision = match self.latest()? {
+                Some(revision) => revision,
+                None => return Ok(None),
+            };
+            match fs::read(self.revision_path(revision)) {
+                Ok(raw) => {
+                    let record = serde_json::from_slice(&raw).map_err(|e| {
+                        format_err!(ErrorKind::SerializationError, "invalid lease: {}", e)
+                    })?;
+                    return Ok(Some((revision, record)));
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => fail!(ErrorKind::IoError, "failed to read the lease: {}", e),
+            }
+        }
+        fail!(ErrorKind::IoError, "the lease keeps changing")
+    }
+
+    fn compare_and_swap(
+        &self,
+        revision: Option<u64>,
+        record: &LeaseRecord,
+    ) -> Result<Option<u64>, Error> {
+        if self.latest()? != revision {
+            return Ok(None);
+        }
+        let next = revision.map_or(1, |r| r + 1);
+        let temp = self.write_temp(record)?;
+        let linked = fs::hard_link(&temp, self.revision_path(next));
+        let _ = fs::remove_file(&temp);
+        match linked {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Ok(None),
+            Err(e) => fail!(ErrorKind::IoError, "failed to update the lease: {}", e),
+        }
+        // a revision is only removed once the one after the next exists,
+        // so a link to the removed one is detected here
+        let revisions = self.revisions()?;
+        if revisions.last() != Some(&next) {
+            let _ = fs::remove_file(self.revision_path(next));
+            return Ok(None);
+        }
+        for old in revisions.into_iter().filter(|r| *r + 1 < next) {
+            let _ = fs::remove_file(self.revision_path(old));
+        }
+        Ok(Some(next))
+    }
+}
+
+/// The lease from the point of view of one signer
+pub struct Lease {
+    store: Box<dyn LeaseStore>,
+    holder: String,
+    ttl: Duration,
+    /// the revision and the record written by this signer while it holds the lease
+    held: Mutex<Option<(u64, LeaseRecord)>>,
+}
+
+impl Lease {
+    /// the lease in the store for the signer named `holder`
+    pub fn new(store: Box<dyn LeaseStore>, holder: impl Into<String>, ttl: Duration) -> Self {
+        Self {
+            store,
+            holder: holder.into(),
+            ttl,
+            held: Mutex::new(None),
+        }
+    }
+
+    fn held(&self) -> MutexGuard<'_, Option<(u64, LeaseRecord)>> {
+        self.held.lock().expect("lease lock")
+    }
+
+    /// whether this signer holds the lease (as far as it knows)
+    pub fn is_held(&self) -> bool {
+        self.held().is_some()
+    }
+
+    /// how long the lease is valid without renewals
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// the current record in the store
+    pub fn record(&self) -> Result<Option<LeaseRecord>, Error> {
+        Ok(self.store.load()?.map(|(_, record)| record))
+    }
+
+    /// takes the lease if it's free (or expired) and returns the written record
+    /// (with the state recorded by the previous holder)
+    pub fn try_acquire(&self) -> Result<Option<LeaseRecord>, Error> {
+        let mut held = self.held();
+        let (revision, state) = match self.store.load()? {
+            Some((_, record)) if record.holder != self.holder && !record.is_expired() => {
+                return Ok(None)
+            }
+            Some((revision, record)) => (Some(revision), record.state),
+            None => (None, None),
+        };
+        let record = LeaseRecord {
+            holder: self.holder.clone(),
+            expires_at_ms: now_ms() + self.ttl.as_millis() as u64,
+            state,
+        };
+        match self.store.compare_and_swap(revision, &record)? {
+            Some(revision) => {
+                *held = Some((revision, record.clone()));
+                Ok(Some(record))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// extends the lease and records the new state (if provided);
+    /// the lease is considered lost if it fails
+    fn update(&self, state: Option<&State>) -> Result<(), Error> {
+        let mut held = self.held();
+        let (revision, mut record) = match held.take() {
+            Some(held) => held,
+            None => fail!(ErrorKind::LeaseLost, "the lease isn't held"),
+        };
+        record.expires_at_ms = now_ms() + self.ttl.as_millis() as u64;
+        if let Some(state) = state {
+            record.state = Some(state.clone());
+        }
+        match self.store.compare_and_swap(Some(revision), &record)? {
+            Some(revision) => {
+                *held = Some((revision, record));
+                Ok(())
+            }
+            None => fail!(ErrorKind::LeaseLost, "the lease was taken over"),
+        }
+    }
+
+    /// extends the lease
+    pub fn renew(&self) -> Result<(), Error> {
+        self.update(None)
+    }
+
+    /// records the new state (before the signature is sent) and extends the lease
+    pub fn record_state(&self, state: &State) -> Result<(), Error> {
+        self.update(Some(state))
+    }
+
+    /// lets the lease expire now (the recorded state is kept for the next holder)
+    pub fn release(&self) -> Result<(), Error> {
+        let mut held = self.held();
+        if let Some((revision, mut record)) = held.take() {
+            record.expires_at_ms = now_ms();
+            self.store.compare_and_swap(Some(revision), &record)?;
+        }
+        Ok(())
+    }
+}
+
+/// Records each new state in the lease before persisting it,
+/// so that no signature is sent without holding the lease
+pub struct LeaseStateSync<S> {
+    inner: S,
+    lease: Arc<Lease>,
+}
+
+impl<S> LeaseStateSync<S> {
+    /// wraps the state persistence
+    pub fn new(inner: S, lease: Arc<Lease>) -> Self {
+        Self { inner, lease }
+    }
+}
+
+impl<S: PersistStateSync> PersistStateSync for LeaseStateSync<S> {
+    fn load_state(&mut self) -> Result<State, StateError> {
+        self.inner.load_state()
+    }
+
+    fn persist_state(&mut self, new_state: &State) -> Result<(), StateError> {
+        self.lease.record_state(new_state).map_err(|e| {
+            StateError::from(format_err!(
+                StateErrorKind::SyncError,
+                "the state wasn't recorded in the lease: {}",
+                e
+            ))
+        })?;
+        self.inner.persist_state(new_state)
+    }
+}
+
+/// Pauses and resumes the signing on the control socket requests:
+/// the signing is only resumed while the lease is held
+pub struct HaSigningControl {
+    lease: Arc<Lease>,
+    switch: SigningSwitch,
+}
+
+impl HaSigningControl {
+    /// controls the signing switch of the lease holder
+    pub fn new(lease: Arc<Lease>, switch: SigningSwitch) -> Self {
+        Self { lease, switch }
+    }
+}
+
+impl SigningControl for HaSigningControl {
+    fn pause(&self) -> Result<Option<consensus::State>, String> {
+        Ok(self.switch.pause())
+    }
+
+    fn resume(&self) -> Result<(), String> {
+        // the lease can't be lost (and the signing paused by the coordinator) in the meantime
+        let held = self.lease.held();
+        if held.is_none() {
+            let holder = match self.lease.record() {
+                Ok(Some(record)) if !record.is_expired() => record.holder,
+                _ => "no one".to_owned(),
+            };
+            return Err(format!(
+                "this signer doesn't hold the lease (held by {})",
+                holder
+            ));
+        }
+        self.switch.resume();
+        Ok(())
+    }
+}
+
+/// Renews the lease while it's held (and pauses the signing if it's lost);
+/// otherwise it keeps the local state in sync with the recorded one
+/// and takes the lease over once it's free.
+pub struct HaCoordinator<S> {
+    lease: Arc<Lease>,
+    switch: SigningSwitch,
+    tracker: StatusTracker,
+    local: S,
+    synced: State,
+    /// whether the signing was resumed (until a lost lease is noticed)
+    leading: bool,
+}
+
+impl<S: PersistStateSync + Send + 'static> HaCoordinator<S> {
+    /// the signing is paused until the lease is taken
+    /// (`local` persists the state of this signer, which is at `state`)
+    pub fn new(
+        lease: Arc<Lease>,
+        switch: SigningSwitch,
+        tracker: StatusTracker,
+        local: S,
+        state: State,
+    ) -> Self {
+        switch.pause();
+        tracker.set_paused(true);
+        Self {
+            lease,
+            switch,
+            tracker,
+            local,
+            synced: state,
+            leading: false,
+        }
+    }
+
+    /// persists the recorded state locally (if it's ahead)
+    fn sync(&mut self, state: &State) -> Result<(), Error> {
+        if self.synced.is_behind(state.consensus_state()) {
+            self.local.persist_state(state).map_err(|e| {
+                format_err!(ErrorKind::IoError, "failed to persist the state: {}", e)
+            })?;
+            debug!(
+                "synced the state at height {}",
+                state.consensus_state().height
+            );
+            self.synced = state.clone();
+        }
+        Ok(())
+    }
+
+    /// renews or tries to take the lease; returns whether it's held
+    pub fn step(&mut self) -> Result<bool, Error> {
+        // the lease may also be dropped by a failed state update
+        let lost = if self.lease.is_held() {
+            match self.lease.renew() {
+                Ok(()) => return Ok(true),
+                Err(e) => Some(e.to_string()),
+            }
+        } else if self.leading {
+            Some("it's no longer held".to_owned())
+        } else {
+            None
+        };
+        if let Some(e) = lost {
+            self.switch.pause();
+            self.tracker.set_paused(true);
+            self.leading = false;
+            warn!("signing paused, the lease was lost: {}", e);
+        }
+        if let Some(state) = self.lease.record()?.and_then(|r| r.state) {
+            self.sync(&state)?;
+        }
+        let record = match self.lease.try_acquire()? {
+            Some(record) => record,
+            None => return Ok(false),
+        };
+        // the handed-over state needs to be persisted before signing
+        if let Some(state) = &record.state {
+            if let Err(e) = self.sync(state) {
+                let _ = self.lease.release();
+                return Err(e);
+            }
+        }
+        self.switch.resume_from(record.state);
+        self.tracker.set_paused(false);
+        self.leading = true;
+        info!("took the lease, signing");
+        Ok(true)
+    }
+
+    /// runs the coordinator in a thread (with the steps at a third of the lease validity)
+    pub fn launch(mut self) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let interval = self.lease.ttl() / 3;
+            loop {
+                if let Err(e) = self.step() {
+                    warn!("lease check failed: {}", e);
+                }
+                thread::sleep(interval);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::{ControlRequest, ControlResponse, ControlServer};
+    use ed25519_dalek::Signature;
+    use tendermint::block;
+
+    #[derive(Clone, Default)]
+    struct MemoryState(Arc<Mutex<Option<State>>>);
+
+    impl PersistStateSync for MemoryState {
+        fn load_state(&mut self) -> Result<State, StateError> {
+            Ok(self
+                .0
+                .lock()
+                .unwrap()
+                .clone()
+                .unwrap_or_else(|| State::from(consensus::State::default())))
+        }
+
+        fn persist_state(&mut self, new_state: &State) -> Result<(), StateError> {
+            *self.0.lock().unwrap() = Some(new_state.clone());
+            Ok(())
+        }
+    }
+
+    fn state(height: u32) -> State {
+        State::from(consensus::State {
+            height: block::Height::from(height),
+            ..Default::default()
+        })
+    }
+
+    fn check_lease_store(store: &dyn LeaseStore) {
+        let record = |holder: &str| LeaseRecord {
+            holder: holder.to_owned(),
+            expires_at_ms: 1,
+            state: None,
+        };
+        assert_eq!(store.load().unwrap(), None);
+        assert_eq!(store.compare_and_swap(None, &record("a")).unwrap(), Some(1));
+        assert_eq!(store.compare_and_swap(None, &record("b")).unwrap(), None);
+        for revision in 1..5 {
+            assert_eq!(
+                store
+                    .compare_and_swap(Some(revision), &record("a"))
+                    .unwrap(),
+                Some(revision + 1)
+            );
+        }
+        assert_eq!(store.compare_and_swap(Some(3), &record("b")).unwrap(), None);
+        assert_eq!(store.load().unwrap(), Some((5, record("a"))));
+    }
+
+    #[test]
+    fn test_lease_stores() {
+        check_lease_store(&MemoryLeaseStore::default());
+        let dir = std::env::temp_dir().join(format!("tmkms-lease-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let store = FileLeaseStore::new(&dir).unwrap();
+        check_lease_store(&store);
+        // only the last two revisions are kept
+        assert_eq!(store.revisions().unwrap(), vec![4, 5]);
+        // the recorded state keeps the last signature
+        let mut signed = state(1);
+        signed
+            .check_update_state(
+                state(2),
+                || Signature::new([7; 64]),
+                &mut MemoryState::default(),
+            )
+            .unwrap();
+        let record = LeaseRecord {
+            holder: "a".to_owned(),
+            expires_at_ms: 1,
+            state: Some(signed),
+        };
+        assert_eq!(store.compare_and_swap(Some(5), &record).unwrap(), Some(6));
+        assert_eq!(store.load().unwrap(), Some((6, record)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_lease_takeover() {
+        let store = MemoryLeaseStore::default();
+        let ttl = Duration::from_millis(100);
+        let a = Lease::new(Box::new(store.clone()), "a", ttl);
+        let b = Lease::new(Box::new(store), "b", ttl);
+        assert!(a.try_acquire().unwrap().is_some());
+        assert!(b.try_acquire().unwrap().is_none());
+        a.record_state(&state(5)).unwrap();
+        assert!(b.try_acquire().unwrap().is_none());
+        // the lease expires without renewals
+        thread::sleep(ttl);
+        let record = b.try_acquire().unwrap().unwrap();
+        assert_eq!(record.holder, "b");
+        assert_eq!(record.state, Some(state(5)));
+        // the old holder can't record any new state
+        let err = a.record_state(&state(6)).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::LeaseLost);
+        assert!(!a.is_held());
+        b.release().unwrap();
+        assert_eq!(a.try_acquire().unwrap().unwrap().state, Some(state(5)));
+    }
+
+    #[test]
+    fn test_coordinators() {
+        let store = MemoryLeaseStore::default();
+        let ttl = Duration::from_secs(60);
+        let lease_a = Arc::new(Lease::new(Box::new(store.clone()), "a", ttl));
+        let lease_b = Arc::new(Lease::new(Box::new(store.clone()), "b", ttl));
+        let (switch_a, switch_b) = (SigningSwitch::default(), SigningSwitch::default());
+        let (tracker_a, tracker_b) = (StatusTracker::new(true), StatusTracker::new(true));
+        let local_b = MemoryState::default();
+        let mut a = HaCoordinator::new(
+            lease_a.clone(),
+            switch_a.clone(),
+            tracker_a.clone(),
+            MemoryState::default(),
+            state(1),
+        );
+        let mut b = HaCoordinator::new(
+            lease_b,
+            switch_b.clone(),
+            tracker_b.clone(),
+            local_b.clone(),
+            state(1),
+        );
+        assert!(switch_a.is_paused() && switch_b.is_paused());
+        assert!(tracker_a.status().paused && tracker_b.status().paused);
+        assert!(a.step().unwrap());
+        assert!(!switch_a.is_paused());
+        assert!(!tracker_a.status().paused);
+        assert!(!b.step().unwrap());
+        assert!(switch_b.is_paused());
+        assert!(tracker_b.status().paused);
+
+        // the leader's states are recorded in the lease and synced by the standby
+        let mut syncer = LeaseStateSync::new(MemoryState::default(), lease_a.clone());
+        syncer.persist_state(&state(7)).unwrap();
+        assert!(!b.step().unwrap());
+        assert_eq!(*local_b.0.lock().unwrap(), Some(state(7)));
+
+        // the standby takes over once the lease expires
+        let (revision, mut record) = store.load().unwrap().unwrap();
+        record.expires_at_ms = 1;
+        store.compare_and_swap(Some(revision), &record).unwrap();
+        assert!(b.step().unwrap());
+        assert!(!switch_b.is_paused());
+        assert!(!tracker_b.status().paused);
+
+        // the old leader notices when recording a state and pauses on its next step
+        assert!(syncer.persist_state(&state(8)).is_err());
+        assert!(!lease_a.is_held());
+        assert!(!switch_a.is_paused());
+        assert!(!a.step().unwrap());
+        assert!(switch_a.is_paused());
+        assert!(tracker_a.status().paused);
+    }
+
+    #[test]
+    fn test_resume_on_standby() {
+        let store = MemoryLeaseStore::default();
+        let ttl = Duration::from_secs(60);
+        let lease_a = Arc::new(Lease::new(Box::new(store.clone()), "a", ttl));
+        let lease_b = Arc::new(Lease::new(Box::new(store), "b", ttl));
+        let (switch_a, switch_b) = (SigningSwitch::default(), SigningSwitch::default());
+        let (tracker_a, tracker_b) = (StatusTracker::new(true), StatusTracker::new(true));
+        let mut a = HaCoordinator::new(
+            lease_a.clone(),
+            switch_a.clone(),
+            tracker_a,
+            MemoryState::default(),
+            state(1),
+        );
+        let mut b = HaCoordinator::new(
+            lease_b.clone(),
+            switch_b.clone(),
+            tracker_b.clone(),
+            MemoryState::default(),
+            state(1),
+        );
+        assert!(a.step().unwrap());
+        assert!(!b.step().unwrap());
+
+        // the standby can't be resumed on the control socket
+        let path =
+            std::env::temp_dir().join(format!("tmkms-ha-control-{}.sock", std::process::id()));
+        let server = ControlServer::bind(
+            &path,
+            tracker_b.clone(),
+            Box::new(HaSigningControl::new(lease_b, switch_b.clone())),
+        )
+        .unwrap();
+        match server.handle_request(ControlRequest::Resume) {
+            ControlResponse::Error(e) => assert!(e.contains("held by a"), "{}", e),
+            r => panic!("unexpected response: {:?}", r),
+        }
+        assert!(switch_b.is_paused());
+        assert!(tracker_b.status().paused);
+        fs::remove_file(&path).unwrap();
+
+        // the holder can be paused and resumed
+        let control_a = HaSigningControl::new(lease_a, switch_a.clone());
+        control_a.pause().unwrap();
+        assert!(switch_a.is_paused());
+        assert!(a.step().unwrap());
+        assert!(switch_a.is_paused());
+        control_a.resume().unwrap();
+        assert!(!switch_a.is_paused());
+    }
+}
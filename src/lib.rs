@@ -1,7 +1,18 @@
 pub mod chain;
+#[cfg(feature = "cli")]
+pub mod cli;
 pub mod config;
 pub mod connection;
+#[cfg(feature = "control")]
+pub mod control;
 pub mod error;
-mod rpc;
+pub mod framing;
+#[cfg(feature = "ha")]
+pub mod ha;
+#[cfg(feature = "logging")]
+pub mod logging;
+#[cfg(any(test, feature = "mock-validator"))]
+pub mod mock_validator;
+pub mod rpc;
 pub mod session;
 pub mod utils;
@@ -0,0 +1,97 @@
+//! Logging setup shared by the provider binaries
+//! Copyright (c) 2021, Foris Limited (licensed under the Apache License, Version 2.0)
+//!
+//! The log level comes from the configuration (or the command line),
+//! but it can be overridden with the `RUST_LOG` environment variable
+//! (using the `EnvFilter` directives, e.g. `tmkms_light=debug`).
+
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use tracing::Level;
+use tracing_subscriber::EnvFilter;
+
+/// Log output format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// human-readable lines
+    Text,
+    /// one JSON object per line (e.g. for log collectors)
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("unknown log format".to_owned()),
+        }
+    }
+}
+
+/// Logging configuration (the `[logging]` section in `tmkms.toml`)
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogConfig {
+    /// The maximum level (`error`, `warn`, `info`, `debug` or `trace`)
+    /// or `EnvFilter` directives; the binary's default level if not set
+    pub level: Option<String>,
+    /// Output format (`text` if not set)
+    pub format: Option<LogFormat>,
+}
+
+impl LogConfig {
+    /// the filter from `RUST_LOG` (if set) or the configured level
+    pub fn filter(&self, default_level: Level) -> Result<EnvFilter, String> {
+        match std::env::var(EnvFilter::DEFAULT_ENV) {
+            Ok(directives) if !directives.trim().is_empty() => EnvFilter::try_new(&directives)
+                .map_err(|e| format!("invalid {}: {}", EnvFilter::DEFAULT_ENV, e)),
+            _ => match &self.level {
+                Some(level) => EnvFilter::try_new(level)
+                    .map_err(|e| format!("invalid log level `{}`: {}", level, e)),
+                None => Ok(EnvFilter::new(default_level.to_string())),
+            },
+        }
+    }
+
+    /// sets up the global subscriber
+    pub fn init(&self, default_level: Level) -> Result<(), String> {
+        let builder = tracing_subscriber::fmt().with_env_filter(self.filter(default_level)?);
+        match self.format.unwrap_or(LogFormat::Text) {
+            LogFormat::Text => builder.try_init(),
+            LogFormat::Json => builder.json().try_init(),
+        }
+        .map_err(|e| format!("setting default subscriber failed: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_config() {
+        let config: LogConfig = toml::from_str("level = \"debug\"\nformat = \"json\"").unwrap();
+        assert_eq!(config.level.as_deref(), Some("debug"));
+        assert_eq!(config.format, Some(LogFormat::Json));
+        let config: LogConfig = toml::from_str("").unwrap();
+        assert_eq!(config.format, None);
+        assert!(toml::from_str::<LogConfig>("format = \"xml\"").is_err());
+        if std::env::var(EnvFilter::DEFAULT_ENV).is_err() {
+            assert!(config.filter(Level::DEBUG).is_ok());
+            let config = LogConfig {
+                level: Some("tmkms_light=trace,warn".to_owned()),
+                ..Default::default()
+            };
+            assert!(config.filter(Level::INFO).is_ok());
+            let config = LogConfig {
+                level: Some("tmkms_light=loud".to_owned()),
+                ..Default::default()
+            };
+            assert!(config.filter(Level::INFO).is_err());
+        }
+    }
+}
@@ -0,0 +1,863 @@
+//! Mock validator (privval client) for local end-to-end testing
+//! Copyright (c) 2021, Foris Limited (licensed under the Apache License, Version 2.0)
+//!
+//! It plays the role of a Tendermint node: it listens on a Unix domain socket
+//! or a TCP port (with SecretConnection), waits for the signer to connect
+//! and then sends privval requests, checking the returned signatures against
+//! the public key reported by the signer.
+
+use crate::{
+    connection::{Connection, PlainConnection},
+    error::{Error, ErrorKind},
+};
+use anomaly::{fail, format_err};
+use ed25519_dalek as ed25519;
+use prost::Message as _;
+use std::convert::TryFrom;
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use tendermint::{
+    account, block, chain,
+    proposal::{self, Proposal, SignProposalRequest},
+    public_key::PublicKey,
+    vote::{self, SignVoteRequest, Vote},
+    Signature, Time,
+};
+use tendermint_p2p::secret_connection::{self, SecretConnection, DATA_MAX_SIZE};
+use tendermint_proto::privval::{
+    message::Sum, Message as PrivMessage, PingRequest, PubKeyRequest, RemoteSignerError,
+};
+use tracing::debug;
+
+/// Listening socket of the mock validator
+enum MockListener {
+    /// plain connection over a Unix domain socket
+    Unix(UnixListener),
+    /// SecretConnection over TCP with the validator's node identity key
+    Tcp(TcpListener, Box<ed25519::Keypair>),
+}
+
+/// Outcome of a signing request
+#[derive(Debug, Clone, PartialEq)]
+pub enum SignOutcome {
+    /// signed (the signature was verified against the signer's public key)
+    Signed(Signature),
+    /// the signer refused to sign
+    Rejected(RemoteSignerError),
+}
+
+/// Scripted request sent by the mock validator
+#[derive(Debug, Clone)]
+pub enum ScriptStep {
+    /// sign a prevote or a precommit
+    Vote {
+        vote_type: vote::Type,
+        height: u32,
+        round: u16,
+        block_id: Option<block::Id>,
+    },
+    /// sign a proposal
+    Proposal {
+        height: u32,
+        round: u16,
+        block_id: Option<block::Id>,
+    },
+}
+
+/// Expected outcome of a scripted request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expect {
+    /// the request is signed
+    Signed,
+    /// the request is refused with the given `RemoteSignerError` code
+    Rejected(i32),
+}
+
+/// Privval client that drives a signer like a Tendermint node would
+pub struct MockValidator {
+    chain_id: chain::Id,
+    listener: MockListener,
+    connection: Option<Box<dyn Connection>>,
+    public_key: Option<PublicKey>,
+}
+
+impl MockValidator {
+    /// Listen for the signer on the Unix domain socket at the given path
+    pub fn bind_unix<P: AsRef<Path>>(path: P, chain_id: chain::Id) -> Result<Self, Error> {
+        let listener = UnixListener::bind(path.as_ref()).map_err(|e| {
+            format_err!(
+                ErrorKind::IoError,
+                "failed to bind {}: {}",
+                path.as_ref().display(),
+                e
+            )
+        })?;
+        Ok(Self::new(MockListener::Unix(listener), chain_id))
+    }
+
+    /// Listen for the signer on the TCP address (use port 0 for a random one);
+    /// connections are upgraded to SecretConnection using the node identity key
+    pub fn bind_tcp(
+        addr: &str,
+        chain_id: chain::Id,
+        node_key: ed25519::Keypair,
+    ) -> Result<Self, Error> {
+        let listener = TcpListener::bind(addr)
+            .map_err(|e| format_err!(ErrorKind::IoError, "failed to bind {}: {}", addr, e))?;
+        Ok(Self::new(
+            MockListener::Tcp(listener, Box::new(node_key)),
+            chain_id,
+        ))
+    }
+
+    fn new(listener: MockListener, chain_id: chain::Id) -> Self {
+        Self {
+            chain_id,
+            listener,
+            connection: None,
+            public_key: None,
+        }
+    }
+
+    /// The bound TCP address (if listening on TCP)
+    pub fn tcp_addr(&self) -> Option<SocketAddr> {
+        match &self.listener {
+            MockListener::Tcp(listener, _) => listener.local_addr().ok(),
+            MockListener::Unix(_) => None,
+        }
+    }
+
+    /// The node ID of the validator (if listening on TCP)
+    pub fn node_id(&self) -> Option<tendermint::node::Id> {
+        match &self.listener {
+            MockListener::Tcp(_, node_key) => Some(node_key.public.into()),
+            MockListener::Unix(_) => None,
+        }
+    }
+
+    /// Chain ID used in requests
+    pub fn chain_id(&self) -> &chain::Id {
+        &self.chain_id
+    }
+
+    /// Change the chain ID used in subsequent requests
+    /// (e.g. to test chain ID mismatches)
+    pub fn set_chain_id(&mut self, chain_id: chain::Id) {
+        self.chain_id = chain_id;
+    }
+
+    /// The signer's public key (if it was already requested)
+    pub fn public_key(&self) -> Option<PublicKey> {
+        self.public_key
+    }
+
+    /// Wait for the signer to connect (replaces the previous connection)
+    pub fn accept(&mut self) -> Result<(), Error> {
+        self.connection = None;
+        let connection: Box<dyn Connection> = match &self.listener {
+            MockListener::Unix(listener) => {
+                let (socket, _) = listener
+                    .accept()
+                    .map_err(|e| format_err!(ErrorKind::IoError, "accept failed: {}", e))?;
+                Box::new(PlainConnection::new(socket))
+            }
+            MockListener::Tcp(listener, node_key) => {
+                let (socket, _) = listener
+                    .accept()
+                    .map_err(|e| format_err!(ErrorKind::IoError, "accept failed: {}", e))?;
+                // the `Clone` is not derived for Keypair
+                let node_key = ed25519::Keypair::from_bytes(&node_key.to_bytes())
+                    .map_err(|e| format_err!(ErrorKind::InvalidKey, "invalid node key: {}", e))?;
+                let connection =
+                    SecretConnection::new(socket, node_key, secret_connection::Version::V0_34)
+                        .map_err(|e| {
+                            format_err!(ErrorKind::ProtocolError, "handshake failed: {}", e)
+                        })?;
+                Box::new(connection)
+            }
+        };
+        debug!("mock validator: signer connected");
+        self.connection = Some(connection);
+        Ok(())
+    }
+
+    /// Drop the current connection to the signer
+    pub fn disconnect(&mut self) {
+        self.connection = None;
+    }
+
+    /// Send a ping request
+    pub fn ping(&mut self) -> Result<(), Error> {
+        match self.request(Sum::PingRequest(PingRequest {}))? {
+            Sum::PingResponse(_) => Ok(()),
+            other => fail!(
+                ErrorKind::ProtocolError,
+                "unexpected ping response: {:?}",
+                other
+            ),
+        }
+    }
+
+    /// Request the signer's public key (remembered for signature checks)
+    pub fn request_public_key(&mut self) -> Result<Result<PublicKey, RemoteSignerError>, Error> {
+        let request = PubKeyRequest {
+            chain_id: self.chain_id.to_string(),
+        };
+        match self.request(Sum::PubKeyRequest(request))? {
+            Sum::PubKeyResponse(resp) => {
+                if let Some(error) = resp.error {
+                    return Ok(Err(error));
+                }
+                let public_key = resp
+                    .pub_key
+                    .ok_or_else(|| format_err!(ErrorKind::ProtocolError, "missing public key"))
+                    .and_then(|pk| {
+                        PublicKey::try_from(pk).map_err(|e| {
+                            format_err!(ErrorKind::ProtocolError, "invalid public key: {}", e)
+                        })
+                    })?;
+                self.public_key = Some(public_key);
+                Ok(Ok(public_key))
+            }
+            other => fail!(
+                ErrorKind::ProtocolError,
+                "unexpected public key response: {:?}",
+                other
+            ),
+        }
+    }
+
+    /// Request a vote signature
+    pub fn sign_vote(&mut self, vote: Vote) -> Result<SignOutcome, Error> {
+        Ok(match self.signed_vote(vote)? {
+            Ok(signed) => SignOutcome::Signed(signed.signature),
+            Err(error) => SignOutcome::Rejected(error),
+        })
+    }
+
+    /// Request a vote signature and return the signed vote
+    /// (e.g. to check its timestamp) or the signer's error
+    pub fn signed_vote(&mut self, vote: Vote) -> Result<Result<Vote, RemoteSignerError>, Error> {
+        let public_key = self.expected_public_key()?;
+        let request = SignVoteRequest {
+            vote: vote.clone(),
+            chain_id: self.chain_id.clone(),
+        };
+        match self.request(Sum::SignVoteRequest(request.into()))? {
+            Sum::SignedVoteResponse(resp) => {
+                if let Some(error) = resp.error {
+                    return Ok(Err(error));
+                }
+                let signed = resp
+                    .vote
+                    .ok_or_else(|| format_err!(ErrorKind::ProtocolError, "missing signed vote"))
+                    .and_then(|v| {
+                        Vote::try_from(v).map_err(|e| {
+                            format_err!(ErrorKind::ProtocolError, "invalid signed vote: {}", e)
+                        })
+                    })?;
+                let signature = signed.signature;
+                let unsigned = Vote {
+                    signature: Signature::default(),
+                    ..signed
+                };
+                if unsigned.height != vote.height
+                    || unsigned.round != vote.round
+                    || unsigned.block_id != vote.block_id
+                    || unsigned.vote_type != vote.vote_type
+                {
+                    fail!(
+                        ErrorKind::VerificationError,
+                        "signed vote differs from the requested one"
+                    );
+                }
+                let signable_bytes = SignVoteRequest {
+                    vote: unsigned.clone(),
+                    chain_id: self.chain_id.clone(),
+                }
+                .to_signable_vec()
+                .map_err(|e| format_err!(ErrorKind::SerializationError, "{}", e))?;
+                Self::verify(&public_key, &signable_bytes, signature)?;
+                Ok(Ok(Vote {
+                    signature,
+                    ..unsigned
+                }))
+            }
+            other => fail!(
+                ErrorKind::ProtocolError,
+                "unexpected vote response: {:?}",
+                other
+            ),
+        }
+    }
+
+    /// Request a proposal signature
+    pub fn sign_proposal(&mut self, proposal: Proposal) -> Result<SignOutcome, Error> {
+        let public_key = self.expected_public_key()?;
+        let request = SignProposalRequest {
+            proposal: proposal.clone(),
+            chain_id: self.chain_id.clone(),
+        };
+        match self.request(Sum::SignProposalRequest(request.into()))? {
+            Sum::SignedProposalResponse(resp) => {
+                if let Some(error) = resp.error {
+                    return Ok(SignOutcome::Rejected(error));
+                }
+                let signed = resp
+                    .proposal
+                    .ok_or_else(|| format_err!(ErrorKind::ProtocolError, "missing signed proposal"))
+                    .and_then(|p| {
+                        Proposal::try_from(p).map_err(|e| {
+                            format_err!(ErrorKind::ProtocolError, "invalid signed proposal: {}", e)
+                        })
+                    })?;
+                let signature = signed.signature;
+                let unsigned = Proposal {
+                    signature: Signature::default(),
+                    ..signed
+                };
+                if unsigned.height != proposal.height
+                    || unsigned.round != proposal.round
+                    || unsigned.block_id != proposal.block_id
+                {
+                    fail!(
+                        ErrorKind::VerificationError,
+                        "signed proposal differs from the requested one"
+                    );
+                }
+                let signable_bytes = SignProposalRequest {
+                    proposal: unsigned,
+                    chain_id: self.chain_id.clone(),
+                }
+                .to_signable_vec()
+                .map_err(|e| format_err!(ErrorKind::SerializationError, "{}", e))?;
+                Self::verify(&public_key, &signable_bytes, signature)
+            }
+            other => fail!(
+                ErrorKind::ProtocolError,
+                "unexpected proposal response: {:?}",
+                other
+            ),
+        }
+    }
+
+    /// Run the scripted requests and check that each of them has
+    /// the expected outcome
+    pub fn run_script(&mut self, script: &[(ScriptStep, Expect)]) -> Result<(), Error> {
+        for (i, (step, expected)) in script.iter().enumerate() {
+            let outcome = match step.clone() {
+                ScriptStep::Vote {
+                    vote_type,
+                    height,
+                    round,
+                    block_id,
+                } => self.sign_vote(Self::vote(vote_type, height, round, block_id))?,
+                ScriptStep::Proposal {
+                    height,
+                    round,
+                    block_id,
+                } => self.sign_proposal(Self::proposal(height, round, block_id))?,
+            };
+            match (&outcome, expected) {
+                (SignOutcome::Signed(_), Expect::Signed) => {}
+                (SignOutcome::Rejected(e), Expect::Rejected(code)) if e.code == *code => {}
+                _ => fail!(
+                    ErrorKind::VerificationError,
+                    "step {} ({:?}): expected {:?}, got {:?}",
+                    i,
+                    step,
+                    expected,
+                    outcome
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    /// Unsigned vote for the given h/r and block ID
+    pub fn vote(
+        vote_type: vote::Type,
+        height: u32,
+        round: u16,
+        block_id: Option<block::Id>,
+    ) -> Vote {
+        Vote {
+            vote_type,
+            height: block::Height::from(height),
+            round: block::Round::from(round),
+            block_id,
+            timestamp: Some(Time::now()),
+            validator_address: account::Id::new([0u8; account::LENGTH]),
+            validator_index: vote::ValidatorIndex::try_from(0u32).expect("zero is a valid index"),
+            signature: Signature::default(),
+        }
+    }
+
+    /// Unsigned proposal for the given h/r and block ID
+    pub fn proposal(height: u32, round: u16, block_id: Option<block::Id>) -> Proposal {
+        Proposal {
+            msg_type: proposal::Type::Proposal,
+            height: block::Height::from(height),
+            round: block::Round::from(round),
+            pol_round: None,
+            block_id,
+            timestamp: Some(Time::now()),
+            signature: Signature::default(),
+        }
+    }
+
+    fn expected_public_key(&mut self) -> Result<PublicKey, Error> {
+        match self.public_key {
+            Some(pk) => Ok(pk),
+            None => match self.request_public_key()? {
+                Ok(pk) => Ok(pk),
+                Err(e) => fail!(
+                    ErrorKind::ProtocolError,
+                    "failed to obtain public key: {}",
+                    e.description
+                ),
+            },
+        }
+    }
+
+    fn verify(
+        public_key: &PublicKey,
+        signable_bytes: &[u8],
+        signature: Signature,
+    ) -> Result<SignOutcome, Error> {
+        public_key
+            .verify(signable_bytes, &signature)
+            .map_err(|e| format_err!(ErrorKind::VerificationError, "invalid signature: {}", e))?;
+        Ok(SignOutcome::Signed(signature))
+    }
+
+    /// Send a request and read the response
+    fn request(&mut self, request: Sum) -> Result<Sum, Error> {
+        let connection = self
+            .connection
+            .as_mut()
+            .ok_or_else(|| format_err!(ErrorKind::IoError, "signer is not connected"))?;
+        let mut buf = Vec::new();
+        PrivMessage { sum: Some(request) }
+            .encode_length_delimited(&mut buf)
+            .map_err(|e| format_err!(ErrorKind::ProtocolError, "failed to encode: {}", e))?;
+        connection
+            .write_all(&buf)
+            .map_err(|e| format_err!(ErrorKind::IoError, "write request failed: {}", e))?;
+        let mut resp = vec![0; DATA_MAX_SIZE];
+        let n = connection
+            .read(&mut resp)
+            .map_err(|e| format_err!(ErrorKind::IoError, "read response failed: {}", e))?;
+        resp.truncate(n);
+        PrivMessage::decode_length_delimited(resp.as_ref())
+            .map_err(|e| format_err!(ErrorKind::ProtocolError, "malformed response: {}", e))?
+            .sum
+            .ok_or_else(|| format_err!(ErrorKind::ProtocolError, "empty response").into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        chain::state::{consensus, PersistStateSync, State, StateError},
+        config::validator::ValidatorConfig,
+        session::{Session, SigningSwitch},
+    };
+    use std::net::TcpStream;
+    use std::os::unix::net::UnixStream;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    const BLOCK_ID: &str = "26C0A41F3243C6BCD7AD2DFF8A8D83A71D29D307B5326C227F734A1A512FE47D";
+
+    const OTHER_BLOCK_ID: &str = "2470A41F3243C6BCD7AD2DFF8A8D83A71D29D307B5326C227F734A1A512FE47D";
+
+    /// `RemoteSignerError` code for chain ID mismatches
+    const INVALID_CHAIN_ID: i32 = 1;
+
+    /// `RemoteSignerError` code for double signing attempts
+    const DOUBLE_SIGN: i32 = 2;
+
+    /// `RemoteSignerError` code for sign requests while paused
+    const PAUSED: i32 = 3;
+
+    /// in-memory state persistence shared with the test
+    #[derive(Clone)]
+    struct MemoryState(Arc<Mutex<State>>);
+
+    impl Default for MemoryState {
+        fn default() -> Self {
+            Self(Arc::new(Mutex::new(State::from(consensus::State {
+                height: 0u32.into(),
+                ..Default::default()
+            }))))
+        }
+    }
+
+    impl MemoryState {
+        fn consensus_state(&self) -> consensus::State {
+            self.0.lock().unwrap().consensus_state().clone()
+        }
+    }
+
+    impl PersistStateSync for MemoryState {
+        fn load_state(&mut self) -> Result<State, StateError> {
+            Ok(self.0.lock().unwrap().clone())
+        }
+
+        fn persist_state(&mut self, new_state: &State) -> Result<(), StateError> {
+            *self.0.lock().unwrap() = new_state.clone();
+            Ok(())
+        }
+    }
+
+    /// where the signer should connect to
+    #[derive(Clone)]
+    enum Target {
+        Unix(PathBuf),
+        Tcp(SocketAddr),
+    }
+
+    fn chain_id(id: &str) -> chain::Id {
+        chain::Id::try_from(id.to_owned()).unwrap()
+    }
+
+    fn block_id(id: &str) -> Option<block::Id> {
+        Some(id.parse::<block::Id>().unwrap())
+    }
+
+    fn keypair(seed: u8) -> ed25519::Keypair {
+        let secret = ed25519::SecretKey::from_bytes(&[seed; 32]).unwrap();
+        let public = ed25519::PublicKey::from(&secret);
+        ed25519::Keypair { secret, public }
+    }
+
+    fn connect(target: &Target) -> Option<Box<dyn Connection>> {
+        match target {
+            Target::Unix(path) => UnixStream::connect(path)
+                .ok()
+                .map(|s| Box::new(PlainConnection::new(s)) as Box<dyn Connection>),
+            Target::Tcp(addr) => {
+                let socket = TcpStream::connect(addr).ok()?;
+                SecretConnection::new(socket, keypair(2), secret_connection::Version::V0_34)
+                    .ok()
+                    .map(|c| Box::new(c) as Box<dyn Connection>)
+            }
+        }
+    }
+
+    /// runs a signer session in a separate thread that (re)connects
+    /// the given number of times
+    fn spawn_signer(
+        target: Target,
+        state: MemoryState,
+        connections: usize,
+    ) -> thread::JoinHandle<()> {
+        spawn_switched_signer(target, state, connections, SigningSwitch::default())
+    }
+
+    /// like `spawn_signer`, but the signing can be paused with the switch
+    fn spawn_switched_signer(
+        target: Target,
+        state: MemoryState,
+        connections: usize,
+        switch: SigningSwitch,
+    ) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let mut syncer = state;
+            let initial_state = syncer.load_state().unwrap();
+            let mut session = Session::new(
+                ValidatorConfig {
+                    chain_id: chain_id("testchain-1"),
+                    max_height: None,
+                },
+                connect(&target).expect("signer connection"),
+                keypair(1),
+                initial_state,
+                syncer,
+            )
+            .with_signing_switch(switch);
+            for _ in 1..connections {
+                let _ = session.request_loop();
+                session.reset_connection(connect(&target).expect("signer reconnection"));
+            }
+            let _ = session.request_loop();
+        })
+    }
+
+    fn unix_validator(name: &str) -> (MockValidator, Target) {
+        let path = std::env::temp_dir().join(format!(
+            "tmkms-light-mock-{}-{}.socket",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let mock = MockValidator::bind_unix(&path, chain_id("testchain-1")).unwrap();
+        (mock, Target::Unix(path))
+    }
+
+    fn vote(vote_type: vote::Type, height: u32, round: u16, id: &str) -> ScriptStep {
+        ScriptStep::Vote {
+            vote_type,
+            height,
+            round,
+            block_id: block_id(id),
+        }
+    }
+
+    #[test]
+    fn test_unix_consensus_rounds() {
+        let (mut mock, target) = unix_validator("rounds");
+        let state = MemoryState::default();
+        let signer = spawn_signer(target, state.clone(), 1);
+        mock.accept().unwrap();
+        mock.ping().unwrap();
+        let public_key = mock.request_public_key().unwrap().unwrap();
+        assert_eq!(public_key.as_bytes(), keypair(1).public.as_bytes());
+        mock.run_script(&[
+            (
+                ScriptStep::Proposal {
+                    height: 1,
+                    round: 0,
+                    block_id: block_id(BLOCK_ID),
+                },
+                Expect::Signed,
+            ),
+            (vote(vote::Type::Prevote, 1, 0, BLOCK_ID), Expect::Signed),
+            (vote(vote::Type::Precommit, 1, 0, BLOCK_ID), Expect::Signed),
+            (
+                ScriptStep::Vote {
+                    vote_type: vote::Type::Prevote,
+                    height: 2,
+                    round: 0,
+                    block_id: None,
+                },
+                Expect::Signed,
+            ),
+            (
+                vote(vote::Type::Prevote, 2, 1, OTHER_BLOCK_ID),
+                Expect::Signed,
+            ),
+            (
+                vote(vote::Type::Precommit, 2, 1, OTHER_BLOCK_ID),
+                Expect::Signed,
+            ),
+        ])
+        .unwrap();
+        mock.disconnect();
+        signer.join().unwrap();
+        let last_state = state.consensus_state();
+        assert_eq!(last_state.height, block::Height::from(2u32));
+        assert_eq!(last_state.round, block::Round::from(1u16));
+        assert_eq!(last_state.step, 2);
+    }
+
+    #[test]
+    fn test_unix_double_sign() {
+        let (mut mock, target) = unix_validator("double-sign");
+        let signer = spawn_signer(target, MemoryState::default(), 1);
+        mock.accept().unwrap();
+        mock.run_script(&[
+            (vote(vote::Type::Prevote, 1, 0, BLOCK_ID), Expect::Signed),
+            (
+                vote(vote::Type::Prevote, 1, 0, OTHER_BLOCK_ID),
+                Expect::Rejected(DOUBLE_SIGN),
+            ),
+            (
+                vote(vote::Type::Precommit, 1, 0, OTHER_BLOCK_ID),
+                Expect::Rejected(DOUBLE_SIGN),
+            ),
+            (vote(vote::Type::Precommit, 1, 0, BLOCK_ID), Expect::Signed),
+        ])
+        .unwrap();
+        // the signer is still responsive after refusing to sign
+        mock.ping().unwrap();
+        mock.disconnect();
+        signer.join().unwrap();
+    }
+
+    #[test]
+    fn test_unix_resent_request() {
+        let (mut mock, target) = unix_validator("resent-request");
+        let signer = spawn_signer(target, MemoryState::default(), 1);
+        mock.accept().unwrap();
+        let signed = |outcome| match outcome {
+            SignOutcome::Signed(signature) => signature,
+            other => panic!("unexpected outcome: {:?}", other),
+        };
+        let proposal = MockValidator::proposal(1, 0, block_id(BLOCK_ID));
+        let first = signed(mock.sign_proposal(proposal.clone()).unwrap());
+        assert_eq!(signed(mock.sign_proposal(proposal).unwrap()), first);
+        let prevote = MockValidator::vote(vote::Type::Prevote, 1, 0, block_id(BLOCK_ID));
+        let first = signed(mock.sign_vote(prevote.clone()).unwrap());
+        assert_eq!(signed(mock.sign_vote(prevote).unwrap()), first);
+        mock.disconnect();
+        signer.join().unwrap();
+    }
+
+    #[test]
+    fn test_unix_resent_vote() {
+        let (mut mock, target) = unix_validator("resent");
+        let state = MemoryState::default();
+        let signer = spawn_signer(target.clone(), state.clone(), 1);
+        mock.accept().unwrap();
+        let prevote = MockValidator::vote(vote::Type::Prevote, 1, 0, block_id(BLOCK_ID));
+        let first = mock.signed_vote(prevote.clone()).unwrap().unwrap();
+        assert_eq!(first.timestamp, prevote.timestamp);
+        assert_eq!(mock.signed_vote(prevote.clone()).unwrap().unwrap(), first);
+        // a different timestamp at the same h/r/s gets the last signed vote
+        // (the signature is verified over the returned vote)
+        let later = Vote {
+            timestamp: prevote
+                .timestamp
+                .map(|t| t + std::time::Duration::from_secs(1)),
+            ..prevote.clone()
+        };
+        let resent = mock.signed_vote(later.clone()).unwrap().unwrap();
+        assert_eq!(resent.timestamp, first.timestamp);
+        assert_eq!(resent.signature, first.signature);
+        mock.disconnect();
+        signer.join().unwrap();
+
+        // the timestamp and the signature are persisted with the state,
+        // so they're returned after a restart as well
+        let signer = spawn_signer(target, state, 1);
+        mock.accept().unwrap();
+        let resent = mock.signed_vote(later).unwrap().unwrap();
+        assert_eq!(resent.timestamp, first.timestamp);
+        assert_eq!(resent.signature, first.signature);
+        assert_eq!(mock.signed_vote(prevote).unwrap().unwrap(), first);
+        mock.disconnect();
+        signer.join().unwrap();
+    }
+
+    #[test]
+    fn test_unix_paused_signing() {
+        let (mut mock, target) = unix_validator("paused");
+        let state = MemoryState::default();
+        let switch = SigningSwitch::default();
+        let signer = spawn_switched_signer(target, state.clone(), 1, switch.clone());
+        mock.accept().unwrap();
+        mock.run_script(&[(vote(vote::Type::Prevote, 1, 0, BLOCK_ID), Expect::Signed)])
+            .unwrap();
+        let paused_at = switch.pause().unwrap();
+        assert_eq!(paused_at.height, block::Height::from(1u32));
+        assert_eq!(paused_at.step, 1);
+        mock.run_script(&[
+            (
+                vote(vote::Type::Precommit, 1, 0, BLOCK_ID),
+                Expect::Rejected(PAUSED),
+            ),
+            (
+                ScriptStep::Proposal {
+                    height: 2,
+                    round: 0,
+                    block_id: block_id(BLOCK_ID),
+                },
+                Expect::Rejected(PAUSED),
+            ),
+        ])
+        .unwrap();
+        // pings and public key requests are still answered
+        mock.ping().unwrap();
+        mock.request_public_key().unwrap().unwrap();
+        assert_eq!(state.consensus_state(), paused_at);
+        switch.resume();
+        mock.run_script(&[(vote(vote::Type::Precommit, 1, 0, BLOCK_ID), Expect::Signed)])
+            .unwrap();
+        mock.disconnect();
+        signer.join().unwrap();
+    }
+
+    #[test]
+    fn test_unix_handover() {
+        let (mut mock, target) = unix_validator("handover");
+        let switch = SigningSwitch::default();
+        switch.pause();
+        let signer = spawn_switched_signer(target, MemoryState::default(), 1, switch.clone());
+        mock.accept().unwrap();
+        // the previous signer prevoted for the block at height 5
+        switch.resume_from(Some(State::from(consensus::State {
+            height: 5u32.into(),
+            round: 0u16.into(),
+            step: 1,
+            block_id: block_id(BLOCK_ID),
+        })));
+        mock.run_script(&[
+            (
+                vote(vote::Type::Prevote, 5, 0, OTHER_BLOCK_ID),
+                Expect::Rejected(DOUBLE_SIGN),
+            ),
+            (vote(vote::Type::Precommit, 5, 0, BLOCK_ID), Expect::Signed),
+        ])
+        .unwrap();
+        mock.disconnect();
+        signer.join().unwrap();
+    }
+
+    #[test]
+    fn test_unix_chain_id_mismatch() {
+        let (mut mock, target) = unix_validator("chain-id");
+        let signer = spawn_signer(target, MemoryState::default(), 1);
+        mock.accept().unwrap();
+        mock.request_public_key().unwrap().unwrap();
+        mock.set_chain_id(chain_id("otherchain-1"));
+        let err = mock.request_public_key().unwrap().unwrap_err();
+        assert_eq!(err.code, INVALID_CHAIN_ID);
+        mock.run_script(&[
+            (
+                vote(vote::Type::Prevote, 1, 0, BLOCK_ID),
+                Expect::Rejected(INVALID_CHAIN_ID),
+            ),
+            (
+                ScriptStep::Proposal {
+                    height: 1,
+                    round: 0,
+                    block_id: block_id(BLOCK_ID),
+                },
+                Expect::Rejected(INVALID_CHAIN_ID),
+            ),
+        ])
+        .unwrap();
+        mock.set_chain_id(chain_id("testchain-1"));
+        mock.run_script(&[(vote(vote::Type::Prevote, 1, 0, BLOCK_ID), Expect::Signed)])
+            .unwrap();
+        mock.disconnect();
+        signer.join().unwrap();
+    }
+
+    #[test]
+    fn test_tcp_secret_connection_reconnect() {
+        let mut mock =
+            MockValidator::bind_tcp("127.0.0.1:0", chain_id("testchain-1"), keypair(3)).unwrap();
+        let target = Target::Tcp(mock.tcp_addr().unwrap());
+        let state = MemoryState::default();
+        let signer = spawn_signer(target, state.clone(), 2);
+        mock.accept().unwrap();
+        mock.run_script(&[
+            (vote(vote::Type::Prevote, 5, 0, BLOCK_ID), Expect::Signed),
+            (vote(vote::Type::Precommit, 5, 0, BLOCK_ID), Expect::Signed),
+        ])
+        .unwrap();
+        mock.disconnect();
+        mock.accept().unwrap();
+        // the signer keeps its state across reconnects
+        mock.run_script(&[
+            (
+                vote(vote::Type::Precommit, 5, 0, OTHER_BLOCK_ID),
+                Expect::Rejected(DOUBLE_SIGN),
+            ),
+            (
+                vote(vote::Type::Prevote, 6, 0, OTHER_BLOCK_ID),
+                Expect::Signed,
+            ),
+        ])
+        .unwrap();
+        mock.disconnect();
+        signer.join().unwrap();
+        assert_eq!(state.consensus_state().height, block::Height::from(6u32));
+    }
+}
@@ -13,6 +13,7 @@ use tendermint::vote::{SignVoteRequest, SignedVoteResponse};
 use tendermint_p2p::secret_connection::DATA_MAX_SIZE;
 use tendermint_proto::{
     crypto::{public_key::Sum as PkSum, PublicKey as RawPublicKey},
+    google::protobuf::Timestamp,
     privval::{
         message::Sum, Message as PrivMessage, PingRequest, PingResponse, PubKeyResponse,
         RemoteSignerError, SignedProposalResponse as RawProposalResponse,
@@ -20,6 +21,32 @@ use tendermint_proto::{
     },
 };
 
+/// Earliest timestamp accepted in requests (0001-01-01T00:00:00Z)
+const MIN_TIMESTAMP_SECONDS: i64 = -62_135_596_800;
+
+/// Latest timestamp accepted in requests (9999-12-31T23:59:59Z)
+const MAX_TIMESTAMP_SECONDS: i64 = 253_402_300_799;
+
+/// Check the timestamp is within the range of valid RFC3339 dates
+/// (out-of-range values panic in the conversion to the domain type)
+fn check_timestamp(timestamp: Option<&Timestamp>) -> Result<(), Error> {
+    if let Some(ts) = timestamp {
+        if ts.seconds < MIN_TIMESTAMP_SECONDS
+            || ts.seconds > MAX_TIMESTAMP_SECONDS
+            || ts.nanos < 0
+            || ts.nanos >= 1_000_000_000
+        {
+            fail!(
+                ErrorKind::InvalidMessageError,
+                "timestamp out of range: {}s {}ns",
+                ts.seconds,
+                ts.nanos
+            );
+        }
+    }
+    Ok(())
+}
+
 /// Requests to the KMS
 #[derive(Debug)]
 pub enum Request {
@@ -44,6 +71,7 @@ impl Request {
 
         match msg {
             Some(Sum::SignVoteRequest(req)) => {
+                check_timestamp(req.vote.as_ref().and_then(|v| v.timestamp.as_ref()))?;
                 let svr = SignVoteRequest::try_from(req).map_err(|e| {
                     format_err!(
                         ErrorKind::ProtocolError,
@@ -54,6 +82,7 @@ impl Request {
                 Ok(Request::SignVote(svr))
             }
             Some(Sum::SignProposalRequest(spr)) => {
+                check_timestamp(spr.proposal.as_ref().and_then(|p| p.timestamp.as_ref()))?;
                 let spr = SignProposalRequest::try_from(spr).map_err(|e| {
                     format_err!(
                         ErrorKind::ProtocolError,
@@ -98,6 +127,12 @@ pub enum DoubleSignErrorType {
     Proposal,
 }
 
+/// possible options for paused signing error
+pub enum PausedErrorType {
+    Vote,
+    Proposal,
+}
+
 /// possible options for chain id error
 pub enum ChainIdErrorType {
     Pubkey,
@@ -141,6 +176,18 @@ impl Response {
         }
     }
 
+    /// signing paused error (e.g. during a validator migration)
+    pub fn signing_paused(req_type: PausedErrorType, height: i64) -> Self {
+        let error = RemoteSignerError {
+            code: 3,
+            description: format!("signing is paused (requested at height: {})", height),
+        };
+        match req_type {
+            PausedErrorType::Vote => Self::SignedVoteError(error),
+            PausedErrorType::Proposal => Self::SignedProposalError(error),
+        }
+    }
+
     /// invalid chain id error
     pub fn invalid_chain_id(req_type: ChainIdErrorType, chain_id: &tendermint::chain::Id) -> Self {
         let error = RemoteSignerError {
@@ -206,3 +253,95 @@ fn read_msg(conn: &mut impl Read) -> Result<Vec<u8>, Error> {
     buf.truncate(buf_read);
     Ok(buf)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tendermint_proto::privval::{
+        SignProposalRequest as RawSignProposalRequest, SignVoteRequest as RawSignVoteRequest,
+    };
+    use tendermint_proto::types::{Proposal as RawProposal, Vote as RawVote};
+
+    fn encode(sum: Sum) -> Vec<u8> {
+        let mut buf = Vec::new();
+        PrivMessage { sum: Some(sum) }
+            .encode_length_delimited(&mut buf)
+            .unwrap();
+        buf
+    }
+
+    fn vote_request(seconds: i64, nanos: i32) -> Vec<u8> {
+        encode(Sum::SignVoteRequest(RawSignVoteRequest {
+            vote: Some(RawVote {
+                r#type: 1,
+                height: 1,
+                round: 0,
+                block_id: None,
+                timestamp: Some(Timestamp { seconds, nanos }),
+                validator_address: vec![0; 20],
+                validator_index: 0,
+                signature: vec![],
+            }),
+            chain_id: "testchain-1".to_owned(),
+        }))
+    }
+
+    fn proposal_request(seconds: i64, nanos: i32) -> Vec<u8> {
+        encode(Sum::SignProposalRequest(RawSignProposalRequest {
+            proposal: Some(RawProposal {
+                r#type: 32,
+                height: 1,
+                round: 0,
+                pol_round: -1,
+                block_id: None,
+                timestamp: Some(Timestamp { seconds, nanos }),
+                signature: vec![],
+            }),
+            chain_id: "testchain-1".to_owned(),
+        }))
+    }
+
+    #[test]
+    fn test_valid_timestamps() {
+        for &(seconds, nanos) in &[
+            (0, 0),
+            (1_616_000_000, 999_999_999),
+            (MIN_TIMESTAMP_SECONDS, 0),
+            (MAX_TIMESTAMP_SECONDS, 0),
+        ] {
+            assert!(matches!(
+                Request::read(&mut &vote_request(seconds, nanos)[..]),
+                Ok(Request::SignVote(_))
+            ));
+            assert!(matches!(
+                Request::read(&mut &proposal_request(seconds, nanos)[..]),
+                Ok(Request::SignProposal(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_out_of_range_timestamps() {
+        for &(seconds, nanos) in &[
+            (i64::MAX, 0),
+            (i64::MIN, 0),
+            (MIN_TIMESTAMP_SECONDS - 1, 0),
+            (MAX_TIMESTAMP_SECONDS + 1, 0),
+            (0, -1),
+            (0, 1_000_000_000),
+        ] {
+            let err = Request::read(&mut &vote_request(seconds, nanos)[..]).unwrap_err();
+            assert_eq!(err.kind(), &ErrorKind::InvalidMessageError);
+            let err = Request::read(&mut &proposal_request(seconds, nanos)[..]).unwrap_err();
+            assert_eq!(err.kind(), &ErrorKind::InvalidMessageError);
+        }
+    }
+
+    #[test]
+    fn test_malformed_requests() {
+        for data in &[&[][..], &[0xff; 16][..], &[0x05, 0x0a][..]] {
+            let err = Request::read(&mut &data[..]).unwrap_err();
+            assert_eq!(err.kind(), &ErrorKind::ProtocolError);
+        }
+    }
+}
@@ -6,13 +6,73 @@ use crate::{
     config::validator::ValidatorConfig,
     connection::Connection,
     error::{Error, ErrorKind},
-    rpc::{ChainIdErrorType, DoubleSignErrorType, Request, Response},
+    rpc::{ChainIdErrorType, DoubleSignErrorType, PausedErrorType, Request, Response},
 };
 use anomaly::{fail, format_err};
-use ed25519_dalek::{Keypair, Signer};
+use ed25519_dalek::{Keypair, Signature, Signer};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::time::Instant;
+use tendermint::{
+    consensus,
+    proposal::{Proposal, SignProposalRequest},
+    vote::{SignVoteRequest, Vote},
+};
 use tendermint_proto::privval::PingResponse;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
+
+#[derive(Debug, Default)]
+struct SwitchState {
+    paused: bool,
+    last_state: Option<consensus::State>,
+    handover: Option<State>,
+}
+
+/// Pauses and resumes the signing of a session from another thread
+/// (e.g. so that the old signer stops before the new one starts during a validator migration).
+/// While paused, the sign requests are answered with an error,
+/// but the pings and public key requests are still answered.
+#[derive(Debug, Clone, Default)]
+pub struct SigningSwitch {
+    inner: Arc<Mutex<SwitchState>>,
+}
+
+impl SigningSwitch {
+    fn lock(&self) -> MutexGuard<'_, SwitchState> {
+        self.inner.lock().expect("signing switch lock")
+    }
+
+    /// stops signing (once a sign request being handled is finished)
+    /// and returns the last signed state
+    pub fn pause(&self) -> Option<consensus::State> {
+        let mut switch = self.lock();
+        switch.paused = true;
+        switch.last_state.clone()
+    }
+
+    /// resumes signing
+    pub fn resume(&self) {
+        self.lock().paused = false;
+    }
+
+    /// resumes signing after taking over from another signer:
+    /// the session continues from the handed-over state if it's ahead of its own
+    pub fn resume_from(&self, state: Option<State>) {
+        let mut switch = self.lock();
+        switch.handover = state;
+        switch.paused = false;
+    }
+
+    /// whether the signing is paused
+    pub fn is_paused(&self) -> bool {
+        self.lock().paused
+    }
+}
+
+/// The last signed request (so that it isn't signed again if re-sent, e.g. after a reconnect)
+struct LastSigned {
+    signable_bytes: Vec<u8>,
+    signature: Signature,
+}
 
 /// Encrypted or plain session with a validator node
 pub struct Session<S: PersistStateSync> {
@@ -30,6 +90,12 @@ pub struct Session<S: PersistStateSync> {
 
     /// consensus state persistence
     state_syncer: S,
+
+    /// pausing of signing
+    switch: SigningSwitch,
+
+    /// the last signed request and its signature
+    last_signed: Option<LastSigned>,
 }
 
 impl<S: PersistStateSync> Session<S> {
@@ -50,6 +116,87 @@ impl<S: PersistStateSync> Session<S> {
             signing_key,
             state,
             state_syncer,
+            switch: SigningSwitch::default(),
+            last_signed: None,
+        }
+    }
+
+    /// the signing can be paused and resumed with the provided switch
+    pub fn with_signing_switch(mut self, switch: SigningSwitch) -> Self {
+        switch.lock().last_state = Some(self.state.consensus_state().clone());
+        self.switch = switch;
+        self
+    }
+
+    /// the signature of the last signed request if the provided one is identical
+    fn cached_signature(&self, signable_bytes: &[u8]) -> Option<Signature> {
+        self.last_signed
+            .as_ref()
+            .filter(|last| last.signable_bytes == signable_bytes)
+            .map(|last| last.signature)
+    }
+
+    /// the proposal with the last signed timestamp and its signature
+    /// if the request differs from the last signed one in the timestamp only
+    /// (as in Tendermint's `FilePV`; the signature is persisted with the state,
+    /// so it's checked against the rebuilt proposal)
+    fn last_signed_proposal(
+        &self,
+        req: &SignProposalRequest,
+        req_cs: &consensus::State,
+    ) -> Option<(SignProposalRequest, Signature)> {
+        let (timestamp, signature) = self.state.last_signed_for(req_cs)?;
+        let last = SignProposalRequest {
+            proposal: Proposal {
+                timestamp,
+                ..req.proposal.clone()
+            },
+            chain_id: req.chain_id.clone(),
+        };
+        self.signing_key
+            .verify(&last.to_signable_vec().ok()?, &signature)
+            .ok()?;
+        Some((last, signature))
+    }
+
+    /// the vote with the last signed timestamp and its signature
+    /// if the request differs from the last signed one in the timestamp only
+    /// (as in Tendermint's `FilePV`; the signature is persisted with the state,
+    /// so it's checked against the rebuilt vote)
+    fn last_signed_vote(
+        &self,
+        req: &SignVoteRequest,
+        req_cs: &consensus::State,
+    ) -> Option<(SignVoteRequest, Signature)> {
+        let (timestamp, signature) = self.state.last_signed_for(req_cs)?;
+        let last = SignVoteRequest {
+            vote: Vote {
+                timestamp,
+                ..req.vote.clone()
+            },
+            chain_id: req.chain_id.clone(),
+        };
+        self.signing_key
+            .verify(&last.to_signable_vec().ok()?, &signature)
+            .ok()?;
+        Some((last, signature))
+    }
+
+    /// adopts the state handed over from another signer (if any)
+    fn take_over(&mut self, switch: &mut SwitchState) {
+        if let Some(handover) = switch.handover.take() {
+            if self.state.is_behind(handover.consensus_state()) {
+                let handover_cs = handover.consensus_state();
+                info!(
+                    chain_id = %self.config.chain_id,
+                    height = handover_cs.height.value(),
+                    round = handover_cs.round.value(),
+                    step = handover_cs.step,
+                    "continuing from the handed-over state"
+                );
+                switch.last_state = Some(handover_cs.clone());
+                self.state = handover;
+            }
         }
     }
 
@@ -100,47 +247,96 @@ impl<S: PersistStateSync> Session<S> {
                     self.check_max_height(req.proposal.height.into())?;
                     let request_state = State::from(req.clone());
                     let req_cs = request_state.consensus_state();
-                    match self
-                        .state
-                        .check_update_consensus_state(req_cs.clone(), &mut self.state_syncer)
+                    let signable_bytes = req.to_signable_vec().map_err(|e| {
+                        format_err!(
+                            ErrorKind::SigningError,
+                            "cannot get proposal signable bytes: {}",
+                            e
+                        )
+                    })?;
+                    // held until the signing is done, so that pausing waits for it
+                    let switch = self.switch.clone();
+                    let mut switch = switch.lock();
+                    self.take_over(&mut switch);
+                    if switch.paused {
+                        warn!(
+                            chain_id = %self.config.chain_id,
+                            height = req_cs.height.value(),
+                            round = req_cs.round.value(),
+                            step = req_cs.step,
+                            "sign request while paused (proposal)"
+                        );
+                        Response::signing_paused(PausedErrorType::Proposal, req_cs.height.into())
+                    } else if let Some(signature) = self.cached_signature(&signable_bytes) {
+                        info!(
+                            chain_id = %self.config.chain_id,
+                            height = req_cs.height.value(),
+                            round = req_cs.round.value(),
+                            step = req_cs.step,
+                            block_id_prefix = %req_cs.block_id_prefix(),
+                            "re-sent proposal, returning the cached signature"
+                        );
+                        Response::proposal_response(req, signature)
+                    } else if let Some((last, signature)) = self.last_signed_proposal(&req, req_cs)
                     {
-                        Ok(_) => {
-                            let signable_bytes = req.to_signable_vec().map_err(|e| {
-                                format_err!(
-                                    ErrorKind::SigningError,
-                                    "cannot get proposal signable bytes: {}",
-                                    e
+                        info!(
+                            chain_id = %self.config.chain_id,
+                            height = req_cs.height.value(),
+                            round = req_cs.round.value(),
+                            step = req_cs.step,
+                            block_id_prefix = %req_cs.block_id_prefix(),
+                            "re-sent proposal, returning the last signature"
+                        );
+                        Response::proposal_response(last, signature)
+                    } else {
+                        let signing_key = &self.signing_key;
+                        let started_at = Instant::now();
+                        match self.state.check_update_state(
+                            request_state.clone(),
+                            || signing_key.sign(&signable_bytes),
+                            &mut self.state_syncer,
+                        ) {
+                            Ok(signature) => {
+                                switch.last_state = Some(req_cs.clone());
+                                self.last_signed = Some(LastSigned {
+                                    signable_bytes,
+                                    signature,
+                                });
+                                info!(
+                                    chain_id = %self.config.chain_id,
+                                    height = req_cs.height.value(),
+                                    round = req_cs.round.value(),
+                                    step = req_cs.step,
+                                    block_id_prefix = %req_cs.block_id_prefix(),
+                                    elapsed_ms = started_at.elapsed().as_millis() as u64,
+                                    "signed proposal"
+                                );
+                                Response::proposal_response(req, signature)
+                            }
+                            Err(e) if e.kind() == &StateErrorKind::DoubleSign => {
+                                // Report double signing error back to the validator
+                                let original_block_id =
+                                    self.state.consensus_state().block_id_prefix();
+
+                                error!(
+                                    chain_id = %self.config.chain_id,
+                                    height = req_cs.height.value(),
+                                    round = req_cs.round.value(),
+                                    step = req_cs.step,
+                                    block_id_prefix = %req_cs.block_id_prefix(),
+                                    signed_block_id_prefix = %original_block_id,
+                                    "attempted double sign (proposal)"
+                                );
+
+                                Response::double_sign(
+                                    DoubleSignErrorType::Proposal,
+                                    req_cs.height.into(),
                                 )
-                            })?;
-                            let started_at = Instant::now();
-                            let signature = self.signing_key.sign(&signable_bytes);
-                            info!(
-                                "[{}] signed:{} at h/r/s {} ({} ms)",
-                                &self.config.chain_id,
-                                req_cs.block_id_prefix(),
-                                req_cs,
-                                started_at.elapsed().as_millis(),
-                            );
-                            Response::proposal_response(req, signature)
+                            }
+                            Err(e) => {
+                                fail!(ErrorKind::SigningError, "failed signing proposal: {}", e)
+                            }
                         }
-                        Err(e) if e.kind() == &StateErrorKind::DoubleSign => {
-                            // Report double signing error back to the validator
-                            let original_block_id = self.state.consensus_state().block_id_prefix();
-
-                            error!(
-                                "[{}] attempted double sign at h/r/s: {} ({} != {})",
-                                &self.config.chain_id,
-                                req_cs,
-                                original_block_id,
-                                req_cs.block_id_prefix()
-                            );
-
-                            Response::double_sign(
-                                DoubleSignErrorType::Proposal,
-                                req_cs.height.into(),
-                            )
-                        }
-                        Err(e) => fail!(ErrorKind::SigningError, "failed signing proposal: {}", e),
                     }
                 }
             }
@@ -151,44 +347,93 @@ impl<S: PersistStateSync> Session<S> {
                     self.check_max_height(req.vote.height.into())?;
                     let request_state = State::from(req.clone());
                     let req_cs = request_state.consensus_state();
-                    match self
-                        .state
-                        .check_update_consensus_state(req_cs.clone(), &mut self.state_syncer)
-                    {
-                        Ok(_) => {
-                            let signable_bytes = req.to_signable_vec().map_err(|e| {
-                                format_err!(
-                                    ErrorKind::SigningError,
-                                    "cannot get vote signable bytes: {}",
-                                    e
+                    let signable_bytes = req.to_signable_vec().map_err(|e| {
+                        format_err!(
+                            ErrorKind::SigningError,
+                            "cannot get vote signable bytes: {}",
+                            e
+                        )
+                    })?;
+                    // held until the signing is done, so that pausing waits for it
+                    let switch = self.switch.clone();
+                    let mut switch = switch.lock();
+                    self.take_over(&mut switch);
+                    if switch.paused {
+                        warn!(
+                            chain_id = %self.config.chain_id,
+                            height = req_cs.height.value(),
+                            round = req_cs.round.value(),
+                            step = req_cs.step,
+                            "sign request while paused (vote)"
+                        );
+                        Response::signing_paused(PausedErrorType::Vote, req_cs.height.into())
+                    } else if let Some(signature) = self.cached_signature(&signable_bytes) {
+                        info!(
+                            chain_id = %self.config.chain_id,
+                            height = req_cs.height.value(),
+                            round = req_cs.round.value(),
+                            step = req_cs.step,
+                            block_id_prefix = %req_cs.block_id_prefix(),
+                            "re-sent vote, returning the cached signature"
+                        );
+                        Response::vote_response(req, signature)
+                    } else if let Some((last, signature)) = self.last_signed_vote(&req, req_cs) {
+                        info!(
+                            chain_id = %self.config.chain_id,
+                            height = req_cs.height.value(),
+                            round = req_cs.round.value(),
+                            step = req_cs.step,
+                            block_id_prefix = %req_cs.block_id_prefix(),
+                            "re-sent vote, returning the last signature"
+                        );
+                        Response::vote_response(last, signature)
+                    } else {
+                        let signing_key = &self.signing_key;
+                        let started_at = Instant::now();
+                        match self.state.check_update_state(
+                            request_state.clone(),
+                            || signing_key.sign(&signable_bytes),
+                            &mut self.state_syncer,
+                        ) {
+                            Ok(signature) => {
+                                switch.last_state = Some(req_cs.clone());
+                                self.last_signed = Some(LastSigned {
+                                    signable_bytes,
+                                    signature,
+                                });
+                                info!(
+                                    chain_id = %self.config.chain_id,
+                                    height = req_cs.height.value(),
+                                    round = req_cs.round.value(),
+                                    step = req_cs.step,
+                                    block_id_prefix = %req_cs.block_id_prefix(),
+                                    elapsed_ms = started_at.elapsed().as_millis() as u64,
+                                    "signed vote"
+                                );
+                                Response::vote_response(req, signature)
+                            }
+                            Err(e) if e.kind() == &StateErrorKind::DoubleSign => {
+                                // Report double signing error back to the validator
+                                let original_block_id =
+                                    self.state.consensus_state().block_id_prefix();
+
+                                error!(
+                                    chain_id = %self.config.chain_id,
+                                    height = req_cs.height.value(),
+                                    round = req_cs.round.value(),
+                                    step = req_cs.step,
+                                    block_id_prefix = %req_cs.block_id_prefix(),
+                                    signed_block_id_prefix = %original_block_id,
+                                    "attempted double sign (vote)"
+                                );
+
+                                Response::double_sign(
+                                    DoubleSignErrorType::Vote,
+                                    req_cs.height.into(),
                                 )
-                            })?;
-                            let started_at = Instant::now();
-                            let signature = self.signing_key.sign(&signable_bytes);
-                            info!(
-                                "[{}] signed:{} at h/r/s {} ({} ms)",
-                                &self.config.chain_id,
-                                req_cs.block_id_prefix(),
-                                req_cs,
-                                started_at.elapsed().as_millis(),
-                            );
-                            Response::vote_response(req, signature)
-                        }
-                        Err(e) if e.kind() == &StateErrorKind::DoubleSign => {
-                            // Report double signing error back to the validator
-                            let original_block_id = self.state.consensus_state().block_id_prefix();
-
-                            error!(
-                                "[{}] attempted double sign at h/r/s: {} ({} != {})",
-                                &self.config.chain_id,
-                                req_cs,
-                                original_block_id,
-                                req_cs.block_id_prefix()
-                            );
-
-                            Response::double_sign(DoubleSignErrorType::Vote, req_cs.height.into())
+                            }
+                            Err(e) => fail!(ErrorKind::SigningError, "failed signing vote: {}", e),
                         }
-                        Err(e) => fail!(ErrorKind::SigningError, "failed signing vote: {}", e),
                     }
                 }
             }
@@ -3,13 +3,21 @@ use std::io::{self, Read, Write};
 use std::str::FromStr;
 use tracing::{debug, trace};
 
-use crate::error::{Error, ErrorKind::IoError};
+use crate::error::{
+    Error,
+    ErrorKind::{FramingError, IoError},
+};
 
 /// Options for displaying public key
 #[derive(Debug)]
 pub enum PubkeyDisplay {
     Base64,
+    /// the legacy amino-encoded key (e.g. `cosmosvalconspub...`)
     Bech32,
+    /// the Cosmos SDK JSON (e.g. for the `create-validator` transaction)
+    Json,
+    /// the address and public key as in Tendermint's `priv_validator_key.json`
+    Tendermint,
 }
 
 impl FromStr for PubkeyDisplay {
@@ -19,62 +27,88 @@ impl FromStr for PubkeyDisplay {
         match s {
             "base64" => Ok(PubkeyDisplay::Base64),
             "bech32" => Ok(PubkeyDisplay::Bech32),
+            "json" => Ok(PubkeyDisplay::Json),
+            "tendermint" => Ok(PubkeyDisplay::Tendermint),
             _ => Err("unknown display type".to_owned()),
         }
     }
 }
 
-/// prints public key in the desired format
-pub fn print_pubkey(
+/// the consensus address (the hex-encoded truncated SHA-256 hash of the public key)
+pub fn consensus_address(public: ed25519_dalek::PublicKey) -> String {
+    tendermint::account::Id::from(public).to_string()
+}
+
+/// formats public key in the desired format
+pub fn format_pubkey(
     bech32_prefix: Option<String>,
     ptype: Option<PubkeyDisplay>,
     public: ed25519_dalek::PublicKey,
-) {
+) -> String {
+    let base64 = String::from_utf8(subtle_encoding::base64::encode(public)).unwrap();
     match ptype {
         Some(PubkeyDisplay::Bech32) => {
             let prefix = bech32_prefix.unwrap_or_else(|| "cosmosvalconspub".to_owned());
             let mut data = vec![0x16, 0x24, 0xDE, 0x64, 0x20];
             data.extend_from_slice(public.as_bytes());
-            println!(
+            format!(
                 "public key: {}",
                 subtle_encoding::bech32::encode(prefix, data)
-            );
-        }
-        _ => {
-            println!(
-                "public key: {}",
-                String::from_utf8(subtle_encoding::base64::encode(public)).unwrap()
-            );
-            let id = tendermint::node::Id::from(public);
-            println!("address: {}", id);
+            )
         }
+        // the base64 and hex encodings don't need escaping
+        Some(PubkeyDisplay::Json) => format!(
+            r#"{{"@type":"/cosmos.crypto.ed25519.PubKey","key":"{}"}}"#,
+            base64
+        ),
+        Some(PubkeyDisplay::Tendermint) => format!(
+            r#"{{"address":"{}","pub_key":{{"type":"tendermint/PubKeyEd25519","value":"{}"}}}}"#,
+            consensus_address(public),
+            base64
+        ),
+        _ => format!(
+            "public key: {}\naddress: {}",
+            base64,
+            tendermint::node::Id::from(public)
+        ),
     }
 }
 
+/// prints public key in the desired format
+pub fn print_pubkey(
+    bech32_prefix: Option<String>,
+    ptype: Option<PubkeyDisplay>,
+    public: ed25519_dalek::PublicKey,
+) {
+    println!("{}", format_pubkey(bech32_prefix, ptype, public));
+}
+
 /// Read u16-size payload (for vsock)
 pub fn read_u16_payload<S: Read>(stream: &mut S) -> Result<Vec<u8>, Error> {
     let mut len_b = [0u8; 2];
+    // no byte read = the stream was closed, one byte = truncated length
     stream
-        .read_exact(&mut len_b)
+        .read_exact(&mut len_b[..1])
         .map_err(|e| format_err!(IoError, "error reading len: {}", e))?;
+    stream.read_exact(&mut len_b[1..]).map_err(|e| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            format_err!(FramingError, "truncated length")
+        } else {
+            format_err!(IoError, "error reading len: {}", e)
+        }
+    })?;
 
     let l = (u16::from_le_bytes(len_b)) as usize;
     if l > 0 {
-        let mut state_raw = vec![0u8; l];
-        let mut total = 0;
-
-        while let Ok(n) = stream.read(&mut state_raw[total..]) {
-            if n == 0 || n + total > l {
-                break;
+        let mut payload = vec![0u8; l];
+        stream.read_exact(&mut payload).map_err(|e| {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                format_err!(FramingError, "truncated payload (expected {} bytes)", l)
+            } else {
+                format_err!(IoError, "error reading payload: {}", e)
             }
-            total += n;
-        }
-
-        if total == 0 {
-            return Err(IoError.into());
-        }
-        state_raw.resize(total, 0);
-        Ok(state_raw)
+        })?;
+        Ok(payload)
     } else {
         trace!("read empty payload");
         Ok(Vec::default())
@@ -95,3 +129,89 @@ pub fn write_u16_payload<S: Write>(stream: &mut S, data: &[u8]) -> io::Result<()
     debug!("successfully wrote u16-sized payload");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorKind;
+
+    /// returns at most `chunk` bytes per read
+    struct ChunkedReader<'a> {
+        data: &'a [u8],
+        chunk: usize,
+    }
+
+    impl<'a> Read for ChunkedReader<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.chunk.min(buf.len()).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_format_pubkey() {
+        let secret = ed25519_dalek::SecretKey::from_bytes(&[1u8; 32]).unwrap();
+        let public = ed25519_dalek::PublicKey::from(&secret);
+        let address = consensus_address(public);
+        assert_eq!(address, "34750F98BD59FCFC946DA45AAABE933BE154A4B5");
+        let base64 = "iojj3XQJ8ZX9UtstPLpdcspnCb8dlBIb83SIAbQPb1w=";
+        assert_eq!(
+            format_pubkey(None, None, public),
+            format!("public key: {}\naddress: {}", base64, address)
+        );
+        // a single line (as before the other formats were added)
+        let bech32 = format_pubkey(
+            Some("crocnclconspub".to_owned()),
+            Some(PubkeyDisplay::Bech32),
+            public,
+        );
+        assert!(bech32.starts_with("public key: crocnclconspub1zcjduepq"));
+        assert!(!bech32.contains('\n'));
+        let json: serde_json::Value =
+            serde_json::from_str(&format_pubkey(None, Some(PubkeyDisplay::Json), public)).unwrap();
+        assert_eq!(json["@type"], "/cosmos.crypto.ed25519.PubKey");
+        assert_eq!(json["key"], base64);
+        let json: serde_json::Value = serde_json::from_str(&format_pubkey(
+            None,
+            Some(PubkeyDisplay::Tendermint),
+            public,
+        ))
+        .unwrap();
+        assert_eq!(json["address"], address.as_str());
+        assert_eq!(json["pub_key"]["type"], "tendermint/PubKeyEd25519");
+        assert_eq!(json["pub_key"]["value"], base64);
+    }
+
+    #[test]
+    fn test_u16_payload_roundtrip() {
+        for &len in &[0usize, 1, 1000, u16::MAX as usize] {
+            let payload = vec![0xab; len];
+            let mut buf = Vec::new();
+            write_u16_payload(&mut buf, &payload).unwrap();
+            let mut reader = ChunkedReader {
+                data: &buf,
+                chunk: 7,
+            };
+            assert_eq!(read_u16_payload(&mut reader).unwrap(), payload);
+        }
+    }
+
+    #[test]
+    fn test_u16_payload_oversize() {
+        let payload = vec![0u8; u16::MAX as usize + 1];
+        let err = write_u16_payload(&mut Vec::new(), &payload).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_u16_payload_truncated() {
+        let err = read_u16_payload(&mut &[0x05, 0x00, 0x01, 0x02][..]).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::FramingError);
+        let err = read_u16_payload(&mut &[0x05][..]).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::FramingError);
+        let err = read_u16_payload(&mut &[][..]).unwrap_err();
+        assert_eq!(err.kind(), &ErrorKind::IoError);
+    }
+}